@@ -0,0 +1,230 @@
+use crate::state::DEFAULT_REWARD_RATE;
+use solana_program::clock::UnixTimestamp;
+
+/// Formats a raw reward token amount for display, placing the decimal point
+/// `decimals` digits from the right, e.g. `1500000` with 6 decimals is
+/// `"1.500000"`.
+pub fn format_reward(raw_amount: u64, decimals: u8) -> String {
+    if decimals == 0 {
+        return raw_amount.to_string();
+    }
+    let decimals = decimals as usize;
+    let digits = format!("{:0>width$}", raw_amount, width = decimals + 1);
+    let (whole, fraction) = digits.split_at(digits.len() - decimals);
+    format!("{}.{}", whole, fraction)
+}
+
+/// A recorded event affecting a single stake's accrual, at unix time `at`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccrualEvent {
+    Staked { at: UnixTimestamp },
+    RateChanged { at: UnixTimestamp, rate: u64 },
+    Paused { at: UnixTimestamp },
+    Resumed { at: UnixTimestamp },
+    Redeemed { at: UnixTimestamp },
+    Unstaked { at: UnixTimestamp },
+}
+
+/// Replays a stake's recorded accrual events, in order, and returns the total
+/// reward its redeems and unstakes should have paid. The rate starts at
+/// `DEFAULT_REWARD_RATE` per second; paused and unstaked time accrues nothing,
+/// and time running backwards between events accrues nothing either. Only the
+/// base per-second accrual is modelled: stake weights, boosts, tiers and fees
+/// are left to the caller.
+pub fn replay_accrual(events: &[AccrualEvent]) -> u64 {
+    let mut rate = DEFAULT_REWARD_RATE;
+    let mut staked = false;
+    let mut paused = false;
+    let mut cursor: UnixTimestamp = 0;
+    let mut pending: u64 = 0;
+    let mut total: u64 = 0;
+    for event in events {
+        let at = match *event {
+            AccrualEvent::Staked { at }
+            | AccrualEvent::RateChanged { at, .. }
+            | AccrualEvent::Paused { at }
+            | AccrualEvent::Resumed { at }
+            | AccrualEvent::Redeemed { at }
+            | AccrualEvent::Unstaked { at } => at,
+        };
+        if staked && !paused && at > cursor {
+            pending = pending.saturating_add(((at - cursor) as u64).saturating_mul(rate));
+        }
+        cursor = cursor.max(at);
+        match *event {
+            AccrualEvent::Staked { .. } => staked = true,
+            AccrualEvent::RateChanged { rate: new_rate, .. } => rate = new_rate,
+            AccrualEvent::Paused { .. } => paused = true,
+            AccrualEvent::Resumed { .. } => paused = false,
+            AccrualEvent::Redeemed { .. } => {
+                total = total.saturating_add(pending);
+                pending = 0;
+            }
+            AccrualEvent::Unstaked { .. } => {
+                total = total.saturating_add(pending);
+                pending = 0;
+                staked = false;
+            }
+        }
+    }
+    total
+}
+
+/// A reward paid to a stake, as reconstructed off-chain from its events.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ClaimReceipt {
+    pub amount: u64,
+    pub claimed_at: UnixTimestamp,
+}
+
+/// Total of the `receipts` claimed within `[from, to)`, e.g. a tax year.
+pub fn aggregate_rewards(receipts: &[ClaimReceipt], from: UnixTimestamp, to: UnixTimestamp) -> u64 {
+    receipts
+        .iter()
+        .filter(|receipt| receipt.claimed_at >= from && receipt.claimed_at < to)
+        .fold(0u64, |total, receipt| total.saturating_add(receipt.amount))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor::{process_redeem, process_set_paused, process_unstake};
+    use crate::test_utils::*;
+    use solana_program::{native_token::LAMPORTS_PER_SOL, pubkey::Pubkey};
+
+    #[test]
+    fn format_reward_places_the_decimal_point() {
+        assert_eq!(format_reward(1_500_000, 6), "1.500000");
+        assert_eq!(format_reward(123_456_789, 9), "0.123456789");
+        assert_eq!(format_reward(42, 2), "0.42");
+    }
+
+    #[test]
+    fn format_reward_pads_amounts_below_one_unit() {
+        assert_eq!(format_reward(0, 6), "0.000000");
+        assert_eq!(format_reward(1, 6), "0.000001");
+        assert_eq!(format_reward(10, 9), "0.000000010");
+    }
+
+    #[test]
+    fn format_reward_without_decimals_is_the_raw_amount() {
+        assert_eq!(format_reward(0, 0), "0");
+        assert_eq!(format_reward(1_500_000, 0), "1500000");
+    }
+
+    #[test]
+    fn format_reward_handles_the_full_u64_range() {
+        assert_eq!(format_reward(u64::MAX, 9), "18446744073.709551615");
+        assert_eq!(format_reward(u64::MAX, 20), "0.18446744073709551615");
+    }
+
+    #[test]
+    fn replay_accrual_sums_redeems_across_rate_changes_and_pauses() {
+        let events = [
+            AccrualEvent::Staked { at: 0 },
+            AccrualEvent::Redeemed { at: 10 },
+            AccrualEvent::RateChanged { at: 20, rate: 3 },
+            AccrualEvent::Paused { at: 30 },
+            AccrualEvent::Resumed { at: 40 },
+            AccrualEvent::Unstaked { at: 50 },
+        ];
+        assert_eq!(replay_accrual(&events), 10 + 10 + 3 * 10 + 3 * 10);
+    }
+
+    #[test]
+    fn replay_accrual_ignores_unstaked_and_backwards_time() {
+        let events = [
+            AccrualEvent::Redeemed { at: 5 },
+            AccrualEvent::Staked { at: 10 },
+            AccrualEvent::Redeemed { at: 20 },
+            AccrualEvent::Redeemed { at: 15 },
+            AccrualEvent::Unstaked { at: 30 },
+            AccrualEvent::Redeemed { at: 40 },
+            AccrualEvent::Staked { at: 100 },
+            AccrualEvent::Unstaked { at: 101 },
+        ];
+        assert_eq!(replay_accrual(&events), 10 + 10 + 1);
+        // Accrual still pending is not counted until redeemed.
+        assert_eq!(replay_accrual(&events[..3]), 10);
+        assert_eq!(replay_accrual(&[AccrualEvent::Staked { at: 0 }]), 0);
+    }
+
+    #[test]
+    fn replay_accrual_matches_what_the_program_pays() {
+        setup();
+        let user = Pubkey::new_unique();
+        let (config, reward_accounts) = reward_pool(user);
+        let nft_mint = Pubkey::new_unique();
+        let nft_token_account = token_account(Pubkey::new_unique(), nft_mint, user, 1);
+        let account_data = active_stake_info(user, nft_token_account.key(), nft_mint, START);
+        let stake_state = stake_account(&account_data);
+        let staked_mint = staked_mint_account(&nft_mint, stake_state.key());
+        // The `Unstake` accounts, then the admin.
+        let mut accounts = vec![
+            TestAccount::wallet(user, LAMPORTS_PER_SOL).signer(),
+            nft_token_account,
+            stake_state,
+            staked_mint,
+            user_stake_account(user, 1),
+            config_account(&config),
+        ];
+        accounts.extend(reward_accounts);
+        accounts.push(TestAccount::wallet(config.admin, LAMPORTS_PER_SOL).signer());
+        let infos = infos(&mut accounts);
+        let pick =
+            |indices: &[usize]| -> Vec<_> { indices.iter().map(|&i| infos[i].clone()).collect() };
+        let redeem_accounts = pick(&[0, 1, 2, 5, 6, 7, 8, 9, 10]);
+        let admin_accounts = pick(&[11, 5]);
+
+        set_time(START + 30);
+        process_redeem(&PROGRAM_ID, &redeem_accounts, None, None).unwrap();
+        set_time(START + 50);
+        process_set_paused(&PROGRAM_ID, &admin_accounts, true).unwrap();
+        set_time(START + 80);
+        process_set_paused(&PROGRAM_ID, &admin_accounts, false).unwrap();
+        set_time(START + 100);
+        process_unstake(&PROGRAM_ID, &infos[..11], None).unwrap();
+        drop(infos);
+
+        let events = [
+            AccrualEvent::Staked { at: START },
+            AccrualEvent::Redeemed { at: START + 30 },
+            AccrualEvent::Paused { at: START + 50 },
+            AccrualEvent::Resumed { at: START + 80 },
+            AccrualEvent::Unstaked { at: START + 100 },
+        ];
+        assert_eq!(accounts[7].token_amount(), 70);
+        assert_eq!(replay_accrual(&events), 70);
+    }
+
+    #[test]
+    fn aggregate_rewards_sums_receipts_within_the_period() {
+        let receipt = |amount, claimed_at| ClaimReceipt { amount, claimed_at };
+        let receipts = [
+            receipt(1, 99),
+            receipt(10, 100),
+            receipt(20, 150),
+            receipt(40, 199),
+            receipt(80, 200),
+        ];
+        assert_eq!(aggregate_rewards(&receipts, 100, 200), 10 + 20 + 40);
+        assert_eq!(aggregate_rewards(&receipts, 0, UnixTimestamp::MAX), 151);
+        assert_eq!(aggregate_rewards(&receipts, 200, 100), 0);
+        assert_eq!(aggregate_rewards(&[], 0, 100), 0);
+    }
+
+    #[test]
+    fn aggregate_rewards_saturates() {
+        let receipts = [
+            ClaimReceipt {
+                amount: u64::MAX,
+                claimed_at: 0,
+            },
+            ClaimReceipt {
+                amount: 1,
+                claimed_at: 1,
+            },
+        ];
+        assert_eq!(aggregate_rewards(&receipts, 0, 2), u64::MAX);
+    }
+}
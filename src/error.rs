@@ -11,6 +11,114 @@ pub enum StakeError {
     InvalidTokenAccount,
     #[error("Invalid stake account passed")]
     InvalidStakeAccount,
+    #[error("Batch is empty, malformed or too large")]
+    InvalidBatchSize,
+    #[error("Reward arithmetic overflowed")]
+    RewardOverflow,
+    #[error("Clock sysvar returned a non-positive unix timestamp")]
+    InvalidClock,
+    #[error("Basis points value exceeds 10000")]
+    InvalidFeeBps,
+    #[error("Signer is not the config admin")]
+    Unauthorized,
+    #[error("Reward mint authority is not the program PDA")]
+    InvalidMintAuthority,
+    #[error("Invalid reward mint passed")]
+    InvalidRewardMint,
+    #[error("Invalid protocol fee account passed")]
+    InvalidProtocolFeeAccount,
+    #[error("Stake account uses a legacy layout and must be migrated")]
+    AccountNeedsMigration,
+    #[error("Emission schedule is unsorted or too long")]
+    InvalidEmissionSchedule,
+    #[error("NFT mint is already staked through another stake account")]
+    NftAlreadyStaked,
+    #[error("Invalid governance token account passed")]
+    InvalidGovernanceAccount,
+    #[error("Boost tiers are unsorted, too many or exceed 10000 bps")]
+    InvalidBoostTiers,
+    #[error("User reached the maximum number of concurrent stakes")]
+    UserStakeLimitReached,
+    #[error("Audit merkle tree is not configured or does not match")]
+    InvalidAuditTree,
+    #[error("Swap returned less than the minimum amount out")]
+    SlippageExceeded,
+    #[error("Swap program or output account is not configured or does not match")]
+    InvalidSwapRoute,
+    #[error("Staked amount is below the configured minimum")]
+    StakeBelowMinimum,
+    #[error("Program reached the maximum number of unique stakers")]
+    StakerLimitReached,
+    #[error("No reward accrued since the last redeem")]
+    NoRewardAccrued,
+    #[error("Floor price feed is stale or invalid")]
+    StaleOracle,
+    #[error("Final reward grace window has expired")]
+    FinalRewardExpired,
+    #[error("Signer is neither the owner nor the approved delegate of the token account")]
+    InvalidStakeAuthority,
+    #[error("Duration tiers are unsorted or too many")]
+    TiersNotSorted,
+    #[error("Burning staked NFTs is disabled or was not confirmed")]
+    BurnDisabled,
+    #[error("Airdrop snapshot has no room for more stake accounts")]
+    SnapshotFull,
+    #[error("Redeem nonce was already used")]
+    DuplicateNonce,
+    #[error("Reward destination is not the preferred one or holds another mint")]
+    InvalidRewardDestination,
+    #[error("Compounding is disabled")]
+    CompoundingDisabled,
+    #[error("Wallet is younger than the minimum wallet age")]
+    WalletTooNew,
+    #[error("Token account is protected and cannot be recovered from")]
+    ProtectedAccount,
+    #[error("No room to record another pause interval")]
+    PauseHistoryFull,
+    #[error("NFT mint authority is not a recognized bridge")]
+    UnsupportedWrappedMint,
+    #[error("Reward payouts are withheld while accrual is paused")]
+    RewardsPaused,
+    #[error("NFT was unstaked too recently to be staked again")]
+    RestakeCooloffActive,
+    #[error("Rent reserve cannot cover the rent")]
+    RentReserveEmpty,
+    #[error("Config changes must be made through the timelock program")]
+    TimelockRequired,
+    #[error("Payer cannot cover the rent of the new account")]
+    InsufficientFundsForRent,
+    #[error("Telemetry counter overflowed")]
+    CounterOverflow,
+    #[error("Too many entries for a bounded config list")]
+    TooManyEntries,
+    #[error("Stake account is still active")]
+    StakeStillActive,
+    #[error("Circuit breaker is tripped")]
+    CircuitBreakerTripped,
+    #[error("Ownership proof is invalid")]
+    InvalidProof,
+    #[error("Stake total_redeemed reached its maximum")]
+    TotalRedeemedSaturated,
+    #[error("Token program does not match the configured one")]
+    WrongTokenProgram,
+    #[error("Points were already converted to tokens")]
+    PointsAlreadyConverted,
+    #[error("Too few NFTs staked to qualify for rewards")]
+    BelowMinimumSetSize,
+    #[error("Compliance check denied the user")]
+    ComplianceCheckFailed,
+    #[error("Compressed NFT proof does not match the configured tree")]
+    InvalidCnftProof,
+    #[error("SOL reward pool holds too few lamports")]
+    InsufficientPoolLamports,
+    #[error("Scheduled change is beyond the configured horizon")]
+    ScheduleTooFar,
+    #[error("Stake is not active")]
+    StakeNotActive,
+    #[error("Pool is finalized")]
+    PoolFinalized,
+    #[error("Transaction is past its valid_until_slot")]
+    TransactionExpired,
 }
 
 impl From<StakeError> for ProgramError {
@@ -0,0 +1,165 @@
+use crate::instruction::ConfigField;
+use borsh::{BorshDeserialize, BorshSerialize};
+use solana_program::{clock::UnixTimestamp, log::sol_log_data, pubkey::Pubkey};
+
+/// Emitted once by every successful state-changing instruction.
+///
+/// Encoding contract: each event is logged with `sol_log_data` as a single
+/// field holding the borsh-serialized `StakeEvent`, which the runtime prints
+/// as `Program data: <base64>`. The first byte is the variant index; variants
+/// are append-only and their fields are never reordered, so indexers can
+/// decode events from any program version.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub enum StakeEvent {
+    StakeAccountInitialized {
+        stake_state: Pubkey,
+        user: Pubkey,
+        nft_token_account: Pubkey,
+    },
+    Staked {
+        stake_state: Pubkey,
+        user: Pubkey,
+        nft_mint: Pubkey,
+        stake_weight: u64,
+        staked_at: UnixTimestamp,
+    },
+    Redeemed {
+        stake_state: Pubkey,
+        user: Pubkey,
+        amount: u64,
+        redeemed_at: UnixTimestamp,
+    },
+    Unstaked {
+        stake_state: Pubkey,
+        user: Pubkey,
+        nft_mint: Pubkey,
+        reward: u64,
+        reward_deferred: bool,
+        unstaked_at: UnixTimestamp,
+    },
+    ConfigInitialized {
+        admin: Pubkey,
+        reward_mint: Pubkey,
+        protocol_fee_account: Pubkey,
+        protocol_fee_bps: u16,
+        withhold_protocol_fee: bool,
+    },
+    ConfigUpdated {
+        field: ConfigField,
+    },
+    StakeAccountMigrated {
+        stake_state: Pubkey,
+    },
+    StakePositionTransferred {
+        from_stake_state: Pubkey,
+        to_stake_state: Pubkey,
+        new_owner: Pubkey,
+        reward: u64,
+    },
+    LastRedeemTimeReset {
+        stake_state: Pubkey,
+        last_redeem_time: UnixTimestamp,
+    },
+    FinalRewardClaimed {
+        stake_state: Pubkey,
+        amount: u64,
+    },
+    NftBurned {
+        stake_state: Pubkey,
+        nft_mint: Pubkey,
+        reward: u64,
+    },
+    SnapshotUpdated {
+        snapshot_id: u64,
+        stake_accounts: u32,
+    },
+    RewardDestinationSet {
+        stake_state: Pubkey,
+        destination: Pubkey,
+    },
+    RewardCompounded {
+        stake_state: Pubkey,
+        amount: u64,
+        virtual_staked_amount: u64,
+    },
+    WalletAgeAttested {
+        wallet: Pubkey,
+        first_seen_slot: u64,
+    },
+    StrayTokensRecovered {
+        token_account: Pubkey,
+        mint: Pubkey,
+        amount: u64,
+    },
+    RateOverrideSet {
+        stake_state: Pubkey,
+        rate: Option<u64>,
+    },
+    PausedSet {
+        paused: bool,
+        at: UnixTimestamp,
+    },
+    StakesConsolidated {
+        stake_state: Pubkey,
+        sources: u32,
+        reward: u64,
+    },
+    RewardMintInitialized {
+        reward_mint: Pubkey,
+        protocol_fee_account: Pubkey,
+        decimals: u8,
+    },
+    RentReserveFunded {
+        lamports: u64,
+    },
+    MintAuthorityRotated {
+        old_authority: Pubkey,
+        new_authority: Pubkey,
+    },
+    InactiveStakesClosed {
+        user: Pubkey,
+        closed: u32,
+        refunded_lamports: u64,
+    },
+    OwnershipProofVerified {
+        stake_state: Pubkey,
+        owner: Pubkey,
+    },
+    ActiveFlagReconciled {
+        stake_state: Pubkey,
+        reconciled_by: Pubkey,
+        reconciled_at: UnixTimestamp,
+    },
+    PointsConverted {
+        stake_state: Pubkey,
+        points: u64,
+        amount: u64,
+    },
+    ConfigMigrated {
+        from_version: u8,
+        to_version: u8,
+    },
+    RewardCheckpointed {
+        stake_state: Pubkey,
+        checkpoint_reward: u64,
+        checkpointed_at: UnixTimestamp,
+    },
+    SolPoolFunded {
+        lamports: u64,
+    },
+    PoolFinalized {
+        admin: Pubkey,
+        finalized_at: UnixTimestamp,
+    },
+    RewardMintSwapped {
+        old_mint: Pubkey,
+        new_mint: Pubkey,
+        protocol_fee_account: Pubkey,
+    },
+}
+
+pub fn emit(event: StakeEvent) {
+    if let Ok(data) = event.try_to_vec() {
+        sol_log_data(&[&data]);
+    }
+}
@@ -1,22 +1,344 @@
-use solana_program::program_error::ProgramError;
+use crate::state::{
+    AccrualMode, DurationTier, EmissionSegment, FeeDiscountTier, GovernanceBoostTier, Rounding,
+};
+use borsh::{BorshDeserialize, BorshSerialize};
+use solana_program::{program_error::ProgramError, pubkey::Pubkey};
+
+#[derive(BorshSerialize, BorshDeserialize, Clone)]
+pub enum ConfigField {
+    ProtocolFeeBps(u16),
+    WithholdProtocolFee(bool),
+    EmissionSchedule(Vec<EmissionSegment>),
+    MaxStakesPerUser(u32),
+    GovernanceMint(Pubkey),
+    GovernanceBoostTiers(Vec<GovernanceBoostTier>),
+    AuditMerkleTree(Pubkey),
+    SwapProgram(Pubkey),
+    SwapTargetMint(Pubkey),
+    MinStakeAmount(u64),
+    MaxStakers(u32),
+    RejectZeroRewardRedeem(bool),
+    FloorPriceFeed(Pubkey),
+    MaxFloorPriceAge(i64),
+    FinalRewardGracePeriod(i64),
+    DurationTiers(Vec<DurationTier>),
+    AllowBurn(bool),
+    BurnReward(u64),
+    StrictNonce(bool),
+    CompoundRateBps(u16),
+    MaxVirtualStakedAmount(u64),
+    MinWalletAgeSlots(u64),
+    LogComputeUnits(bool),
+    FinalRewardRounding(Rounding),
+    RewardCliff(i64),
+    CliffPaysFromStart(bool),
+    ProofOfHold(bool),
+    MaxBackpaySeconds(i64),
+    WrappedMintAuthorities(Vec<Pubkey>),
+    FeeDiscountTiers(Vec<FeeDiscountTier>),
+    MinProtocolFeeBps(u16),
+    PayRewardOnPausedUnstake(bool),
+    MasterEditionBoostBps(u16),
+    RestakeCooloff(i64),
+    ProratePartialDays(bool),
+    StakeDilutionDivisor(u32),
+    SponsorRent(bool),
+    TimelockProgram(Pubkey),
+    AutoRedeemOnUnstake(bool),
+    RewardMilestones(Vec<DurationTier>),
+    CircuitBreaker(Pubkey),
+    StrictTotalRedeemed(bool),
+    TokenProgram(Pubkey),
+    AccrualMode(AccrualMode),
+    PointsToTokenRate(u64),
+    MinNftsToQualify(u32),
+    ComplianceProgram(Pubkey),
+    CompressedNftTree(Pubkey),
+    RequireCurrentSeedVersion(bool),
+    MaxScheduleHorizon(i64),
+    AccrueFromInitialize(bool),
+    DelegateStakeAuthorities(Vec<Pubkey>),
+}
+
+/// Several config changes applied atomically by `UpdateConfigBatch`.
+#[derive(BorshDeserialize)]
+pub struct ConfigUpdate {
+    pub fields: Vec<ConfigField>,
+    pub paused: Option<bool>,
+}
+
+/// A compressed NFT's Bubblegum leaf and where it sits in its tree, for
+/// `StakeCompressed`. The `proof_len` proof nodes are passed as accounts.
+#[derive(BorshDeserialize)]
+pub struct CompressedNftLeaf {
+    pub root: [u8; 32],
+    pub data_hash: [u8; 32],
+    pub creator_hash: [u8; 32],
+    pub nonce: u64,
+    pub index: u32,
+    pub delegate: Pubkey,
+    pub proof_len: u8,
+}
+
+#[derive(BorshDeserialize)]
+struct InitializeConfigPayload {
+    protocol_fee_bps: u16,
+    withhold_protocol_fee: bool,
+}
+
+#[derive(BorshDeserialize)]
+struct RedeemAndSwapPayload {
+    min_amount_out: u64,
+    swap_accounts_len: u8,
+    swap_data: Vec<u8>,
+}
+
+#[derive(BorshDeserialize)]
+struct SnapshotStakersPayload {
+    snapshot_id: u64,
+    min_duration: i64,
+}
+
+#[derive(BorshDeserialize)]
+struct AttestWalletAgePayload {
+    wallet: Pubkey,
+    first_seen_slot: u64,
+}
+
+#[derive(BorshDeserialize)]
+struct SwapRewardMintPayload {
+    new_mint: Pubkey,
+    old_decimals: u8,
+}
 
 pub enum StakeInstruction {
     InitializeStakeAccount,
     Stake,
-    Redeem,
-    Unstake,
+    Redeem {
+        nonce: Option<u64>,
+        valid_until_slot: Option<u64>,
+    },
+    Unstake {
+        valid_until_slot: Option<u64>,
+    },
+    UnstakeBatch,
+    ReconcileStake,
+    InitializeConfig {
+        protocol_fee_bps: u16,
+        withhold_protocol_fee: bool,
+    },
+    UpdateConfig(ConfigField),
+    MigrateStakeAccount,
+    TransferStakePosition {
+        new_owner: Pubkey,
+    },
+    RedeemCompressed,
+    RedeemAndSwap {
+        min_amount_out: u64,
+        swap_accounts_len: u8,
+        swap_data: Vec<u8>,
+    },
+    ResetLastRedeemTime,
+    GetEffectiveRate,
+    ClaimFinalReward,
+    BurnAndReward {
+        confirm_burn: bool,
+    },
+    SnapshotStakers {
+        snapshot_id: u64,
+        min_duration: i64,
+    },
+    SetRewardDestination {
+        destination: Pubkey,
+    },
+    CompoundReward,
+    AttestWalletAge {
+        wallet: Pubkey,
+        first_seen_slot: u64,
+    },
+    RecoverStrayTokens,
+    SetRateOverride {
+        rate: Option<u64>,
+    },
+    SetPaused {
+        paused: bool,
+    },
+    CanUnstake,
+    ConsolidateStakes,
+    InitializeRewardMint {
+        decimals: u8,
+    },
+    GetConfigVersion,
+    FundRentReserve {
+        lamports: u64,
+    },
+    UpdateConfigBatch(ConfigUpdate),
+    RotateMintAuthority {
+        new_authority: Pubkey,
+    },
+    CloseInactiveStakes,
+    SubmitOwnershipProof,
+    ReconcileActiveFlag,
+    GetRewardBreakdown,
+    ConvertPointsToTokens,
+    PreviewReward {
+        duration_seconds: i64,
+    },
+    MigrateConfig,
+    Checkpoint,
+    StakeCompressed(CompressedNftLeaf),
+    FundSolPool {
+        lamports: u64,
+    },
+    FinalizePool,
+    SwapRewardMint {
+        new_mint: Pubkey,
+        old_decimals: u8,
+    },
+}
+
+/// An optional trailing `u64`: absent when `data` is empty.
+fn unpack_optional_u64(data: &[u8]) -> Result<Option<u64>, ProgramError> {
+    if data.is_empty() {
+        return Ok(None);
+    }
+    u64::try_from_slice(data)
+        .map(Some)
+        .map_err(|_| ProgramError::InvalidInstructionData)
 }
 
 impl StakeInstruction {
     pub fn unpack(data: &[u8]) -> Result<Self, ProgramError> {
-        let (&variant, _rest) = data
+        let (&variant, rest) = data
             .split_first()
             .ok_or(ProgramError::InvalidInstructionData)?;
         Ok(match variant {
             0 => Self::InitializeStakeAccount,
             1 => Self::Stake,
-            2 => Self::Redeem,
-            3 => Self::Unstake,
+            2 => {
+                // The nonce, then the deadline; a nonce of zero sets none.
+                let (nonce, valid_until_slot) = rest.split_at(rest.len().min(8));
+                Self::Redeem {
+                    nonce: unpack_optional_u64(nonce)?,
+                    valid_until_slot: unpack_optional_u64(valid_until_slot)?,
+                }
+            }
+            3 => Self::Unstake {
+                valid_until_slot: unpack_optional_u64(rest)?,
+            },
+            4 => Self::UnstakeBatch,
+            5 => Self::ReconcileStake,
+            6 => {
+                let payload = InitializeConfigPayload::try_from_slice(rest)
+                    .map_err(|_| ProgramError::InvalidInstructionData)?;
+                Self::InitializeConfig {
+                    protocol_fee_bps: payload.protocol_fee_bps,
+                    withhold_protocol_fee: payload.withhold_protocol_fee,
+                }
+            }
+            7 => Self::UpdateConfig(
+                ConfigField::try_from_slice(rest)
+                    .map_err(|_| ProgramError::InvalidInstructionData)?,
+            ),
+            8 => Self::MigrateStakeAccount,
+            9 => Self::TransferStakePosition {
+                new_owner: Pubkey::try_from_slice(rest)
+                    .map_err(|_| ProgramError::InvalidInstructionData)?,
+            },
+            10 => Self::RedeemCompressed,
+            11 => {
+                let payload = RedeemAndSwapPayload::try_from_slice(rest)
+                    .map_err(|_| ProgramError::InvalidInstructionData)?;
+                Self::RedeemAndSwap {
+                    min_amount_out: payload.min_amount_out,
+                    swap_accounts_len: payload.swap_accounts_len,
+                    swap_data: payload.swap_data,
+                }
+            }
+            12 => Self::ResetLastRedeemTime,
+            13 => Self::GetEffectiveRate,
+            14 => Self::ClaimFinalReward,
+            15 => Self::BurnAndReward {
+                confirm_burn: bool::try_from_slice(rest)
+                    .map_err(|_| ProgramError::InvalidInstructionData)?,
+            },
+            16 => {
+                let payload = SnapshotStakersPayload::try_from_slice(rest)
+                    .map_err(|_| ProgramError::InvalidInstructionData)?;
+                Self::SnapshotStakers {
+                    snapshot_id: payload.snapshot_id,
+                    min_duration: payload.min_duration,
+                }
+            }
+            17 => Self::SetRewardDestination {
+                destination: Pubkey::try_from_slice(rest)
+                    .map_err(|_| ProgramError::InvalidInstructionData)?,
+            },
+            18 => Self::CompoundReward,
+            19 => {
+                let payload = AttestWalletAgePayload::try_from_slice(rest)
+                    .map_err(|_| ProgramError::InvalidInstructionData)?;
+                Self::AttestWalletAge {
+                    wallet: payload.wallet,
+                    first_seen_slot: payload.first_seen_slot,
+                }
+            }
+            20 => Self::RecoverStrayTokens,
+            21 => Self::SetRateOverride {
+                rate: Option::<u64>::try_from_slice(rest)
+                    .map_err(|_| ProgramError::InvalidInstructionData)?,
+            },
+            22 => Self::SetPaused {
+                paused: bool::try_from_slice(rest)
+                    .map_err(|_| ProgramError::InvalidInstructionData)?,
+            },
+            23 => Self::CanUnstake,
+            24 => Self::ConsolidateStakes,
+            25 => Self::InitializeRewardMint {
+                decimals: u8::try_from_slice(rest)
+                    .map_err(|_| ProgramError::InvalidInstructionData)?,
+            },
+            26 => Self::GetConfigVersion,
+            27 => Self::FundRentReserve {
+                lamports: u64::try_from_slice(rest)
+                    .map_err(|_| ProgramError::InvalidInstructionData)?,
+            },
+            28 => Self::UpdateConfigBatch(
+                ConfigUpdate::try_from_slice(rest)
+                    .map_err(|_| ProgramError::InvalidInstructionData)?,
+            ),
+            29 => Self::RotateMintAuthority {
+                new_authority: Pubkey::try_from_slice(rest)
+                    .map_err(|_| ProgramError::InvalidInstructionData)?,
+            },
+            30 => Self::CloseInactiveStakes,
+            31 => Self::SubmitOwnershipProof,
+            32 => Self::ReconcileActiveFlag,
+            33 => Self::GetRewardBreakdown,
+            34 => Self::ConvertPointsToTokens,
+            35 => Self::PreviewReward {
+                duration_seconds: i64::try_from_slice(rest)
+                    .map_err(|_| ProgramError::InvalidInstructionData)?,
+            },
+            36 => Self::MigrateConfig,
+            37 => Self::Checkpoint,
+            38 => Self::StakeCompressed(
+                CompressedNftLeaf::try_from_slice(rest)
+                    .map_err(|_| ProgramError::InvalidInstructionData)?,
+            ),
+            39 => Self::FundSolPool {
+                lamports: u64::try_from_slice(rest)
+                    .map_err(|_| ProgramError::InvalidInstructionData)?,
+            },
+            40 => Self::FinalizePool,
+            41 => {
+                let payload = SwapRewardMintPayload::try_from_slice(rest)
+                    .map_err(|_| ProgramError::InvalidInstructionData)?;
+                Self::SwapRewardMint {
+                    new_mint: payload.new_mint,
+                    old_decimals: payload.old_decimals,
+                }
+            }
             _ => return Err(ProgramError::InvalidInstructionData),
         })
     }
@@ -1,5 +1,9 @@
+pub mod client;
 pub mod entrypoint;
 pub mod error;
+pub mod events;
 pub mod instruction;
 pub mod processor;
 pub mod state;
+#[cfg(test)]
+mod test_utils;
@@ -1,26 +1,104 @@
 use crate::error::StakeError;
-use crate::instruction::StakeInstruction;
-use crate::state::UserStakeInfo;
+use crate::events::{self, StakeEvent};
+use crate::instruction::{CompressedNftLeaf, ConfigField, ConfigUpdate, StakeInstruction};
+use crate::state::{
+    AccrualMode, AirdropSnapshot, CircuitBreaker, ConfigVersion, EmissionSegment, FloorPriceFeed,
+    LegacyUserStakeInfo, PauseInterval, RewardBreakdown, RewardMintSwap, Rounding, StakeConfig,
+    StakedMint, UnstakeEligibility, UnstakeStatus, UserStakeAccount, UserStakeInfo,
+    WalletAgeRecord, AIRDROP_SNAPSHOT_SEED, AUDIT_TREE_AUTHORITY_SEED, CONFIG_SEED,
+    DEFAULT_INITIALIZE_BACKDATE, DEFAULT_REWARD_RATE, FUTURE_REDEEM_TIME_TOLERANCE, MAX_BPS,
+    MAX_DELEGATE_STAKE_AUTHORITIES, MAX_DURATION_TIERS, MAX_EMISSION_SEGMENTS,
+    MAX_FEE_DISCOUNT_TIERS, MAX_GOVERNANCE_BOOST_TIERS, MAX_PAUSE_INTERVALS, MAX_PROOF_AGE_SLOTS,
+    MAX_REWARD_MINT_SWAPS, MAX_SNAPSHOT_STAKE_ACCOUNTS, MAX_WRAPPED_MINT_AUTHORITIES,
+    MINT_AUTHORITY_SEED, RENT_RESERVE_SEED, REWARD_MINT_SEED, REWARD_PRECISION, SECONDS_PER_DAY,
+    SOL_POOL_SEED, STAKED_MINT_SEED, STAKE_SEED_VERSION, USER_STAKE_SEED, WALLET_AGE_SEED,
+};
 use borsh::BorshSerialize;
 use solana_program::{
     account_info::{next_account_info, AccountInfo},
-    borsh::try_from_slice_unchecked,
-    clock::Clock,
+    borsh0_10::try_from_slice_unchecked,
+    clock::{Clock, UnixTimestamp},
+    ed25519_program,
     entrypoint::ProgramResult,
+    instruction::{AccountMeta, Instruction},
+    keccak,
+    log::sol_log_compute_units,
     msg,
-    program::invoke_signed,
+    native_token::LAMPORTS_PER_SOL,
+    program::{get_return_data, invoke, invoke_signed, set_return_data},
     program_error::ProgramError,
-    program_pack::IsInitialized,
+    program_option::COption,
+    program_pack::{IsInitialized, Pack},
     pubkey::Pubkey,
     system_instruction,
-    sysvar::{rent::Rent, Sysvar},
+    sysvar::{self, instructions, rent::Rent, Sysvar},
+};
+use spl_token::{
+    instruction::AuthorityType,
+    state::{Account as TokenAccount, Mint},
 };
 use std::convert::TryInto;
 
+pub const MAX_UNSTAKE_BATCH_SIZE: usize = 16;
+pub const ACCOUNT_COMPRESSION_PROGRAM_ID: Pubkey =
+    solana_program::pubkey!("cmtDvXumGCrqC1Age74AVPhSRVXJMd8PJS91L8KbNCK");
+pub const NOOP_PROGRAM_ID: Pubkey =
+    solana_program::pubkey!("noopb9bkMVfRPU8AsbpTUg8AQkHtKwMYZiFUjNRtMmV");
+pub const TOKEN_METADATA_PROGRAM_ID: Pubkey =
+    solana_program::pubkey!("metaqbxxUerdq28cj1RbAWkYQm3ybzjb6a8bt518x1s");
+pub const TOKEN_2022_PROGRAM_ID: Pubkey =
+    solana_program::pubkey!("TokenzQdBNbLqP5VEhdkAS6EPFLC1PHnBqCXEpPxuEb");
+/// Leading `Key` bytes of Metaplex `MasterEditionV1` and `MasterEditionV2`
+/// accounts.
+const MASTER_EDITION_KEYS: [u8; 2] = [2, 6];
+/// Anchor discriminator of the account compression program's `append`.
+const APPEND_DISCRIMINATOR: [u8; 8] = [149, 120, 18, 222, 236, 225, 88, 203];
+/// Anchor discriminator of the account compression program's `verify_leaf`.
+const VERIFY_LEAF_DISCRIMINATOR: [u8; 8] = [124, 220, 22, 223, 104, 10, 250, 224];
+pub const BUBBLEGUM_PROGRAM_ID: Pubkey =
+    solana_program::pubkey!("BGUMAp9Gq7iTEuizy4pqaxsTyUCBK68MDfK752saRPUY");
+/// Leaf schema version Bubblegum hashes first into a V1 leaf.
+const BUBBLEGUM_LEAF_VERSION: u8 = 1;
+
 pub fn process_instruction(
     program_id: &Pubkey,
     accounts: &[AccountInfo],
     instruction_data: &[u8],
+) -> ProgramResult {
+    let log_compute_units = compute_unit_logging_enabled(program_id, accounts);
+    if log_compute_units {
+        sol_log_compute_units();
+    }
+    let result = dispatch_instruction(program_id, accounts, instruction_data);
+    if log_compute_units {
+        sol_log_compute_units();
+    }
+    result
+}
+
+/// Whether the config, if among `accounts`, has `log_compute_units` set. The
+/// config is recognized by its owner and size rather than by deriving its PDA,
+/// which would cost more than the logging saves. Reading the flag means
+/// deserializing the whole config, so only builds with the
+/// `compute-unit-logging` feature look at all.
+#[cfg(feature = "compute-unit-logging")]
+fn compute_unit_logging_enabled(program_id: &Pubkey, accounts: &[AccountInfo]) -> bool {
+    accounts
+        .iter()
+        .find(|account| account.owner == program_id && account.data_len() == StakeConfig::SIZE)
+        .and_then(|account| try_from_slice_unchecked::<StakeConfig>(&account.data.borrow()).ok())
+        .is_some_and(|config| config.is_initialized() && config.log_compute_units)
+}
+
+#[cfg(not(feature = "compute-unit-logging"))]
+fn compute_unit_logging_enabled(_program_id: &Pubkey, _accounts: &[AccountInfo]) -> bool {
+    false
+}
+
+fn dispatch_instruction(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    instruction_data: &[u8],
 ) -> ProgramResult {
     let instruction = StakeInstruction::unpack(instruction_data)?;
     match instruction {
@@ -28,11 +106,123 @@ pub fn process_instruction(
             process_initialize_stake_account(program_id, accounts)
         }
         StakeInstruction::Stake => process_stake(program_id, accounts),
-        StakeInstruction::Redeem => process_redeem(program_id, accounts),
-        StakeInstruction::Unstake => process_unstake(program_id, accounts),
+        StakeInstruction::Redeem {
+            nonce,
+            valid_until_slot,
+        } => process_redeem(program_id, accounts, nonce, valid_until_slot),
+        StakeInstruction::Unstake { valid_until_slot } => {
+            process_unstake(program_id, accounts, valid_until_slot)
+        }
+        StakeInstruction::UnstakeBatch => process_unstake_batch(program_id, accounts),
+        StakeInstruction::ReconcileStake => process_reconcile_stake(program_id, accounts),
+        StakeInstruction::InitializeConfig {
+            protocol_fee_bps,
+            withhold_protocol_fee,
+        } => process_initialize_config(
+            program_id,
+            accounts,
+            protocol_fee_bps,
+            withhold_protocol_fee,
+        ),
+        StakeInstruction::UpdateConfig(field) => process_update_config(program_id, accounts, field),
+        StakeInstruction::MigrateStakeAccount => {
+            process_migrate_stake_account(program_id, accounts)
+        }
+        StakeInstruction::TransferStakePosition { new_owner } => {
+            process_transfer_stake_position(program_id, accounts, new_owner)
+        }
+        StakeInstruction::RedeemCompressed => process_redeem_compressed(program_id, accounts),
+        StakeInstruction::RedeemAndSwap {
+            min_amount_out,
+            swap_accounts_len,
+            swap_data,
+        } => process_redeem_and_swap(
+            program_id,
+            accounts,
+            min_amount_out,
+            swap_accounts_len,
+            swap_data,
+        ),
+        StakeInstruction::ResetLastRedeemTime => {
+            process_reset_last_redeem_time(program_id, accounts)
+        }
+        StakeInstruction::GetEffectiveRate => process_get_effective_rate(program_id, accounts),
+        StakeInstruction::ClaimFinalReward => process_claim_final_reward(program_id, accounts),
+        StakeInstruction::BurnAndReward { confirm_burn } => {
+            process_burn_and_reward(program_id, accounts, confirm_burn)
+        }
+        StakeInstruction::SnapshotStakers {
+            snapshot_id,
+            min_duration,
+        } => process_snapshot_stakers(program_id, accounts, snapshot_id, min_duration),
+        StakeInstruction::SetRewardDestination { destination } => {
+            process_set_reward_destination(program_id, accounts, destination)
+        }
+        StakeInstruction::CompoundReward => process_compound_reward(program_id, accounts),
+        StakeInstruction::AttestWalletAge {
+            wallet,
+            first_seen_slot,
+        } => process_attest_wallet_age(program_id, accounts, wallet, first_seen_slot),
+        StakeInstruction::RecoverStrayTokens => process_recover_stray_tokens(program_id, accounts),
+        StakeInstruction::SetRateOverride { rate } => {
+            process_set_rate_override(program_id, accounts, rate)
+        }
+        StakeInstruction::SetPaused { paused } => process_set_paused(program_id, accounts, paused),
+        StakeInstruction::CanUnstake => process_can_unstake(program_id, accounts),
+        StakeInstruction::ConsolidateStakes => process_consolidate_stakes(program_id, accounts),
+        StakeInstruction::InitializeRewardMint { decimals } => {
+            process_initialize_reward_mint(program_id, accounts, decimals)
+        }
+        StakeInstruction::GetConfigVersion => process_get_config_version(program_id, accounts),
+        StakeInstruction::FundRentReserve { lamports } => {
+            process_fund_rent_reserve(program_id, accounts, lamports)
+        }
+        StakeInstruction::UpdateConfigBatch(update) => {
+            process_update_config_batch(program_id, accounts, update)
+        }
+        StakeInstruction::RotateMintAuthority { new_authority } => {
+            process_rotate_mint_authority(program_id, accounts, new_authority)
+        }
+        StakeInstruction::CloseInactiveStakes => {
+            process_close_inactive_stakes(program_id, accounts)
+        }
+        StakeInstruction::SubmitOwnershipProof => {
+            process_submit_ownership_proof(program_id, accounts)
+        }
+        StakeInstruction::ReconcileActiveFlag => {
+            process_reconcile_active_flag(program_id, accounts)
+        }
+        StakeInstruction::GetRewardBreakdown => process_get_reward_breakdown(program_id, accounts),
+        StakeInstruction::ConvertPointsToTokens => {
+            process_convert_points_to_tokens(program_id, accounts)
+        }
+        StakeInstruction::PreviewReward { duration_seconds } => {
+            process_preview_reward(program_id, accounts, duration_seconds)
+        }
+        StakeInstruction::MigrateConfig => process_migrate_config(program_id, accounts),
+        StakeInstruction::Checkpoint => process_checkpoint(program_id, accounts),
+        StakeInstruction::StakeCompressed(leaf) => {
+            process_stake_compressed(program_id, accounts, leaf)
+        }
+        StakeInstruction::FundSolPool { lamports } => {
+            process_fund_sol_pool(program_id, accounts, lamports)
+        }
+        StakeInstruction::FinalizePool => process_finalize_pool(program_id, accounts),
+        StakeInstruction::SwapRewardMint {
+            new_mint,
+            old_decimals,
+        } => process_swap_reward_mint(program_id, accounts, new_mint, old_decimals),
     }
 }
 
+/// Creates the stake account of `user` for `nft_token_account`. A stake
+/// account closed before, even earlier in the same transaction, can be
+/// initialized again; it starts from scratch, with no lifetime totals carried
+/// over from the closed one. The NFT's last unstake lives on its `StakedMint`
+/// record instead, so reopening does not lift the restake cool-off. When `nft_token_account` is a token account of
+/// the user's holding exactly one token, the creation time and that mint are
+/// recorded for `accrue_from_initialize`. Other accounts, such as a
+/// compressed NFT's asset id, initialize without them.
 pub fn process_initialize_stake_account(
     program_id: &Pubkey,
     accounts: &[AccountInfo],
@@ -43,85 +233,611 @@ pub fn process_initialize_stake_account(
     let stake_state = next_account_info(account_info_iter)?;
     let system_program = next_account_info(account_info_iter)?;
 
-    let (stake_state_pda, bump) = Pubkey::find_program_address(
-        &[user.key.as_ref(), nft_token_account.key.as_ref()],
-        program_id,
-    );
+    let seeds = stake_seeds(&STAKE_SEED_VERSION, user.key, nft_token_account.key);
+    let (stake_state_pda, bump) = Pubkey::find_program_address(&seeds, program_id);
     if stake_state_pda != *stake_state.key {
         msg!("Invalid PDA passed");
         return Err(StakeError::InvalidPda.into());
     }
     let space = UserStakeInfo::SIZE;
     let rent_lamports = Rent::get()?.minimum_balance(space);
+    let shortfall = rent_lamports.saturating_sub(stake_state.lamports());
+    if user.lamports() < shortfall {
+        msg!(
+            "User holds {} lamports, rent needs {}",
+            user.lamports(),
+            shortfall
+        );
+        return Err(StakeError::InsufficientFundsForRent.into());
+    }
+    let bump_seed = [bump];
+    let signer_seeds = [seeds.as_slice(), &[&bump_seed]].concat();
+    if stake_state.owner == program_id || stake_state.lamports() > 0 {
+        // Left behind by a close: either earlier in this transaction, before
+        // the runtime reclaims the account, or topped up by a transfer to the
+        // address since, which would make `create_account` fail. Fund what is
+        // missing and allocate and assign the account step by step.
+        if shortfall > 0 {
+            invoke(
+                &system_instruction::transfer(user.key, stake_state.key, shortfall),
+                &[user.clone(), stake_state.clone(), system_program.clone()],
+            )?;
+        }
+        if stake_state.owner != program_id {
+            invoke_signed(
+                &system_instruction::allocate(stake_state.key, space.try_into().unwrap()),
+                &[stake_state.clone(), system_program.clone()],
+                &[&signer_seeds],
+            )?;
+            invoke_signed(
+                &system_instruction::assign(stake_state.key, program_id),
+                &[stake_state.clone(), system_program.clone()],
+                &[&signer_seeds],
+            )?;
+        } else if stake_state.data_len() < space {
+            stake_state.realloc(space, true)?;
+        }
+    } else {
+        invoke_signed(
+            &system_instruction::create_account(
+                user.key,
+                stake_state.key,
+                rent_lamports,
+                space.try_into().unwrap(),
+                program_id,
+            ),
+            &[user.clone(), stake_state.clone(), system_program.clone()],
+            &[&signer_seeds],
+        )?;
+    }
 
-    invoke_signed(
-        &system_instruction::create_account(
-            user.key,
-            stake_state.key,
-            rent_lamports,
-            space.try_into().unwrap(),
-            program_id,
-        ),
-        &[user.clone(), stake_state.clone(), system_program.clone()],
-        &[&[user.key.as_ref(), nft_token_account.key.as_ref(), &[bump]]],
-    )?;
-
-    let mut account_data =
-        try_from_slice_unchecked::<UserStakeInfo>(&stake_state.data.borrow()).unwrap();
+    let mut account_data = load_stake_info(stake_state)?;
     if account_data.is_initialized() {
         return Err(ProgramError::AccountAlreadyInitialized);
     }
     account_data.user = *user.key;
     account_data.token_account = *nft_token_account.key;
-    account_data.is_stake_active = false;
-    account_data.is_initialized = true;
-    account_data.serialize(&mut &mut stake_state.data.borrow_mut()[..])?;
+    account_data.version = UserStakeInfo::VERSION;
+    account_data.seed_version = STAKE_SEED_VERSION;
+    match held_nft_mint(user.key, nft_token_account) {
+        Some(nft_mint) => {
+            account_data.initialized_at = current_time(accounts)?.unix_timestamp;
+            account_data.initialized_mint = nft_mint;
+        }
+        None => {
+            account_data.initialized_at = 0;
+            account_data.initialized_mint = Pubkey::default();
+        }
+    }
+    account_data.set_stake_active(false);
+    account_data.set_initialized(true);
+    account_data.bump = bump;
+    save_stake_info(&account_data, stake_state)?;
+    events::emit(StakeEvent::StakeAccountInitialized {
+        stake_state: *stake_state.key,
+        user: *user.key,
+        nft_token_account: *nft_token_account.key,
+    });
     Ok(())
 }
 
+/// The mint of the NFT `nft_token_account` holds for `user`: a token account
+/// owned by them holding exactly one token of a set mint.
+fn held_nft_mint(user: &Pubkey, nft_token_account: &AccountInfo) -> Option<Pubkey> {
+    if !is_token_program(nft_token_account.owner) {
+        return None;
+    }
+    let token_account = unpack_token_account(nft_token_account).ok()?;
+    (token_account.owner == *user
+        && token_account.mint != Pubkey::default()
+        && token_account.amount == 1)
+        .then_some(token_account.mint)
+}
+
 pub fn process_stake(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    stake(program_id, accounts, None)
+}
+
+/// Stakes a compressed NFT whose Bubblegum leaf is verified by the account
+/// compression program. The `merkle_tree` and `compression_program` accounts
+/// and the leaf's proof nodes come first, followed by the `Stake` accounts
+/// with the asset id in place of the NFT token account. The tree must be the
+/// configured `compressed_nft_tree` and the leaf owned by the user.
+///
+/// As with token accounts the NFT stays in the user's wallet, but a
+/// compressed stake is only verified when staked: `proof_of_hold` and
+/// `ReconcileActiveFlag` cannot re-check it.
+pub fn process_stake_compressed(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    leaf: CompressedNftLeaf,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let merkle_tree = next_account_info(account_info_iter)?;
+    let compression_program = next_account_info(account_info_iter)?;
+    let remaining = account_info_iter.as_slice();
+    let proof_len = leaf.proof_len as usize;
+    if remaining.len() < proof_len {
+        return Err(ProgramError::NotEnoughAccountKeys);
+    }
+    let (proof, stake_accounts) = remaining.split_at(proof_len);
+    let user = stake_accounts
+        .first()
+        .ok_or(ProgramError::NotEnoughAccountKeys)?;
+    let asset_account = stake_accounts
+        .get(1)
+        .ok_or(ProgramError::NotEnoughAccountKeys)?;
+
+    if *compression_program.key != ACCOUNT_COMPRESSION_PROGRAM_ID
+        || *merkle_tree.owner != ACCOUNT_COMPRESSION_PROGRAM_ID
+    {
+        return Err(ProgramError::IncorrectProgramId);
+    }
+    let (asset_id, _bump) = Pubkey::find_program_address(
+        &[
+            b"asset",
+            merkle_tree.key.as_ref(),
+            &leaf.nonce.to_le_bytes(),
+        ],
+        &BUBBLEGUM_PROGRAM_ID,
+    );
+    if asset_id != *asset_account.key {
+        return Err(StakeError::InvalidCnftProof.into());
+    }
+    let leaf_hash = keccak::hashv(&[
+        &[BUBBLEGUM_LEAF_VERSION],
+        asset_id.as_ref(),
+        user.key.as_ref(),
+        leaf.delegate.as_ref(),
+        &leaf.nonce.to_le_bytes(),
+        &leaf.data_hash,
+        &leaf.creator_hash,
+    ]);
+    let mut data = VERIFY_LEAF_DISCRIMINATOR.to_vec();
+    data.extend_from_slice(&leaf.root);
+    data.extend_from_slice(&leaf_hash.to_bytes());
+    data.extend_from_slice(&leaf.index.to_le_bytes());
+    let mut metas = vec![AccountMeta::new_readonly(*merkle_tree.key, false)];
+    metas.extend(
+        proof
+            .iter()
+            .map(|node| AccountMeta::new_readonly(*node.key, false)),
+    );
+    let mut infos = vec![merkle_tree.clone()];
+    infos.extend(proof.iter().cloned());
+    invoke(
+        &Instruction {
+            program_id: ACCOUNT_COMPRESSION_PROGRAM_ID,
+            accounts: metas,
+            data,
+        },
+        &infos,
+    )?;
+    stake(
+        program_id,
+        stake_accounts,
+        Some(CompressedNft {
+            tree: *merkle_tree.key,
+            asset_id,
+        }),
+    )
+}
+
+/// A compressed NFT whose leaf has been verified for `stake`.
+struct CompressedNft {
+    tree: Pubkey,
+    asset_id: Pubkey,
+}
+
+fn stake(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    compressed: Option<CompressedNft>,
+) -> ProgramResult {
     let account_info_iter = &mut accounts.iter();
     let user = next_account_info(account_info_iter)?;
     let nft_token_account = next_account_info(account_info_iter)?;
     let stake_state = next_account_info(account_info_iter)?;
+    let staked_mint = next_account_info(account_info_iter)?;
+    let user_stake_account = next_account_info(account_info_iter)?;
+    let stake_config = next_account_info(account_info_iter)?;
+    let system_program = next_account_info(account_info_iter)?;
 
     if !user.is_signer {
         return Err(ProgramError::MissingRequiredSignature);
     }
+    let mut config = load_config(program_id, stake_config)?;
+    // A compressed NFT's asset id stands in for both its token account and
+    // its mint.
+    let (nft_mint, amount, token_account) = match &compressed {
+        Some(compressed) => (compressed.asset_id, 1, None),
+        None => {
+            if !is_token_program(nft_token_account.owner) {
+                return Err(StakeError::InvalidTokenAccount.into());
+            }
+            let token_account = unpack_token_account(nft_token_account)?;
+            (
+                token_account.mint,
+                token_account.amount,
+                Some(token_account),
+            )
+        }
+    };
+    check_circuit_breaker(&config, accounts)?;
+    check_not_finalized(&config)?;
+    if let Some(compressed) = &compressed {
+        if config.compressed_nft_tree == Pubkey::default()
+            || config.compressed_nft_tree != compressed.tree
+        {
+            return Err(StakeError::InvalidCnftProof.into());
+        }
+        if !config.wrapped_mint_authorities.is_empty() {
+            msg!("Mint provenance cannot be checked for a compressed NFT");
+            return Err(StakeError::InvalidCnftProof.into());
+        }
+    }
+    if amount < config.min_stake_amount {
+        msg!(
+            "Staked amount {} is below the minimum {}",
+            amount,
+            config.min_stake_amount
+        );
+        return Err(StakeError::StakeBelowMinimum.into());
+    }
+    if amount != 1 {
+        return Err(StakeError::InvalidTokenAccount.into());
+    }
     if stake_state.owner != program_id {
         return Err(ProgramError::IllegalOwner);
     }
-    let (pda, _bump) = Pubkey::find_program_address(
-        &[user.key.as_ref(), nft_token_account.key.as_ref()],
+    let mut account_data = load_stake_info(stake_state)?;
+    verify_stake_pda(
         program_id,
-    );
-    if pda != *stake_state.key {
-        msg!("Invalid PDA seeds");
-        return Err(StakeError::InvalidPda.into());
-    }
-    let mut account_data =
-        try_from_slice_unchecked::<UserStakeInfo>(&stake_state.data.borrow()).unwrap();
+        user.key,
+        nft_token_account.key,
+        stake_state,
+        account_data.seed_version,
+        account_data.bump,
+    )?;
     if !account_data.is_initialized() {
         return Err(StakeError::UninitializedAccount.into());
     }
-    if account_data.is_stake_active {
+    if let Some(token_account) = &token_account {
+        // Token accounts owned by a multisig or PDA are staked by the
+        // delegate their owner approved for the whole balance, as long as
+        // that delegate is a configured governance or escrow authority, or
+        // by the user once the owner proved control with
+        // `SubmitOwnershipProof`.
+        let is_approved_delegate = token_account.delegate == COption::Some(*user.key)
+            && token_account.delegated_amount >= token_account.amount
+            && config.delegate_stake_authorities.contains(user.key);
+        if token_account.owner != *user.key
+            && !is_approved_delegate
+            && !account_data.is_proof_verified_for(&token_account.owner)
+        {
+            return Err(StakeError::InvalidStakeAuthority.into());
+        }
+    }
+    if account_data.is_stake_active() {
         return Err(ProgramError::InvalidArgument);
     }
-    let clock = Clock::get()?;
+    if config.require_current_seed_version && account_data.seed_version != STAKE_SEED_VERSION {
+        msg!(
+            "Stake account uses seed version {}, reinitialize it under {}",
+            account_data.seed_version,
+            STAKE_SEED_VERSION
+        );
+        return Err(StakeError::InvalidPda.into());
+    }
+    let clock = current_time(accounts)?;
+    if clock.unix_timestamp <= 0 {
+        msg!("Clock unix_timestamp is not set: {}", clock.unix_timestamp);
+        return Err(StakeError::InvalidClock.into());
+    }
+    // The mint's record follows the NFT into any token account; the stake
+    // account's own time covers mints whose record is still legacy sized.
+    let last_unstake_time = mint_last_unstake_time(program_id, staked_mint, &nft_mint)?
+        .max(account_data.last_unstake_time);
+    let cooloff_ends = last_unstake_time.saturating_add(config.restake_cooloff);
+    if last_unstake_time != 0 && clock.unix_timestamp < cooloff_ends {
+        msg!("Restake cool-off ends at {}", cooloff_ends);
+        return Err(StakeError::RestakeCooloffActive.into());
+    }
+    // Trailing accounts, passed in this order only when their check is
+    // configured.
+    let floor_price_feed = if config.floor_price_feed != Pubkey::default() {
+        Some(next_account_info(account_info_iter)?)
+    } else {
+        None
+    };
+    if config.min_wallet_age_slots != 0 {
+        let wallet_age_record = next_account_info(account_info_iter)?;
+        check_wallet_age(program_id, &config, user.key, wallet_age_record, &clock)?;
+    }
+    if !config.wrapped_mint_authorities.is_empty() {
+        let mint_account = next_account_info(account_info_iter)?;
+        check_nft_provenance(&config, &nft_mint, mint_account)?;
+    }
+    // Compressed NFTs have no master edition account to boost by.
+    let edition_boost_bps = if config.master_edition_boost_bps != 0 && compressed.is_none() {
+        let edition = next_account_info(account_info_iter)?;
+        edition_boost_bps(&config, &nft_mint, edition)?
+    } else {
+        0
+    };
+    if config.sponsor_rent {
+        let rent_reserve = next_account_info(account_info_iter)?;
+        let rent = Rent::get()?;
+        let mut sponsored = 0;
+        if user_stake_account.data_is_empty() {
+            sponsored += rent.minimum_balance(UserStakeAccount::SIZE);
+        }
+        if staked_mint.data_is_empty() {
+            sponsored += rent.minimum_balance(StakedMint::SIZE);
+        }
+        draw_rent_reserve(program_id, rent_reserve, user, sponsored)?;
+    }
+    if user_stake_account.data_is_empty() {
+        register_new_staker(&mut config)?;
+    }
+    config.total_active_stakes = config.total_active_stakes.saturating_add(1);
+    bump_counter(&mut config.stake_count, 1)?;
+    config.serialize(&mut &mut stake_config.data.borrow_mut()[..])?;
+    let mut user_stakes = load_or_create_user_stake_account(
+        program_id,
+        user,
+        user.key,
+        user_stake_account,
+        system_program,
+    )?;
+    if config.max_stakes_per_user != 0 && user_stakes.active_stakes >= config.max_stakes_per_user {
+        msg!(
+            "User already has {} active stakes",
+            user_stakes.active_stakes
+        );
+        return Err(StakeError::UserStakeLimitReached.into());
+    }
+    let active_stakes = user_stakes.active_stakes + 1;
+    set_active_stakes(
+        &config,
+        &mut user_stakes,
+        active_stakes,
+        clock.unix_timestamp,
+    );
+    save_user_stakes(&user_stakes, user_stake_account)?;
+    claim_staked_mint(
+        program_id,
+        user,
+        staked_mint,
+        system_program,
+        &nft_mint,
+        stake_state.key,
+    )?;
+    rescale_to_current_mint(&mut account_data, &config);
     account_data.user = *user.key;
     account_data.token_account = *nft_token_account.key;
-    account_data.stake_start_time = clock.unix_timestamp;
-    account_data.last_redeem_time = clock.unix_timestamp;
-    account_data.is_stake_active = true;
-    account_data.serialize(&mut &mut stake_state.data.borrow_mut()[..])?;
+    account_data.nft_mint = nft_mint;
+    account_data.set_compressed(compressed.is_some());
+    // With `accrue_from_initialize` a stake accrues from when its account was
+    // created, if it then verifiably held the NFT now staked, but not from
+    // before the NFT was last unstaked, through this or any other stake
+    // account, nor for longer than `max_backpay_seconds`, or
+    // `DEFAULT_INITIALIZE_BACKDATE` while that is unset.
+    let accrues_from = if config.accrue_from_initialize
+        && account_data.initialized_at > 0
+        && account_data.initialized_mint == nft_mint
+    {
+        let backdate_floor = if config.max_backpay_seconds > 0 {
+            backpay_floor(&config, clock.unix_timestamp)
+        } else {
+            clock
+                .unix_timestamp
+                .saturating_sub(DEFAULT_INITIALIZE_BACKDATE)
+        };
+        account_data
+            .initialized_at
+            .max(backdate_floor)
+            .max(last_unstake_time)
+            .min(clock.unix_timestamp)
+    } else {
+        clock.unix_timestamp
+    };
+    account_data.stake_start_time = accrues_from;
+    account_data.last_redeem_time = accrues_from;
+    account_data.set_stake_active(true);
+    account_data.total_redeemed = 0;
+    account_data.virtual_staked_amount = 0;
+    account_data.stake_weight = snapshot_stake_weight(&config, floor_price_feed, &clock)?;
+    account_data.edition_boost_bps = edition_boost_bps;
+    account_data.milestone_multiplier_bps = 0;
+    refresh_rate_cache(&mut account_data, &config);
+    save_stake_info(&account_data, stake_state)?;
+    events::emit(StakeEvent::Staked {
+        stake_state: *stake_state.key,
+        user: *user.key,
+        nft_mint: account_data.nft_mint,
+        stake_weight: account_data.stake_weight,
+        staked_at: clock.unix_timestamp,
+    });
+    Ok(())
+}
+
+/// Redeems the pending reward. A non-zero `nonce` makes client retries safe:
+/// a redeem repeating the stake account's `last_nonce` pays nothing, and fails
+/// with `DuplicateNonce` when `strict_nonce` is configured.
+///
+/// With `proof_of_hold` configured the NFT is re-verified in the recorded
+/// token account first; if it is gone the accrual since the last redeem is
+/// forfeited and nothing is paid.
+///
+/// With `min_nfts_to_qualify` set, the user's `UserStakeAccount` has to be
+/// passed and count that many active stakes, and only time since the user's
+/// set was completed is paid; see `qualified_stretch`.
+///
+/// With a `valid_until_slot` the redeem fails with `TransactionExpired` once
+/// the slot is past it.
+pub fn process_redeem(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    nonce: Option<u64>,
+    valid_until_slot: Option<u64>,
+) -> ProgramResult {
+    check_deadline(valid_until_slot, accounts)?;
+    redeem(program_id, accounts, nonce)?;
+    Ok(())
+}
+
+/// Redeems like `Redeem` and appends the redemption as a leaf of the
+/// configured audit merkle tree. The `merkle_tree`, `tree_authority`,
+/// `compression_program` and `noop_program` accounts come first, followed by
+/// the `Redeem` accounts. The leaf is the keccak hash of the user, NFT mint,
+/// minted amount (`u64` little endian) and redeem time (`i64` little endian).
+pub fn process_redeem_compressed(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let merkle_tree = next_account_info(account_info_iter)?;
+    let tree_authority = next_account_info(account_info_iter)?;
+    let compression_program = next_account_info(account_info_iter)?;
+    let noop_program = next_account_info(account_info_iter)?;
+    let redeem_accounts = account_info_iter.as_slice();
+
+    if *compression_program.key != ACCOUNT_COMPRESSION_PROGRAM_ID
+        || *noop_program.key != NOOP_PROGRAM_ID
+    {
+        return Err(ProgramError::IncorrectProgramId);
+    }
+    let (authority, bump) = Pubkey::find_program_address(&[AUDIT_TREE_AUTHORITY_SEED], program_id);
+    if authority != *tree_authority.key {
+        return Err(StakeError::InvalidPda.into());
+    }
+    let Some(redemption) = redeem(program_id, redeem_accounts, None)? else {
+        return Ok(());
+    };
+    if redemption.config.audit_merkle_tree == Pubkey::default()
+        || redemption.config.audit_merkle_tree != *merkle_tree.key
+    {
+        return Err(StakeError::InvalidAuditTree.into());
+    }
+    let leaf = keccak::hashv(&[
+        redemption.user.as_ref(),
+        redemption.nft_mint.as_ref(),
+        &redemption.amount.to_le_bytes(),
+        &redemption.redeemed_at.to_le_bytes(),
+    ]);
+    msg!(
+        "Audit leaf {}: user {}, mint {}, amount {}, time {}",
+        leaf,
+        redemption.user,
+        redemption.nft_mint,
+        redemption.amount,
+        redemption.redeemed_at
+    );
+    let mut data = APPEND_DISCRIMINATOR.to_vec();
+    data.extend_from_slice(&leaf.to_bytes());
+    invoke_signed(
+        &Instruction {
+            program_id: ACCOUNT_COMPRESSION_PROGRAM_ID,
+            accounts: vec![
+                AccountMeta::new(*merkle_tree.key, false),
+                AccountMeta::new_readonly(*tree_authority.key, true),
+                AccountMeta::new_readonly(*noop_program.key, false),
+            ],
+            data,
+        },
+        &[
+            merkle_tree.clone(),
+            tree_authority.clone(),
+            noop_program.clone(),
+            compression_program.clone(),
+        ],
+        &[&[AUDIT_TREE_AUTHORITY_SEED, &[bump]]],
+    )
+}
+
+/// Redeems like `Redeem`, then swaps the reward by invoking the configured
+/// `swap_program` with `swap_data` and the `swap_accounts_len` accounts that
+/// follow the `swap_program` and `output_token_account` accounts, the `Redeem`
+/// accounts coming last. The user's signature is forwarded to the swap. Fails
+/// with `SlippageExceeded` unless the user's `output_token_account` of
+/// `swap_target_mint` grew by at least `min_amount_out`.
+pub fn process_redeem_and_swap(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    min_amount_out: u64,
+    swap_accounts_len: u8,
+    swap_data: Vec<u8>,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let swap_program = next_account_info(account_info_iter)?;
+    let output_token_account = next_account_info(account_info_iter)?;
+    let swap_accounts = account_info_iter
+        .as_slice()
+        .get(..swap_accounts_len as usize)
+        .ok_or(ProgramError::NotEnoughAccountKeys)?;
+    let redeem_accounts = &account_info_iter.as_slice()[swap_accounts_len as usize..];
+
+    let Some(redemption) = redeem(program_id, redeem_accounts, None)? else {
+        return Ok(());
+    };
+    let config = &redemption.config;
+    if config.swap_program == Pubkey::default() || config.swap_program != *swap_program.key {
+        return Err(StakeError::InvalidSwapRoute.into());
+    }
+    if !is_token_program(output_token_account.owner) {
+        return Err(StakeError::InvalidSwapRoute.into());
+    }
+    let output = unpack_token_account(output_token_account)?;
+    if output.owner != redemption.user || output.mint != config.swap_target_mint {
+        return Err(StakeError::InvalidSwapRoute.into());
+    }
+    let mut swap_account_infos = swap_accounts.to_vec();
+    swap_account_infos.push(swap_program.clone());
+    invoke(
+        &Instruction {
+            program_id: *swap_program.key,
+            accounts: swap_accounts
+                .iter()
+                .map(|account| AccountMeta {
+                    pubkey: *account.key,
+                    is_signer: account.is_signer,
+                    is_writable: account.is_writable,
+                })
+                .collect(),
+            data: swap_data,
+        },
+        &swap_account_infos,
+    )?;
+    let received = unpack_token_account(output_token_account)?
+        .amount
+        .saturating_sub(output.amount);
+    if received < min_amount_out {
+        msg!("Swap returned {}, minimum is {}", received, min_amount_out);
+        return Err(StakeError::SlippageExceeded.into());
+    }
     Ok(())
 }
 
-pub fn process_redeem(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+struct Redemption {
+    user: Pubkey,
+    nft_mint: Pubkey,
+    amount: u64,
+    redeemed_at: UnixTimestamp,
+    config: StakeConfig,
+}
+
+/// Shared by the redeem instructions. Returns `None` when `nonce` repeats the
+/// last redeem's and nothing was paid.
+fn redeem(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    nonce: Option<u64>,
+) -> Result<Option<Redemption>, ProgramError> {
     let account_info_iter = &mut accounts.iter();
     let user = next_account_info(account_info_iter)?;
     let nft_token_account = next_account_info(account_info_iter)?;
     let stake_state = next_account_info(account_info_iter)?;
+    let stake_config = next_account_info(account_info_iter)?;
+    let reward_accounts = RewardAccounts::next(account_info_iter)?;
+    let governance_token_account = next_account_info(account_info_iter).ok();
 
     if !user.is_signer {
         return Err(ProgramError::MissingRequiredSignature);
@@ -129,21 +845,20 @@ pub fn process_redeem(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramR
     if stake_state.owner != program_id {
         return Err(ProgramError::IllegalOwner);
     }
-    let (pda, _bump) = Pubkey::find_program_address(
-        &[user.key.as_ref(), nft_token_account.key.as_ref()],
+    let mut account_data = load_stake_info(stake_state)?;
+    verify_stake_pda(
         program_id,
-    );
-    if pda != *stake_state.key {
-        return Err(StakeError::InvalidPda.into());
-    }
-    let mut account_data =
-        try_from_slice_unchecked::<UserStakeInfo>(&stake_state.data.borrow()).unwrap();
+        user.key,
+        nft_token_account.key,
+        stake_state,
+        account_data.seed_version,
+        account_data.bump,
+    )?;
     if !account_data.is_initialized() {
         return Err(StakeError::UninitializedAccount.into());
     }
-    if !account_data.is_stake_active {
-        msg!("Not staked yet");
-        return Err(ProgramError::InvalidArgument);
+    if !account_data.is_stake_active() {
+        return Err(StakeError::StakeNotActive.into());
     }
     if account_data.user != *user.key {
         return Err(StakeError::InvalidStakeAccount.into());
@@ -151,19 +866,436 @@ pub fn process_redeem(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramR
     if account_data.token_account != *nft_token_account.key {
         return Err(StakeError::InvalidTokenAccount.into());
     }
-    let clock = Clock::get()?;
-    let reward_amt = clock.unix_timestamp - account_data.last_redeem_time;
-    msg!("Reward: {}", reward_amt);
-    account_data.last_redeem_time = clock.unix_timestamp;
-    account_data.serialize(&mut &mut stake_state.data.borrow_mut()[..])?;
+    let mut config = load_config(program_id, stake_config)?;
+    check_circuit_breaker(&config, accounts)?;
+    let stretch = check_set_size(program_id, &config, user.key, accounts)?;
+    check_compliance(&config, user, accounts)?;
+    if let Some(nonce) = nonce.filter(|&nonce| nonce != 0) {
+        if nonce == account_data.last_nonce {
+            msg!("Nonce {} was already redeemed", nonce);
+            if config.strict_nonce {
+                return Err(StakeError::DuplicateNonce.into());
+            }
+            return Ok(None);
+        }
+        account_data.last_nonce = nonce;
+    }
+    let mint_authority_bump = check_reward_accounts(program_id, &config, &reward_accounts)?;
+    check_preferred_destination(&account_data, &reward_accounts)?;
+    let clock = current_time(accounts)?;
+    let settlement =
+        if config.proof_of_hold && !still_holds_nft(&account_data, &config, nft_token_account) {
+            msg!(
+                "NFT no longer held, forfeiting accrual since {}",
+                account_data.last_redeem_time
+            );
+            Settlement::Forfeit
+        } else {
+            Settlement::Pay
+        };
+    let settle_at = within_qualified_stretch(&mut account_data, stretch, clock.unix_timestamp);
+    let reward_amt = apply_reward(&mut account_data, settle_at, &config, settlement)?;
+    if reward_amt == 0 && config.reject_zero_reward_redeem {
+        return Err(StakeError::NoRewardAccrued.into());
+    }
+    let amount = pay_stake_reward(
+        &mut config,
+        &mut account_data,
+        &reward_accounts,
+        mint_authority_bump,
+        reward_amt,
+        clock.unix_timestamp,
+        governance_token_account,
+    )?;
+    bump_counter(&mut config.redeem_count, 1)?;
+    config.serialize(&mut &mut stake_config.data.borrow_mut()[..])?;
+    save_stake_info(&account_data, stake_state)?;
+    events::emit(StakeEvent::Redeemed {
+        stake_state: *stake_state.key,
+        user: *user.key,
+        amount,
+        redeemed_at: clock.unix_timestamp,
+    });
+    Ok(Some(Redemption {
+        user: *user.key,
+        nft_mint: account_data.nft_mint,
+        amount,
+        redeemed_at: clock.unix_timestamp,
+        config,
+    }))
+}
+
+/// Whether the recorded `nft_token_account` still holds the staked NFT for the
+/// staker, as its owner, as the approved delegate when the staker is one of
+/// the `delegate_stake_authorities`, or in the account of the owner whose
+/// ownership proof is on record.
+fn still_holds_nft(
+    account_data: &UserStakeInfo,
+    config: &StakeConfig,
+    nft_token_account: &AccountInfo,
+) -> bool {
+    if account_data.is_compressed() {
+        return true;
+    }
+    if !is_token_program(nft_token_account.owner) {
+        return false;
+    }
+    let token_account = match unpack_token_account(nft_token_account) {
+        Ok(token_account) => token_account,
+        Err(_) => return false,
+    };
+    let held_by_user = token_account.owner == account_data.user
+        || account_data.is_proof_verified_for(&token_account.owner)
+        || (token_account.delegate == COption::Some(account_data.user)
+            && token_account.delegated_amount >= token_account.amount
+            && config
+                .delegate_stake_authorities
+                .contains(&account_data.user));
+    token_account.mint == account_data.nft_mint && token_account.amount >= 1 && held_by_user
+}
+
+/// Rejects a reward account other than the stake's preferred destination, if
+/// one is set.
+fn check_preferred_destination(
+    account_data: &UserStakeInfo,
+    reward_accounts: &RewardAccounts,
+) -> ProgramResult {
+    if account_data.preferred_reward_destination != Pubkey::default()
+        && account_data.preferred_reward_destination != *reward_accounts.user_reward_account.key
+    {
+        msg!(
+            "Rewards go to the preferred destination {}",
+            account_data.preferred_reward_destination
+        );
+        return Err(StakeError::InvalidRewardDestination.into());
+    }
+    Ok(())
+}
+
+/// Boost earned by the balance of the user's governance token account: the
+/// `boost_bps` of the highest tier whose `min_balance` it reaches. No boost
+/// applies when governance boosts are not configured or no account is passed.
+fn governance_boost_bps(
+    config: &StakeConfig,
+    user: &Pubkey,
+    governance_token_account: Option<&AccountInfo>,
+) -> Result<u16, ProgramError> {
+    let governance_token_account = match governance_token_account {
+        Some(account) if config.governance_mint != Pubkey::default() => account,
+        _ => return Ok(0),
+    };
+    if !is_token_program(governance_token_account.owner) {
+        return Err(StakeError::InvalidGovernanceAccount.into());
+    }
+    let token_account = unpack_token_account(governance_token_account)?;
+    if token_account.owner != *user || token_account.mint != config.governance_mint {
+        return Err(StakeError::InvalidGovernanceAccount.into());
+    }
+    Ok(config
+        .governance_boost_tiers
+        .iter()
+        .rev()
+        .find(|tier| token_account.amount >= tier.min_balance)
+        .map_or(0, |tier| tier.boost_bps))
+}
+
+/// Ends a stake, paying or deferring its final reward. Like `Redeem`, it
+/// fails with `TransactionExpired` once the slot is past the optional
+/// `valid_until_slot`.
+pub fn process_unstake(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    valid_until_slot: Option<u64>,
+) -> ProgramResult {
+    check_deadline(valid_until_slot, accounts)?;
+    let account_info_iter = &mut accounts.iter();
+    let user = next_account_info(account_info_iter)?;
+    let position = StakePosition::next(account_info_iter)?;
+    let user_stake_account = next_account_info(account_info_iter)?;
+    let stake_config = next_account_info(account_info_iter)?;
+    let reward_accounts = RewardAccounts::next(account_info_iter)?;
+    let governance_token_account = next_account_info(account_info_iter).ok();
+
+    if !user.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    let mut config = load_config(program_id, stake_config)?;
+    let mint_authority_bump = check_reward_accounts(program_id, &config, &reward_accounts)?;
+    unstake(
+        program_id,
+        user,
+        user_stake_account,
+        &position,
+        &mut config,
+        &reward_accounts,
+        mint_authority_bump,
+        current_time(accounts)?.unix_timestamp,
+        governance_token_account,
+    )?;
+    record_unstakes(&mut config, stake_config, 1)
+}
+
+/// Unstakes every `(nft_token_account, stake_state, staked_mint)` position
+/// passed after the shared `user`, `user_stake_account`, `stake_config` and
+/// reward accounts.
+///
+/// A missing `user` signature, invalid config or reward accounts, or a
+/// malformed batch (empty, trailing accounts, more than
+/// `MAX_UNSTAKE_BATCH_SIZE` positions) fails the whole instruction. A position
+/// failing validation (see `check_unstake`) is skipped before anything is paid
+/// or written for it, and the remaining ones are still processed. Once a
+/// position passes validation, any error while paying or saving it fails the
+/// whole instruction, so no reward is ever paid for a stake left active. The
+/// return data is a little endian `u16` bitmap with one bit per
+/// position, in order, least significant bit first, set when that position was
+/// unstaked.
+pub fn process_unstake_batch(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let user = next_account_info(account_info_iter)?;
+    let user_stake_account = next_account_info(account_info_iter)?;
+    let stake_config = next_account_info(account_info_iter)?;
+    let reward_accounts = RewardAccounts::next(account_info_iter)?;
+
+    if !user.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    let mut config = load_config(program_id, stake_config)?;
+    let mint_authority_bump = check_reward_accounts(program_id, &config, &reward_accounts)?;
+    load_user_stake_account(program_id, user.key, user_stake_account)?;
+    let (positions, governance_token_account) = split_governance_account(account_info_iter);
+    let positions = positions.chunks_exact(3);
+    if positions.len() == 0
+        || positions.len() > MAX_UNSTAKE_BATCH_SIZE
+        || !positions.remainder().is_empty()
+    {
+        return Err(StakeError::InvalidBatchSize.into());
+    }
+    let now = current_time(accounts)?.unix_timestamp;
+    let mut results: u16 = 0;
+    for (i, accounts) in positions.enumerate() {
+        let position = StakePosition {
+            nft_token_account: &accounts[0],
+            stake_state: &accounts[1],
+            staked_mint: &accounts[2],
+        };
+        let account_data = match check_unstake(program_id, user, user_stake_account, &position) {
+            Ok(account_data) => account_data,
+            Err(e) => {
+                msg!("Skipping stake account {}: {}", position.stake_state.key, e);
+                continue;
+            }
+        };
+        finish_unstake(
+            program_id,
+            user,
+            user_stake_account,
+            &position,
+            &mut config,
+            &reward_accounts,
+            mint_authority_bump,
+            now,
+            governance_token_account,
+            account_data,
+        )?;
+        results |= 1 << i;
+    }
+    record_unstakes(&mut config, stake_config, results.count_ones())?;
+    set_return_data(&results.to_le_bytes());
+    Ok(())
+}
+
+struct StakePosition<'a, 'b> {
+    nft_token_account: &'a AccountInfo<'b>,
+    stake_state: &'a AccountInfo<'b>,
+    staked_mint: &'a AccountInfo<'b>,
+}
+
+impl<'a, 'b> StakePosition<'a, 'b> {
+    fn next<I: Iterator<Item = &'a AccountInfo<'b>>>(iter: &mut I) -> Result<Self, ProgramError> {
+        Ok(Self {
+            nft_token_account: next_account_info(iter)?,
+            stake_state: next_account_info(iter)?,
+            staked_mint: next_account_info(iter)?,
+        })
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn unstake(
+    program_id: &Pubkey,
+    user: &AccountInfo,
+    user_stake_account: &AccountInfo,
+    position: &StakePosition,
+    config: &mut StakeConfig,
+    reward_accounts: &RewardAccounts,
+    mint_authority_bump: u8,
+    now: UnixTimestamp,
+    governance_token_account: Option<&AccountInfo>,
+) -> ProgramResult {
+    let account_data = check_unstake(program_id, user, user_stake_account, position)?;
+    finish_unstake(
+        program_id,
+        user,
+        user_stake_account,
+        position,
+        config,
+        reward_accounts,
+        mint_authority_bump,
+        now,
+        governance_token_account,
+        account_data,
+    )
+}
+
+/// The accounts left in `account_info_iter`, less a trailing governance token
+/// account: the one left over after whole three-account positions.
+fn split_governance_account<'a, 'b>(
+    account_info_iter: &mut std::slice::Iter<'a, AccountInfo<'b>>,
+) -> (&'a [AccountInfo<'b>], Option<&'a AccountInfo<'b>>) {
+    let rest = account_info_iter.as_slice();
+    match rest.split_last() {
+        Some((governance_token_account, positions)) if rest.len() % 3 == 1 => {
+            (positions, Some(governance_token_account))
+        }
+        _ => (rest, None),
+    }
+}
+
+/// Every check `finish_unstake` relies on, run before anything is paid or
+/// written: the active stake, its `StakedMint` record, the user's
+/// `UserStakeAccount` and a stake account large enough to be saved. Returns
+/// the loaded stake.
+fn check_unstake(
+    program_id: &Pubkey,
+    user: &AccountInfo,
+    user_stake_account: &AccountInfo,
+    position: &StakePosition,
+) -> Result<UserStakeInfo, ProgramError> {
+    let account_data = load_active_stake(
+        program_id,
+        user,
+        position.nft_token_account,
+        position.stake_state,
+    )?;
+    if position.stake_state.data_len() < UserStakeInfo::SIZE {
+        msg!("Stake account predates the current layout, migrate it first");
+        return Err(StakeError::AccountNeedsMigration.into());
+    }
+    if account_data.nft_mint != Pubkey::default() {
+        load_staked_mint(program_id, position.staked_mint, &account_data.nft_mint)?;
+    }
+    load_user_stake_account(program_id, user.key, user_stake_account)?;
+    Ok(account_data)
+}
+
+/// Pays or defers the final reward of a stake `check_unstake` accepted and
+/// ends it.
+#[allow(clippy::too_many_arguments)]
+fn finish_unstake(
+    program_id: &Pubkey,
+    user: &AccountInfo,
+    user_stake_account: &AccountInfo,
+    position: &StakePosition,
+    config: &mut StakeConfig,
+    reward_accounts: &RewardAccounts,
+    mint_authority_bump: u8,
+    now: UnixTimestamp,
+    governance_token_account: Option<&AccountInfo>,
+    mut account_data: UserStakeInfo,
+) -> ProgramResult {
+    let StakePosition {
+        stake_state,
+        staked_mint,
+        ..
+    } = *position;
+    let mut user_stakes = load_user_stake_account(program_id, user.key, user_stake_account)?;
+    let settle_at = within_qualified_stretch(
+        &mut account_data,
+        qualified_stretch(config, &user_stakes),
+        now,
+    );
+    let mut reward_amt = apply_reward(&mut account_data, settle_at, config, Settlement::Exit)?;
+    // Points are credited to the stake as it ends; only tokens and SOL are
+    // deferred.
+    let withheld_for_pause = payouts_withheld(config);
+    let reward_deferred = config.accrual_mode != AccrualMode::Points
+        && (!config.auto_redeems_on_unstake()
+            || config.final_reward_grace_period > 0
+            || withheld_for_pause);
+    if reward_deferred {
+        msg!("Final reward deferred: {}", reward_amt);
+        if now > account_data.final_reward_expires_at {
+            account_data.unclaimed_final_reward = 0;
+        }
+        account_data.unclaimed_final_reward = account_data
+            .unclaimed_final_reward
+            .checked_add(reward_amt)
+            .ok_or(StakeError::RewardOverflow)?;
+        // Without a grace period, or while withheld for a pause, the reward
+        // stays claimable until it is claimed.
+        account_data.final_reward_expires_at =
+            if withheld_for_pause || config.final_reward_grace_period <= 0 {
+                UnixTimestamp::MAX
+            } else {
+                now.saturating_add(config.final_reward_grace_period)
+            };
+    } else {
+        reward_amt = pay_stake_reward(
+            config,
+            &mut account_data,
+            reward_accounts,
+            mint_authority_bump,
+            reward_amt,
+            now,
+            governance_token_account,
+        )?;
+    }
+    account_data.set_stake_active(false);
+    account_data.set_proof_verified(false);
+    account_data.last_unstake_time = now;
+    release_staked_mint(
+        program_id,
+        staked_mint,
+        &account_data.nft_mint,
+        stake_state.key,
+        now,
+    )?;
+    let active_stakes = user_stakes.active_stakes.saturating_sub(1);
+    set_active_stakes(config, &mut user_stakes, active_stakes, now);
+    save_user_stakes(&user_stakes, user_stake_account)?;
+    save_stake_info(&account_data, stake_state)?;
+    events::emit(StakeEvent::Unstaked {
+        stake_state: *stake_state.key,
+        user: *user.key,
+        nft_mint: account_data.nft_mint,
+        reward: reward_amt,
+        reward_deferred,
+        unstaked_at: now,
+    });
     Ok(())
 }
 
-pub fn process_unstake(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+/// Whether unstake payouts are withheld: accrual is paused and
+/// `pay_reward_on_paused_unstake` is not set. Unstake then still releases the
+/// NFT but defers the final reward until after the pause.
+fn payouts_withheld(config: &StakeConfig) -> bool {
+    config.paused_since != 0 && !config.pay_reward_on_paused_unstake
+}
+
+/// Mints reward tokens for the points a stake earned under
+/// `AccrualMode::Points`, at `points_to_token_rate` token units per point in
+/// fixed point (see `REWARD_PRECISION`), and zeroes its points. Each stake
+/// account converts once; a zero rate leaves conversion disabled.
+pub fn process_convert_points_to_tokens(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+) -> ProgramResult {
     let account_info_iter = &mut accounts.iter();
     let user = next_account_info(account_info_iter)?;
     let nft_token_account = next_account_info(account_info_iter)?;
     let stake_state = next_account_info(account_info_iter)?;
+    let stake_config = next_account_info(account_info_iter)?;
+    let reward_accounts = RewardAccounts::next(account_info_iter)?;
 
     if !user.is_signer {
         return Err(ProgramError::MissingRequiredSignature);
@@ -171,32 +1303,8827 @@ pub fn process_unstake(program_id: &Pubkey, accounts: &[AccountInfo]) -> Program
     if stake_state.owner != program_id {
         return Err(ProgramError::IllegalOwner);
     }
-    let (pda, _bump) = Pubkey::find_program_address(
-        &[user.key.as_ref(), nft_token_account.key.as_ref()],
+    let mut account_data = load_stake_info(stake_state)?;
+    verify_stake_pda(
         program_id,
-    );
-    if pda != *stake_state.key {
-        return Err(StakeError::InvalidPda.into());
-    }
-    let mut account_data =
-        try_from_slice_unchecked::<UserStakeInfo>(&stake_state.data.borrow()).unwrap();
+        user.key,
+        nft_token_account.key,
+        stake_state,
+        account_data.seed_version,
+        account_data.bump,
+    )?;
     if !account_data.is_initialized() {
         return Err(StakeError::UninitializedAccount.into());
     }
-    if !account_data.is_stake_active {
-        return Err(ProgramError::InvalidArgument);
-    }
     if account_data.user != *user.key {
         return Err(StakeError::InvalidStakeAccount.into());
     }
-    if account_data.token_account != *nft_token_account.key {
-        return Err(StakeError::InvalidTokenAccount.into());
+    if account_data.is_points_converted() {
+        return Err(StakeError::PointsAlreadyConverted.into());
     }
-    let clock = Clock::get()?;
-    let reward_amt = clock.unix_timestamp - account_data.last_redeem_time;
-    msg!("Reward: {}", reward_amt);
-    account_data.last_redeem_time = clock.unix_timestamp;
-    account_data.is_stake_active = false;
-    account_data.serialize(&mut &mut stake_state.data.borrow_mut()[..])?;
+    if account_data.points == 0 {
+        return Err(StakeError::NoRewardAccrued.into());
+    }
+    let mut config = load_config(program_id, stake_config)?;
+    if config.points_to_token_rate == 0 {
+        msg!("Points conversion is disabled");
+        return Err(ProgramError::InvalidArgument);
+    }
+    if payouts_withheld(&config) {
+        return Err(StakeError::RewardsPaused.into());
+    }
+    let mint_authority_bump = check_reward_accounts(program_id, &config, &reward_accounts)?;
+    check_preferred_destination(&account_data, &reward_accounts)?;
+    rescale_to_current_mint(&mut account_data, &config);
+    let points = account_data.points;
+    let amount = (points as u128)
+        .checked_mul(config.points_to_token_rate as u128)
+        .map(|scaled| scaled / REWARD_PRECISION as u128)
+        .and_then(|amount| u64::try_from(amount).ok())
+        .ok_or(StakeError::RewardOverflow)?;
+    msg!("Converting {} points to {}", points, amount);
+    mint_reward(&mut config, &reward_accounts, mint_authority_bump, amount)?;
+    config.total_points = config.total_points.saturating_sub(points);
+    config.serialize(&mut &mut stake_config.data.borrow_mut()[..])?;
+    account_data.points = 0;
+    account_data.set_points_converted(true);
+    save_stake_info(&account_data, stake_state)?;
+    events::emit(StakeEvent::PointsConverted {
+        stake_state: *stake_state.key,
+        points,
+        amount,
+    });
     Ok(())
 }
+
+/// Pays the final reward that `Unstake` deferred while a
+/// `final_reward_grace_period` is configured, while paused, or because
+/// `auto_redeem_on_unstake` is off. Once its grace window has passed the
+/// reward is forfeited; rewards deferred without a grace period do not expire,
+/// and those withheld for a pause can only be claimed once accrual resumes.
+pub fn process_claim_final_reward(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let user = next_account_info(account_info_iter)?;
+    let nft_token_account = next_account_info(account_info_iter)?;
+    let stake_state = next_account_info(account_info_iter)?;
+    let stake_config = next_account_info(account_info_iter)?;
+    let reward_accounts = RewardAccounts::next(account_info_iter)?;
+    let governance_token_account = next_account_info(account_info_iter).ok();
+
+    if !user.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    if stake_state.owner != program_id {
+        return Err(ProgramError::IllegalOwner);
+    }
+    let mut account_data = load_stake_info(stake_state)?;
+    verify_stake_pda(
+        program_id,
+        user.key,
+        nft_token_account.key,
+        stake_state,
+        account_data.seed_version,
+        account_data.bump,
+    )?;
+    if !account_data.is_initialized() {
+        return Err(StakeError::UninitializedAccount.into());
+    }
+    if account_data.user != *user.key {
+        return Err(StakeError::InvalidStakeAccount.into());
+    }
+    if account_data.unclaimed_final_reward == 0 {
+        return Err(StakeError::NoRewardAccrued.into());
+    }
+    let clock = current_time(accounts)?;
+    if clock.unix_timestamp > account_data.final_reward_expires_at {
+        msg!(
+            "Final reward expired at {}",
+            account_data.final_reward_expires_at
+        );
+        return Err(StakeError::FinalRewardExpired.into());
+    }
+    let mut config = load_config(program_id, stake_config)?;
+    if payouts_withheld(&config) {
+        return Err(StakeError::RewardsPaused.into());
+    }
+    let mint_authority_bump = check_reward_accounts(program_id, &config, &reward_accounts)?;
+    rescale_to_current_mint(&mut account_data, &config);
+    msg!("Final reward: {}", account_data.unclaimed_final_reward);
+    // The fee discount is for how long the NFT was staked, up to its unstake.
+    let (reward, unstaked_at) = (
+        account_data.unclaimed_final_reward,
+        account_data.last_unstake_time,
+    );
+    let amount = pay_stake_reward(
+        &mut config,
+        &mut account_data,
+        &reward_accounts,
+        mint_authority_bump,
+        reward,
+        unstaked_at,
+        governance_token_account,
+    )?;
+    config.serialize(&mut &mut stake_config.data.borrow_mut()[..])?;
+    events::emit(StakeEvent::FinalRewardClaimed {
+        stake_state: *stake_state.key,
+        amount,
+    });
+    account_data.unclaimed_final_reward = 0;
+    save_stake_info(&account_data, stake_state)
+}
+
+/// Burns an actively staked NFT for a one-time `burn_reward`. The stake is
+/// first unstaked as usual, then the NFT is burned and the burn reward, along
+/// with any deferred final reward still within its grace window, is paid
+/// before the stake account is closed. Requires `allow_burn` and an explicit `confirm_burn`.
+pub fn process_burn_and_reward(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    confirm_burn: bool,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let user = next_account_info(account_info_iter)?;
+    let position = StakePosition::next(account_info_iter)?;
+    let nft_mint = next_account_info(account_info_iter)?;
+    let user_stake_account = next_account_info(account_info_iter)?;
+    let stake_config = next_account_info(account_info_iter)?;
+    let reward_accounts = RewardAccounts::next(account_info_iter)?;
+    let governance_token_account = next_account_info(account_info_iter).ok();
+
+    if !user.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    let mut config = load_config(program_id, stake_config)?;
+    if !config.allow_burn || !confirm_burn {
+        return Err(StakeError::BurnDisabled.into());
+    }
+    if payouts_withheld(&config) {
+        return Err(StakeError::RewardsPaused.into());
+    }
+    let mint_authority_bump = check_reward_accounts(program_id, &config, &reward_accounts)?;
+    let now = current_time(accounts)?.unix_timestamp;
+    unstake(
+        program_id,
+        user,
+        user_stake_account,
+        &position,
+        &mut config,
+        &reward_accounts,
+        mint_authority_bump,
+        now,
+        governance_token_account,
+    )?;
+
+    let token_account = unpack_token_account(position.nft_token_account)?;
+    if token_account.mint != *nft_mint.key {
+        return Err(StakeError::InvalidTokenAccount.into());
+    }
+    invoke(
+        &for_token_program(
+            spl_token::instruction::burn(
+                &spl_token::id(),
+                position.nft_token_account.key,
+                nft_mint.key,
+                user.key,
+                &[],
+                token_account.amount,
+            )?,
+            reward_accounts.token_program.key,
+        ),
+        &[
+            position.nft_token_account.clone(),
+            nft_mint.clone(),
+            user.clone(),
+            reward_accounts.token_program.clone(),
+        ],
+    )?;
+
+    // Closing the account settles the deferred final reward with it, unless
+    // its grace window has run out.
+    let account_data = load_stake_info(position.stake_state)?;
+    let mut payout = config.burn_reward;
+    if now <= account_data.final_reward_expires_at {
+        payout = payout
+            .checked_add(account_data.unclaimed_final_reward)
+            .ok_or(StakeError::RewardOverflow)?;
+    }
+    msg!("Burn reward: {}", payout);
+    mint_reward(&mut config, &reward_accounts, mint_authority_bump, payout)?;
+    record_unstakes(&mut config, stake_config, 1)?;
+
+    let refund = position.stake_state.lamports();
+    **position.stake_state.try_borrow_mut_lamports()? = 0;
+    **user.try_borrow_mut_lamports()? += refund;
+    position.stake_state.data.borrow_mut().fill(0);
+    events::emit(StakeEvent::NftBurned {
+        stake_state: *position.stake_state.key,
+        nft_mint: *nft_mint.key,
+        reward: payout,
+    });
+    Ok(())
+}
+
+/// Admin only. Records the stake accounts passed after the `admin`,
+/// `stake_config`, `snapshot` and `system_program` accounts, and the
+/// instructions sysvar with a timelock configured, that qualify for
+/// airdrop `snapshot_id` into its `AirdropSnapshot` PDA, creating it on the
+/// first call. Later calls for the same id append to it, evaluated at the
+/// original `taken_at` and `min_duration`. Accounts that are not active stake
+/// accounts, have not been staked long enough or are already recorded are
+/// skipped.
+pub fn process_snapshot_stakers(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    snapshot_id: u64,
+    min_duration: UnixTimestamp,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let admin = next_account_info(account_info_iter)?;
+    let stake_config = next_account_info(account_info_iter)?;
+    let snapshot = next_account_info(account_info_iter)?;
+    let system_program = next_account_info(account_info_iter)?;
+
+    if !admin.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    let config = load_config(program_id, stake_config)?;
+    if config.admin != *admin.key {
+        return Err(StakeError::Unauthorized.into());
+    }
+    check_not_finalized(&config)?;
+    check_timelock(&config, account_info_iter)?;
+    let (pda, bump) = Pubkey::find_program_address(
+        &[AIRDROP_SNAPSHOT_SEED, &snapshot_id.to_le_bytes()],
+        program_id,
+    );
+    if pda != *snapshot.key {
+        return Err(StakeError::InvalidPda.into());
+    }
+    let mut record = if snapshot.data_is_empty() {
+        let space = AirdropSnapshot::SIZE;
+        let rent_lamports = Rent::get()?.minimum_balance(space);
+        invoke_signed(
+            &system_instruction::create_account(
+                admin.key,
+                snapshot.key,
+                rent_lamports,
+                space.try_into().unwrap(),
+                program_id,
+            ),
+            &[admin.clone(), snapshot.clone(), system_program.clone()],
+            &[&[AIRDROP_SNAPSHOT_SEED, &snapshot_id.to_le_bytes(), &[bump]]],
+        )?;
+        AirdropSnapshot {
+            is_initialized: true,
+            snapshot_id,
+            taken_at: current_time(accounts)?.unix_timestamp,
+            min_duration,
+            stake_accounts: Vec::new(),
+        }
+    } else {
+        if snapshot.owner != program_id {
+            return Err(ProgramError::IllegalOwner);
+        }
+        try_from_slice_unchecked::<AirdropSnapshot>(&snapshot.data.borrow())?
+    };
+
+    for stake_state in account_info_iter {
+        if stake_state.owner != program_id || record.stake_accounts.contains(stake_state.key) {
+            continue;
+        }
+        let account_data = match load_stake_info(stake_state) {
+            Ok(account_data) => account_data,
+            Err(_) => continue,
+        };
+        if !account_data.is_initialized()
+            || !account_data.is_stake_active()
+            || record
+                .taken_at
+                .saturating_sub(account_data.stake_start_time)
+                < record.min_duration
+        {
+            continue;
+        }
+        if record.stake_accounts.len() >= MAX_SNAPSHOT_STAKE_ACCOUNTS {
+            return Err(StakeError::SnapshotFull.into());
+        }
+        record.stake_accounts.push(*stake_state.key);
+    }
+    msg!(
+        "Snapshot {} holds {} stake accounts",
+        snapshot_id,
+        record.stake_accounts.len()
+    );
+    record.serialize(&mut &mut snapshot.data.borrow_mut()[..])?;
+    events::emit(StakeEvent::SnapshotUpdated {
+        snapshot_id,
+        stake_accounts: record.stake_accounts.len() as u32,
+    });
+    Ok(())
+}
+
+/// Sets the reward token account `Redeem` pays this stake's rewards to. While
+/// set, redeems must pass it as the `user_reward_account`; the default pubkey
+/// clears the preference.
+pub fn process_set_reward_destination(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    destination: Pubkey,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let user = next_account_info(account_info_iter)?;
+    let nft_token_account = next_account_info(account_info_iter)?;
+    let stake_state = next_account_info(account_info_iter)?;
+    let stake_config = next_account_info(account_info_iter)?;
+    let destination_account = next_account_info(account_info_iter)?;
+
+    if !user.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    if stake_state.owner != program_id {
+        return Err(ProgramError::IllegalOwner);
+    }
+    let mut account_data = load_stake_info(stake_state)?;
+    verify_stake_pda(
+        program_id,
+        user.key,
+        nft_token_account.key,
+        stake_state,
+        account_data.seed_version,
+        account_data.bump,
+    )?;
+    if !account_data.is_initialized() {
+        return Err(StakeError::UninitializedAccount.into());
+    }
+    if account_data.user != *user.key {
+        return Err(StakeError::InvalidStakeAccount.into());
+    }
+    if destination != Pubkey::default() {
+        let config = load_config(program_id, stake_config)?;
+        if *destination_account.key != destination
+            || *destination_account.owner != pinned_token_program(&config)
+            || unpack_token_account(destination_account)?.mint != config.reward_mint
+        {
+            return Err(StakeError::InvalidRewardDestination.into());
+        }
+    }
+    account_data.preferred_reward_destination = destination;
+    save_stake_info(&account_data, stake_state)?;
+    events::emit(StakeEvent::RewardDestinationSet {
+        stake_state: *stake_state.key,
+        destination,
+    });
+    Ok(())
+}
+
+/// Compounds the pending reward into the stake's `virtual_staked_amount`
+/// instead of minting it, so it earns `compound_rate_bps` per day from then
+/// on. The virtual balance is capped at `max_virtual_staked_amount`; reward
+/// beyond the cap is forfeited. With `min_nfts_to_qualify` set, the user's
+/// `UserStakeAccount` has to be passed and only their `qualified_stretch` is
+/// compounded.
+pub fn process_compound_reward(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let user = next_account_info(account_info_iter)?;
+    let nft_token_account = next_account_info(account_info_iter)?;
+    let stake_state = next_account_info(account_info_iter)?;
+    let stake_config = next_account_info(account_info_iter)?;
+
+    if !user.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    if stake_state.owner != program_id {
+        return Err(ProgramError::IllegalOwner);
+    }
+    let mut account_data = load_stake_info(stake_state)?;
+    verify_stake_pda(
+        program_id,
+        user.key,
+        nft_token_account.key,
+        stake_state,
+        account_data.seed_version,
+        account_data.bump,
+    )?;
+    if !account_data.is_initialized() {
+        return Err(StakeError::UninitializedAccount.into());
+    }
+    if !account_data.is_stake_active() {
+        return Err(StakeError::StakeNotActive.into());
+    }
+    if account_data.user != *user.key {
+        return Err(StakeError::InvalidStakeAccount.into());
+    }
+    let config = load_config(program_id, stake_config)?;
+    if config.max_virtual_staked_amount == 0 {
+        return Err(StakeError::CompoundingDisabled.into());
+    }
+    let clock = current_time(accounts)?;
+    let stretch = find_qualified_stretch(program_id, &config, user.key, accounts)?;
+    let settle_at = within_qualified_stretch(&mut account_data, stretch, clock.unix_timestamp);
+    let reward_amt = apply_reward(&mut account_data, settle_at, &config, Settlement::Pay)?;
+    account_data.virtual_staked_amount = account_data
+        .virtual_staked_amount
+        .saturating_add(reward_amt)
+        .min(config.max_virtual_staked_amount);
+    msg!(
+        "Compounded {}, virtual staked amount: {}",
+        reward_amt,
+        account_data.virtual_staked_amount
+    );
+    save_stake_info(&account_data, stake_state)?;
+    events::emit(StakeEvent::RewardCompounded {
+        stake_state: *stake_state.key,
+        amount: reward_amt,
+        virtual_staked_amount: account_data.virtual_staked_amount,
+    });
+    Ok(())
+}
+
+/// Admin only. Records the slot `wallet` was first seen, as attested by the
+/// admin, in its `WalletAgeRecord` PDA, creating it if needed.
+pub fn process_attest_wallet_age(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    wallet: Pubkey,
+    first_seen_slot: u64,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let admin = next_account_info(account_info_iter)?;
+    let stake_config = next_account_info(account_info_iter)?;
+    let wallet_age_record = next_account_info(account_info_iter)?;
+    let system_program = next_account_info(account_info_iter)?;
+
+    if !admin.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    let config = load_config(program_id, stake_config)?;
+    if config.admin != *admin.key {
+        return Err(StakeError::Unauthorized.into());
+    }
+    check_not_finalized(&config)?;
+    check_timelock(&config, account_info_iter)?;
+    let (pda, bump) = Pubkey::find_program_address(&[WALLET_AGE_SEED, wallet.as_ref()], program_id);
+    if pda != *wallet_age_record.key {
+        return Err(StakeError::InvalidPda.into());
+    }
+    if wallet_age_record.data_is_empty() {
+        let space = WalletAgeRecord::SIZE;
+        let rent_lamports = Rent::get()?.minimum_balance(space);
+        invoke_signed(
+            &system_instruction::create_account(
+                admin.key,
+                wallet_age_record.key,
+                rent_lamports,
+                space.try_into().unwrap(),
+                program_id,
+            ),
+            &[
+                admin.clone(),
+                wallet_age_record.clone(),
+                system_program.clone(),
+            ],
+            &[&[WALLET_AGE_SEED, wallet.as_ref(), &[bump]]],
+        )?;
+    } else if wallet_age_record.owner != program_id {
+        return Err(ProgramError::IllegalOwner);
+    }
+    let record = WalletAgeRecord {
+        is_initialized: true,
+        wallet,
+        first_seen_slot,
+    };
+    record.serialize(&mut &mut wallet_age_record.data.borrow_mut()[..])?;
+    events::emit(StakeEvent::WalletAgeAttested {
+        wallet,
+        first_seen_slot,
+    });
+    Ok(())
+}
+
+/// Admin only. Moves the whole balance of a token account owned by the mint
+/// authority PDA, typically tokens sent to the program by mistake, to the
+/// admin's `destination` account. Accounts the program itself relies on are
+/// protected, see `is_protected_token_account`.
+pub fn process_recover_stray_tokens(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let admin = next_account_info(account_info_iter)?;
+    let stake_config = next_account_info(account_info_iter)?;
+    let stray_token_account = next_account_info(account_info_iter)?;
+    let destination = next_account_info(account_info_iter)?;
+    let program_authority = next_account_info(account_info_iter)?;
+    let token_program = next_account_info(account_info_iter)?;
+
+    if !admin.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    let config = load_config(program_id, stake_config)?;
+    if config.admin != *admin.key {
+        return Err(StakeError::Unauthorized.into());
+    }
+    check_not_finalized(&config)?;
+    check_timelock(&config, account_info_iter)?;
+    check_token_program(&config, token_program)?;
+    let (authority, bump) = Pubkey::find_program_address(&[MINT_AUTHORITY_SEED], program_id);
+    if authority != *program_authority.key {
+        return Err(StakeError::InvalidPda.into());
+    }
+    if *stray_token_account.owner != *token_program.key {
+        return Err(StakeError::InvalidTokenAccount.into());
+    }
+    let stray = unpack_token_account(stray_token_account)?;
+    if is_protected_token_account(program_id, &config, stray_token_account.key, &stray) {
+        return Err(StakeError::ProtectedAccount.into());
+    }
+    if stray.owner != authority {
+        return Err(StakeError::InvalidTokenAccount.into());
+    }
+    msg!("Recovering {} of mint {}", stray.amount, stray.mint);
+    invoke_signed(
+        &for_token_program(
+            spl_token::instruction::transfer(
+                &spl_token::id(),
+                stray_token_account.key,
+                destination.key,
+                program_authority.key,
+                &[],
+                stray.amount,
+            )?,
+            token_program.key,
+        ),
+        &[
+            stray_token_account.clone(),
+            destination.clone(),
+            program_authority.clone(),
+            token_program.clone(),
+        ],
+        &[&[MINT_AUTHORITY_SEED, &[bump]]],
+    )?;
+    events::emit(StakeEvent::StrayTokensRecovered {
+        token_account: *stray_token_account.key,
+        mint: stray.mint,
+        amount: stray.amount,
+    });
+    Ok(())
+}
+
+/// Whether `RecoverStrayTokens` must leave the token account at `key` alone:
+/// the protocol fee account, reward tokens, which back the pool rather than
+/// being stray, and any account at, or delegated to or closable by, one of
+/// the program's PDAs.
+fn is_protected_token_account(
+    program_id: &Pubkey,
+    config: &StakeConfig,
+    key: &Pubkey,
+    token_account: &TokenAccount,
+) -> bool {
+    if *key == config.protocol_fee_account || token_account.mint == config.reward_mint {
+        return true;
+    }
+    let program_pdas = [
+        CONFIG_SEED,
+        RENT_RESERVE_SEED,
+        SOL_POOL_SEED,
+        REWARD_MINT_SEED,
+    ]
+    .map(|seed| Pubkey::find_program_address(&[seed], program_id).0);
+    program_pdas.iter().any(|pda| {
+        key == pda
+            || token_account.delegate == COption::Some(*pda)
+            || token_account.close_authority == COption::Some(*pda)
+    })
+}
+
+/// Admin only. Sets or, with `None`, clears a stake's per-second rate override,
+/// which replaces the emission schedule for that stake. The reward pending
+/// under the previous rate is checkpointed first, so a change only applies
+/// from now on. With `min_nfts_to_qualify` set, the stake owner's
+/// `UserStakeAccount` has to be passed as well.
+pub fn process_set_rate_override(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    rate: Option<u64>,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let admin = next_account_info(account_info_iter)?;
+    let stake_config = next_account_info(account_info_iter)?;
+    let stake_state = next_account_info(account_info_iter)?;
+
+    if !admin.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    let config = load_config(program_id, stake_config)?;
+    if config.admin != *admin.key {
+        return Err(StakeError::Unauthorized.into());
+    }
+    check_not_finalized(&config)?;
+    check_timelock(&config, account_info_iter)?;
+    if stake_state.owner != program_id {
+        return Err(ProgramError::IllegalOwner);
+    }
+    let mut account_data = load_stake_info(stake_state)?;
+    if !account_data.is_initialized() {
+        return Err(StakeError::UninitializedAccount.into());
+    }
+    rescale_to_current_mint(&mut account_data, &config);
+    if account_data.is_stake_active() {
+        let stretch = find_qualified_stretch(program_id, &config, &account_data.user, accounts)?;
+        let now = current_time(accounts)?.unix_timestamp;
+        let settle_at = within_qualified_stretch(&mut account_data, stretch, now);
+        checkpoint_accrual(&mut account_data, &config, settle_at);
+    }
+    account_data.set_rate_override(rate);
+    refresh_rate_cache(&mut account_data, &config);
+    save_stake_info(&account_data, stake_state)?;
+    events::emit(StakeEvent::RateOverrideSet {
+        stake_state: *stake_state.key,
+        rate,
+    });
+    Ok(())
+}
+
+/// Admin only. Pauses or resumes reward accrual for every stake. Time spent
+/// paused never accrues; the interval is recorded when accrual resumes. Once
+/// `MAX_PAUSE_INTERVALS` are recorded accrual can no longer be paused, while
+/// an ongoing pause can always be resumed.
+pub fn process_set_paused(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    paused: bool,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let admin = next_account_info(account_info_iter)?;
+    let stake_config = next_account_info(account_info_iter)?;
+
+    if !admin.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    let mut config = load_config(program_id, stake_config)?;
+    if config.admin != *admin.key {
+        return Err(StakeError::Unauthorized.into());
+    }
+    check_not_finalized(&config)?;
+    check_timelock(&config, account_info_iter)?;
+    set_paused(&mut config, paused, current_time(accounts)?.unix_timestamp)?;
+    config.serialize(&mut &mut stake_config.data.borrow_mut()[..])?;
+    Ok(())
+}
+
+/// Admin only. Finalizes the pool for good: afterwards `Stake` and every admin
+/// instruction fail with `PoolFinalized`, while redeems, unstakes,
+/// `ReconcileActiveFlag` and `ClaimFinalReward` keep working so stakes can
+/// end. The one exception is
+/// `SwapRewardMint`, kept as a rescue so rewards can still be paid if the
+/// reward mint becomes unusable. There is no way to undo it.
+pub fn process_finalize_pool(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let admin = next_account_info(account_info_iter)?;
+    let stake_config = next_account_info(account_info_iter)?;
+
+    if !admin.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    let mut config = load_config(program_id, stake_config)?;
+    if config.admin != *admin.key {
+        return Err(StakeError::Unauthorized.into());
+    }
+    check_not_finalized(&config)?;
+    check_timelock(&config, account_info_iter)?;
+    config.finalized = true;
+    config.serialize(&mut &mut stake_config.data.borrow_mut()[..])?;
+    events::emit(StakeEvent::PoolFinalized {
+        admin: *admin.key,
+        finalized_at: current_time(accounts)?.unix_timestamp,
+    });
+    Ok(())
+}
+
+/// With a `timelock_program` configured, `admin` is a PDA of that program
+/// and config changes must come from it through CPI: the top-level
+/// instruction, read from the trailing instructions sysvar account, has to be
+/// the timelock program's.
+fn check_timelock<'a, 'b: 'a, I: Iterator<Item = &'a AccountInfo<'b>>>(
+    config: &StakeConfig,
+    account_info_iter: &mut I,
+) -> ProgramResult {
+    if config.timelock_program == Pubkey::default() {
+        return Ok(());
+    }
+    let instructions_sysvar = next_account_info(account_info_iter)?;
+    if !instructions::check_id(instructions_sysvar.key) {
+        return Err(ProgramError::InvalidArgument);
+    }
+    let top_level = instructions::get_instruction_relative(0, instructions_sysvar)?;
+    if top_level.program_id != config.timelock_program {
+        msg!("Called from {}", top_level.program_id);
+        return Err(StakeError::TimelockRequired.into());
+    }
+    Ok(())
+}
+
+/// Rejects a config list of `len` entries longer than its `max`, the
+/// capacity reserved for it in `StakeConfig::SIZE`.
+fn check_entries(len: usize, max: usize) -> ProgramResult {
+    if len > max {
+        msg!("{} entries, at most {} allowed", len, max);
+        return Err(StakeError::TooManyEntries.into());
+    }
+    Ok(())
+}
+
+fn set_paused(config: &mut StakeConfig, paused: bool, now: UnixTimestamp) -> ProgramResult {
+    if paused == (config.paused_since != 0) {
+        return Err(ProgramError::InvalidArgument);
+    }
+    if paused {
+        // Checked on pause rather than on resume, so a pause can always end.
+        if config.pause_intervals.len() >= MAX_PAUSE_INTERVALS {
+            return Err(StakeError::PauseHistoryFull.into());
+        }
+        config.paused_since = now;
+    } else {
+        config.pause_intervals.push(PauseInterval {
+            start: config.paused_since,
+            end: now,
+        });
+        config.paused_since = 0;
+    }
+    events::emit(StakeEvent::PausedSet { paused, at: now });
+    Ok(())
+}
+
+/// Read-only. Evaluates whether `user` could unstake the position now and
+/// writes a borsh `UnstakeEligibility` to return data. Takes the `Unstake`
+/// accounts up to the `UserStakeAccount` and runs the same `check_unstake`,
+/// so accounts `Unstake` would reject are rejected here too rather than
+/// reported as unblocked; only the signature is not required.
+pub fn process_can_unstake(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let user = next_account_info(account_info_iter)?;
+    let position = StakePosition::next(account_info_iter)?;
+    let user_stake_account = next_account_info(account_info_iter)?;
+
+    let status = match check_unstake(program_id, user, user_stake_account, &position) {
+        Ok(_) => UnstakeStatus::Unblocked,
+        Err(error) if error == StakeError::AccountNeedsMigration.into() => {
+            UnstakeStatus::NeedsMigration
+        }
+        Err(error)
+            if error == StakeError::StakeNotActive.into()
+                || error == StakeError::UninitializedAccount.into() =>
+        {
+            UnstakeStatus::NotStaked
+        }
+        Err(error) => return Err(error),
+    };
+    let eligibility = UnstakeEligibility { status };
+    set_return_data(&eligibility.try_to_vec()?);
+    Ok(())
+}
+
+/// Admin only. Creates the reward mint as the program's `REWARD_MINT_SEED`
+/// PDA with `decimals` and the mint authority PDA as its mint authority, plus
+/// a token account of it owned by the admin at the `protocol_fee_account`
+/// keypair, and makes them the config's `reward_mint` and
+/// `protocol_fee_account`. The mint can only be created once.
+pub fn process_initialize_reward_mint(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    decimals: u8,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let admin = next_account_info(account_info_iter)?;
+    let stake_config = next_account_info(account_info_iter)?;
+    let reward_mint = next_account_info(account_info_iter)?;
+    let protocol_fee_account = next_account_info(account_info_iter)?;
+    let token_program = next_account_info(account_info_iter)?;
+    let system_program = next_account_info(account_info_iter)?;
+
+    if !admin.is_signer || !protocol_fee_account.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    let mut config = load_config(program_id, stake_config)?;
+    if config.admin != *admin.key {
+        return Err(StakeError::Unauthorized.into());
+    }
+    check_not_finalized(&config)?;
+    check_timelock(&config, account_info_iter)?;
+    check_token_program(&config, token_program)?;
+    let (mint_pda, mint_bump) = Pubkey::find_program_address(&[REWARD_MINT_SEED], program_id);
+    if mint_pda != *reward_mint.key {
+        return Err(StakeError::InvalidPda.into());
+    }
+    if !reward_mint.data_is_empty() {
+        return Err(ProgramError::AccountAlreadyInitialized);
+    }
+    let (mint_authority, _mint_authority_bump) =
+        Pubkey::find_program_address(&[MINT_AUTHORITY_SEED], program_id);
+    let rent = Rent::get()?;
+
+    invoke_signed(
+        &system_instruction::create_account(
+            admin.key,
+            reward_mint.key,
+            rent.minimum_balance(Mint::LEN),
+            Mint::LEN as u64,
+            token_program.key,
+        ),
+        &[admin.clone(), reward_mint.clone(), system_program.clone()],
+        &[&[REWARD_MINT_SEED, &[mint_bump]]],
+    )?;
+    invoke(
+        &for_token_program(
+            spl_token::instruction::initialize_mint2(
+                &spl_token::id(),
+                reward_mint.key,
+                &mint_authority,
+                None,
+                decimals,
+            )?,
+            token_program.key,
+        ),
+        &[reward_mint.clone(), token_program.clone()],
+    )?;
+    invoke(
+        &system_instruction::create_account(
+            admin.key,
+            protocol_fee_account.key,
+            rent.minimum_balance(TokenAccount::LEN),
+            TokenAccount::LEN as u64,
+            token_program.key,
+        ),
+        &[
+            admin.clone(),
+            protocol_fee_account.clone(),
+            system_program.clone(),
+        ],
+    )?;
+    invoke(
+        &for_token_program(
+            spl_token::instruction::initialize_account3(
+                &spl_token::id(),
+                protocol_fee_account.key,
+                reward_mint.key,
+                admin.key,
+            )?,
+            token_program.key,
+        ),
+        &[
+            protocol_fee_account.clone(),
+            reward_mint.clone(),
+            token_program.clone(),
+        ],
+    )?;
+    msg!("Reward mint {} created", reward_mint.key);
+
+    config.reward_mint = *reward_mint.key;
+    config.protocol_fee_account = *protocol_fee_account.key;
+    config.serialize(&mut &mut stake_config.data.borrow_mut()[..])?;
+    events::emit(StakeEvent::RewardMintInitialized {
+        reward_mint: *reward_mint.key,
+        protocol_fee_account: *protocol_fee_account.key,
+        decimals,
+    });
+    Ok(())
+}
+
+/// Admin only. Replaces a reward mint that can no longer be minted, e.g. one
+/// that was frozen or whose authority was lost, with `new_mint`, together with
+/// a protocol fee token account of it. The new mint must have the current
+/// mint authority. `old_decimals` are the replaced mint's decimals: they are
+/// checked against the old mint when it can still be read, and taken on trust
+/// when it cannot, e.g. once it was closed. When the new mint's decimals
+/// differ, the emission schedule and the config's token amounts are scaled by
+/// 10^(new - old) here, and each stake's stored amounts the next time it is
+/// touched, so stakes carry on accruing at the same value without a break.
+/// The only admin instruction allowed after `FinalizePool`, so users can still
+/// exit with their rewards.
+pub fn process_swap_reward_mint(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    new_mint: Pubkey,
+    old_decimals: u8,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let admin = next_account_info(account_info_iter)?;
+    let stake_config = next_account_info(account_info_iter)?;
+    let old_reward_mint = next_account_info(account_info_iter)?;
+    let new_reward_mint = next_account_info(account_info_iter)?;
+    let protocol_fee_account = next_account_info(account_info_iter)?;
+
+    if !admin.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    let mut config = load_config(program_id, stake_config)?;
+    if config.admin != *admin.key {
+        return Err(StakeError::Unauthorized.into());
+    }
+    check_timelock(&config, account_info_iter)?;
+    check_entries(config.reward_mint_swaps.len() + 1, MAX_REWARD_MINT_SWAPS)?;
+    let token_program = pinned_token_program(&config);
+    if *old_reward_mint.key != config.reward_mint {
+        return Err(StakeError::InvalidRewardMint.into());
+    }
+    if let Some(old_mint) = (*old_reward_mint.owner == token_program)
+        .then(|| unpack_mint(old_reward_mint).ok())
+        .flatten()
+    {
+        if old_mint.decimals != old_decimals {
+            msg!(
+                "Current reward mint has {} decimals, not {}",
+                old_mint.decimals,
+                old_decimals
+            );
+            return Err(StakeError::InvalidRewardMint.into());
+        }
+    }
+    if *new_reward_mint.key != new_mint
+        || new_mint == config.reward_mint
+        || *new_reward_mint.owner != token_program
+    {
+        return Err(StakeError::InvalidRewardMint.into());
+    }
+    let mint = unpack_mint(new_reward_mint)?;
+    let (pda, _bump) = Pubkey::find_program_address(&[MINT_AUTHORITY_SEED], program_id);
+    let mint_authority = current_mint_authority(&config, pda);
+    if mint.mint_authority != COption::Some(mint_authority) {
+        msg!("Reward mint authority must be {}", mint_authority);
+        return Err(StakeError::InvalidMintAuthority.into());
+    }
+    if *protocol_fee_account.owner != token_program {
+        return Err(StakeError::InvalidProtocolFeeAccount.into());
+    }
+    let fee_account = unpack_token_account(protocol_fee_account)?;
+    if fee_account.mint != new_mint {
+        return Err(StakeError::InvalidProtocolFeeAccount.into());
+    }
+
+    let swap = RewardMintSwap {
+        old_decimals,
+        new_decimals: mint.decimals,
+    };
+    rescale_config(&mut config, decimal_shift(&[swap]));
+    config.reward_mint_swaps.push(swap);
+    let old_mint = config.reward_mint;
+    config.reward_mint = new_mint;
+    config.protocol_fee_account = *protocol_fee_account.key;
+    config.serialize(&mut &mut stake_config.data.borrow_mut()[..])?;
+    events::emit(StakeEvent::RewardMintSwapped {
+        old_mint,
+        new_mint,
+        protocol_fee_account: *protocol_fee_account.key,
+    });
+    Ok(())
+}
+
+/// The power of ten amounts denominated before `swaps` are scaled by after
+/// them.
+fn decimal_shift(swaps: &[RewardMintSwap]) -> i32 {
+    swaps
+        .iter()
+        .map(|swap| swap.new_decimals as i32 - swap.old_decimals as i32)
+        .sum()
+}
+
+/// `amount` scaled by 10^`shift`, saturating when scaled up and rounding down
+/// when scaled down.
+fn rescale_amount(amount: u64, shift: i32) -> u64 {
+    let factor = 10u64.checked_pow(shift.unsigned_abs());
+    match (shift >= 0, factor) {
+        (true, Some(factor)) => amount.saturating_mul(factor),
+        (true, None) => amount.saturating_mul(u64::MAX),
+        (false, Some(factor)) => amount / factor,
+        (false, None) => 0,
+    }
+}
+
+/// Scales the config's token denominated amounts by 10^`shift` for a reward
+/// mint swap. An empty emission schedule, which pays `DEFAULT_REWARD_RATE`,
+/// is written out as a single segment first so the rate can scale. Cached
+/// rates are invalidated.
+fn rescale_config(config: &mut StakeConfig, shift: i32) {
+    if shift == 0 {
+        return;
+    }
+    if config.emission_schedule.is_empty() {
+        config.emission_schedule.push(EmissionSegment {
+            start_time: UnixTimestamp::MIN,
+            rate: DEFAULT_REWARD_RATE,
+        });
+    }
+    for segment in &mut config.emission_schedule {
+        segment.rate = rescale_amount(segment.rate, shift);
+    }
+    config.burn_reward = rescale_amount(config.burn_reward, shift);
+    config.max_virtual_staked_amount = rescale_amount(config.max_virtual_staked_amount, shift);
+    config.total_points = rescale_amount(config.total_points, shift);
+    config.rate_revision = config.rate_revision.wrapping_add(1);
+}
+
+/// Brings a stake's stored reward amounts into the units of the current
+/// reward mint, scaling them for every `SwapRewardMint` since the stake's
+/// `reward_mint_generation`. Every instruction reading or settling those
+/// amounts calls this first.
+fn rescale_to_current_mint(account_data: &mut UserStakeInfo, config: &StakeConfig) {
+    let generation = config.reward_mint_swaps.len();
+    let since = (account_data.reward_mint_generation as usize).min(generation);
+    let shift = decimal_shift(&config.reward_mint_swaps[since..]);
+    if shift != 0 {
+        for amount in [
+            &mut account_data.cached_rate,
+            &mut account_data.override_rate,
+            &mut account_data.checkpoint_reward,
+            &mut account_data.reward_remainder,
+            &mut account_data.unclaimed_final_reward,
+            &mut account_data.virtual_staked_amount,
+            &mut account_data.points,
+        ] {
+            *amount = rescale_amount(*amount, shift);
+        }
+    }
+    account_data.reward_mint_generation = generation as u8;
+}
+
+/// Read-only. Writes a borsh `ConfigVersion` comparing the stored config's
+/// schema version with the one this program reads to return data.
+pub fn process_get_config_version(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let stake_config = next_account_info(account_info_iter)?;
+
+    let config = load_config(program_id, stake_config)?;
+    let version = ConfigVersion {
+        stored_version: config.version,
+        program_version: StakeConfig::VERSION,
+        compatible: config.version == StakeConfig::VERSION,
+    };
+    set_return_data(&version.try_to_vec()?);
+    Ok(())
+}
+
+/// Admin only. Adds `lamports` to the `RENT_RESERVE_SEED` PDA, creating it
+/// on first use. While `sponsor_rent` is set, `Stake` draws the rent of the
+/// `StakedMint` and `UserStakeAccount` it creates from the reserve instead of
+/// charging the user. Both are never closed, so sponsored rent is not refunded
+/// to users.
+pub fn process_fund_rent_reserve(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    lamports: u64,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let admin = next_account_info(account_info_iter)?;
+    let stake_config = next_account_info(account_info_iter)?;
+    let rent_reserve = next_account_info(account_info_iter)?;
+    let system_program = next_account_info(account_info_iter)?;
+
+    if !admin.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    let config = load_config(program_id, stake_config)?;
+    if config.admin != *admin.key {
+        return Err(StakeError::Unauthorized.into());
+    }
+    check_not_finalized(&config)?;
+    check_timelock(&config, account_info_iter)?;
+    let (pda, bump) = Pubkey::find_program_address(&[RENT_RESERVE_SEED], program_id);
+    if pda != *rent_reserve.key {
+        return Err(StakeError::InvalidPda.into());
+    }
+    if rent_reserve.owner != program_id {
+        let rent_lamports = Rent::get()?.minimum_balance(0);
+        invoke_signed(
+            &system_instruction::create_account(
+                admin.key,
+                rent_reserve.key,
+                rent_lamports.saturating_add(lamports),
+                0,
+                program_id,
+            ),
+            &[admin.clone(), rent_reserve.clone(), system_program.clone()],
+            &[&[RENT_RESERVE_SEED, &[bump]]],
+        )?;
+    } else {
+        invoke(
+            &system_instruction::transfer(admin.key, rent_reserve.key, lamports),
+            &[admin.clone(), rent_reserve.clone(), system_program.clone()],
+        )?;
+    }
+    msg!("Rent reserve holds {}", rent_reserve.lamports());
+    events::emit(StakeEvent::RentReserveFunded { lamports });
+    Ok(())
+}
+
+/// Admin only. Adds `lamports` to the `SOL_POOL_SEED` PDA, creating it on
+/// first use. Under `AccrualMode::Sol` rewards are paid out of it.
+pub fn process_fund_sol_pool(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    lamports: u64,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let admin = next_account_info(account_info_iter)?;
+    let stake_config = next_account_info(account_info_iter)?;
+    let sol_pool = next_account_info(account_info_iter)?;
+    let system_program = next_account_info(account_info_iter)?;
+
+    if !admin.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    let config = load_config(program_id, stake_config)?;
+    if config.admin != *admin.key {
+        return Err(StakeError::Unauthorized.into());
+    }
+    check_not_finalized(&config)?;
+    check_timelock(&config, account_info_iter)?;
+    let (pda, bump) = Pubkey::find_program_address(&[SOL_POOL_SEED], program_id);
+    if pda != *sol_pool.key {
+        return Err(StakeError::InvalidPda.into());
+    }
+    if sol_pool.owner != program_id {
+        let rent_lamports = Rent::get()?.minimum_balance(0);
+        invoke_signed(
+            &system_instruction::create_account(
+                admin.key,
+                sol_pool.key,
+                rent_lamports.saturating_add(lamports),
+                0,
+                program_id,
+            ),
+            &[admin.clone(), sol_pool.clone(), system_program.clone()],
+            &[&[SOL_POOL_SEED, &[bump]]],
+        )?;
+    } else {
+        invoke(
+            &system_instruction::transfer(admin.key, sol_pool.key, lamports),
+            &[admin.clone(), sol_pool.clone(), system_program.clone()],
+        )?;
+    }
+    msg!("SOL pool holds {}", sol_pool.lamports());
+    events::emit(StakeEvent::SolPoolFunded { lamports });
+    Ok(())
+}
+
+/// Admin only. Hands the reward mint's authority to `new_authority`, e.g. the
+/// mint authority PDA of a successor program, through a `SetAuthority` CPI
+/// signed by the current authority, and records it as the config's
+/// `mint_authority`. Rewards keep being minted afterwards only with the new
+/// authority passed as a signer; rotating back to the mint authority PDA
+/// restores program-signed minting.
+pub fn process_rotate_mint_authority(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    new_authority: Pubkey,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let admin = next_account_info(account_info_iter)?;
+    let stake_config = next_account_info(account_info_iter)?;
+    let reward_mint = next_account_info(account_info_iter)?;
+    let mint_authority = next_account_info(account_info_iter)?;
+    let token_program = next_account_info(account_info_iter)?;
+
+    if !admin.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    let mut config = load_config(program_id, stake_config)?;
+    if config.admin != *admin.key {
+        return Err(StakeError::Unauthorized.into());
+    }
+    check_not_finalized(&config)?;
+    check_timelock(&config, account_info_iter)?;
+    if *reward_mint.key != config.reward_mint {
+        return Err(StakeError::InvalidRewardMint.into());
+    }
+    check_token_program(&config, token_program)?;
+    if new_authority == Pubkey::default() {
+        return Err(ProgramError::InvalidArgument);
+    }
+    let (pda, bump) = Pubkey::find_program_address(&[MINT_AUTHORITY_SEED], program_id);
+    let old_authority = current_mint_authority(&config, pda);
+    if *mint_authority.key != old_authority {
+        return Err(StakeError::InvalidMintAuthority.into());
+    }
+    if old_authority != pda && !mint_authority.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    invoke_signed(
+        &for_token_program(
+            spl_token::instruction::set_authority(
+                &spl_token::id(),
+                reward_mint.key,
+                Some(&new_authority),
+                AuthorityType::MintTokens,
+                mint_authority.key,
+                &[],
+            )?,
+            token_program.key,
+        ),
+        &[
+            reward_mint.clone(),
+            mint_authority.clone(),
+            token_program.clone(),
+        ],
+        &[&[MINT_AUTHORITY_SEED, &[bump]]],
+    )?;
+    msg!("Mint authority rotated to {}", new_authority);
+    config.mint_authority = if new_authority == pda {
+        Pubkey::default()
+    } else {
+        new_authority
+    };
+    config.serialize(&mut &mut stake_config.data.borrow_mut()[..])?;
+    events::emit(StakeEvent::MintAuthorityRotated {
+        old_authority,
+        new_authority,
+    });
+    Ok(())
+}
+
+/// The reward mint's authority: the program's mint authority PDA `pda`
+/// unless `RotateMintAuthority` handed it to another key.
+fn current_mint_authority(config: &StakeConfig, pda: Pubkey) -> Pubkey {
+    if config.mint_authority == Pubkey::default() {
+        pda
+    } else {
+        config.mint_authority
+    }
+}
+
+/// Closes every stake account of `user` passed after it, up to
+/// `MAX_UNSTAKE_BATCH_SIZE`, refunding their combined rent to the user. Each
+/// must be unstaked and hold no deferred final reward that could still be
+/// claimed; any that does not qualify fails the whole instruction.
+pub fn process_close_inactive_stakes(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let user = next_account_info(account_info_iter)?;
+
+    if !user.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    let stake_states = account_info_iter.as_slice();
+    if stake_states.is_empty() || stake_states.len() > MAX_UNSTAKE_BATCH_SIZE {
+        return Err(StakeError::InvalidBatchSize.into());
+    }
+    let now = current_time(accounts)?.unix_timestamp;
+    let mut refunded: u64 = 0;
+    for stake_state in stake_states {
+        if stake_state.owner != program_id {
+            return Err(ProgramError::IllegalOwner);
+        }
+        let account_data = load_stake_info(stake_state)?;
+        if !account_data.is_initialized() {
+            return Err(StakeError::UninitializedAccount.into());
+        }
+        if account_data.user != *user.key {
+            return Err(StakeError::InvalidStakeAccount.into());
+        }
+        verify_stake_pda(
+            program_id,
+            user.key,
+            &account_data.token_account,
+            stake_state,
+            account_data.seed_version,
+            account_data.bump,
+        )?;
+        if account_data.is_stake_active() {
+            msg!("Stake account {} is still active", stake_state.key);
+            return Err(StakeError::StakeStillActive.into());
+        }
+        if account_data.unclaimed_final_reward > 0 && now <= account_data.final_reward_expires_at {
+            msg!(
+                "Stake account {} has an unclaimed final reward",
+                stake_state.key
+            );
+            return Err(ProgramError::InvalidArgument);
+        }
+        let refund = stake_state.lamports();
+        **stake_state.try_borrow_mut_lamports()? = 0;
+        **user.try_borrow_mut_lamports()? += refund;
+        stake_state.data.borrow_mut().fill(0);
+        refunded = refunded.saturating_add(refund);
+    }
+    msg!("Refunded {} lamports", refunded);
+    events::emit(StakeEvent::InactiveStakesClosed {
+        user: *user.key,
+        closed: stake_states.len() as u32,
+        refunded_lamports: refunded,
+    });
+    Ok(())
+}
+
+/// Records that the owner of `nft_token_account` controls the NFT behind
+/// `stake_state`, for token accounts whose owner cannot sign the transaction
+/// itself, so `Stake` and `proof_of_hold` accept the NFT in that owner's
+/// account. The fourth account is the proof, either of:
+///
+/// - the token account's owner signing, as a PDA does when its program
+///   invokes this instruction with `invoke_signed`;
+/// - the instructions sysvar, with the instruction right before this one an
+///   ed25519 program instruction verifying a single signature by the owner
+///   over the `stake_state` address, the owner and a little endian `u64`
+///   slot; the signature, key and message all have to sit in that
+///   instruction's own data.
+///
+/// A signed slot must be at most `MAX_PROOF_AGE_SLOTS` old and later than
+/// that of the proof on record, so a proof cannot be replayed. The proof
+/// lapses when the stake is unstaked.
+pub fn process_submit_ownership_proof(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let user = next_account_info(account_info_iter)?;
+    let nft_token_account = next_account_info(account_info_iter)?;
+    let stake_state = next_account_info(account_info_iter)?;
+    let proof_account = next_account_info(account_info_iter)?;
+
+    if !user.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    if stake_state.owner != program_id {
+        return Err(ProgramError::IllegalOwner);
+    }
+    let mut account_data = load_stake_info(stake_state)?;
+    verify_stake_pda(
+        program_id,
+        user.key,
+        nft_token_account.key,
+        stake_state,
+        account_data.seed_version,
+        account_data.bump,
+    )?;
+    if !account_data.is_initialized() {
+        return Err(StakeError::UninitializedAccount.into());
+    }
+    if account_data.user != *user.key {
+        return Err(StakeError::InvalidStakeAccount.into());
+    }
+    if account_data.token_account != *nft_token_account.key
+        || !is_token_program(nft_token_account.owner)
+    {
+        return Err(StakeError::InvalidTokenAccount.into());
+    }
+    let token_account = unpack_token_account(nft_token_account)?;
+    if token_account.amount != 1 {
+        return Err(StakeError::InvalidTokenAccount.into());
+    }
+    let current_slot = current_time(accounts)?.slot;
+    let proof_slot = if *proof_account.key == token_account.owner {
+        if !proof_account.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        current_slot
+    } else {
+        if !instructions::check_id(proof_account.key) {
+            return Err(ProgramError::InvalidArgument);
+        }
+        let proof = instructions::get_instruction_relative(-1, proof_account)
+            .map_err(|_| StakeError::InvalidProof)?;
+        let signed_slot = if proof.program_id == ed25519_program::id() {
+            signed_message(&proof.data, &token_account.owner).and_then(|message| {
+                proof_message_slot(message, stake_state.key, &token_account.owner)
+            })
+        } else {
+            None
+        };
+        let Some(signed_slot) = signed_slot else {
+            msg!("No ed25519 proof by {}", token_account.owner);
+            return Err(StakeError::InvalidProof.into());
+        };
+        if signed_slot > current_slot
+            || current_slot - signed_slot > MAX_PROOF_AGE_SLOTS
+            || signed_slot <= account_data.proof_slot
+        {
+            msg!(
+                "Proof for slot {} is stale or replayed at slot {}",
+                signed_slot,
+                current_slot
+            );
+            return Err(StakeError::InvalidProof.into());
+        }
+        signed_slot
+    };
+    account_data.set_proof_verified(true);
+    account_data.proof_owner = token_account.owner;
+    account_data.proof_slot = proof_slot;
+    save_stake_info(&account_data, stake_state)?;
+    events::emit(StakeEvent::OwnershipProofVerified {
+        stake_state: *stake_state.key,
+        owner: token_account.owner,
+    });
+    Ok(())
+}
+
+/// The message of ed25519 program instruction `data` if it verifies exactly
+/// one signature by `signer`, with every offset pointing into `data` itself.
+/// The ed25519 program has already checked the signature when this runs.
+fn signed_message<'a>(data: &'a [u8], signer: &Pubkey) -> Option<&'a [u8]> {
+    const OFFSETS_START: usize = 2;
+    const OFFSETS_LEN: usize = 14;
+    const THIS_INSTRUCTION: u16 = u16::MAX;
+    if data.len() < OFFSETS_START + OFFSETS_LEN || data[0] != 1 {
+        return None;
+    }
+    let field = |i: usize| {
+        let at = OFFSETS_START + i * 2;
+        u16::from_le_bytes([data[at], data[at + 1]])
+    };
+    let (signature_ix, public_key_offset, public_key_ix) = (field(1), field(2), field(3));
+    let (message_offset, message_size, message_ix) = (field(4), field(5), field(6));
+    if signature_ix != THIS_INSTRUCTION
+        || public_key_ix != THIS_INSTRUCTION
+        || message_ix != THIS_INSTRUCTION
+    {
+        return None;
+    }
+    let slice = |offset: u16, len: usize| data.get(offset as usize..offset as usize + len);
+    if slice(public_key_offset, 32) != Some(signer.as_ref()) {
+        return None;
+    }
+    slice(message_offset, message_size as usize)
+}
+
+/// The slot an ownership proof `message` was signed for, if it is the
+/// `stake_state` address, then `owner`, then that slot.
+fn proof_message_slot(message: &[u8], stake_state: &Pubkey, owner: &Pubkey) -> Option<u64> {
+    if message.len() != 32 + 32 + 8
+        || message[..32] != stake_state.as_ref()[..]
+        || message[32..64] != owner.as_ref()[..]
+    {
+        return None;
+    }
+    Some(u64::from_le_bytes(message[64..].try_into().ok()?))
+}
+
+/// Ends a stake whose NFT has left the recorded `nft_token_account`, as can
+/// happen to a stake that was never unstaked before the NFT was transferred
+/// away. Callable by the staker or the admin, and only while the token account
+/// no longer holds the NFT for the staker. The pending reward is forfeited,
+/// as a proof-of-hold redeem would, and the stake is released like an
+/// unstake so the account can be staked again.
+pub fn process_reconcile_active_flag(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let authority = next_account_info(account_info_iter)?;
+    let nft_token_account = next_account_info(account_info_iter)?;
+    let stake_state = next_account_info(account_info_iter)?;
+    let staked_mint = next_account_info(account_info_iter)?;
+    let user_stake_account = next_account_info(account_info_iter)?;
+    let stake_config = next_account_info(account_info_iter)?;
+
+    if !authority.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    if stake_state.owner != program_id {
+        return Err(ProgramError::IllegalOwner);
+    }
+    let mut account_data = load_stake_info(stake_state)?;
+    if !account_data.is_initialized() {
+        return Err(StakeError::UninitializedAccount.into());
+    }
+    verify_stake_pda(
+        program_id,
+        &account_data.user,
+        nft_token_account.key,
+        stake_state,
+        account_data.seed_version,
+        account_data.bump,
+    )?;
+    let mut config = load_config(program_id, stake_config)?;
+    if *authority.key != account_data.user && *authority.key != config.admin {
+        return Err(StakeError::Unauthorized.into());
+    }
+    if !account_data.is_stake_active() {
+        return Err(StakeError::StakeNotActive.into());
+    }
+    if account_data.nft_mint == Pubkey::default() {
+        msg!("Stake has no recorded NFT mint to check");
+        return Err(ProgramError::InvalidArgument);
+    }
+    if still_holds_nft(&account_data, &config, nft_token_account) {
+        msg!("NFT is still held, unstake instead");
+        return Err(ProgramError::InvalidArgument);
+    }
+    let now = current_time(accounts)?.unix_timestamp;
+    apply_reward(&mut account_data, now, &config, Settlement::Forfeit)?;
+    account_data.set_stake_active(false);
+    account_data.set_proof_verified(false);
+    account_data.last_unstake_time = now;
+    release_staked_mint(
+        program_id,
+        staked_mint,
+        &account_data.nft_mint,
+        stake_state.key,
+        now,
+    )?;
+    let mut user_stakes =
+        load_user_stake_account(program_id, &account_data.user, user_stake_account)?;
+    let active_stakes = user_stakes.active_stakes.saturating_sub(1);
+    set_active_stakes(&config, &mut user_stakes, active_stakes, now);
+    save_user_stakes(&user_stakes, user_stake_account)?;
+    record_unstakes(&mut config, stake_config, 1)?;
+    save_stake_info(&account_data, stake_state)?;
+    events::emit(StakeEvent::ActiveFlagReconciled {
+        stake_state: *stake_state.key,
+        reconciled_by: *authority.key,
+        reconciled_at: now,
+    });
+    Ok(())
+}
+
+/// Permissionless. Checkpoints any active stake's pending reward into
+/// `checkpoint_reward` and restarts its accrual window at now. It takes no
+/// parameters, pays nothing and settles only the window: milestones and the
+/// cached rate are left to the stake's next settlement, so the caller cannot
+/// steer them by choosing when to checkpoint. Keepers can call it to keep
+/// windows short. With `min_nfts_to_qualify` set, the stake owner's
+/// `UserStakeAccount` has to be passed as well.
+pub fn process_checkpoint(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let stake_state = next_account_info(account_info_iter)?;
+    let stake_config = next_account_info(account_info_iter)?;
+
+    if stake_state.owner != program_id {
+        return Err(ProgramError::IllegalOwner);
+    }
+    let mut account_data = load_stake_info(stake_state)?;
+    if !account_data.is_initialized() {
+        return Err(StakeError::UninitializedAccount.into());
+    }
+    verify_stake_pda(
+        program_id,
+        &account_data.user,
+        &account_data.token_account,
+        stake_state,
+        account_data.seed_version,
+        account_data.bump,
+    )?;
+    if !account_data.is_stake_active() {
+        return Err(StakeError::StakeNotActive.into());
+    }
+    let config = load_config(program_id, stake_config)?;
+    let now = current_time(accounts)?.unix_timestamp;
+    let stretch = find_qualified_stretch(program_id, &config, &account_data.user, accounts)?;
+    let settle_at = within_qualified_stretch(&mut account_data, stretch, now);
+    checkpoint_accrual(&mut account_data, &config, settle_at);
+    save_stake_info(&account_data, stake_state)?;
+    events::emit(StakeEvent::RewardCheckpointed {
+        stake_state: *stake_state.key,
+        checkpoint_reward: account_data.checkpoint_reward,
+        checkpointed_at: now,
+    });
+    Ok(())
+}
+
+/// Folds several of the user's stake positions into the target
+/// `stake_state`. Every `(nft_token_account, stake_state, staked_mint)` source
+/// passed after the shared accounts is paid its final reward, releases its
+/// mint and is closed with its rent refunded to the user. The target is paid
+/// its accrued reward and stays active. A source's unexpired deferred final
+/// reward is paid along with it.
+///
+/// Each reward is computed from its own position's timestamps, so the target
+/// keeps its own `stake_start_time` and never inherits an older one from a
+/// source. Any invalid source fails the whole instruction. An optional
+/// governance token account may follow the sources.
+pub fn process_consolidate_stakes(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let user = next_account_info(account_info_iter)?;
+    let nft_token_account = next_account_info(account_info_iter)?;
+    let stake_state = next_account_info(account_info_iter)?;
+    let user_stake_account = next_account_info(account_info_iter)?;
+    let stake_config = next_account_info(account_info_iter)?;
+    let reward_accounts = RewardAccounts::next(account_info_iter)?;
+
+    if !user.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    let (sources, governance_token_account) = split_governance_account(account_info_iter);
+    let sources = sources.chunks_exact(3);
+    if sources.len() == 0
+        || sources.len() > MAX_UNSTAKE_BATCH_SIZE
+        || !sources.remainder().is_empty()
+    {
+        return Err(StakeError::InvalidBatchSize.into());
+    }
+    let source_count = sources.len() as u32;
+    let mut config = load_config(program_id, stake_config)?;
+    let mint_authority_bump = check_reward_accounts(program_id, &config, &reward_accounts)?;
+    let clock = current_time(accounts)?;
+
+    let mut user_stakes = load_user_stake_account(program_id, user.key, user_stake_account)?;
+    let stretch = qualified_stretch(&config, &user_stakes);
+
+    let mut account_data = load_active_stake(program_id, user, nft_token_account, stake_state)?;
+    check_preferred_destination(&account_data, &reward_accounts)?;
+    let settle_at = within_qualified_stretch(&mut account_data, stretch, clock.unix_timestamp);
+    let reward_amt = apply_reward(&mut account_data, settle_at, &config, Settlement::Pay)?;
+    let mut total = 0u64;
+    for accounts in sources {
+        let source = StakePosition {
+            nft_token_account: &accounts[0],
+            stake_state: &accounts[1],
+            staked_mint: &accounts[2],
+        };
+        if source.stake_state.key == stake_state.key {
+            msg!("Target stake account passed as a source");
+            return Err(ProgramError::InvalidArgument);
+        }
+        let mut source_data = load_active_stake(
+            program_id,
+            user,
+            source.nft_token_account,
+            source.stake_state,
+        )?;
+        check_preferred_destination(&source_data, &reward_accounts)?;
+        let settle_at = within_qualified_stretch(&mut source_data, stretch, clock.unix_timestamp);
+        let mut source_reward =
+            apply_reward(&mut source_data, settle_at, &config, Settlement::Exit)?;
+        // Closing the source would otherwise forfeit a deferred final reward
+        // still within its grace window.
+        if clock.unix_timestamp <= source_data.final_reward_expires_at {
+            source_reward = source_reward
+                .checked_add(source_data.unclaimed_final_reward)
+                .ok_or(StakeError::RewardOverflow)?;
+        }
+        msg!("Reward of {}: {}", source.stake_state.key, source_reward);
+        // Each source is paid at its own holding period's fee discount.
+        let source_reward = pay_stake_reward(
+            &mut config,
+            &mut source_data,
+            &reward_accounts,
+            mint_authority_bump,
+            source_reward,
+            clock.unix_timestamp,
+            governance_token_account,
+        )?;
+        account_data.points = account_data
+            .points
+            .checked_add(source_data.points)
+            .ok_or(StakeError::RewardOverflow)?;
+        total = total
+            .checked_add(source_reward)
+            .ok_or(StakeError::RewardOverflow)?;
+        release_staked_mint(
+            program_id,
+            source.staked_mint,
+            &source_data.nft_mint,
+            source.stake_state.key,
+            clock.unix_timestamp,
+        )?;
+        let refund = source.stake_state.lamports();
+        **source.stake_state.try_borrow_mut_lamports()? = 0;
+        **user.try_borrow_mut_lamports()? += refund;
+        source.stake_state.data.borrow_mut().fill(0);
+    }
+    let reward_amt = pay_stake_reward(
+        &mut config,
+        &mut account_data,
+        &reward_accounts,
+        mint_authority_bump,
+        reward_amt,
+        clock.unix_timestamp,
+        governance_token_account,
+    )?;
+    total = total
+        .checked_add(reward_amt)
+        .ok_or(StakeError::RewardOverflow)?;
+    let active_stakes = user_stakes.active_stakes.saturating_sub(source_count);
+    set_active_stakes(
+        &config,
+        &mut user_stakes,
+        active_stakes,
+        clock.unix_timestamp,
+    );
+    save_user_stakes(&user_stakes, user_stake_account)?;
+    record_unstakes(&mut config, stake_config, source_count)?;
+    save_stake_info(&account_data, stake_state)?;
+    events::emit(StakeEvent::StakesConsolidated {
+        stake_state: *stake_state.key,
+        sources: source_count,
+        reward: total,
+    });
+    Ok(())
+}
+
+/// Loads `user`'s active stake of `nft_token_account` from `stake_state`.
+fn load_active_stake(
+    program_id: &Pubkey,
+    user: &AccountInfo,
+    nft_token_account: &AccountInfo,
+    stake_state: &AccountInfo,
+) -> Result<UserStakeInfo, ProgramError> {
+    if stake_state.owner != program_id {
+        return Err(ProgramError::IllegalOwner);
+    }
+    let account_data = load_stake_info(stake_state)?;
+    verify_stake_pda(
+        program_id,
+        user.key,
+        nft_token_account.key,
+        stake_state,
+        account_data.seed_version,
+        account_data.bump,
+    )?;
+    if !account_data.is_initialized() {
+        return Err(StakeError::UninitializedAccount.into());
+    }
+    if !account_data.is_stake_active() {
+        return Err(StakeError::StakeNotActive.into());
+    }
+    if account_data.user != *user.key {
+        return Err(StakeError::InvalidStakeAccount.into());
+    }
+    if account_data.token_account != *nft_token_account.key {
+        return Err(StakeError::InvalidTokenAccount.into());
+    }
+    Ok(account_data)
+}
+
+/// Read-only audit of a stake account: compares the reward accrued since
+/// `stake_start_time` with `total_redeemed` plus the pending reward and writes
+/// the difference (theoretical minus accounted, `i64` little endian) to return
+/// data.
+///
+/// The theoretical reward is the stake's pending reward as if it had never
+/// been paid, computed by `reward_breakdown` like every payout, so pauses,
+/// weights, boosts, tiers and the cliff all count. No history of past inputs
+/// is kept, so the whole stake is replayed with the config and stake as they
+/// stand now: the comparison is approximate. It is exact, and anything other
+/// than zero means accrual was lost or paid twice, only while every input
+/// stayed the same over the stake: no config update, rate override,
+/// milestone, compounding, dilution or backpay cap changed what a second
+/// paid. Otherwise a difference is expected and is not by itself a bug.
+pub fn process_reconcile_stake(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let stake_state = next_account_info(account_info_iter)?;
+    let stake_config = next_account_info(account_info_iter)?;
+
+    if stake_state.owner != program_id {
+        return Err(ProgramError::IllegalOwner);
+    }
+    let mut account_data = load_stake_info(stake_state)?;
+    if !account_data.is_initialized() {
+        return Err(StakeError::UninitializedAccount.into());
+    }
+    if !account_data.is_stake_active() {
+        return Err(StakeError::StakeNotActive.into());
+    }
+    let config = load_config(program_id, stake_config)?;
+    rescale_to_current_mint(&mut account_data, &config);
+    let clock = current_time(accounts)?;
+    let pending = compute_reward(&account_data, &config, clock.unix_timestamp);
+    // Replay the whole stake as one window, with nothing carried in.
+    account_data.last_redeem_time = account_data.stake_start_time;
+    account_data.checkpoint_reward = 0;
+    account_data.reward_remainder = 0;
+    refresh_rate_cache(&mut account_data, &config);
+    let theoretical = reward_breakdown(&account_data, &config, clock.unix_timestamp).reward;
+    let discrepancy = (theoretical as i128 - account_data.total_redeemed as i128 - pending as i128)
+        .clamp(i64::MIN as i128, i64::MAX as i128) as i64;
+    if discrepancy != 0 {
+        msg!(
+            "Replay with current inputs differs by {}; exact only if they never changed",
+            discrepancy
+        );
+    }
+    set_return_data(&discrepancy.to_le_bytes());
+    Ok(())
+}
+
+/// Read-only. Writes a borsh `RewardBreakdown` of the stake account's pending
+/// reward to return data, with the same inputs `Redeem` would use now.
+pub fn process_get_reward_breakdown(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let stake_state = next_account_info(account_info_iter)?;
+    let stake_config = next_account_info(account_info_iter)?;
+
+    if stake_state.owner != program_id {
+        return Err(ProgramError::IllegalOwner);
+    }
+    let mut account_data = load_stake_info(stake_state)?;
+    if !account_data.is_initialized() {
+        return Err(StakeError::UninitializedAccount.into());
+    }
+    if !account_data.is_stake_active() {
+        return Err(StakeError::StakeNotActive.into());
+    }
+    let config = load_config(program_id, stake_config)?;
+    rescale_to_current_mint(&mut account_data, &config);
+    let breakdown = reward_breakdown(
+        &account_data,
+        &config,
+        current_time(accounts)?.unix_timestamp,
+    );
+    set_return_data(&breakdown.try_to_vec()?);
+    Ok(())
+}
+
+/// Read-only. Writes the reward, as a little endian `u64`, that an unweighted
+/// stake made now and unstaked `duration_seconds` later would be paid in a
+/// single final payout under the current config: emission schedule, pauses,
+/// cliff, backpay cap, dilution, duration tiers and `final_reward_rounding`
+/// all apply as they stand. Interim redeems, boosts locked in at stake time,
+/// the governance boost and the protocol fee are left out.
+pub fn process_preview_reward(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    duration_seconds: i64,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let stake_config = next_account_info(account_info_iter)?;
+
+    if duration_seconds < 0 {
+        return Err(ProgramError::InvalidArgument);
+    }
+    let config = load_config(program_id, stake_config)?;
+    let now = current_time(accounts)?.unix_timestamp;
+    // A freshly created stake account reads as all zeroes.
+    let mut account_data = try_from_slice_unchecked::<UserStakeInfo>(&[0; UserStakeInfo::SIZE])?;
+    account_data.stake_start_time = now;
+    account_data.last_redeem_time = now;
+    account_data.set_stake_active(true);
+    let pending =
+        pending_reward_fixed(&account_data, &config, now.saturating_add(duration_seconds));
+    let reward = to_whole_units(pending, config.final_reward_rounding);
+    set_return_data(&reward.to_le_bytes());
+    Ok(())
+}
+
+/// Writes the per-second reward rate a stake account currently earns, as a
+/// little endian `u64` in fixed point (see `REWARD_PRECISION`), to return
+/// data: what `reward_breakdown`, and so `Redeem`, accrues for the next second
+/// once the stake is settled now, with every modifier applied. Fixed point
+/// keeps rates below one token unit a second, as dilution or a light stake
+/// weight give, from reading as zero; it saturates at `u64::MAX`. It is zero while paused or before the
+/// `reward_cliff`. The governance boost of the optional
+/// `governance_token_account` is added, and the protocol share at the stake's
+/// discounted fee is taken off when it is withheld from the user.
+pub fn process_get_effective_rate(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let stake_state = next_account_info(account_info_iter)?;
+    let stake_config = next_account_info(account_info_iter)?;
+    let governance_token_account = next_account_info(account_info_iter).ok();
+
+    if stake_state.owner != program_id {
+        return Err(ProgramError::IllegalOwner);
+    }
+    let mut account_data = load_stake_info(stake_state)?;
+    if !account_data.is_initialized() {
+        return Err(StakeError::UninitializedAccount.into());
+    }
+    if !account_data.is_stake_active() {
+        return Err(StakeError::StakeNotActive.into());
+    }
+    let mut config = load_config(program_id, stake_config)?;
+    let now = current_time(accounts)?.unix_timestamp;
+    // Settle a copy of the stake now and price one more second; partial days
+    // accrue here so the second is not carried to a later redeem.
+    config.prorate_partial_days = true;
+    rescale_to_current_mint(&mut account_data, &config);
+    settle_accrual(&mut account_data, &config, now);
+    account_data.checkpoint_reward = 0;
+    account_data.reward_remainder = 0;
+    let pending = reward_breakdown(&account_data, &config, now.saturating_add(1)).pending_fixed;
+    let base_rate = u64::try_from(pending).unwrap_or(u64::MAX);
+    let boost_bps = governance_boost_bps(&config, &account_data.user, governance_token_account)?;
+    let boost = (base_rate as u128 * boost_bps as u128 / MAX_BPS as u128) as u64;
+    let mut rate = base_rate.saturating_add(boost);
+    if config.withhold_protocol_fee && config.accrual_mode != AccrualMode::Points {
+        let fee_bps =
+            discounted_fee_bps(&config, now.saturating_sub(account_data.stake_start_time));
+        rate -= (rate as u128 * fee_bps as u128 / MAX_BPS as u128) as u64;
+    }
+    set_return_data(&rate.to_le_bytes());
+    Ok(())
+}
+
+/// Per-second emission rate in effect at `now`.
+fn emission_rate_at(config: &StakeConfig, now: UnixTimestamp) -> u64 {
+    if config.emission_schedule.is_empty() {
+        return DEFAULT_REWARD_RATE;
+    }
+    config
+        .emission_schedule
+        .iter()
+        .rev()
+        .find(|segment| segment.start_time <= now)
+        .map_or(0, |segment| segment.rate)
+}
+
+/// Reward pending since `last_redeem_time`. A `last_redeem_time` ahead of
+/// `now` accrues nothing; beyond `FUTURE_REDEEM_TIME_TOLERANCE` it is logged so
+/// the admin can fix it with `ResetLastRedeemTime`.
+///
+/// With `max_backpay_seconds` configured only the most recent
+/// `max_backpay_seconds` of the window are paid: time older than that is
+/// forfeited, so stakers have to redeem at least that often to be paid in full.
+///
+/// Modifiers apply in a fixed order, each to the result of the one before:
+/// 1. base accrual over the window, skipping pauses, at the override, cached
+///    or scheduled rate;
+/// 2. dilution by the number of active stakes;
+/// 3. the floor price stake weight;
+/// 4. the master edition boost;
+/// 5. the milestone multiplier;
+/// 6. the duration tier multiplier;
+/// 7. plus the compound bonus, the checkpointed reward and the carried
+///    remainder.
+///
+/// Every step multiplies or divides by a non-negative factor, or adds, in
+/// `u128` fixed point and saturates instead of overflowing, so reductions
+/// bottom out at zero and the result never underflows. The protocol share is
+/// taken from the result afterwards and never exceeds it.
+pub fn compute_reward(
+    account_data: &UserStakeInfo,
+    config: &StakeConfig,
+    now: UnixTimestamp,
+) -> u64 {
+    to_whole_units(
+        pending_reward_fixed(account_data, config, now),
+        Rounding::Down,
+    )
+}
+
+/// Reward pending at `now` in fixed point (see `REWARD_PRECISION`): the
+/// accrual since `last_redeem_time` with every weight and bonus applied
+/// without rounding, plus the checkpointed reward and the carried
+/// `reward_remainder`.
+fn pending_reward_fixed(
+    account_data: &UserStakeInfo,
+    config: &StakeConfig,
+    now: UnixTimestamp,
+) -> u128 {
+    reward_breakdown(account_data, config, now).pending_fixed
+}
+
+/// Works out a stake's pending reward at `now` step by step, keeping every
+/// input and intermediate amount.
+fn reward_breakdown(
+    account_data: &UserStakeInfo,
+    config: &StakeConfig,
+    now: UnixTimestamp,
+) -> RewardBreakdown {
+    let carried = account_data.checkpoint_reward as u128 * REWARD_PRECISION as u128
+        + account_data.reward_remainder as u128;
+    let mut breakdown = RewardBreakdown {
+        now,
+        rate_override: account_data.rate_override(),
+        dilution_factor: dilution_factor(config),
+        stake_weight: account_data.stake_weight,
+        edition_boost_bps: account_data.edition_boost_bps,
+        milestone_multiplier_bps: account_data.milestone_multiplier_bps,
+        duration_tier_bps: duration_tier_bps(config, account_data, now),
+        carried_fixed: carried,
+        pending_fixed: carried,
+        ..RewardBreakdown::default()
+    };
+    if account_data.last_redeem_time > now.saturating_add(FUTURE_REDEEM_TIME_TOLERANCE) {
+        msg!(
+            "Warning: last_redeem_time {} is ahead of the clock {}",
+            account_data.last_redeem_time,
+            now
+        );
+        breakdown.clock_behind = true;
+    } else if !account_data.is_stake_active() {
+        // Never staked, or already unstaked: the zeroed or stale timestamps
+        // must not open a window.
+    } else if let Some((from, to)) = accrual_window(account_data, config, now) {
+        let accrued = unpaused_accrual(config, from, to, |start, end| {
+            if let Some(rate) = account_data.rate_override() {
+                (end.saturating_sub(start).max(0) as u64).saturating_mul(rate)
+            } else if account_data.cached_rate_revision == config.rate_revision
+                && now <= account_data.cached_rate_valid_until
+            {
+                (end.saturating_sub(start).max(0) as u64).saturating_mul(account_data.cached_rate)
+            } else {
+                accrued_between(config, start, end)
+            }
+        });
+        // Modifiers apply one after another in the order documented on
+        // `compute_reward`, each to the amount the previous one left.
+        let diluted = dilute(config, accrued as u128 * REWARD_PRECISION as u128);
+        let weighted = apply_stake_weight(account_data, diluted);
+        let edition_boosted = apply_bonus_bps(account_data.edition_boost_bps, weighted);
+        let milestone_boosted =
+            apply_bonus_bps(account_data.milestone_multiplier_bps, edition_boosted);
+        let scaled = apply_duration_tier(config, account_data, now, milestone_boosted);
+        let compound = compound_bonus(account_data, config, from, to);
+        breakdown.window_from = from;
+        breakdown.window_to = to;
+        breakdown.backpay_capped = backpay_floor(config, now) > account_data.last_redeem_time;
+        breakdown.deferred_seconds = now.saturating_sub(to);
+        breakdown.base_accrual = accrued;
+        breakdown.scaled_accrual_fixed = scaled;
+        breakdown.compound_bonus_fixed = compound;
+        breakdown.pending_fixed = scaled.saturating_add(compound).saturating_add(carried);
+    } else {
+        breakdown.before_cliff = true;
+    }
+    breakdown.reward = to_whole_units(breakdown.pending_fixed, Rounding::Down);
+    breakdown
+}
+
+/// A fixed-point `amount` in whole reward token units, saturating at
+/// `u64::MAX`.
+fn to_whole_units(amount: u128, rounding: Rounding) -> u64 {
+    let whole = match rounding {
+        Rounding::Down => amount / REWARD_PRECISION as u128,
+        Rounding::Up => amount.div_ceil(REWARD_PRECISION as u128),
+    };
+    u64::try_from(whole).unwrap_or(u64::MAX)
+}
+
+/// Earliest time still paid at `now` under `max_backpay_seconds`.
+fn backpay_floor(config: &StakeConfig, now: UnixTimestamp) -> UnixTimestamp {
+    if config.max_backpay_seconds > 0 {
+        now.saturating_sub(config.max_backpay_seconds)
+    } else {
+        UnixTimestamp::MIN
+    }
+}
+
+/// `accrue(from, to)` less what it accrues over the parts of `[from, to)`
+/// covered by a recorded pause or by the ongoing one, however the window and
+/// the pauses overlap.
+fn unpaused_accrual<F: Fn(UnixTimestamp, UnixTimestamp) -> u64>(
+    config: &StakeConfig,
+    from: UnixTimestamp,
+    to: UnixTimestamp,
+    accrue: F,
+) -> u64 {
+    let ongoing = (config.paused_since != 0).then_some(PauseInterval {
+        start: config.paused_since,
+        end: UnixTimestamp::MAX,
+    });
+    config.pause_intervals.iter().copied().chain(ongoing).fold(
+        accrue(from, to),
+        |accrued, pause| {
+            let start = from.max(pause.start);
+            let end = to.min(pause.end);
+            if end > start {
+                accrued.saturating_sub(accrue(start, end))
+            } else {
+                accrued
+            }
+        },
+    )
+}
+
+/// Interest on the compounded `virtual_staked_amount`: `compound_rate_bps` of
+/// it per day over the accrual window `[from, to)`, less any time it was
+/// paused, in fixed point.
+fn compound_bonus(
+    account_data: &UserStakeInfo,
+    config: &StakeConfig,
+    from: UnixTimestamp,
+    to: UnixTimestamp,
+) -> u128 {
+    let elapsed = unpaused_accrual(config, from, to, |start, end| {
+        end.saturating_sub(start).max(0) as u64
+    });
+    mul_div(
+        account_data.virtual_staked_amount as u128 * REWARD_PRECISION as u128,
+        config.compound_rate_bps as u128 * elapsed as u128,
+        MAX_BPS as u128 * SECONDS_PER_DAY as u128,
+    )
+}
+
+/// `value * numerator / denominator` rounded down, saturating at
+/// `u128::MAX`.
+fn mul_div(value: u128, numerator: u128, denominator: u128) -> u128 {
+    value.saturating_mul(numerator) / denominator
+}
+
+/// Scales `amount` by the multiplier of the highest duration tier the stake
+/// has reached by `now`, found by binary search over the sorted tiers. Stakes
+/// below the first tier, or without tiers configured, are not scaled.
+fn apply_duration_tier(
+    config: &StakeConfig,
+    account_data: &UserStakeInfo,
+    now: UnixTimestamp,
+    amount: u128,
+) -> u128 {
+    match duration_tier_bps(config, account_data, now) {
+        Some(multiplier_bps) => mul_div(amount, multiplier_bps as u128, MAX_BPS as u128),
+        None => amount,
+    }
+}
+
+/// Multiplier of the highest duration tier the stake has reached by `now`.
+fn duration_tier_bps(
+    config: &StakeConfig,
+    account_data: &UserStakeInfo,
+    now: UnixTimestamp,
+) -> Option<u16> {
+    let duration = now.saturating_sub(account_data.stake_start_time);
+    let reached = config
+        .duration_tiers
+        .partition_point(|tier| tier.min_duration <= duration);
+    reached
+        .checked_sub(1)
+        .map(|i| config.duration_tiers[i].multiplier_bps)
+}
+
+/// How `apply_reward` treats the pending reward.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Settlement {
+    /// An interim payout, rounded down.
+    Pay,
+    /// The final payout of a stake that is ending, with the fraction carried
+    /// over from interim payouts, rounded per `final_reward_rounding`:
+    /// rounding up pays at most one unit more.
+    Exit,
+    /// Nothing is paid and the window is dropped.
+    Forfeit,
+}
+
+/// The one place a stake's pending reward is taken: computes it, credits it to
+/// `total_redeemed` and settles the stake past it. Returns the amount the
+/// caller must pay out (or defer); every paying instruction goes through here
+/// so reward changes apply to all of them alike.
+fn apply_reward(
+    account_data: &mut UserStakeInfo,
+    now: UnixTimestamp,
+    config: &StakeConfig,
+    settlement: Settlement,
+) -> Result<u64, ProgramError> {
+    rescale_to_current_mint(account_data, config);
+    let pending = pending_reward_fixed(account_data, config, now);
+    let reward_amt = match settlement {
+        Settlement::Pay => to_whole_units(pending, Rounding::Down),
+        Settlement::Exit => to_whole_units(pending, config.final_reward_rounding),
+        Settlement::Forfeit => 0,
+    };
+    // Only an interim payout carries its fraction forward; a stake that is
+    // ending or forfeiting has nothing left to pay it with.
+    account_data.reward_remainder = match settlement {
+        Settlement::Pay => (pending % REWARD_PRECISION as u128) as u64,
+        Settlement::Exit | Settlement::Forfeit => 0,
+    };
+    // `total_redeemed` is bookkeeping only, so it caps at `u64::MAX` rather
+    // than failing the payout, unless `strict_total_redeemed` asks to fail.
+    account_data.total_redeemed = match account_data.total_redeemed.checked_add(reward_amt) {
+        Some(total_redeemed) => total_redeemed,
+        None if config.strict_total_redeemed => {
+            return Err(StakeError::TotalRedeemedSaturated.into());
+        }
+        None => {
+            msg!("total_redeemed saturated");
+            u64::MAX
+        }
+    };
+    settle_accrual(account_data, config, now);
+    Ok(reward_amt)
+}
+
+/// Settles the window accrued by `now` without paying it: the whole units go
+/// to `checkpoint_reward` and the fraction to `reward_remainder`, where the
+/// next settlement picks them up. Milestones and the cached rate are left for
+/// that settlement to move on, as if no checkpoint had been taken.
+fn checkpoint_accrual(account_data: &mut UserStakeInfo, config: &StakeConfig, now: UnixTimestamp) {
+    rescale_to_current_mint(account_data, config);
+    let pending = pending_reward_fixed(account_data, config, now);
+    advance_window(account_data, config, now);
+    account_data.checkpoint_reward = to_whole_units(pending, Rounding::Down);
+    account_data.reward_remainder = (pending % REWARD_PRECISION as u128) as u64;
+    msg!("Checkpointed reward: {}", account_data.checkpoint_reward);
+}
+
+/// Moves a stake past the reward just paid, compounded or checkpointed up to
+/// `now`. Before the `reward_cliff` nothing is paid, so when the cliff pays
+/// from `stake_start_time` the accrual start is left where it is.
+fn settle_accrual(account_data: &mut UserStakeInfo, config: &StakeConfig, now: UnixTimestamp) {
+    advance_window(account_data, config, now);
+    // Milestones crossed by now apply from the next window on and stick for
+    // the rest of the stake, even if the milestones are reconfigured.
+    let held_for = now.saturating_sub(account_data.stake_start_time);
+    account_data.milestone_multiplier_bps = account_data
+        .milestone_multiplier_bps
+        .max(reached_milestone_bps(config, held_for));
+    refresh_rate_cache(account_data, config);
+}
+
+/// Moves the accrual start past the window settled at `now`.
+fn advance_window(account_data: &mut UserStakeInfo, config: &StakeConfig, now: UnixTimestamp) {
+    match accrual_window(account_data, config, now) {
+        Some((_from, to)) => account_data.advance_last_redeem_time(to),
+        None if !config.cliff_pays_from_start => account_data.advance_last_redeem_time(now),
+        None => {}
+    }
+}
+
+/// The `[from, to)` window a reward at `now` pays for, or `None` before the
+/// `reward_cliff`. Without `prorate_partial_days` the window ends at the last
+/// completed day since `from`, and the partial day is carried to the next
+/// redeem.
+fn accrual_window(
+    account_data: &UserStakeInfo,
+    config: &StakeConfig,
+    now: UnixTimestamp,
+) -> Option<(UnixTimestamp, UnixTimestamp)> {
+    let cliff_end = account_data
+        .stake_start_time
+        .saturating_add(config.reward_cliff);
+    if now < cliff_end {
+        return None;
+    }
+    let from = if config.cliff_pays_from_start {
+        account_data.last_redeem_time
+    } else {
+        account_data.last_redeem_time.max(cliff_end)
+    };
+    let from = from.max(backpay_floor(config, now));
+    let to = if config.prorates_partial_days() {
+        now
+    } else {
+        let whole_days = now.saturating_sub(from).max(0) / SECONDS_PER_DAY;
+        from.saturating_add(whole_days * SECONDS_PER_DAY)
+    };
+    Some((from, to))
+}
+
+/// Caches the emission rate in effect at `last_redeem_time` along with the
+/// start of the next emission segment, until which `compute_reward` can skip
+/// walking the schedule. Any `UpdateConfig` bumps `rate_revision` and so
+/// invalidates every cached rate.
+fn refresh_rate_cache(account_data: &mut UserStakeInfo, config: &StakeConfig) {
+    let from = account_data.last_redeem_time;
+    account_data.cached_rate = emission_rate_at(config, from);
+    account_data.cached_rate_valid_until = config
+        .emission_schedule
+        .iter()
+        .find(|segment| segment.start_time > from)
+        .map_or(UnixTimestamp::MAX, |segment| segment.start_time);
+    account_data.cached_rate_revision = config.rate_revision;
+}
+
+/// Scales `amount` by the stake's floor price snapshot, so the emission rate is
+/// per SOL of floor value. Stakes without a snapshot are unweighted.
+fn apply_stake_weight(account_data: &UserStakeInfo, amount: u128) -> u128 {
+    if account_data.stake_weight == 0 {
+        return amount;
+    }
+    mul_div(
+        amount,
+        account_data.stake_weight as u128,
+        LAMPORTS_PER_SOL as u128,
+    )
+}
+
+/// Divides `amount` by `max(1, total_active_stakes / stake_dilution_divisor)`
+/// when a divisor is configured, so rewards dilute as more stakes join. The
+/// divisor reads the global counter at the time the reward is computed, not
+/// when it accrued: the same window pays differently depending on how many
+/// stakes are active when it is redeemed.
+fn dilute(config: &StakeConfig, amount: u128) -> u128 {
+    mul_div(amount, 1, dilution_factor(config) as u128)
+}
+
+fn dilution_factor(config: &StakeConfig) -> u32 {
+    if config.stake_dilution_divisor == 0 {
+        return 1;
+    }
+    (config.total_active_stakes / config.stake_dilution_divisor).max(1)
+}
+
+/// Adds a stored per-stake bonus, such as the master edition boost locked in
+/// at stake time or the milestone multiplier, of `bonus_bps` to `amount`.
+fn apply_bonus_bps(bonus_bps: u16, amount: u128) -> u128 {
+    if bonus_bps == 0 {
+        return amount;
+    }
+    mul_div(amount, MAX_BPS as u128 + bonus_bps as u128, MAX_BPS as u128)
+}
+
+/// Bonus of the highest `reward_milestones` entry a stake held for `held_for`
+/// seconds has crossed.
+fn reached_milestone_bps(config: &StakeConfig, held_for: UnixTimestamp) -> u16 {
+    config
+        .reward_milestones
+        .iter()
+        .rev()
+        .find(|milestone| held_for >= milestone.min_duration)
+        .map_or(0, |milestone| milestone.multiplier_bps)
+}
+
+/// `master_edition_boost_bps` when `edition` is the Metaplex edition PDA of
+/// `nft_mint` and holds a master edition. Print editions, and mints without an
+/// edition account, earn the base rate.
+fn edition_boost_bps(
+    config: &StakeConfig,
+    nft_mint: &Pubkey,
+    edition: &AccountInfo,
+) -> Result<u16, ProgramError> {
+    let (pda, _bump) = Pubkey::find_program_address(
+        &[
+            b"metadata",
+            TOKEN_METADATA_PROGRAM_ID.as_ref(),
+            nft_mint.as_ref(),
+            b"edition",
+        ],
+        &TOKEN_METADATA_PROGRAM_ID,
+    );
+    if pda != *edition.key {
+        return Err(StakeError::InvalidPda.into());
+    }
+    if edition.data_is_empty() {
+        return Ok(0);
+    }
+    if *edition.owner != TOKEN_METADATA_PROGRAM_ID {
+        return Err(ProgramError::IllegalOwner);
+    }
+    let is_master_edition = edition
+        .data
+        .borrow()
+        .first()
+        .is_some_and(|key| MASTER_EDITION_KEYS.contains(key));
+    Ok(if is_master_edition {
+        config.master_edition_boost_bps
+    } else {
+        0
+    })
+}
+
+/// Reads the configured floor price feed for a new stake's `stake_weight`.
+/// Without a configured feed stakes are unweighted and no feed is required.
+fn snapshot_stake_weight(
+    config: &StakeConfig,
+    floor_price_feed: Option<&AccountInfo>,
+    clock: &Clock,
+) -> Result<u64, ProgramError> {
+    if config.floor_price_feed == Pubkey::default() {
+        return Ok(0);
+    }
+    let floor_price_feed = floor_price_feed.ok_or(ProgramError::NotEnoughAccountKeys)?;
+    if *floor_price_feed.key != config.floor_price_feed {
+        return Err(StakeError::StaleOracle.into());
+    }
+    let feed = try_from_slice_unchecked::<FloorPriceFeed>(&floor_price_feed.data.borrow())?;
+    if feed.price == 0
+        || clock.unix_timestamp.saturating_sub(feed.updated_at) > config.max_floor_price_age
+    {
+        msg!("Floor price {} updated at {}", feed.price, feed.updated_at);
+        return Err(StakeError::StaleOracle.into());
+    }
+    Ok(feed.price)
+}
+
+/// Reward accrued over `[from, to)`. Without an emission schedule every second
+/// earns `DEFAULT_REWARD_RATE`. With one, each segment's rate applies as a step
+/// from its `start_time` until the next segment starts (the last segment runs
+/// forever) and time before the first segment earns nothing.
+fn accrued_between(config: &StakeConfig, from: UnixTimestamp, to: UnixTimestamp) -> u64 {
+    if to <= from {
+        return 0;
+    }
+    if config.emission_schedule.is_empty() {
+        return (to.saturating_sub(from) as u64).saturating_mul(DEFAULT_REWARD_RATE);
+    }
+    let mut total: u64 = 0;
+    for (i, segment) in config.emission_schedule.iter().enumerate() {
+        let segment_end = config
+            .emission_schedule
+            .get(i + 1)
+            .map_or(UnixTimestamp::MAX, |next| next.start_time);
+        let start = from.max(segment.start_time);
+        let end = to.min(segment_end);
+        if end > start {
+            let elapsed = end.saturating_sub(start) as u64;
+            total = total.saturating_add(elapsed.saturating_mul(segment.rate));
+        }
+    }
+    total
+}
+
+pub fn process_initialize_config(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    protocol_fee_bps: u16,
+    withhold_protocol_fee: bool,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let admin = next_account_info(account_info_iter)?;
+    let stake_config = next_account_info(account_info_iter)?;
+    let reward_mint = next_account_info(account_info_iter)?;
+    let protocol_fee_account = next_account_info(account_info_iter)?;
+    let system_program = next_account_info(account_info_iter)?;
+
+    if !admin.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    if protocol_fee_bps > MAX_BPS {
+        return Err(StakeError::InvalidFeeBps.into());
+    }
+    let (config_pda, bump) = Pubkey::find_program_address(&[CONFIG_SEED], program_id);
+    if config_pda != *stake_config.key {
+        msg!("Invalid PDA passed");
+        return Err(StakeError::InvalidPda.into());
+    }
+    let (mint_authority, _mint_authority_bump) =
+        Pubkey::find_program_address(&[MINT_AUTHORITY_SEED], program_id);
+    if *reward_mint.owner != spl_token::id() {
+        return Err(StakeError::InvalidRewardMint.into());
+    }
+    let mint = unpack_mint(reward_mint)?;
+    if mint.mint_authority != COption::Some(mint_authority) {
+        msg!("Reward mint authority must be {}", mint_authority);
+        return Err(StakeError::InvalidMintAuthority.into());
+    }
+    if *protocol_fee_account.owner != spl_token::id() {
+        return Err(StakeError::InvalidProtocolFeeAccount.into());
+    }
+    let fee_account = unpack_token_account(protocol_fee_account)?;
+    if fee_account.mint != *reward_mint.key {
+        return Err(StakeError::InvalidProtocolFeeAccount.into());
+    }
+    let space = StakeConfig::SIZE;
+    let rent_lamports = Rent::get()?.minimum_balance(space);
+
+    invoke_signed(
+        &system_instruction::create_account(
+            admin.key,
+            stake_config.key,
+            rent_lamports,
+            space.try_into().unwrap(),
+            program_id,
+        ),
+        &[admin.clone(), stake_config.clone(), system_program.clone()],
+        &[&[CONFIG_SEED, &[bump]]],
+    )?;
+
+    let mut config = try_from_slice_unchecked::<StakeConfig>(&stake_config.data.borrow())?;
+    if config.is_initialized() {
+        return Err(ProgramError::AccountAlreadyInitialized);
+    }
+    config.admin = *admin.key;
+    config.reward_mint = *reward_mint.key;
+    config.protocol_fee_account = *protocol_fee_account.key;
+    config.protocol_fee_bps = protocol_fee_bps;
+    config.withhold_protocol_fee = withhold_protocol_fee;
+    config.version = StakeConfig::VERSION;
+    config.prorate_partial_days = true;
+    config.auto_redeem_on_unstake = true;
+    config.is_initialized = true;
+    config.serialize(&mut &mut stake_config.data.borrow_mut()[..])?;
+    events::emit(StakeEvent::ConfigInitialized {
+        admin: *admin.key,
+        reward_mint: *reward_mint.key,
+        protocol_fee_account: *protocol_fee_account.key,
+        protocol_fee_bps,
+        withhold_protocol_fee,
+    });
+    Ok(())
+}
+
+pub fn process_update_config(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    field: ConfigField,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let admin = next_account_info(account_info_iter)?;
+    let stake_config = next_account_info(account_info_iter)?;
+
+    if !admin.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    let mut config = load_config(program_id, stake_config)?;
+    if config.admin != *admin.key {
+        return Err(StakeError::Unauthorized.into());
+    }
+    check_not_finalized(&config)?;
+    check_timelock(&config, account_info_iter)?;
+    apply_config_field(&mut config, field, current_time(accounts)?.unix_timestamp)?;
+    config.rate_revision = config.rate_revision.wrapping_add(1);
+    config.serialize(&mut &mut stake_config.data.borrow_mut()[..])?;
+    Ok(())
+}
+
+/// Admin only. Applies every change in `update` to the config at once: any
+/// invalid field fails the whole instruction and nothing is written. Fields
+/// are applied in order, then the optional pause change.
+pub fn process_update_config_batch(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    update: ConfigUpdate,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let admin = next_account_info(account_info_iter)?;
+    let stake_config = next_account_info(account_info_iter)?;
+
+    if !admin.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    let mut config = load_config(program_id, stake_config)?;
+    if config.admin != *admin.key {
+        return Err(StakeError::Unauthorized.into());
+    }
+    check_not_finalized(&config)?;
+    check_timelock(&config, account_info_iter)?;
+    let now = current_time(accounts)?.unix_timestamp;
+    for field in update.fields {
+        apply_config_field(&mut config, field, now)?;
+    }
+    if let Some(paused) = update.paused {
+        set_paused(&mut config, paused, now)?;
+    }
+    config.rate_revision = config.rate_revision.wrapping_add(1);
+    config.serialize(&mut &mut stake_config.data.borrow_mut()[..])?;
+    Ok(())
+}
+
+/// Validates and applies a single config change. With `max_schedule_horizon`
+/// set, emission segments may start at most that long after `now`.
+fn apply_config_field(
+    config: &mut StakeConfig,
+    field: ConfigField,
+    now: UnixTimestamp,
+) -> ProgramResult {
+    let event = StakeEvent::ConfigUpdated {
+        field: field.clone(),
+    };
+    match field {
+        ConfigField::ProtocolFeeBps(protocol_fee_bps) => {
+            if protocol_fee_bps > MAX_BPS {
+                return Err(StakeError::InvalidFeeBps.into());
+            }
+            config.protocol_fee_bps = protocol_fee_bps;
+        }
+        ConfigField::WithholdProtocolFee(withhold_protocol_fee) => {
+            config.withhold_protocol_fee = withhold_protocol_fee;
+        }
+        ConfigField::EmissionSchedule(emission_schedule) => {
+            check_entries(emission_schedule.len(), MAX_EMISSION_SEGMENTS)?;
+            if emission_schedule
+                .windows(2)
+                .any(|pair| pair[0].start_time >= pair[1].start_time)
+            {
+                return Err(StakeError::InvalidEmissionSchedule.into());
+            }
+            let horizon_end = now.saturating_add(config.max_schedule_horizon);
+            if config.max_schedule_horizon > 0
+                && emission_schedule
+                    .iter()
+                    .any(|segment| segment.start_time > horizon_end)
+            {
+                msg!("Emission segments must start by {}", horizon_end);
+                return Err(StakeError::ScheduleTooFar.into());
+            }
+            config.emission_schedule = emission_schedule;
+        }
+        ConfigField::MaxStakesPerUser(max_stakes_per_user) => {
+            config.max_stakes_per_user = max_stakes_per_user;
+        }
+        ConfigField::GovernanceMint(governance_mint) => {
+            config.governance_mint = governance_mint;
+        }
+        ConfigField::GovernanceBoostTiers(governance_boost_tiers) => {
+            check_entries(governance_boost_tiers.len(), MAX_GOVERNANCE_BOOST_TIERS)?;
+            if governance_boost_tiers
+                .iter()
+                .any(|tier| tier.boost_bps > MAX_BPS)
+                || governance_boost_tiers
+                    .windows(2)
+                    .any(|pair| pair[0].min_balance >= pair[1].min_balance)
+            {
+                return Err(StakeError::InvalidBoostTiers.into());
+            }
+            config.governance_boost_tiers = governance_boost_tiers;
+        }
+        ConfigField::AuditMerkleTree(audit_merkle_tree) => {
+            config.audit_merkle_tree = audit_merkle_tree;
+        }
+        ConfigField::SwapProgram(swap_program) => {
+            config.swap_program = swap_program;
+        }
+        ConfigField::SwapTargetMint(swap_target_mint) => {
+            config.swap_target_mint = swap_target_mint;
+        }
+        ConfigField::MinStakeAmount(min_stake_amount) => {
+            // Every stake is a single NFT, so any higher minimum would
+            // reject them all.
+            if min_stake_amount > 1 {
+                msg!("Minimum stake amount cannot exceed 1");
+                return Err(ProgramError::InvalidArgument);
+            }
+            config.min_stake_amount = min_stake_amount;
+        }
+        ConfigField::MaxStakers(max_stakers) => {
+            config.max_stakers = max_stakers;
+        }
+        ConfigField::RejectZeroRewardRedeem(reject_zero_reward_redeem) => {
+            config.reject_zero_reward_redeem = reject_zero_reward_redeem;
+        }
+        ConfigField::FloorPriceFeed(floor_price_feed) => {
+            config.floor_price_feed = floor_price_feed;
+        }
+        ConfigField::MaxFloorPriceAge(max_floor_price_age) => {
+            config.max_floor_price_age = max_floor_price_age;
+        }
+        ConfigField::FinalRewardGracePeriod(final_reward_grace_period) => {
+            config.final_reward_grace_period = final_reward_grace_period;
+        }
+        ConfigField::DurationTiers(duration_tiers) => {
+            check_entries(duration_tiers.len(), MAX_DURATION_TIERS)?;
+            if duration_tiers
+                .windows(2)
+                .any(|pair| pair[0].min_duration >= pair[1].min_duration)
+            {
+                return Err(StakeError::TiersNotSorted.into());
+            }
+            config.duration_tiers = duration_tiers;
+        }
+        ConfigField::AllowBurn(allow_burn) => {
+            config.allow_burn = allow_burn;
+        }
+        ConfigField::BurnReward(burn_reward) => {
+            config.burn_reward = burn_reward;
+        }
+        ConfigField::StrictNonce(strict_nonce) => {
+            config.strict_nonce = strict_nonce;
+        }
+        ConfigField::CompoundRateBps(compound_rate_bps) => {
+            config.compound_rate_bps = compound_rate_bps;
+        }
+        ConfigField::MaxVirtualStakedAmount(max_virtual_staked_amount) => {
+            config.max_virtual_staked_amount = max_virtual_staked_amount;
+        }
+        ConfigField::MinWalletAgeSlots(min_wallet_age_slots) => {
+            config.min_wallet_age_slots = min_wallet_age_slots;
+        }
+        ConfigField::LogComputeUnits(log_compute_units) => {
+            config.log_compute_units = log_compute_units;
+        }
+        ConfigField::FinalRewardRounding(final_reward_rounding) => {
+            config.final_reward_rounding = final_reward_rounding;
+        }
+        ConfigField::RewardCliff(reward_cliff) => {
+            config.reward_cliff = reward_cliff;
+        }
+        ConfigField::CliffPaysFromStart(cliff_pays_from_start) => {
+            config.cliff_pays_from_start = cliff_pays_from_start;
+        }
+        ConfigField::ProofOfHold(proof_of_hold) => {
+            config.proof_of_hold = proof_of_hold;
+        }
+        ConfigField::MaxBackpaySeconds(max_backpay_seconds) => {
+            config.max_backpay_seconds = max_backpay_seconds;
+        }
+        ConfigField::WrappedMintAuthorities(wrapped_mint_authorities) => {
+            check_entries(wrapped_mint_authorities.len(), MAX_WRAPPED_MINT_AUTHORITIES)?;
+            config.wrapped_mint_authorities = wrapped_mint_authorities;
+        }
+        ConfigField::FeeDiscountTiers(fee_discount_tiers) => {
+            check_entries(fee_discount_tiers.len(), MAX_FEE_DISCOUNT_TIERS)?;
+            if fee_discount_tiers
+                .windows(2)
+                .any(|pair| pair[0].min_duration >= pair[1].min_duration)
+            {
+                return Err(StakeError::TiersNotSorted.into());
+            }
+            config.fee_discount_tiers = fee_discount_tiers;
+        }
+        ConfigField::MinProtocolFeeBps(min_protocol_fee_bps) => {
+            if min_protocol_fee_bps > MAX_BPS {
+                return Err(StakeError::InvalidFeeBps.into());
+            }
+            config.min_protocol_fee_bps = min_protocol_fee_bps;
+        }
+        ConfigField::PayRewardOnPausedUnstake(pay_reward_on_paused_unstake) => {
+            config.pay_reward_on_paused_unstake = pay_reward_on_paused_unstake;
+        }
+        ConfigField::MasterEditionBoostBps(master_edition_boost_bps) => {
+            if master_edition_boost_bps > MAX_BPS {
+                return Err(StakeError::InvalidFeeBps.into());
+            }
+            config.master_edition_boost_bps = master_edition_boost_bps;
+        }
+        ConfigField::RestakeCooloff(restake_cooloff) => {
+            config.restake_cooloff = restake_cooloff;
+        }
+        ConfigField::ProratePartialDays(prorate_partial_days) => {
+            config.prorate_partial_days = prorate_partial_days;
+        }
+        ConfigField::StakeDilutionDivisor(stake_dilution_divisor) => {
+            config.stake_dilution_divisor = stake_dilution_divisor;
+        }
+        ConfigField::SponsorRent(sponsor_rent) => {
+            config.sponsor_rent = sponsor_rent;
+        }
+        ConfigField::TimelockProgram(timelock_program) => {
+            config.timelock_program = timelock_program;
+        }
+        ConfigField::AutoRedeemOnUnstake(auto_redeem_on_unstake) => {
+            config.auto_redeem_on_unstake = auto_redeem_on_unstake;
+        }
+        ConfigField::RewardMilestones(reward_milestones) => {
+            check_entries(reward_milestones.len(), MAX_DURATION_TIERS)?;
+            if reward_milestones
+                .windows(2)
+                .any(|pair| pair[0].min_duration >= pair[1].min_duration)
+            {
+                return Err(StakeError::TiersNotSorted.into());
+            }
+            config.reward_milestones = reward_milestones;
+        }
+        ConfigField::CircuitBreaker(circuit_breaker) => {
+            config.circuit_breaker = circuit_breaker;
+        }
+        ConfigField::StrictTotalRedeemed(strict_total_redeemed) => {
+            config.strict_total_redeemed = strict_total_redeemed;
+        }
+        ConfigField::TokenProgram(token_program) => {
+            if token_program != Pubkey::default() && !is_token_program(&token_program) {
+                msg!("{} is neither SPL Token nor Token-2022", token_program);
+                return Err(StakeError::WrongTokenProgram.into());
+            }
+            config.token_program = token_program;
+        }
+        ConfigField::AccrualMode(accrual_mode) => {
+            config.accrual_mode = accrual_mode;
+        }
+        ConfigField::PointsToTokenRate(points_to_token_rate) => {
+            config.points_to_token_rate = points_to_token_rate;
+        }
+        ConfigField::MinNftsToQualify(min_nfts_to_qualify) => {
+            config.min_nfts_to_qualify = min_nfts_to_qualify;
+        }
+        ConfigField::ComplianceProgram(compliance_program) => {
+            config.compliance_program = compliance_program;
+        }
+        ConfigField::CompressedNftTree(compressed_nft_tree) => {
+            config.compressed_nft_tree = compressed_nft_tree;
+        }
+        ConfigField::RequireCurrentSeedVersion(require_current_seed_version) => {
+            config.require_current_seed_version = require_current_seed_version;
+        }
+        ConfigField::MaxScheduleHorizon(max_schedule_horizon) => {
+            config.max_schedule_horizon = max_schedule_horizon;
+        }
+        ConfigField::AccrueFromInitialize(accrue_from_initialize) => {
+            config.accrue_from_initialize = accrue_from_initialize;
+        }
+        ConfigField::DelegateStakeAuthorities(delegate_stake_authorities) => {
+            check_entries(
+                delegate_stake_authorities.len(),
+                MAX_DELEGATE_STAKE_AUTHORITIES,
+            )?;
+            config.delegate_stake_authorities = delegate_stake_authorities;
+        }
+    }
+    events::emit(event);
+    Ok(())
+}
+
+/// Admin repair for a stake account whose `last_redeem_time` is in the future,
+/// which would otherwise block accrual until the clock catches up. Pulls it
+/// back to the current time; accounts not ahead of the clock are rejected.
+pub fn process_reset_last_redeem_time(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let admin = next_account_info(account_info_iter)?;
+    let stake_config = next_account_info(account_info_iter)?;
+    let stake_state = next_account_info(account_info_iter)?;
+
+    if !admin.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    let config = load_config(program_id, stake_config)?;
+    if config.admin != *admin.key {
+        return Err(StakeError::Unauthorized.into());
+    }
+    check_not_finalized(&config)?;
+    check_timelock(&config, account_info_iter)?;
+    if stake_state.owner != program_id {
+        return Err(ProgramError::IllegalOwner);
+    }
+    let mut account_data = load_stake_info(stake_state)?;
+    if !account_data.is_initialized() {
+        return Err(StakeError::UninitializedAccount.into());
+    }
+    let clock = current_time(accounts)?;
+    if account_data.last_redeem_time <= clock.unix_timestamp {
+        return Err(ProgramError::InvalidArgument);
+    }
+    msg!(
+        "Resetting last_redeem_time from {} to {}",
+        account_data.last_redeem_time,
+        clock.unix_timestamp
+    );
+    account_data.last_redeem_time = clock.unix_timestamp;
+    refresh_rate_cache(&mut account_data, &config);
+    save_stake_info(&account_data, stake_state)?;
+    events::emit(StakeEvent::LastRedeemTimeReset {
+        stake_state: *stake_state.key,
+        last_redeem_time: account_data.last_redeem_time,
+    });
+    Ok(())
+}
+
+/// Brings a stake account up to the current `UserStakeInfo` layout: accounts
+/// in the `LegacyUserStakeInfo` layout or in version 2 are converted, and
+/// accounts allocated before the latest fields were appended are grown. The account is resized to
+/// `UserStakeInfo::SIZE`, with the user topping up or getting back the rent
+/// difference.
+pub fn process_migrate_stake_account(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let user = next_account_info(account_info_iter)?;
+    let stake_state = next_account_info(account_info_iter)?;
+    let system_program = next_account_info(account_info_iter)?;
+
+    if !user.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    if stake_state.owner != program_id {
+        return Err(ProgramError::IllegalOwner);
+    }
+    let is_legacy = stake_state.data.borrow().first() == Some(&LegacyUserStakeInfo::VERSION);
+    let mut account_data = if is_legacy {
+        let legacy = try_from_slice_unchecked::<LegacyUserStakeInfo>(&stake_state.data.borrow())?;
+        let mut account_data = UserStakeInfo {
+            version: UserStakeInfo::VERSION,
+            flags: 0,
+            user: legacy.user,
+            token_account: legacy.token_account,
+            stake_start_time: legacy.stake_start_time,
+            last_redeem_time: legacy.last_redeem_time,
+            total_redeemed: legacy.total_redeemed,
+            nft_mint: Pubkey::default(),
+            stake_weight: 0,
+            unclaimed_final_reward: 0,
+            final_reward_expires_at: 0,
+            cached_rate: 0,
+            cached_rate_valid_until: 0,
+            cached_rate_revision: 0,
+            bump: 0,
+            last_nonce: 0,
+            preferred_reward_destination: Pubkey::default(),
+            virtual_staked_amount: 0,
+            has_rate_override: false,
+            override_rate: 0,
+            checkpoint_reward: 0,
+            edition_boost_bps: 0,
+            last_unstake_time: 0,
+            milestone_multiplier_bps: 0,
+            reward_remainder: 0,
+            points: 0,
+            seed_version: 0,
+            initialized_at: 0,
+            proof_owner: Pubkey::default(),
+            proof_slot: 0,
+            initialized_mint: Pubkey::default(),
+            reward_mint_generation: 0,
+        };
+        account_data.set_initialized(legacy.is_initialized);
+        account_data.set_stake_active(legacy.is_stake_active);
+        account_data
+    } else {
+        if stake_state.data_len() >= UserStakeInfo::SIZE
+            && stake_state.data.borrow().first() != Some(&UserStakeInfo::V2_VERSION)
+        {
+            msg!("Stake account already uses the current layout");
+            return Err(ProgramError::InvalidAccountData);
+        }
+        load_stake_info(stake_state)?
+    };
+    if !account_data.is_initialized() {
+        return Err(StakeError::UninitializedAccount.into());
+    }
+    if account_data.user != *user.key {
+        return Err(StakeError::InvalidStakeAccount.into());
+    }
+    // Derived rather than verified against the stored bump, which accounts
+    // created before it was stored are missing.
+    let (pda, bump) = Pubkey::find_program_address(
+        &stake_seeds(
+            &account_data.seed_version,
+            user.key,
+            &account_data.token_account,
+        ),
+        program_id,
+    );
+    if pda != *stake_state.key {
+        return Err(StakeError::InvalidPda.into());
+    }
+    account_data.bump = bump;
+
+    let rent_lamports = Rent::get()?.minimum_balance(UserStakeInfo::SIZE);
+    if stake_state.lamports() < rent_lamports {
+        invoke(
+            &system_instruction::transfer(
+                user.key,
+                stake_state.key,
+                rent_lamports - stake_state.lamports(),
+            ),
+            &[user.clone(), stake_state.clone(), system_program.clone()],
+        )?;
+    }
+    stake_state.realloc(UserStakeInfo::SIZE, true)?;
+    let refund = stake_state.lamports().saturating_sub(rent_lamports);
+    **stake_state.try_borrow_mut_lamports()? -= refund;
+    **user.try_borrow_mut_lamports()? += refund;
+    save_stake_info(&account_data, stake_state)?;
+    events::emit(StakeEvent::StakeAccountMigrated {
+        stake_state: *stake_state.key,
+    });
+    Ok(())
+}
+
+/// Admin only. Brings the config up to the current `StakeConfig` schema: the
+/// account is grown to `StakeConfig::SIZE`, with the admin topping up the
+/// rent, and flags an older version predates are written with the values the
+/// version-aware accessors already read for it, so accrual in flight is
+/// unchanged by the migration.
+pub fn process_migrate_config(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let admin = next_account_info(account_info_iter)?;
+    let stake_config = next_account_info(account_info_iter)?;
+    let system_program = next_account_info(account_info_iter)?;
+
+    if !admin.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    let mut config = load_config(program_id, stake_config)?;
+    if config.admin != *admin.key {
+        return Err(StakeError::Unauthorized.into());
+    }
+    check_not_finalized(&config)?;
+    check_timelock(&config, account_info_iter)?;
+    if config.version == StakeConfig::VERSION && stake_config.data_len() >= StakeConfig::SIZE {
+        msg!("Config already uses the current schema");
+        return Err(ProgramError::InvalidAccountData);
+    }
+    let from_version = config.version;
+    config.prorate_partial_days = config.prorates_partial_days();
+    config.auto_redeem_on_unstake = config.auto_redeems_on_unstake();
+    config.version = StakeConfig::VERSION;
+
+    let rent_lamports = Rent::get()?.minimum_balance(StakeConfig::SIZE);
+    if stake_config.lamports() < rent_lamports {
+        invoke(
+            &system_instruction::transfer(
+                admin.key,
+                stake_config.key,
+                rent_lamports - stake_config.lamports(),
+            ),
+            &[admin.clone(), stake_config.clone(), system_program.clone()],
+        )?;
+    }
+    if stake_config.data_len() < StakeConfig::SIZE {
+        stake_config.realloc(StakeConfig::SIZE, true)?;
+    }
+    config.serialize(&mut &mut stake_config.data.borrow_mut()[..])?;
+    events::emit(StakeEvent::ConfigMigrated {
+        from_version,
+        to_version: StakeConfig::VERSION,
+    });
+    Ok(())
+}
+
+/// Hands an active stake position to `new_owner`. The reward accrued so far is
+/// paid to the current owner, boosted by an optional trailing governance
+/// token account of theirs, then the position (keeping its
+/// `stake_start_time` and `total_redeemed`) moves to the stake PDA derived for
+/// `new_owner` and the old stake account is closed with its rent refunded.
+///
+/// The NFT goes with the position: `nft_token_account` must already be owned
+/// by `new_owner`, e.g. through a `SetAuthority` of its owner earlier in the
+/// same transaction, so the buyer holds what they accrue on. Compressed
+/// stakes cannot be transferred, as the new leaf owner cannot be verified.
+pub fn process_transfer_stake_position(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    new_owner: Pubkey,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let user = next_account_info(account_info_iter)?;
+    let nft_token_account = next_account_info(account_info_iter)?;
+    let stake_state = next_account_info(account_info_iter)?;
+    let new_stake_state = next_account_info(account_info_iter)?;
+    let staked_mint = next_account_info(account_info_iter)?;
+    let user_stake_account = next_account_info(account_info_iter)?;
+    let new_user_stake_account = next_account_info(account_info_iter)?;
+    let stake_config = next_account_info(account_info_iter)?;
+    let reward_accounts = RewardAccounts::next(account_info_iter)?;
+    let system_program = next_account_info(account_info_iter)?;
+    let governance_token_account = next_account_info(account_info_iter).ok();
+
+    if !user.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    if new_owner == *user.key {
+        return Err(ProgramError::InvalidArgument);
+    }
+    if stake_state.owner != program_id {
+        return Err(ProgramError::IllegalOwner);
+    }
+    let new_seeds = stake_seeds(&STAKE_SEED_VERSION, &new_owner, nft_token_account.key);
+    let (new_pda, new_bump) = Pubkey::find_program_address(&new_seeds, program_id);
+    if new_pda != *new_stake_state.key {
+        msg!("Invalid PDA passed for the new owner");
+        return Err(StakeError::InvalidPda.into());
+    }
+    let mut account_data = load_stake_info(stake_state)?;
+    verify_stake_pda(
+        program_id,
+        user.key,
+        nft_token_account.key,
+        stake_state,
+        account_data.seed_version,
+        account_data.bump,
+    )?;
+    if !account_data.is_initialized() {
+        return Err(StakeError::UninitializedAccount.into());
+    }
+    if !account_data.is_stake_active() {
+        return Err(StakeError::StakeNotActive.into());
+    }
+    if account_data.user != *user.key {
+        return Err(StakeError::InvalidStakeAccount.into());
+    }
+    if account_data.token_account != *nft_token_account.key {
+        return Err(StakeError::InvalidTokenAccount.into());
+    }
+    if account_data.is_compressed() || !is_token_program(nft_token_account.owner) {
+        return Err(StakeError::InvalidTokenAccount.into());
+    }
+    let token_account = unpack_token_account(nft_token_account)?;
+    if token_account.owner != new_owner
+        || token_account.mint != account_data.nft_mint
+        || token_account.amount != 1
+    {
+        msg!("The NFT must be held by {} before the transfer", new_owner);
+        return Err(StakeError::InvalidTokenAccount.into());
+    }
+    let mut config = load_config(program_id, stake_config)?;
+    let mint_authority_bump = check_reward_accounts(program_id, &config, &reward_accounts)?;
+    let clock = current_time(accounts)?;
+    let mut user_stakes = load_user_stake_account(program_id, user.key, user_stake_account)?;
+    let settle_at = within_qualified_stretch(
+        &mut account_data,
+        qualified_stretch(&config, &user_stakes),
+        clock.unix_timestamp,
+    );
+    let reward_amt = apply_reward(&mut account_data, settle_at, &config, Settlement::Pay)?;
+    pay_stake_reward(
+        &mut config,
+        &mut account_data,
+        &reward_accounts,
+        mint_authority_bump,
+        reward_amt,
+        clock.unix_timestamp,
+        governance_token_account,
+    )?;
+    account_data.user = new_owner;
+    account_data.seed_version = STAKE_SEED_VERSION;
+    account_data.bump = new_bump;
+    account_data.set_proof_verified(false);
+
+    if new_user_stake_account.data_is_empty() {
+        register_new_staker(&mut config)?;
+    }
+    config.serialize(&mut &mut stake_config.data.borrow_mut()[..])?;
+    let mut new_owner_stakes = load_or_create_user_stake_account(
+        program_id,
+        user,
+        &new_owner,
+        new_user_stake_account,
+        system_program,
+    )?;
+    if config.max_stakes_per_user != 0
+        && new_owner_stakes.active_stakes >= config.max_stakes_per_user
+    {
+        return Err(StakeError::UserStakeLimitReached.into());
+    }
+    let active_stakes = new_owner_stakes.active_stakes + 1;
+    set_active_stakes(
+        &config,
+        &mut new_owner_stakes,
+        active_stakes,
+        clock.unix_timestamp,
+    );
+    save_user_stakes(&new_owner_stakes, new_user_stake_account)?;
+    let active_stakes = user_stakes.active_stakes.saturating_sub(1);
+    set_active_stakes(
+        &config,
+        &mut user_stakes,
+        active_stakes,
+        clock.unix_timestamp,
+    );
+    save_user_stakes(&user_stakes, user_stake_account)?;
+
+    let space = UserStakeInfo::SIZE;
+    let rent_lamports = Rent::get()?.minimum_balance(space);
+    invoke_signed(
+        &system_instruction::create_account(
+            user.key,
+            new_stake_state.key,
+            rent_lamports,
+            space.try_into().unwrap(),
+            program_id,
+        ),
+        &[
+            user.clone(),
+            new_stake_state.clone(),
+            system_program.clone(),
+        ],
+        &[&[new_seeds.as_slice(), &[&[new_bump]]].concat()],
+    )?;
+    save_stake_info(&account_data, new_stake_state)?;
+    if account_data.nft_mint != Pubkey::default() {
+        let mut record = load_staked_mint(program_id, staked_mint, &account_data.nft_mint)?;
+        record.stake_state = *new_stake_state.key;
+        record.serialize(&mut &mut staked_mint.data.borrow_mut()[..])?;
+    }
+
+    let refund = stake_state.lamports();
+    **stake_state.try_borrow_mut_lamports()? = 0;
+    **user.try_borrow_mut_lamports()? += refund;
+    stake_state.data.borrow_mut().fill(0);
+    events::emit(StakeEvent::StakePositionTransferred {
+        from_stake_state: *stake_state.key,
+        to_stake_state: *new_stake_state.key,
+        new_owner,
+        reward: reward_amt,
+    });
+    Ok(())
+}
+
+/// Records `stake_state` as the active stake of `nft_mint`, creating the
+/// mint's `StakedMint` PDA on first use. Fails if the mint is already actively
+/// staked through another stake account.
+fn claim_staked_mint<'a>(
+    program_id: &Pubkey,
+    payer: &AccountInfo<'a>,
+    staked_mint: &AccountInfo<'a>,
+    system_program: &AccountInfo<'a>,
+    nft_mint: &Pubkey,
+    stake_state: &Pubkey,
+) -> ProgramResult {
+    let (pda, bump) =
+        Pubkey::find_program_address(&[STAKED_MINT_SEED, nft_mint.as_ref()], program_id);
+    if pda != *staked_mint.key {
+        return Err(StakeError::InvalidPda.into());
+    }
+    if staked_mint.data_is_empty() {
+        let space = StakedMint::SIZE;
+        let rent_lamports = Rent::get()?.minimum_balance(space);
+        invoke_signed(
+            &system_instruction::create_account(
+                payer.key,
+                staked_mint.key,
+                rent_lamports,
+                space.try_into().unwrap(),
+                program_id,
+            ),
+            &[payer.clone(), staked_mint.clone(), system_program.clone()],
+            &[&[STAKED_MINT_SEED, nft_mint.as_ref(), &[bump]]],
+        )?;
+    } else if staked_mint.owner != program_id {
+        return Err(ProgramError::IllegalOwner);
+    } else if staked_mint.data_len() < StakedMint::SIZE {
+        // A legacy record: grow it so the next release can record its time.
+        let shortfall = Rent::get()?
+            .minimum_balance(StakedMint::SIZE)
+            .saturating_sub(staked_mint.lamports());
+        if shortfall > 0 {
+            invoke(
+                &system_instruction::transfer(payer.key, staked_mint.key, shortfall),
+                &[payer.clone(), staked_mint.clone(), system_program.clone()],
+            )?;
+        }
+        staked_mint.realloc(StakedMint::SIZE, true)?;
+    }
+    let mut record = read_staked_mint(staked_mint)?;
+    if record.stake_state != Pubkey::default() && record.stake_state != *stake_state {
+        msg!(
+            "Mint {} is already staked by {}",
+            nft_mint,
+            record.stake_state
+        );
+        return Err(StakeError::NftAlreadyStaked.into());
+    }
+    record.stake_state = *stake_state;
+    record.serialize(&mut &mut staked_mint.data.borrow_mut()[..])?;
+    Ok(())
+}
+
+/// Clears the `StakedMint` record of `nft_mint` if it still points at
+/// `stake_state`, recording `now` as the mint's last unstake. Stakes migrated
+/// from the legacy layout have no recorded mint and are skipped. A record
+/// still `StakedMint::LEGACY_SIZE` long, which only a mint staked before the
+/// field was added and not restaked since can have, has no room for the time:
+/// the mint's next stake is then only held to the stake account's own
+/// `last_unstake_time`.
+fn release_staked_mint(
+    program_id: &Pubkey,
+    staked_mint: &AccountInfo,
+    nft_mint: &Pubkey,
+    stake_state: &Pubkey,
+    now: UnixTimestamp,
+) -> ProgramResult {
+    if *nft_mint == Pubkey::default() {
+        return Ok(());
+    }
+    let mut record = load_staked_mint(program_id, staked_mint, nft_mint)?;
+    if record.stake_state == *stake_state {
+        record.stake_state = Pubkey::default();
+        record.last_unstake_time = now;
+        let len = staked_mint.data_len();
+        let bytes = record.try_to_vec()?;
+        staked_mint.data.borrow_mut()[..len.min(bytes.len())]
+            .copy_from_slice(&bytes[..len.min(bytes.len())]);
+    }
+    Ok(())
+}
+
+/// When `nft_mint` was last unstaked through any stake account, from its
+/// `StakedMint` record, or zero if it has none yet.
+fn mint_last_unstake_time(
+    program_id: &Pubkey,
+    staked_mint: &AccountInfo,
+    nft_mint: &Pubkey,
+) -> Result<UnixTimestamp, ProgramError> {
+    if staked_mint.data_is_empty() {
+        return Ok(0);
+    }
+    Ok(load_staked_mint(program_id, staked_mint, nft_mint)?.last_unstake_time)
+}
+
+/// A `StakedMint` record, with the fields a legacy record lacks read as zero.
+fn read_staked_mint(staked_mint: &AccountInfo) -> Result<StakedMint, ProgramError> {
+    let mut data = staked_mint.data.borrow().to_vec();
+    data.resize(data.len().max(StakedMint::SIZE), 0);
+    Ok(try_from_slice_unchecked::<StakedMint>(&data)?)
+}
+
+fn load_staked_mint(
+    program_id: &Pubkey,
+    staked_mint: &AccountInfo,
+    nft_mint: &Pubkey,
+) -> Result<StakedMint, ProgramError> {
+    if staked_mint.owner != program_id {
+        return Err(ProgramError::IllegalOwner);
+    }
+    let (pda, _bump) =
+        Pubkey::find_program_address(&[STAKED_MINT_SEED, nft_mint.as_ref()], program_id);
+    if pda != *staked_mint.key {
+        return Err(StakeError::InvalidPda.into());
+    }
+    read_staked_mint(staked_mint)
+}
+
+/// Rejects wallets whose attested `WalletAgeRecord` is younger than
+/// `min_wallet_age_slots`. Wallets without a record count as new.
+fn check_wallet_age(
+    program_id: &Pubkey,
+    config: &StakeConfig,
+    wallet: &Pubkey,
+    wallet_age_record: &AccountInfo,
+    clock: &Clock,
+) -> ProgramResult {
+    let (pda, _bump) =
+        Pubkey::find_program_address(&[WALLET_AGE_SEED, wallet.as_ref()], program_id);
+    if pda != *wallet_age_record.key {
+        return Err(StakeError::InvalidPda.into());
+    }
+    if wallet_age_record.data_is_empty() {
+        return Err(StakeError::WalletTooNew.into());
+    }
+    if wallet_age_record.owner != program_id {
+        return Err(ProgramError::IllegalOwner);
+    }
+    let record = try_from_slice_unchecked::<WalletAgeRecord>(&wallet_age_record.data.borrow())?;
+    if !record.is_initialized()
+        || clock.slot.saturating_sub(record.first_seen_slot) < config.min_wallet_age_slots
+    {
+        msg!("Wallet first seen at slot {}", record.first_seen_slot);
+        return Err(StakeError::WalletTooNew.into());
+    }
+    Ok(())
+}
+
+/// Checks the staked NFT's mint. While `wrapped_mint_authorities` is
+/// configured, a mint that still has a mint authority is a wrapped mint and
+/// must be controlled by one of the listed bridges; mints without an authority
+/// are native NFTs and pass.
+fn check_nft_provenance(
+    config: &StakeConfig,
+    mint_key: &Pubkey,
+    nft_mint: &AccountInfo,
+) -> ProgramResult {
+    if *nft_mint.key != *mint_key || !is_token_program(nft_mint.owner) {
+        return Err(StakeError::InvalidTokenAccount.into());
+    }
+    let mint = unpack_mint(nft_mint)?;
+    if mint.decimals != 0 || mint.supply != 1 {
+        return Err(StakeError::InvalidTokenAccount.into());
+    }
+    if let COption::Some(authority) = mint.mint_authority {
+        if !config.wrapped_mint_authorities.contains(&authority) {
+            msg!("Mint authority {} is not a recognized bridge", authority);
+            return Err(StakeError::UnsupportedWrappedMint.into());
+        }
+    }
+    Ok(())
+}
+
+/// Moves `lamports` from the rent reserve to `payer` ahead of creating
+/// accounts it pays the rent of, keeping the reserve itself rent exempt.
+fn draw_rent_reserve(
+    program_id: &Pubkey,
+    rent_reserve: &AccountInfo,
+    payer: &AccountInfo,
+    lamports: u64,
+) -> ProgramResult {
+    let (pda, _bump) = Pubkey::find_program_address(&[RENT_RESERVE_SEED], program_id);
+    if pda != *rent_reserve.key {
+        return Err(StakeError::InvalidPda.into());
+    }
+    if rent_reserve.owner != program_id {
+        return Err(StakeError::RentReserveEmpty.into());
+    }
+    let available = rent_reserve
+        .lamports()
+        .saturating_sub(Rent::get()?.minimum_balance(rent_reserve.data_len()));
+    if available < lamports {
+        msg!("Rent reserve holds {}, needs {}", available, lamports);
+        return Err(StakeError::RentReserveEmpty.into());
+    }
+    **rent_reserve.try_borrow_mut_lamports()? -= lamports;
+    **payer.try_borrow_mut_lamports()? += lamports;
+    Ok(())
+}
+
+/// Takes `count` ended stakes off the config's `total_active_stakes`.
+fn record_unstakes(
+    config: &mut StakeConfig,
+    stake_config: &AccountInfo,
+    count: u32,
+) -> ProgramResult {
+    config.total_active_stakes = config.total_active_stakes.saturating_sub(count);
+    bump_counter(&mut config.unstake_count, count as u64)?;
+    config.serialize(&mut &mut stake_config.data.borrow_mut()[..])?;
+    Ok(())
+}
+
+/// Advances one of the config's telemetry counters by `by`.
+fn bump_counter(counter: &mut u64, by: u64) -> ProgramResult {
+    *counter = counter.checked_add(by).ok_or(StakeError::CounterOverflow)?;
+    Ok(())
+}
+
+/// Counts a user staking for the first time, i.e. one without a
+/// `UserStakeAccount` yet, against `max_stakers`.
+fn register_new_staker(config: &mut StakeConfig) -> ProgramResult {
+    if config.max_stakers != 0 && config.total_unique_stakers >= config.max_stakers {
+        msg!("Staker limit of {} reached", config.max_stakers);
+        return Err(StakeError::StakerLimitReached.into());
+    }
+    config.total_unique_stakers += 1;
+    Ok(())
+}
+
+/// Loads `owner`'s `UserStakeAccount`, creating it with `payer` funding the
+/// rent if it does not exist yet.
+fn load_or_create_user_stake_account<'a>(
+    program_id: &Pubkey,
+    payer: &AccountInfo<'a>,
+    owner: &Pubkey,
+    user_stake_account: &AccountInfo<'a>,
+    system_program: &AccountInfo<'a>,
+) -> Result<UserStakeAccount, ProgramError> {
+    if !user_stake_account.data_is_empty() {
+        let user_stakes = load_user_stake_account(program_id, owner, user_stake_account)?;
+        if user_stake_account.data_len() < UserStakeAccount::SIZE {
+            // A legacy account: grow it so the qualification times can be
+            // recorded.
+            let shortfall = Rent::get()?
+                .minimum_balance(UserStakeAccount::SIZE)
+                .saturating_sub(user_stake_account.lamports());
+            if shortfall > 0 {
+                invoke(
+                    &system_instruction::transfer(payer.key, user_stake_account.key, shortfall),
+                    &[
+                        payer.clone(),
+                        user_stake_account.clone(),
+                        system_program.clone(),
+                    ],
+                )?;
+            }
+            user_stake_account.realloc(UserStakeAccount::SIZE, true)?;
+        }
+        return Ok(user_stakes);
+    }
+    let (pda, bump) = Pubkey::find_program_address(&[USER_STAKE_SEED, owner.as_ref()], program_id);
+    if pda != *user_stake_account.key {
+        return Err(StakeError::InvalidPda.into());
+    }
+    let space = UserStakeAccount::SIZE;
+    let rent_lamports = Rent::get()?.minimum_balance(space);
+    invoke_signed(
+        &system_instruction::create_account(
+            payer.key,
+            user_stake_account.key,
+            rent_lamports,
+            space.try_into().unwrap(),
+            program_id,
+        ),
+        &[
+            payer.clone(),
+            user_stake_account.clone(),
+            system_program.clone(),
+        ],
+        &[&[USER_STAKE_SEED, owner.as_ref(), &[bump]]],
+    )?;
+    Ok(UserStakeAccount {
+        is_initialized: true,
+        user: *owner,
+        active_stakes: 0,
+        qualified_since: 0,
+        qualified_until: 0,
+    })
+}
+
+fn load_user_stake_account(
+    program_id: &Pubkey,
+    owner: &Pubkey,
+    user_stake_account: &AccountInfo,
+) -> Result<UserStakeAccount, ProgramError> {
+    if user_stake_account.owner != program_id {
+        return Err(ProgramError::IllegalOwner);
+    }
+    let (pda, _bump) = Pubkey::find_program_address(&[USER_STAKE_SEED, owner.as_ref()], program_id);
+    if pda != *user_stake_account.key {
+        return Err(StakeError::InvalidPda.into());
+    }
+    let user_stakes = read_user_stakes(user_stake_account)?;
+    if !user_stakes.is_initialized() {
+        return Err(StakeError::UninitializedAccount.into());
+    }
+    Ok(user_stakes)
+}
+
+/// A `UserStakeAccount`. A legacy account, without the qualification times,
+/// reads as qualified without bounds, the rule it accrued under, until it is
+/// grown.
+fn read_user_stakes(user_stake_account: &AccountInfo) -> Result<UserStakeAccount, ProgramError> {
+    let data = user_stake_account.data.borrow();
+    if data.len() >= UserStakeAccount::SIZE {
+        return Ok(try_from_slice_unchecked::<UserStakeAccount>(&data)?);
+    }
+    let mut padded = data.to_vec();
+    padded.resize(UserStakeAccount::SIZE, 0);
+    let mut user_stakes = try_from_slice_unchecked::<UserStakeAccount>(&padded)?;
+    user_stakes.qualified_until = UnixTimestamp::MAX;
+    Ok(user_stakes)
+}
+
+/// Writes `user_stakes`, leaving out the qualification times a legacy account
+/// has no room for.
+fn save_user_stakes(
+    user_stakes: &UserStakeAccount,
+    user_stake_account: &AccountInfo,
+) -> ProgramResult {
+    let bytes = user_stakes.try_to_vec()?;
+    let len = user_stake_account.data_len().min(bytes.len());
+    user_stake_account.data.borrow_mut()[..len].copy_from_slice(&bytes[..len]);
+    Ok(())
+}
+
+/// Seeds of the stake PDA of `user` and `nft_token_account` under
+/// `seed_version`, without the bump.
+fn stake_seeds<'a>(
+    seed_version: &'a u8,
+    user: &'a Pubkey,
+    nft_token_account: &'a Pubkey,
+) -> Vec<&'a [u8]> {
+    let mut seeds = Vec::with_capacity(4);
+    if *seed_version != 0 {
+        seeds.push(std::slice::from_ref(seed_version));
+    }
+    seeds.push(user.as_ref());
+    seeds.push(nft_token_account.as_ref());
+    seeds
+}
+
+/// Checks that `stake_state` is the stake PDA of `user` and `nft_token_account`
+/// under `seed_version`. Accounts that stored their bump are checked with a
+/// single `create_program_address`; a `stored_bump` of zero means the account
+/// predates the stored bump and the address is derived with
+/// `find_program_address`.
+fn verify_stake_pda(
+    program_id: &Pubkey,
+    user: &Pubkey,
+    nft_token_account: &Pubkey,
+    stake_state: &AccountInfo,
+    seed_version: u8,
+    stored_bump: u8,
+) -> ProgramResult {
+    if seed_version > STAKE_SEED_VERSION {
+        return Err(StakeError::InvalidPda.into());
+    }
+    let seeds = stake_seeds(&seed_version, user, nft_token_account);
+    let pda = if stored_bump != 0 {
+        let bump_seed = [stored_bump];
+        let mut seeds = seeds;
+        seeds.push(&bump_seed);
+        Pubkey::create_program_address(&seeds, program_id).map_err(|_| StakeError::InvalidPda)?
+    } else {
+        Pubkey::find_program_address(&seeds, program_id).0
+    };
+    if pda != *stake_state.key {
+        msg!("Invalid PDA seeds");
+        return Err(StakeError::InvalidPda.into());
+    }
+    Ok(())
+}
+
+/// Reads a stake account, treating fields appended after the account was
+/// allocated as zero.
+fn load_stake_info(stake_state: &AccountInfo) -> Result<UserStakeInfo, ProgramError> {
+    let data = stake_state.data.borrow();
+    if data.first() == Some(&LegacyUserStakeInfo::VERSION) {
+        msg!("Stake account uses the legacy layout, migrate it first");
+        return Err(StakeError::AccountNeedsMigration.into());
+    }
+    let account_data =
+        if data.len() < UserStakeInfo::SIZE || data.first() == Some(&UserStakeInfo::V2_VERSION) {
+            let mut padded = data.to_vec();
+            padded.resize(padded.len().max(UserStakeInfo::SIZE), 0);
+            if padded[0] == UserStakeInfo::V2_VERSION {
+                UserStakeInfo::upgrade_from_v2(&mut padded);
+            }
+            try_from_slice_unchecked::<UserStakeInfo>(&padded)?
+        } else {
+            try_from_slice_unchecked::<UserStakeInfo>(&data)?
+        };
+    if account_data.is_initialized() && account_data.version != UserStakeInfo::VERSION {
+        return Err(StakeError::InvalidStakeAccount.into());
+    }
+    Ok(account_data)
+}
+
+fn save_stake_info(account_data: &UserStakeInfo, stake_state: &AccountInfo) -> ProgramResult {
+    if stake_state.data_len() < UserStakeInfo::SIZE {
+        msg!("Stake account predates the current layout, migrate it first");
+        return Err(StakeError::AccountNeedsMigration.into());
+    }
+    #[cfg(feature = "debug-invariants")]
+    check_stake_invariants(account_data, stake_state)?;
+    account_data.serialize(&mut &mut stake_state.data.borrow_mut()[..])?;
+    Ok(())
+}
+
+/// Development builds only: logs every broken stake account invariant and
+/// then panics, failing the transaction.
+#[cfg(feature = "debug-invariants")]
+fn check_stake_invariants(
+    account_data: &UserStakeInfo,
+    stake_state: &AccountInfo,
+) -> ProgramResult {
+    use crate::state::ALL_FLAGS;
+
+    let mut violations = 0;
+    let rent_lamports = Rent::get()?.minimum_balance(stake_state.data_len());
+    if stake_state.lamports() < rent_lamports {
+        msg!(
+            "Invariant: {} lamports is below the rent exempt {}",
+            stake_state.lamports(),
+            rent_lamports
+        );
+        violations += 1;
+    }
+    if account_data.bump != 0 {
+        let (_pda, canonical_bump) = Pubkey::find_program_address(
+            &stake_seeds(
+                &account_data.seed_version,
+                &account_data.user,
+                &account_data.token_account,
+            ),
+            stake_state.owner,
+        );
+        if account_data.bump != canonical_bump {
+            msg!(
+                "Invariant: bump {} is not the canonical {}",
+                account_data.bump,
+                canonical_bump
+            );
+            violations += 1;
+        }
+    }
+    if account_data.last_redeem_time < account_data.stake_start_time {
+        msg!(
+            "Invariant: last_redeem_time {} precedes stake_start_time {}",
+            account_data.last_redeem_time,
+            account_data.stake_start_time
+        );
+        violations += 1;
+    }
+    if account_data.flags & !ALL_FLAGS != 0
+        || (account_data.is_stake_active() && !account_data.is_initialized())
+    {
+        msg!("Invariant: inconsistent flags {:#04x}", account_data.flags);
+        violations += 1;
+    }
+    assert!(
+        violations == 0,
+        "{} stake account invariants broken",
+        violations
+    );
+    Ok(())
+}
+
+/// The current `Clock`, read from the Clock sysvar account when the caller
+/// passes one among `accounts` and from `Clock::get()` otherwise. Only the
+/// account with the sysvar's id is read, so no other account can stand in for
+/// it.
+fn current_time(accounts: &[AccountInfo]) -> Result<Clock, ProgramError> {
+    match accounts
+        .iter()
+        .find(|account| sysvar::clock::check_id(account.key))
+    {
+        Some(clock_sysvar) => Clock::from_account_info(clock_sysvar),
+        None => Clock::get(),
+    }
+}
+
+/// Fails once `FinalizePool` has run. Only staking and admin changes are
+/// refused; exits and reward claims stay open.
+fn check_not_finalized(config: &StakeConfig) -> ProgramResult {
+    if config.finalized {
+        return Err(StakeError::PoolFinalized.into());
+    }
+    Ok(())
+}
+
+/// Fails once the current slot is past `valid_until_slot`, so a transaction
+/// delayed until conditions have changed cannot land.
+fn check_deadline(valid_until_slot: Option<u64>, accounts: &[AccountInfo]) -> ProgramResult {
+    let Some(valid_until_slot) = valid_until_slot else {
+        return Ok(());
+    };
+    let slot = current_time(accounts)?.slot;
+    if slot > valid_until_slot {
+        msg!(
+            "Slot {} is past valid_until_slot {}",
+            slot,
+            valid_until_slot
+        );
+        return Err(StakeError::TransactionExpired.into());
+    }
+    Ok(())
+}
+
+/// With a `circuit_breaker` configured, that account has to be passed among
+/// `accounts` and fails the instruction while it is tripped.
+fn check_circuit_breaker(config: &StakeConfig, accounts: &[AccountInfo]) -> ProgramResult {
+    if config.circuit_breaker == Pubkey::default() {
+        return Ok(());
+    }
+    let circuit_breaker = accounts
+        .iter()
+        .find(|account| *account.key == config.circuit_breaker)
+        .ok_or(ProgramError::NotEnoughAccountKeys)?;
+    let breaker = try_from_slice_unchecked::<CircuitBreaker>(&circuit_breaker.data.borrow())?;
+    if breaker.tripped {
+        msg!("Circuit breaker {} is tripped", circuit_breaker.key);
+        return Err(StakeError::CircuitBreakerTripped.into());
+    }
+    Ok(())
+}
+
+/// With `min_nfts_to_qualify` set, `user`'s `UserStakeAccount` has to be
+/// passed among `accounts` and count at least that many active stakes.
+/// Returns the user's `qualified_stretch`.
+fn check_set_size(
+    program_id: &Pubkey,
+    config: &StakeConfig,
+    user: &Pubkey,
+    accounts: &[AccountInfo],
+) -> Result<(UnixTimestamp, UnixTimestamp), ProgramError> {
+    if config.min_nfts_to_qualify == 0 {
+        return Ok((UnixTimestamp::MIN, UnixTimestamp::MAX));
+    }
+    let user_stakes = find_user_stakes(program_id, user, accounts)?;
+    if user_stakes.active_stakes < config.min_nfts_to_qualify {
+        msg!(
+            "{} of {} NFTs staked",
+            user_stakes.active_stakes,
+            config.min_nfts_to_qualify
+        );
+        return Err(StakeError::BelowMinimumSetSize.into());
+    }
+    Ok(qualified_stretch(config, &user_stakes))
+}
+
+/// `user`'s `qualified_stretch`. With `min_nfts_to_qualify` set, their
+/// `UserStakeAccount` has to be passed among `accounts`.
+fn find_qualified_stretch(
+    program_id: &Pubkey,
+    config: &StakeConfig,
+    user: &Pubkey,
+    accounts: &[AccountInfo],
+) -> Result<(UnixTimestamp, UnixTimestamp), ProgramError> {
+    if config.min_nfts_to_qualify == 0 {
+        return Ok((UnixTimestamp::MIN, UnixTimestamp::MAX));
+    }
+    Ok(qualified_stretch(
+        config,
+        &find_user_stakes(program_id, user, accounts)?,
+    ))
+}
+
+/// `user`'s `UserStakeAccount`, found among `accounts` by its address.
+fn find_user_stakes(
+    program_id: &Pubkey,
+    user: &Pubkey,
+    accounts: &[AccountInfo],
+) -> Result<UserStakeAccount, ProgramError> {
+    let (pda, _bump) = Pubkey::find_program_address(&[USER_STAKE_SEED, user.as_ref()], program_id);
+    let user_stake_account = accounts
+        .iter()
+        .find(|account| *account.key == pda)
+        .ok_or(ProgramError::NotEnoughAccountKeys)?;
+    load_user_stake_account(program_id, user, user_stake_account)
+}
+
+/// The `[since, until)` stretch in which the user of `user_stakes` has had at
+/// least `min_nfts_to_qualify` NFTs staked, the only time their stakes accrue
+/// in. Unbounded without a minimum, open-ended while the set is complete and
+/// ending when it was last broken otherwise, which leaves it empty if it never
+/// was complete.
+fn qualified_stretch(
+    config: &StakeConfig,
+    user_stakes: &UserStakeAccount,
+) -> (UnixTimestamp, UnixTimestamp) {
+    if config.min_nfts_to_qualify == 0 {
+        (UnixTimestamp::MIN, UnixTimestamp::MAX)
+    } else if user_stakes.active_stakes >= config.min_nfts_to_qualify {
+        (user_stakes.qualified_since, UnixTimestamp::MAX)
+    } else {
+        (user_stakes.qualified_since, user_stakes.qualified_until)
+    }
+}
+
+/// Settles the part of a stake's window before the user's `qualified_stretch`
+/// at a zero rate, keeping what was checkpointed, and returns the time the
+/// rest may be settled up to: `now`, or when the set was broken if it still
+/// is. A stretch that ended before the current one began is not paid.
+fn within_qualified_stretch(
+    account_data: &mut UserStakeInfo,
+    (since, until): (UnixTimestamp, UnixTimestamp),
+    now: UnixTimestamp,
+) -> UnixTimestamp {
+    if account_data.last_redeem_time < since {
+        msg!(
+            "Set complete since {}, nothing accrues from {}",
+            since,
+            account_data.last_redeem_time
+        );
+        account_data.last_redeem_time = since;
+    }
+    now.min(until.max(account_data.last_redeem_time))
+}
+
+/// Sets `user_stakes.active_stakes`, recording `now` as the time the set was
+/// completed or broken when the count crosses `min_nfts_to_qualify`.
+fn set_active_stakes(
+    config: &StakeConfig,
+    user_stakes: &mut UserStakeAccount,
+    active_stakes: u32,
+    now: UnixTimestamp,
+) {
+    let min = config.min_nfts_to_qualify;
+    if min != 0 {
+        let was_complete = user_stakes.active_stakes >= min;
+        if active_stakes >= min && !was_complete {
+            user_stakes.qualified_since = now;
+        } else if active_stakes < min && was_complete {
+            user_stakes.qualified_until = now;
+        }
+    }
+    user_stakes.active_stakes = active_stakes;
+}
+
+/// With a `compliance_program` configured, that program has to be passed
+/// among `accounts` and allow `user`. It is invoked with the user as its only,
+/// read-only account and no data, and allows the user by setting its return
+/// data to `[1]`; anything else denies them.
+fn check_compliance<'a>(
+    config: &StakeConfig,
+    user: &AccountInfo<'a>,
+    accounts: &[AccountInfo<'a>],
+) -> ProgramResult {
+    if config.compliance_program == Pubkey::default() {
+        return Ok(());
+    }
+    let compliance_program = accounts
+        .iter()
+        .find(|account| *account.key == config.compliance_program)
+        .ok_or(ProgramError::NotEnoughAccountKeys)?;
+    if !compliance_program.executable {
+        return Err(ProgramError::IncorrectProgramId);
+    }
+    invoke(
+        &Instruction {
+            program_id: config.compliance_program,
+            accounts: vec![AccountMeta::new_readonly(*user.key, false)],
+            data: Vec::new(),
+        },
+        &[user.clone(), compliance_program.clone()],
+    )?;
+    match get_return_data() {
+        Some((program, data)) if program == config.compliance_program && data == [1] => Ok(()),
+        _ => {
+            msg!("Compliance check denied {}", user.key);
+            Err(StakeError::ComplianceCheckFailed.into())
+        }
+    }
+}
+
+/// Reads the config, treating fields appended after the account was allocated
+/// as zero.
+fn load_config(
+    program_id: &Pubkey,
+    stake_config: &AccountInfo,
+) -> Result<StakeConfig, ProgramError> {
+    if stake_config.owner != program_id {
+        return Err(ProgramError::IllegalOwner);
+    }
+    let (config_pda, _bump) = Pubkey::find_program_address(&[CONFIG_SEED], program_id);
+    if config_pda != *stake_config.key {
+        return Err(StakeError::InvalidPda.into());
+    }
+    let data = stake_config.data.borrow();
+    let config = if data.len() < StakeConfig::SIZE {
+        let mut padded = data.to_vec();
+        padded.resize(StakeConfig::SIZE, 0);
+        try_from_slice_unchecked::<StakeConfig>(&padded)?
+    } else {
+        try_from_slice_unchecked::<StakeConfig>(&data)?
+    };
+    if !config.is_initialized() {
+        return Err(StakeError::UninitializedAccount.into());
+    }
+    Ok(config)
+}
+
+struct RewardAccounts<'a, 'b> {
+    reward_mint: &'a AccountInfo<'b>,
+    user_reward_account: &'a AccountInfo<'b>,
+    protocol_fee_account: &'a AccountInfo<'b>,
+    mint_authority: &'a AccountInfo<'b>,
+    token_program: &'a AccountInfo<'b>,
+}
+
+impl<'a, 'b> RewardAccounts<'a, 'b> {
+    fn next<I: Iterator<Item = &'a AccountInfo<'b>>>(iter: &mut I) -> Result<Self, ProgramError> {
+        Ok(Self {
+            reward_mint: next_account_info(iter)?,
+            user_reward_account: next_account_info(iter)?,
+            protocol_fee_account: next_account_info(iter)?,
+            mint_authority: next_account_info(iter)?,
+            token_program: next_account_info(iter)?,
+        })
+    }
+}
+
+/// Checks the reward accounts against the config and returns the bump of the
+/// mint authority PDA. A rotated mint authority has to sign itself.
+///
+/// Under `AccrualMode::Sol` the SOL pool is passed in the reward mint's place
+/// and the user's wallet in the reward token account's; the mint authority
+/// and token program are not used.
+fn check_reward_accounts(
+    program_id: &Pubkey,
+    config: &StakeConfig,
+    reward_accounts: &RewardAccounts,
+) -> Result<u8, ProgramError> {
+    if *reward_accounts.protocol_fee_account.key != config.protocol_fee_account {
+        return Err(StakeError::InvalidProtocolFeeAccount.into());
+    }
+    if config.accrual_mode == AccrualMode::Sol {
+        let (pda, bump) = Pubkey::find_program_address(&[SOL_POOL_SEED], program_id);
+        if pda != *reward_accounts.reward_mint.key {
+            return Err(StakeError::InvalidPda.into());
+        }
+        if reward_accounts.reward_mint.owner != program_id {
+            return Err(StakeError::InsufficientPoolLamports.into());
+        }
+        return Ok(bump);
+    }
+    if *reward_accounts.reward_mint.key != config.reward_mint {
+        return Err(StakeError::InvalidRewardMint.into());
+    }
+    check_token_program(config, reward_accounts.token_program)?;
+    let (pda, bump) = Pubkey::find_program_address(&[MINT_AUTHORITY_SEED], program_id);
+    let mint_authority = current_mint_authority(config, pda);
+    if mint_authority != *reward_accounts.mint_authority.key {
+        return Err(StakeError::InvalidPda.into());
+    }
+    if mint_authority != pda && !reward_accounts.mint_authority.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    Ok(bump)
+}
+
+/// Checks `token_program` is the program the config pins for reward mint and
+/// token CPIs: its `token_program`, or SPL Token when none is pinned.
+fn check_token_program(config: &StakeConfig, token_program: &AccountInfo) -> ProgramResult {
+    let expected = pinned_token_program(config);
+    if *token_program.key != expected {
+        msg!("Expected token program {}", expected);
+        return Err(StakeError::WrongTokenProgram.into());
+    }
+    Ok(())
+}
+
+fn pinned_token_program(config: &StakeConfig) -> Pubkey {
+    if config.token_program == Pubkey::default() {
+        spl_token::id()
+    } else {
+        config.token_program
+    }
+}
+
+/// Whether `program_id` is SPL Token or Token-2022, the programs NFTs and
+/// reward tokens may live under.
+fn is_token_program(program_id: &Pubkey) -> bool {
+    *program_id == spl_token::id() || *program_id == TOKEN_2022_PROGRAM_ID
+}
+
+/// Retargets an SPL Token instruction at `token_program`. The `spl_token`
+/// builders only accept SPL Token's own id, while Token-2022 takes the same
+/// instructions for base token features.
+fn for_token_program(mut instruction: Instruction, token_program: &Pubkey) -> Instruction {
+    instruction.program_id = *token_program;
+    instruction
+}
+
+/// The base token account state of a token account of either token program,
+/// ignoring any Token-2022 extensions after it.
+fn unpack_token_account(account: &AccountInfo) -> Result<TokenAccount, ProgramError> {
+    let data = account.data.borrow();
+    let base = data
+        .get(..TokenAccount::LEN)
+        .ok_or(ProgramError::InvalidAccountData)?;
+    TokenAccount::unpack(base)
+}
+
+/// The base mint state of a mint of either token program, ignoring any
+/// Token-2022 extensions after it.
+fn unpack_mint(account: &AccountInfo) -> Result<Mint, ProgramError> {
+    let data = account.data.borrow();
+    let base = data
+        .get(..Mint::LEN)
+        .ok_or(ProgramError::InvalidAccountData)?;
+    Mint::unpack(base)
+}
+
+/// Protocol fee on a redeem of a stake held for `held_for` seconds:
+/// `protocol_fee_bps` less the `discount_bps` of the highest fee discount tier
+/// reached, floored at `min_protocol_fee_bps` (or the undiscounted fee, if
+/// lower).
+fn discounted_fee_bps(config: &StakeConfig, held_for: UnixTimestamp) -> u16 {
+    let discount_bps = config
+        .fee_discount_tiers
+        .iter()
+        .rev()
+        .find(|tier| held_for >= tier.min_duration)
+        .map_or(0, |tier| tier.discount_bps);
+    config
+        .protocol_fee_bps
+        .saturating_sub(discount_bps)
+        .max(config.min_protocol_fee_bps.min(config.protocol_fee_bps))
+}
+
+/// Pays a stake's accrued `reward` the same way on every path: adds the
+/// governance boost of the optional `governance_token_account`, then pays the
+/// boosted amount with the protocol share at the stake's `discounted_fee_bps`
+/// for how long it was held by `held_until`. Returns the boosted amount.
+#[allow(clippy::too_many_arguments)]
+fn pay_stake_reward(
+    config: &mut StakeConfig,
+    account_data: &mut UserStakeInfo,
+    reward_accounts: &RewardAccounts,
+    mint_authority_bump: u8,
+    reward: u64,
+    held_until: UnixTimestamp,
+    governance_token_account: Option<&AccountInfo>,
+) -> Result<u64, ProgramError> {
+    let boost_bps = governance_boost_bps(config, &account_data.user, governance_token_account)?;
+    let boost = (reward as u128 * boost_bps as u128 / MAX_BPS as u128) as u64;
+    msg!("Reward: {}, governance boost: {}", reward, boost);
+    let amount = reward.saturating_add(boost);
+    let fee_bps = discounted_fee_bps(
+        config,
+        held_until.saturating_sub(account_data.stake_start_time),
+    );
+    pay_reward(
+        config,
+        account_data,
+        reward_accounts,
+        mint_authority_bump,
+        amount,
+        fee_bps,
+    )?;
+    Ok(amount)
+}
+
+/// Pays a stake's `reward`: mints it with the protocol share taken at
+/// `fee_bps` or, under `AccrualMode::Points`, adds it in full to the stake's
+/// `points` and the config's `total_points` without any token CPI.
+fn pay_reward(
+    config: &mut StakeConfig,
+    account_data: &mut UserStakeInfo,
+    reward_accounts: &RewardAccounts,
+    mint_authority_bump: u8,
+    reward: u64,
+    fee_bps: u16,
+) -> ProgramResult {
+    if config.accrual_mode == AccrualMode::Points {
+        account_data.points = account_data
+            .points
+            .checked_add(reward)
+            .ok_or(StakeError::RewardOverflow)?;
+        return bump_counter(&mut config.total_points, reward);
+    }
+    mint_reward_with_fee(
+        config,
+        reward_accounts,
+        mint_authority_bump,
+        reward,
+        fee_bps,
+    )
+}
+
+/// Mints `reward` to the user and the protocol's `protocol_fee_bps` share of it
+/// to the protocol fee account. With `withhold_protocol_fee` the protocol share
+/// is taken out of the user's payout, otherwise it is minted on top of it.
+fn mint_reward(
+    config: &mut StakeConfig,
+    reward_accounts: &RewardAccounts,
+    mint_authority_bump: u8,
+    reward: u64,
+) -> ProgramResult {
+    mint_reward_with_fee(
+        config,
+        reward_accounts,
+        mint_authority_bump,
+        reward,
+        config.protocol_fee_bps,
+    )
+}
+
+/// `mint_reward` with the protocol share taken at `fee_bps`. Both shares are
+/// added to the config's `total_emitted`, which the caller persists. Under
+/// `AccrualMode::Sol` the shares are paid in lamports from the SOL pool
+/// instead.
+fn mint_reward_with_fee(
+    config: &mut StakeConfig,
+    reward_accounts: &RewardAccounts,
+    mint_authority_bump: u8,
+    reward: u64,
+    fee_bps: u16,
+) -> ProgramResult {
+    let protocol_share = (reward as u128 * fee_bps as u128 / MAX_BPS as u128) as u64;
+    let user_share = if config.withhold_protocol_fee {
+        reward.saturating_sub(protocol_share)
+    } else {
+        reward
+    };
+    msg!(
+        "User share: {}, protocol share: {}",
+        user_share,
+        protocol_share
+    );
+    bump_counter(&mut config.total_emitted, user_share)?;
+    bump_counter(&mut config.total_emitted, protocol_share)?;
+    if config.accrual_mode == AccrualMode::Sol {
+        return pay_pool_lamports(reward_accounts, user_share, protocol_share);
+    }
+    mint_reward_tokens(
+        reward_accounts,
+        reward_accounts.user_reward_account,
+        mint_authority_bump,
+        user_share,
+    )?;
+    mint_reward_tokens(
+        reward_accounts,
+        reward_accounts.protocol_fee_account,
+        mint_authority_bump,
+        protocol_share,
+    )
+}
+
+/// Moves the user and protocol shares out of the SOL pool, passed in the
+/// reward mint's place, keeping the pool itself rent exempt.
+fn pay_pool_lamports(
+    reward_accounts: &RewardAccounts,
+    user_share: u64,
+    protocol_share: u64,
+) -> ProgramResult {
+    let sol_pool = reward_accounts.reward_mint;
+    let available = sol_pool
+        .lamports()
+        .saturating_sub(Rent::get()?.minimum_balance(sol_pool.data_len()));
+    let needed = user_share.saturating_add(protocol_share);
+    if available < needed {
+        msg!("SOL pool holds {}, needs {}", available, needed);
+        return Err(StakeError::InsufficientPoolLamports.into());
+    }
+    **sol_pool.try_borrow_mut_lamports()? -= needed;
+    **reward_accounts
+        .user_reward_account
+        .try_borrow_mut_lamports()? += user_share;
+    **reward_accounts
+        .protocol_fee_account
+        .try_borrow_mut_lamports()? += protocol_share;
+    Ok(())
+}
+
+fn mint_reward_tokens<'b>(
+    reward_accounts: &RewardAccounts<'_, 'b>,
+    destination: &AccountInfo<'b>,
+    mint_authority_bump: u8,
+    amount: u64,
+) -> ProgramResult {
+    if amount == 0 {
+        return Ok(());
+    }
+    invoke_signed(
+        &for_token_program(
+            spl_token::instruction::mint_to(
+                &spl_token::id(),
+                reward_accounts.reward_mint.key,
+                destination.key,
+                reward_accounts.mint_authority.key,
+                &[],
+                amount,
+            )?,
+            reward_accounts.token_program.key,
+        ),
+        &[
+            reward_accounts.reward_mint.clone(),
+            destination.clone(),
+            reward_accounts.mint_authority.clone(),
+            reward_accounts.token_program.clone(),
+        ],
+        &[&[MINT_AUTHORITY_SEED, &[mint_authority_bump]]],
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{
+        DurationTier, EmissionSegment, FeeDiscountTier, GovernanceBoostTier, FLAG_INITIALIZED,
+        FLAG_STAKE_ACTIVE,
+    };
+    use crate::test_utils::*;
+    use solana_program::system_program;
+
+    /// A staked NFT's token account, stake account and `StakedMint` record,
+    /// in the order `UnstakeBatch` takes positions.
+    fn staked_position(user: Pubkey, staked_at: UnixTimestamp) -> Vec<TestAccount> {
+        let nft_mint = Pubkey::new_unique();
+        let nft_token_account = token_account(Pubkey::new_unique(), nft_mint, user, 1);
+        let account_data = active_stake_info(user, nft_token_account.key(), nft_mint, staked_at);
+        let stake_state = stake_account(&account_data);
+        let staked_mint = staked_mint_account(&nft_mint, stake_state.key());
+        vec![nft_token_account, stake_state, staked_mint]
+    }
+
+    /// `UnstakeBatch` accounts: the user, their `UserStakeAccount`, the config,
+    /// the reward accounts, then `positions`.
+    fn unstake_batch_accounts(
+        user: Pubkey,
+        config: &StakeConfig,
+        reward_accounts: Vec<TestAccount>,
+        active_stakes: u32,
+        positions: Vec<Vec<TestAccount>>,
+    ) -> Vec<TestAccount> {
+        let mut accounts = vec![
+            TestAccount::wallet(user, LAMPORTS_PER_SOL).signer(),
+            user_stake_account(user, active_stakes),
+            config_account(config),
+        ];
+        accounts.extend(reward_accounts);
+        accounts.extend(positions.into_iter().flatten());
+        accounts
+    }
+
+    /// `Redeem` accounts: the user, the NFT token account and stake account of
+    /// `position`, the config and the reward accounts.
+    fn redeem_accounts(
+        user: Pubkey,
+        config: &StakeConfig,
+        reward_accounts: Vec<TestAccount>,
+        position: Vec<TestAccount>,
+    ) -> Vec<TestAccount> {
+        let mut accounts = vec![TestAccount::wallet(user, LAMPORTS_PER_SOL).signer()];
+        accounts.extend(position.into_iter().take(2));
+        accounts.push(config_account(config));
+        accounts.extend(reward_accounts);
+        accounts
+    }
+
+    /// `Unstake` accounts: the user, `position`, their `UserStakeAccount`
+    /// with one active stake, the config and the reward accounts.
+    fn unstake_accounts(
+        user: Pubkey,
+        config: &StakeConfig,
+        reward_accounts: Vec<TestAccount>,
+        position: Vec<TestAccount>,
+    ) -> Vec<TestAccount> {
+        let mut accounts = vec![TestAccount::wallet(user, LAMPORTS_PER_SOL).signer()];
+        accounts.extend(position);
+        accounts.insert(4, user_stake_account(user, 1));
+        accounts.push(config_account(config));
+        accounts.extend(reward_accounts);
+        accounts
+    }
+
+    /// `Stake` accounts for a fresh NFT: the user, an NFT token account they
+    /// own, their initialized stake account for it, the not yet created
+    /// `StakedMint` and `UserStakeAccount`, the config and the system program.
+    fn stake_accounts(user: Pubkey, config: &StakeConfig) -> Vec<TestAccount> {
+        let nft_mint = Pubkey::new_unique();
+        let nft_token_account = token_account(Pubkey::new_unique(), nft_mint, user, 1);
+        let stake_state = stake_account(&new_stake_info(user, nft_token_account.key()));
+        vec![
+            TestAccount::wallet(user, LAMPORTS_PER_SOL).signer(),
+            nft_token_account,
+            stake_state,
+            empty_account(staked_mint_address(&nft_mint)),
+            empty_account(user_stake_address(&user)),
+            config_account(config),
+            TestAccount::program(system_program::id()),
+        ]
+    }
+
+    /// `Stake` accounts for `nft_mint` moved to a fresh token account of
+    /// `user`, with a new stake account for it and the mint's `staked_mint`
+    /// record as an earlier stake left it.
+    fn restake_accounts(
+        user: Pubkey,
+        config: &StakeConfig,
+        nft_mint: Pubkey,
+        staked_mint: TestAccount,
+    ) -> Vec<TestAccount> {
+        let mut accounts = stake_accounts(user, config);
+        accounts[1] = token_account(Pubkey::new_unique(), nft_mint, user, 1);
+        accounts[2] = stake_account(&new_stake_info(user, accounts[1].key()));
+        accounts[3] = staked_mint;
+        accounts
+    }
+
+    fn return_i64() -> i64 {
+        i64::from_le_bytes(return_data().unwrap().1.try_into().unwrap())
+    }
+
+    #[test]
+    fn unstake_batch_skips_positions_failing_validation() {
+        setup();
+        let user = Pubkey::new_unique();
+        let (config, reward_accounts) = reward_pool(user);
+        let mut not_staked = staked_position(user, START - 100);
+        let mut account_data = not_staked[1].stake_info();
+        account_data.set_stake_active(false);
+        not_staked[1].set_stake_info(&account_data);
+        let mut accounts = unstake_batch_accounts(
+            user,
+            &config,
+            reward_accounts,
+            2,
+            vec![
+                staked_position(user, START - 100),
+                not_staked,
+                staked_position(user, START - 50),
+            ],
+        );
+
+        process_unstake_batch(&PROGRAM_ID, &infos(&mut accounts)).unwrap();
+
+        assert_eq!(
+            return_data().unwrap(),
+            (PROGRAM_ID, 0b101u16.to_le_bytes().to_vec())
+        );
+        assert!(!accounts[9].stake_info().is_stake_active());
+        assert!(!accounts[15].stake_info().is_stake_active());
+        assert_eq!(accounts[10].staked_mint().stake_state, Pubkey::default());
+        assert_eq!(accounts[4].token_amount(), 150);
+        assert_eq!(accounts[1].user_stakes().active_stakes, 0);
+        assert_eq!(accounts[2].config().unstake_count, 2);
+    }
+
+    #[test]
+    fn unstake_batch_pays_nothing_for_a_stake_it_cannot_save() {
+        setup();
+        let user = Pubkey::new_unique();
+        let (config, reward_accounts) = reward_pool(user);
+        let mut unmigrated = staked_position(user, START - 100);
+        let data = unmigrated[1].data()[..UserStakeInfo::SIZE - 8].to_vec();
+        unmigrated[1].set_data(&data);
+        let mut accounts = unstake_batch_accounts(
+            user,
+            &config,
+            reward_accounts,
+            2,
+            vec![unmigrated, staked_position(user, START - 40)],
+        );
+
+        process_unstake_batch(&PROGRAM_ID, &infos(&mut accounts)).unwrap();
+
+        assert_eq!(
+            return_data().unwrap(),
+            (PROGRAM_ID, 0b10u16.to_le_bytes().to_vec())
+        );
+        assert!(accounts[9].stake_info().is_stake_active());
+        assert_eq!(accounts[10].staked_mint().stake_state, accounts[9].key());
+        assert!(!accounts[12].stake_info().is_stake_active());
+        assert_eq!(accounts[4].token_amount(), 40);
+        assert_eq!(accounts[3].mint_supply(), 40);
+        assert_eq!(accounts[1].user_stakes().active_stakes, 1);
+    }
+
+    #[test]
+    fn unstake_batch_rejects_a_partial_position() {
+        setup();
+        let user = Pubkey::new_unique();
+        let (config, reward_accounts) = reward_pool(user);
+        let mut position = staked_position(user, START - 100);
+        position.pop();
+        let mut accounts =
+            unstake_batch_accounts(user, &config, reward_accounts, 1, vec![position]);
+
+        assert_eq!(
+            process_unstake_batch(&PROGRAM_ID, &infos(&mut accounts)),
+            Err(StakeError::InvalidBatchSize.into())
+        );
+    }
+
+    #[test]
+    fn reconcile_stake_balances_after_a_redeem() {
+        setup();
+        let user = Pubkey::new_unique();
+        let (config, reward_accounts) = reward_pool(user);
+        let position = staked_position(user, START - 1_000);
+        let mut accounts = redeem_accounts(user, &config, reward_accounts, position);
+        set_time(START - 400);
+        process_redeem(&PROGRAM_ID, &infos(&mut accounts), None, None).unwrap();
+        assert_eq!(accounts[5].token_amount(), 600);
+
+        set_time(START);
+        let infos = infos(&mut accounts);
+        process_reconcile_stake(&PROGRAM_ID, &[infos[2].clone(), infos[3].clone()]).unwrap();
+        assert_eq!(return_i64(), 0);
+    }
+
+    #[test]
+    fn reconcile_stake_reports_a_corrupted_total_redeemed() {
+        setup();
+        let user = Pubkey::new_unique();
+        let (config, _reward_accounts) = reward_pool(user);
+        let mut position = staked_position(user, START - 1_000);
+        let mut account_data = position[1].stake_info();
+        account_data.total_redeemed = 7;
+        position[1].set_stake_info(&account_data);
+        let mut accounts = vec![position.swap_remove(1), config_account(&config)];
+
+        process_reconcile_stake(&PROGRAM_ID, &infos(&mut accounts)).unwrap();
+        assert_eq!(return_i64(), -7);
+    }
+
+    #[test]
+    fn reconcile_stake_replays_a_changed_rate_as_it_stands_now() {
+        setup();
+        let user = Pubkey::new_unique();
+        let (mut config, reward_accounts) = reward_pool(user);
+        let position = staked_position(user, START - 1_000);
+        let mut accounts = redeem_accounts(user, &config, reward_accounts, position);
+        set_time(START - 400);
+        process_redeem(&PROGRAM_ID, &infos(&mut accounts), None, None).unwrap();
+        assert_eq!(accounts[5].token_amount(), 600);
+
+        // The rate doubles, as an `UpdateConfig` would set it, after 600 was
+        // correctly paid at the old one.
+        config.emission_schedule = vec![EmissionSegment {
+            start_time: 0,
+            rate: 2,
+        }];
+        config.rate_revision = 1;
+        accounts[3] = config_account(&config);
+        set_time(START);
+        let infos = infos(&mut accounts);
+        process_reconcile_stake(&PROGRAM_ID, &[infos[2].clone(), infos[3].clone()]).unwrap();
+        // 1_000 seconds replayed at 2, against 600 paid and 800 pending.
+        assert_eq!(return_i64(), 600);
+    }
+
+    #[test]
+    fn stake_records_the_stake_and_its_mint() {
+        setup();
+        let user = Pubkey::new_unique();
+        let (config, _reward_accounts) = reward_pool(user);
+        let mut accounts = stake_accounts(user, &config);
+
+        process_stake(&PROGRAM_ID, &infos(&mut accounts)).unwrap();
+
+        let account_data = accounts[2].stake_info();
+        assert!(account_data.is_stake_active());
+        assert_eq!(account_data.stake_start_time, START);
+        assert_eq!(account_data.last_redeem_time, START);
+        assert_eq!(accounts[3].staked_mint().stake_state, accounts[2].key());
+        assert_eq!(accounts[4].user_stakes().active_stakes, 1);
+        assert_eq!(accounts[5].config().total_active_stakes, 1);
+    }
+
+    #[test]
+    fn stake_rejects_an_unset_clock() {
+        setup();
+        let user = Pubkey::new_unique();
+        let (config, _reward_accounts) = reward_pool(user);
+        for unix_timestamp in [0, -1] {
+            set_time(unix_timestamp);
+            let mut accounts = stake_accounts(user, &config);
+            assert_eq!(
+                process_stake(&PROGRAM_ID, &infos(&mut accounts)),
+                Err(StakeError::InvalidClock.into())
+            );
+            assert!(!accounts[2].stake_info().is_stake_active());
+        }
+    }
+
+    /// Redeems 1,000 seconds of accrual under a 10% protocol fee and returns
+    /// the user's and the protocol's balances.
+    fn redeem_with_protocol_fee(withhold_protocol_fee: bool) -> (u64, u64) {
+        setup();
+        let user = Pubkey::new_unique();
+        let (mut config, reward_accounts) = reward_pool(user);
+        config.protocol_fee_bps = 1_000;
+        config.withhold_protocol_fee = withhold_protocol_fee;
+        let position = staked_position(user, START - 1_000);
+        let mut accounts = redeem_accounts(user, &config, reward_accounts, position);
+
+        process_redeem(&PROGRAM_ID, &infos(&mut accounts), None, None).unwrap();
+
+        assert_eq!(
+            accounts[3].config().total_emitted,
+            accounts[4].mint_supply()
+        );
+        (accounts[5].token_amount(), accounts[6].token_amount())
+    }
+
+    #[test]
+    fn redeem_mints_the_protocol_fee_on_top() {
+        assert_eq!(redeem_with_protocol_fee(false), (1_000, 100));
+    }
+
+    #[test]
+    fn redeem_withholds_the_protocol_fee_from_the_user() {
+        assert_eq!(redeem_with_protocol_fee(true), (900, 100));
+    }
+
+    #[test]
+    fn migrate_stake_account_packs_legacy_bools_into_flags() {
+        setup();
+        let user = Pubkey::new_unique();
+        let nft_token_account = Pubkey::new_unique();
+        let legacy = LegacyUserStakeInfo {
+            is_initialized: true,
+            user,
+            token_account: nft_token_account,
+            stake_start_time: START - 500,
+            last_redeem_time: START - 200,
+            is_stake_active: true,
+            total_redeemed: 300,
+        };
+        let (stake_state, bump) =
+            Pubkey::find_program_address(&[user.as_ref(), nft_token_account.as_ref()], &PROGRAM_ID);
+        let mut accounts = vec![
+            TestAccount::wallet(user, LAMPORTS_PER_SOL).signer(),
+            TestAccount::with_data(stake_state, PROGRAM_ID, &legacy.try_to_vec().unwrap()),
+            TestAccount::program(system_program::id()),
+        ];
+
+        process_migrate_stake_account(&PROGRAM_ID, &infos(&mut accounts)).unwrap();
+
+        assert_eq!(accounts[1].data().len(), UserStakeInfo::SIZE);
+        assert_eq!(
+            accounts[1].lamports,
+            Rent::default().minimum_balance(UserStakeInfo::SIZE)
+        );
+        let account_data = accounts[1].stake_info();
+        assert_eq!(account_data.version, UserStakeInfo::VERSION);
+        assert_eq!(account_data.flags, FLAG_INITIALIZED | FLAG_STAKE_ACTIVE);
+        assert_eq!(account_data.user, user);
+        assert_eq!(account_data.token_account, nft_token_account);
+        assert_eq!(account_data.stake_start_time, START - 500);
+        assert_eq!(account_data.last_redeem_time, START - 200);
+        assert_eq!(account_data.total_redeemed, 300);
+        assert_eq!(account_data.bump, bump);
+    }
+
+    #[test]
+    fn migrate_stake_account_keeps_an_inactive_legacy_stake_inactive() {
+        setup();
+        let user = Pubkey::new_unique();
+        let nft_token_account = Pubkey::new_unique();
+        let legacy = LegacyUserStakeInfo {
+            is_initialized: true,
+            user,
+            token_account: nft_token_account,
+            stake_start_time: 0,
+            last_redeem_time: 0,
+            is_stake_active: false,
+            total_redeemed: 0,
+        };
+        let (stake_state, _bump) =
+            Pubkey::find_program_address(&[user.as_ref(), nft_token_account.as_ref()], &PROGRAM_ID);
+        let mut accounts = vec![
+            TestAccount::wallet(user, LAMPORTS_PER_SOL).signer(),
+            TestAccount::with_data(stake_state, PROGRAM_ID, &legacy.try_to_vec().unwrap()),
+            TestAccount::program(system_program::id()),
+        ];
+
+        process_migrate_stake_account(&PROGRAM_ID, &infos(&mut accounts)).unwrap();
+
+        assert_eq!(accounts[1].stake_info().flags, FLAG_INITIALIZED);
+    }
+
+    /// `TransferStakePosition` accounts handing `user`'s position, staked
+    /// since `staked_at`, to `new_owner`, with the NFT token account owned by
+    /// `nft_owner`.
+    fn transfer_accounts(
+        user: Pubkey,
+        new_owner: Pubkey,
+        nft_owner: Pubkey,
+        config: &StakeConfig,
+        reward_accounts: Vec<TestAccount>,
+        staked_at: UnixTimestamp,
+    ) -> Vec<TestAccount> {
+        let [nft_token_account, stake_state, staked_mint]: [TestAccount; 3] =
+            staked_position(user, staked_at).try_into().ok().unwrap();
+        let nft_mint = stake_state.stake_info().nft_mint;
+        let new_stake_state = stake_address(&new_owner, &nft_token_account.key()).0;
+        let mut accounts = vec![
+            TestAccount::wallet(user, LAMPORTS_PER_SOL).signer(),
+            token_account(nft_token_account.key(), nft_mint, nft_owner, 1),
+            stake_state,
+            empty_account(new_stake_state),
+            staked_mint,
+            user_stake_account(user, 1),
+            empty_account(user_stake_address(&new_owner)),
+            config_account(config),
+        ];
+        accounts.extend(reward_accounts);
+        accounts.push(TestAccount::program(system_program::id()));
+        accounts
+    }
+
+    #[test]
+    fn transferred_stake_pays_the_seller_then_redeems_for_the_buyer() {
+        setup();
+        let user = Pubkey::new_unique();
+        let new_owner = Pubkey::new_unique();
+        let (mut config, reward_accounts) = reward_pool(user);
+        config.proof_of_hold = true;
+        let mut accounts = transfer_accounts(
+            user,
+            new_owner,
+            new_owner,
+            &config,
+            reward_accounts,
+            START - 300,
+        );
+        let new_stake_state = accounts[3].key();
+
+        process_transfer_stake_position(&PROGRAM_ID, &infos(&mut accounts), new_owner).unwrap();
+
+        assert_eq!(accounts[9].token_amount(), 300);
+        assert_eq!(accounts[2].lamports, 0);
+        assert!(accounts[2].data().iter().all(|&byte| byte == 0));
+        let account_data = accounts[3].stake_info();
+        assert!(account_data.is_stake_active());
+        assert_eq!(account_data.user, new_owner);
+        assert_eq!(account_data.stake_start_time, START - 300);
+        assert_eq!(account_data.last_redeem_time, START);
+        assert_eq!(accounts[4].staked_mint().stake_state, new_stake_state);
+        assert_eq!(accounts[5].user_stakes().active_stakes, 0);
+        assert_eq!(accounts[6].user_stakes().active_stakes, 1);
+
+        set_time(START + 50);
+        let mut accounts = vec![
+            TestAccount::wallet(new_owner, LAMPORTS_PER_SOL).signer(),
+            accounts[1].clone(),
+            accounts[3].clone(),
+            accounts[7].clone(),
+            accounts[8].clone(),
+            token_account(Pubkey::new_unique(), config.reward_mint, new_owner, 0),
+            accounts[10].clone(),
+            accounts[11].clone(),
+            accounts[12].clone(),
+        ];
+        // Proof of hold passes: the buyer holds the NFT they accrue on.
+        process_redeem(&PROGRAM_ID, &infos(&mut accounts), None, None).unwrap();
+        assert_eq!(accounts[5].token_amount(), 50);
+        assert_eq!(accounts[2].stake_info().last_redeem_time, START + 50);
+
+        accounts[0] = TestAccount::wallet(user, LAMPORTS_PER_SOL).signer();
+        assert_eq!(
+            process_redeem(&PROGRAM_ID, &infos(&mut accounts), None, None),
+            Err(StakeError::InvalidPda.into())
+        );
+    }
+
+    #[test]
+    fn a_stake_cannot_be_transferred_while_the_seller_still_holds_the_nft() {
+        setup();
+        let user = Pubkey::new_unique();
+        let new_owner = Pubkey::new_unique();
+        let (config, reward_accounts) = reward_pool(user);
+        let mut accounts =
+            transfer_accounts(user, new_owner, user, &config, reward_accounts, START - 300);
+
+        assert_eq!(
+            process_transfer_stake_position(&PROGRAM_ID, &infos(&mut accounts), new_owner),
+            Err(StakeError::InvalidTokenAccount.into())
+        );
+        assert!(accounts[2].stake_info().is_stake_active());
+        assert_eq!(accounts[9].token_amount(), 0);
+    }
+
+    fn segment(start_time: UnixTimestamp, rate: u64) -> EmissionSegment {
+        EmissionSegment { start_time, rate }
+    }
+
+    #[test]
+    fn accrual_blends_the_rates_of_every_schedule_segment_it_spans() {
+        let mut config = new_config(
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+        );
+        config.emission_schedule = vec![segment(100, 10), segment(200, 4), segment(300, 1)];
+
+        assert_eq!(accrued_between(&config, 0, 100), 0);
+        assert_eq!(accrued_between(&config, 150, 180), 300);
+        assert_eq!(accrued_between(&config, 150, 250), 50 * 10 + 50 * 4);
+        assert_eq!(accrued_between(&config, 50, 1_000), 1_000 + 400 + 700);
+        assert_eq!(accrued_between(&config, 250, 250), 0);
+        config.emission_schedule.clear();
+        assert_eq!(accrued_between(&config, 0, 100), 100 * DEFAULT_REWARD_RATE);
+    }
+
+    #[test]
+    fn redeem_pays_the_scheduled_rates() {
+        setup();
+        let user = Pubkey::new_unique();
+        let (mut config, reward_accounts) = reward_pool(user);
+        config.emission_schedule = vec![segment(START - 1_000, 5), segment(START - 100, 2)];
+        let position = staked_position(user, START - 400);
+        let mut accounts = redeem_accounts(user, &config, reward_accounts, position);
+
+        process_redeem(&PROGRAM_ID, &infos(&mut accounts), None, None).unwrap();
+
+        assert_eq!(accounts[5].token_amount(), 300 * 5 + 100 * 2);
+    }
+
+    #[test]
+    fn stake_rejects_a_mint_already_staked_through_another_token_account() {
+        setup();
+        let user = Pubkey::new_unique();
+        let (config, _reward_accounts) = reward_pool(user);
+        let mut accounts = stake_accounts(user, &config);
+        process_stake(&PROGRAM_ID, &infos(&mut accounts)).unwrap();
+        let first_stake_state = accounts[2].key();
+
+        let nft_mint = accounts[2].stake_info().nft_mint;
+        let other_token_account = token_account(Pubkey::new_unique(), nft_mint, user, 1);
+        accounts[2] = stake_account(&new_stake_info(user, other_token_account.key()));
+        accounts[1] = other_token_account;
+        assert_eq!(
+            process_stake(&PROGRAM_ID, &infos(&mut accounts)),
+            Err(StakeError::NftAlreadyStaked.into())
+        );
+        assert_eq!(accounts[3].staked_mint().stake_state, first_stake_state);
+
+        accounts[3] = staked_mint_account(&nft_mint, Pubkey::default());
+        process_stake(&PROGRAM_ID, &infos(&mut accounts)).unwrap();
+        assert_eq!(accounts[3].staked_mint().stake_state, accounts[2].key());
+    }
+
+    #[test]
+    fn unstake_and_restake_in_one_transaction_neither_loses_nor_repeats_time() {
+        setup();
+        let user = Pubkey::new_unique();
+        let (config, reward_accounts) = reward_pool(user);
+        let mut accounts = vec![TestAccount::wallet(user, LAMPORTS_PER_SOL).signer()];
+        accounts.extend(staked_position(user, START - 700));
+        accounts.push(user_stake_account(user, 1));
+        accounts.push(config_account(&config));
+        accounts.extend(reward_accounts);
+        accounts.push(TestAccount::program(system_program::id()));
+        let infos = infos(&mut accounts);
+        let stake_infos = [&infos[..6], &infos[11..]].concat();
+
+        process_unstake(&PROGRAM_ID, &infos[..11], None).unwrap();
+        process_stake(&PROGRAM_ID, &stake_infos).unwrap();
+        let redeem_infos = [&infos[..3], &infos[5..11]].concat();
+        process_redeem(&PROGRAM_ID, &redeem_infos, None, None).unwrap();
+        drop(infos);
+
+        assert_eq!(accounts[7].token_amount(), 700);
+        let account_data = accounts[2].stake_info();
+        assert!(account_data.is_stake_active());
+        assert_eq!(account_data.stake_start_time, START);
+        assert_eq!(account_data.last_redeem_time, START);
+        assert_eq!(account_data.total_redeemed, 0);
+        assert_eq!(accounts[3].staked_mint().stake_state, accounts[2].key());
+        assert_eq!(accounts[4].user_stakes().active_stakes, 1);
+    }
+
+    /// Redeems 1,000 seconds of accrual holding `balance` governance tokens,
+    /// under tiers boosting 10% from 100 tokens and 50% from 1,000, and
+    /// returns the user's payout.
+    fn redeem_with_governance_balance(balance: u64) -> u64 {
+        setup();
+        let user = Pubkey::new_unique();
+        let (mut config, reward_accounts) = reward_pool(user);
+        config.governance_mint = Pubkey::new_unique();
+        config.governance_boost_tiers = vec![
+            GovernanceBoostTier {
+                min_balance: 100,
+                boost_bps: 1_000,
+            },
+            GovernanceBoostTier {
+                min_balance: 1_000,
+                boost_bps: 5_000,
+            },
+        ];
+        let position = staked_position(user, START - 1_000);
+        let mut accounts = redeem_accounts(user, &config, reward_accounts, position);
+        accounts.push(token_account(
+            Pubkey::new_unique(),
+            config.governance_mint,
+            user,
+            balance,
+        ));
+
+        process_redeem(&PROGRAM_ID, &infos(&mut accounts), None, None).unwrap();
+        accounts[5].token_amount()
+    }
+
+    #[test]
+    fn redeem_boosts_by_the_highest_governance_tier_reached() {
+        assert_eq!(redeem_with_governance_balance(0), 1_000);
+        assert_eq!(redeem_with_governance_balance(99), 1_000);
+        assert_eq!(redeem_with_governance_balance(100), 1_100);
+        assert_eq!(redeem_with_governance_balance(999), 1_100);
+        assert_eq!(redeem_with_governance_balance(5_000), 1_500);
+    }
+
+    #[test]
+    fn redeem_rejects_a_governance_account_of_another_mint() {
+        setup();
+        let user = Pubkey::new_unique();
+        let (mut config, reward_accounts) = reward_pool(user);
+        config.governance_mint = Pubkey::new_unique();
+        let position = staked_position(user, START - 1_000);
+        let mut accounts = redeem_accounts(user, &config, reward_accounts, position);
+        accounts.push(token_account(
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            user,
+            5_000,
+        ));
+
+        assert_eq!(
+            process_redeem(&PROGRAM_ID, &infos(&mut accounts), None, None),
+            Err(StakeError::InvalidGovernanceAccount.into())
+        );
+    }
+
+    #[test]
+    fn stakes_per_user_are_capped_until_an_unstake_frees_a_slot() {
+        setup();
+        let user = Pubkey::new_unique();
+        let (mut config, reward_accounts) = reward_pool(user);
+        config.max_stakes_per_user = 1;
+        let mut first = stake_accounts(user, &config);
+        process_stake(&PROGRAM_ID, &infos(&mut first)).unwrap();
+
+        let mut second = stake_accounts(user, &config);
+        second[4] = first[4].clone();
+        second[5] = first[5].clone();
+        assert_eq!(
+            process_stake(&PROGRAM_ID, &infos(&mut second)),
+            Err(StakeError::UserStakeLimitReached.into())
+        );
+
+        // A failed instruction's writes are rolled back, so carry on from the
+        // accounts as the first stake left them.
+        let mut unstake = first[..6].to_vec();
+        unstake.extend(reward_accounts);
+        process_unstake(&PROGRAM_ID, &infos(&mut unstake), None).unwrap();
+        assert_eq!(unstake[4].user_stakes().active_stakes, 0);
+
+        second[4] = unstake[4].clone();
+        second[5] = unstake[5].clone();
+        process_stake(&PROGRAM_ID, &infos(&mut second)).unwrap();
+        assert_eq!(second[4].user_stakes().active_stakes, 1);
+        assert_eq!(second[5].config().total_active_stakes, 1);
+    }
+
+    #[test]
+    fn stake_accounts_written_by_an_earlier_version_keep_accruing() {
+        setup();
+        let user = Pubkey::new_unique();
+        let nft_token_account = Pubkey::new_unique();
+        let nft_mint = Pubkey::new_unique();
+        // A version 2 account as first released: `version` through `nft_mint`,
+        // under the unversioned seeds and without a stored bump.
+        let mut bytes = vec![
+            UserStakeInfo::V2_VERSION,
+            FLAG_INITIALIZED | FLAG_STAKE_ACTIVE,
+        ];
+        bytes.extend_from_slice(user.as_ref());
+        bytes.extend_from_slice(nft_token_account.as_ref());
+        bytes.extend_from_slice(&(START - 1_000).to_le_bytes());
+        bytes.extend_from_slice(&(START - 400).to_le_bytes());
+        bytes.extend_from_slice(&600u64.to_le_bytes());
+        bytes.extend_from_slice(nft_mint.as_ref());
+        let (stake_state, _bump) =
+            Pubkey::find_program_address(&[user.as_ref(), nft_token_account.as_ref()], &PROGRAM_ID);
+        let config = new_config(
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+        );
+        let mut accounts = vec![
+            TestAccount::with_data(stake_state, PROGRAM_ID, &bytes),
+            config_account(&config),
+        ];
+        let infos = infos(&mut accounts);
+
+        let account_data = load_stake_info(&infos[0]).unwrap();
+        assert_eq!(account_data.user, user);
+        assert_eq!(account_data.nft_mint, nft_mint);
+        assert_eq!(account_data.total_redeemed, 600);
+        assert_eq!(account_data.seed_version, 0);
+        assert_eq!(account_data.rate_override(), None);
+        verify_stake_pda(
+            &PROGRAM_ID,
+            &user,
+            &nft_token_account,
+            &infos[0],
+            account_data.seed_version,
+            account_data.bump,
+        )
+        .unwrap();
+        assert_eq!(compute_reward(&account_data, &config, START), 400);
+
+        process_reconcile_stake(&PROGRAM_ID, &infos).unwrap();
+        assert_eq!(return_i64(), 0);
+    }
+
+    /// Account compression stand-in appending each leaf to the tree account's
+    /// data after a `u64` leaf count.
+    fn mock_append(instruction: &Instruction, accounts: &[AccountInfo]) -> ProgramResult {
+        assert_eq!(instruction.data[..8], APPEND_DISCRIMINATOR);
+        assert!(accounts[1].is_signer);
+        let mut tree = accounts[0].data.borrow_mut();
+        let count = u64::from_le_bytes(tree[..8].try_into().unwrap());
+        let offset = 8 + count as usize * 32;
+        tree[offset..offset + 32].copy_from_slice(&instruction.data[8..40]);
+        tree[..8].copy_from_slice(&(count + 1).to_le_bytes());
+        Ok(())
+    }
+
+    #[test]
+    fn redeem_compressed_appends_one_leaf_per_redeem() {
+        setup();
+        mock_program(ACCOUNT_COMPRESSION_PROGRAM_ID, mock_append);
+        let user = Pubkey::new_unique();
+        let (mut config, reward_accounts) = reward_pool(user);
+        config.audit_merkle_tree = Pubkey::new_unique();
+        let position = staked_position(user, START - 1_000);
+        let nft_mint = position[1].stake_info().nft_mint;
+        let tree_authority =
+            Pubkey::find_program_address(&[AUDIT_TREE_AUTHORITY_SEED], &PROGRAM_ID).0;
+        let mut accounts = vec![
+            TestAccount::with_data(
+                config.audit_merkle_tree,
+                ACCOUNT_COMPRESSION_PROGRAM_ID,
+                &[0; 8 + 2 * 32],
+            ),
+            TestAccount::wallet(tree_authority, 0),
+            TestAccount::program(ACCOUNT_COMPRESSION_PROGRAM_ID),
+            TestAccount::program(NOOP_PROGRAM_ID),
+        ];
+        accounts.extend(redeem_accounts(user, &config, reward_accounts, position));
+
+        process_redeem_compressed(&PROGRAM_ID, &infos(&mut accounts)).unwrap();
+        set_time(START + 10);
+        process_redeem_compressed(&PROGRAM_ID, &infos(&mut accounts)).unwrap();
+
+        let leaf = |amount: u64, time: UnixTimestamp| {
+            keccak::hashv(&[
+                user.as_ref(),
+                nft_mint.as_ref(),
+                &amount.to_le_bytes(),
+                &time.to_le_bytes(),
+            ])
+            .to_bytes()
+        };
+        let tree = accounts[0].data();
+        assert_eq!(tree[..8], 2u64.to_le_bytes());
+        assert_eq!(tree[8..40], leaf(1_000, START));
+        assert_eq!(tree[40..72], leaf(10, START + 10));
+        let appends = invoked()
+            .iter()
+            .filter(|ix| ix.program_id == ACCOUNT_COMPRESSION_PROGRAM_ID)
+            .count();
+        assert_eq!(appends, 2);
+    }
+
+    #[test]
+    fn redeem_compressed_rejects_a_tree_other_than_the_configured_one() {
+        setup();
+        mock_program(ACCOUNT_COMPRESSION_PROGRAM_ID, mock_append);
+        let user = Pubkey::new_unique();
+        let (mut config, reward_accounts) = reward_pool(user);
+        config.audit_merkle_tree = Pubkey::new_unique();
+        let tree_authority =
+            Pubkey::find_program_address(&[AUDIT_TREE_AUTHORITY_SEED], &PROGRAM_ID).0;
+        let mut accounts = vec![
+            TestAccount::with_data(
+                Pubkey::new_unique(),
+                ACCOUNT_COMPRESSION_PROGRAM_ID,
+                &[0; 8 + 32],
+            ),
+            TestAccount::wallet(tree_authority, 0),
+            TestAccount::program(ACCOUNT_COMPRESSION_PROGRAM_ID),
+            TestAccount::program(NOOP_PROGRAM_ID),
+        ];
+        let position = staked_position(user, START - 1_000);
+        accounts.extend(redeem_accounts(user, &config, reward_accounts, position));
+
+        assert_eq!(
+            process_redeem_compressed(&PROGRAM_ID, &infos(&mut accounts)),
+            Err(StakeError::InvalidAuditTree.into())
+        );
+    }
+
+    /// Swap stand-in crediting the output token account, its first account,
+    /// with the `u64` amount its data asks for.
+    fn mock_swap(instruction: &Instruction, accounts: &[AccountInfo]) -> ProgramResult {
+        let amount_out = u64::from_le_bytes(instruction.data[..8].try_into().unwrap());
+        let mut output = TokenAccount::unpack(&accounts[0].data.borrow())?;
+        output.amount += amount_out;
+        TokenAccount::pack(output, &mut accounts[0].data.borrow_mut())
+    }
+
+    /// Redeems and swaps through `mock_swap` yielding `amount_out`, requiring
+    /// `min_amount_out`.
+    fn redeem_and_swap(amount_out: u64, min_amount_out: u64) -> Result<u64, ProgramError> {
+        setup();
+        let swap_program = Pubkey::new_unique();
+        mock_program(swap_program, mock_swap);
+        let user = Pubkey::new_unique();
+        let (mut config, reward_accounts) = reward_pool(user);
+        config.swap_program = swap_program;
+        config.swap_target_mint = Pubkey::new_unique();
+        let output = token_account(Pubkey::new_unique(), config.swap_target_mint, user, 5);
+        let mut accounts = vec![TestAccount::program(swap_program), output.clone(), output];
+        let position = staked_position(user, START - 1_000);
+        accounts.extend(redeem_accounts(user, &config, reward_accounts, position));
+        // The output account is also the swap's only account, and like any
+        // account passed twice it shares its data.
+        let mut infos = infos(&mut accounts);
+        infos[2] = infos[1].clone();
+
+        process_redeem_and_swap(
+            &PROGRAM_ID,
+            &infos,
+            min_amount_out,
+            1,
+            amount_out.to_le_bytes().to_vec(),
+        )?;
+        drop(infos);
+        Ok(accounts[1].token_amount())
+    }
+
+    #[test]
+    fn redeem_and_swap_accepts_the_minimum_out() {
+        assert_eq!(redeem_and_swap(100, 100), Ok(105));
+        assert_eq!(redeem_and_swap(150, 100), Ok(155));
+    }
+
+    #[test]
+    fn redeem_and_swap_fails_below_the_minimum_out() {
+        assert_eq!(
+            redeem_and_swap(99, 100),
+            Err(StakeError::SlippageExceeded.into())
+        );
+    }
+
+    #[test]
+    fn redeem_and_swap_rejects_an_unconfigured_swap_program() {
+        setup();
+        let user = Pubkey::new_unique();
+        let (mut config, reward_accounts) = reward_pool(user);
+        config.swap_program = Pubkey::new_unique();
+        config.swap_target_mint = Pubkey::new_unique();
+        let mut accounts = vec![
+            TestAccount::program(Pubkey::new_unique()),
+            token_account(Pubkey::new_unique(), config.swap_target_mint, user, 0),
+        ];
+        let position = staked_position(user, START - 1_000);
+        accounts.extend(redeem_accounts(user, &config, reward_accounts, position));
+
+        assert_eq!(
+            process_redeem_and_swap(&PROGRAM_ID, &infos(&mut accounts), 0, 0, Vec::new()),
+            Err(StakeError::InvalidSwapRoute.into())
+        );
+    }
+
+    #[test]
+    fn future_last_redeem_time_pays_nothing_and_stays_put() {
+        setup();
+        let user = Pubkey::new_unique();
+        let (config, reward_accounts) = reward_pool(user);
+        let mut position = staked_position(user, START - 1_000);
+        let mut account_data = position[1].stake_info();
+        account_data.last_redeem_time = START + 3_600;
+        position[1].set_stake_info(&account_data);
+        let mut accounts = redeem_accounts(user, &config, reward_accounts, position);
+
+        assert!(reward_breakdown(&account_data, &config, START).clock_behind);
+        assert_eq!(compute_reward(&account_data, &config, START), 0);
+        process_redeem(&PROGRAM_ID, &infos(&mut accounts), None, None).unwrap();
+
+        assert_eq!(accounts[5].token_amount(), 0);
+        let account_data = accounts[2].stake_info();
+        assert_eq!(account_data.last_redeem_time, START + 3_600);
+        assert_eq!(account_data.total_redeemed, 0);
+    }
+
+    #[test]
+    fn reset_last_redeem_time_pulls_a_future_time_back_to_now() {
+        setup();
+        let user = Pubkey::new_unique();
+        let (config, _reward_accounts) = reward_pool(user);
+        let mut position = staked_position(user, START - 1_000);
+        let mut account_data = position[1].stake_info();
+        account_data.last_redeem_time = START + 3_600;
+        position[1].set_stake_info(&account_data);
+        let mut accounts = vec![
+            TestAccount::wallet(config.admin, LAMPORTS_PER_SOL).signer(),
+            config_account(&config),
+            position.swap_remove(1),
+        ];
+
+        process_reset_last_redeem_time(&PROGRAM_ID, &infos(&mut accounts)).unwrap();
+        let account_data = accounts[2].stake_info();
+        assert_eq!(account_data.last_redeem_time, START);
+        assert_eq!(compute_reward(&account_data, &config, START + 25), 25);
+
+        assert_eq!(
+            process_reset_last_redeem_time(&PROGRAM_ID, &infos(&mut accounts)),
+            Err(ProgramError::InvalidArgument)
+        );
+        accounts[0] = TestAccount::wallet(user, LAMPORTS_PER_SOL).signer();
+        assert_eq!(
+            process_reset_last_redeem_time(&PROGRAM_ID, &infos(&mut accounts)),
+            Err(StakeError::Unauthorized.into())
+        );
+    }
+
+    #[test]
+    fn stake_enforces_the_minimum_stake_amount() {
+        setup();
+        let user = Pubkey::new_unique();
+        let (mut config, _reward_accounts) = reward_pool(user);
+        // As a config written before the minimum was bounded may hold it.
+        config.min_stake_amount = 2;
+        let mut accounts = stake_accounts(user, &config);
+        assert_eq!(
+            process_stake(&PROGRAM_ID, &infos(&mut accounts)),
+            Err(StakeError::StakeBelowMinimum.into())
+        );
+
+        config.min_stake_amount = 1;
+        let mut accounts = stake_accounts(user, &config);
+        process_stake(&PROGRAM_ID, &infos(&mut accounts)).unwrap();
+        assert!(accounts[2].stake_info().is_stake_active());
+    }
+
+    #[test]
+    fn a_minimum_stake_amount_above_one_nft_is_rejected() {
+        setup();
+        let config = new_config(
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+        );
+        let mut accounts = vec![
+            TestAccount::wallet(config.admin, LAMPORTS_PER_SOL).signer(),
+            config_account(&config),
+        ];
+        assert_eq!(
+            process_update_config(
+                &PROGRAM_ID,
+                &infos(&mut accounts),
+                ConfigField::MinStakeAmount(2)
+            ),
+            Err(ProgramError::InvalidArgument)
+        );
+        assert_eq!(accounts[1].config().min_stake_amount, 0);
+
+        process_update_config(
+            &PROGRAM_ID,
+            &infos(&mut accounts),
+            ConfigField::MinStakeAmount(1),
+        )
+        .unwrap();
+        assert_eq!(accounts[1].config().min_stake_amount, 1);
+    }
+
+    /// The `GetEffectiveRate` of an active stake, adjusted by `edit`, under
+    /// `config`.
+    fn effective_rate(config: &StakeConfig, edit: fn(&mut UserStakeInfo)) -> u64 {
+        setup();
+        let user = Pubkey::new_unique();
+        let mut position = staked_position(user, START - 1_000);
+        let mut account_data = position[1].stake_info();
+        edit(&mut account_data);
+        position[1].set_stake_info(&account_data);
+        let mut accounts = vec![position.swap_remove(1), config_account(config)];
+        process_get_effective_rate(&PROGRAM_ID, &infos(&mut accounts)).unwrap();
+        u64::from_le_bytes(return_data().unwrap().1.try_into().unwrap())
+    }
+
+    #[test]
+    fn effective_rate_reflects_each_active_modifier() {
+        let mut config = new_config(
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+        );
+        assert_eq!(
+            effective_rate(&config, |_| {}),
+            DEFAULT_REWARD_RATE * REWARD_PRECISION
+        );
+
+        config.emission_schedule = vec![segment(0, 100)];
+        assert_eq!(effective_rate(&config, |_| {}), 100 * REWARD_PRECISION);
+        assert_eq!(
+            effective_rate(&config, |stake| stake.set_rate_override(Some(40))),
+            40 * REWARD_PRECISION
+        );
+        assert_eq!(
+            effective_rate(&config, |stake| stake.edition_boost_bps = 5_000),
+            150 * REWARD_PRECISION
+        );
+        assert_eq!(
+            effective_rate(&config, |stake| stake.stake_weight = LAMPORTS_PER_SOL / 4),
+            25 * REWARD_PRECISION
+        );
+
+        config.duration_tiers = vec![DurationTier {
+            min_duration: 500,
+            multiplier_bps: 20_000,
+        }];
+        assert_eq!(effective_rate(&config, |_| {}), 200 * REWARD_PRECISION);
+
+        config.protocol_fee_bps = 1_000;
+        config.withhold_protocol_fee = true;
+        assert_eq!(effective_rate(&config, |_| {}), 180 * REWARD_PRECISION);
+        config.fee_discount_tiers = vec![FeeDiscountTier {
+            min_duration: 500,
+            discount_bps: 500,
+        }];
+        assert_eq!(effective_rate(&config, |_| {}), 190 * REWARD_PRECISION);
+
+        config.paused_since = START - 10;
+        assert_eq!(effective_rate(&config, |_| {}), 0);
+    }
+
+    #[test]
+    fn effective_rate_keeps_rates_below_one_unit_a_second() {
+        let mut config = new_config(
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+        );
+        assert_eq!(
+            effective_rate(&config, |stake| stake.stake_weight = LAMPORTS_PER_SOL / 2),
+            REWARD_PRECISION / 2
+        );
+
+        config.stake_dilution_divisor = 1;
+        config.total_active_stakes = 4;
+        assert_eq!(effective_rate(&config, |_| {}), REWARD_PRECISION / 4);
+    }
+
+    #[test]
+    fn staker_cap_turns_away_new_stakers_only() {
+        setup();
+        let (mut config, _reward_accounts) = reward_pool(Pubkey::new_unique());
+        config.max_stakers = 2;
+        let mut first = stake_accounts(Pubkey::new_unique(), &config);
+        process_stake(&PROGRAM_ID, &infos(&mut first)).unwrap();
+        let config = first[5].config();
+        assert_eq!(config.total_unique_stakers, 1);
+
+        let mut second = stake_accounts(Pubkey::new_unique(), &config);
+        process_stake(&PROGRAM_ID, &infos(&mut second)).unwrap();
+        let config = second[5].config();
+        assert_eq!(config.total_unique_stakers, 2);
+
+        let mut third = stake_accounts(Pubkey::new_unique(), &config);
+        assert_eq!(
+            process_stake(&PROGRAM_ID, &infos(&mut third)),
+            Err(StakeError::StakerLimitReached.into())
+        );
+
+        let user = first[0].key();
+        let mut another_nft = stake_accounts(user, &config);
+        another_nft[4] = first[4].clone();
+        process_stake(&PROGRAM_ID, &infos(&mut another_nft)).unwrap();
+        assert_eq!(another_nft[4].user_stakes().active_stakes, 2);
+        assert_eq!(another_nft[5].config().total_unique_stakers, 2);
+    }
+
+    /// Redeems twice at the same time, the second time with nothing accrued.
+    fn redeem_twice(reject_zero_reward_redeem: bool) -> ProgramResult {
+        setup();
+        let user = Pubkey::new_unique();
+        let (mut config, reward_accounts) = reward_pool(user);
+        config.reject_zero_reward_redeem = reject_zero_reward_redeem;
+        let position = staked_position(user, START - 100);
+        let mut accounts = redeem_accounts(user, &config, reward_accounts, position);
+        process_redeem(&PROGRAM_ID, &infos(&mut accounts), None, None).unwrap();
+        assert_eq!(accounts[5].token_amount(), 100);
+        process_redeem(&PROGRAM_ID, &infos(&mut accounts), None, None)?;
+        assert_eq!(accounts[5].token_amount(), 100);
+        assert_eq!(accounts[2].stake_info().last_redeem_time, START);
+        Ok(())
+    }
+
+    #[test]
+    fn zero_reward_redeem_succeeds_by_default() {
+        assert_eq!(redeem_twice(false), Ok(()));
+    }
+
+    #[test]
+    fn zero_reward_redeem_fails_when_configured() {
+        assert_eq!(redeem_twice(true), Err(StakeError::NoRewardAccrued.into()));
+    }
+
+    fn floor_price_feed(key: Pubkey, price: u64, updated_at: UnixTimestamp) -> TestAccount {
+        let feed = FloorPriceFeed { price, updated_at };
+        TestAccount::with_data(key, Pubkey::new_unique(), &feed.try_to_vec().unwrap())
+    }
+
+    #[test]
+    fn stake_snapshots_the_floor_price_and_weights_rewards_by_it() {
+        setup();
+        let user = Pubkey::new_unique();
+        let (mut config, _reward_accounts) = reward_pool(user);
+        config.floor_price_feed = Pubkey::new_unique();
+        config.max_floor_price_age = 60;
+        let mut accounts = stake_accounts(user, &config);
+        accounts.push(floor_price_feed(
+            config.floor_price_feed,
+            2 * LAMPORTS_PER_SOL,
+            START - 60,
+        ));
+
+        process_stake(&PROGRAM_ID, &infos(&mut accounts)).unwrap();
+
+        let account_data = accounts[2].stake_info();
+        assert_eq!(account_data.stake_weight, 2 * LAMPORTS_PER_SOL);
+        assert_eq!(compute_reward(&account_data, &config, START + 100), 200);
+        let mut account_data = account_data;
+        account_data.stake_weight = LAMPORTS_PER_SOL / 2;
+        assert_eq!(compute_reward(&account_data, &config, START + 100), 50);
+    }
+
+    #[test]
+    fn stake_rejects_a_stale_or_unexpected_floor_price_feed() {
+        setup();
+        let user = Pubkey::new_unique();
+        let (mut config, _reward_accounts) = reward_pool(user);
+        config.floor_price_feed = Pubkey::new_unique();
+        config.max_floor_price_age = 60;
+        for feed in [
+            floor_price_feed(config.floor_price_feed, LAMPORTS_PER_SOL, START - 61),
+            floor_price_feed(config.floor_price_feed, 0, START),
+            floor_price_feed(Pubkey::new_unique(), LAMPORTS_PER_SOL, START),
+        ] {
+            let mut accounts = stake_accounts(user, &config);
+            accounts.push(feed);
+            assert_eq!(
+                process_stake(&PROGRAM_ID, &infos(&mut accounts)),
+                Err(StakeError::StaleOracle.into())
+            );
+        }
+    }
+
+    #[test]
+    fn unstake_on_a_clock_just_behind_last_redeem_pays_nothing() {
+        setup();
+        let user = Pubkey::new_unique();
+        let (config, reward_accounts) = reward_pool(user);
+        let mut position = staked_position(user, START - 1_000);
+        let mut account_data = position[1].stake_info();
+        account_data.last_redeem_time = START + 1;
+        position[1].set_stake_info(&account_data);
+        let mut accounts = unstake_accounts(user, &config, reward_accounts, position);
+
+        assert_eq!(compute_reward(&account_data, &config, START), 0);
+        process_unstake(&PROGRAM_ID, &infos(&mut accounts), None).unwrap();
+
+        assert_eq!(accounts[7].token_amount(), 0);
+        let account_data = accounts[2].stake_info();
+        assert!(!account_data.is_stake_active());
+        assert_eq!(account_data.last_redeem_time, START + 1);
+        assert_eq!(account_data.total_redeemed, 0);
+    }
+
+    /// Unstakes a stake held for 1,000 seconds under a 100 second
+    /// `final_reward_grace_period`, then claims its final reward `claim_after`
+    /// seconds later, returning what the user was paid.
+    fn claim_final_reward_after(claim_after: i64) -> Result<u64, ProgramError> {
+        setup();
+        let user = Pubkey::new_unique();
+        let (mut config, reward_accounts) = reward_pool(user);
+        config.final_reward_grace_period = 100;
+        let position = staked_position(user, START - 1_000);
+        let mut accounts = unstake_accounts(user, &config, reward_accounts, position);
+        process_unstake(&PROGRAM_ID, &infos(&mut accounts), None).unwrap();
+        assert_eq!(accounts[7].token_amount(), 0);
+        let account_data = accounts[2].stake_info();
+        assert_eq!(account_data.unclaimed_final_reward, 1_000);
+        assert_eq!(account_data.final_reward_expires_at, START + 100);
+
+        set_time(START + claim_after);
+        let mut claim_accounts = accounts.drain(..6).collect::<Vec<_>>();
+        claim_accounts.drain(3..5);
+        claim_accounts.extend(accounts);
+        process_claim_final_reward(&PROGRAM_ID, &infos(&mut claim_accounts))?;
+        assert_eq!(claim_accounts[2].stake_info().unclaimed_final_reward, 0);
+        Ok(claim_accounts[5].token_amount())
+    }
+
+    #[test]
+    fn final_reward_is_claimable_within_the_grace_window() {
+        assert_eq!(claim_final_reward_after(100), Ok(1_000));
+    }
+
+    #[test]
+    fn final_reward_expires_after_the_grace_window() {
+        assert_eq!(
+            claim_final_reward_after(101),
+            Err(StakeError::FinalRewardExpired.into())
+        );
+    }
+
+    #[test]
+    fn rate_cache_is_reused_until_a_config_update_invalidates_it() {
+        setup();
+        let user = Pubkey::new_unique();
+        let (mut config, _reward_accounts) = reward_pool(user);
+        config.emission_schedule = vec![segment(START - 10, 5), segment(START + 100, 9)];
+        let mut accounts = stake_accounts(user, &config);
+        process_stake(&PROGRAM_ID, &infos(&mut accounts)).unwrap();
+        let account_data = accounts[2].stake_info();
+        assert_eq!(account_data.cached_rate, 5);
+        assert_eq!(account_data.cached_rate_valid_until, START + 100);
+        assert_eq!(compute_reward(&account_data, &config, START + 10), 50);
+
+        // Without a revision bump the cached rate is used as is.
+        let schedule = vec![segment(START - 10, 50), segment(START + 100, 9)];
+        let mut edited = accounts[5].config();
+        edited.emission_schedule = schedule.clone();
+        assert_eq!(compute_reward(&account_data, &edited, START + 10), 50);
+        // Past its validity window the schedule is walked again.
+        assert_eq!(
+            compute_reward(&account_data, &config, START + 110),
+            500 + 90
+        );
+
+        let mut admin_accounts = vec![
+            TestAccount::wallet(config.admin, LAMPORTS_PER_SOL).signer(),
+            accounts.swap_remove(5),
+        ];
+        process_update_config(
+            &PROGRAM_ID,
+            &infos(&mut admin_accounts),
+            ConfigField::EmissionSchedule(schedule),
+        )
+        .unwrap();
+        let updated = admin_accounts[1].config();
+        assert_eq!(updated.rate_revision, config.rate_revision.wrapping_add(1));
+        assert_eq!(compute_reward(&account_data, &updated, START + 10), 500);
+    }
+
+    /// `Stake` accounts whose NFT token account belongs to a multisig that
+    /// approved `delegate` for its whole balance.
+    fn delegated_stake_accounts(delegate: Pubkey, config: &StakeConfig) -> Vec<TestAccount> {
+        let mut accounts = stake_accounts(delegate, config);
+        let mut token_account = TokenAccount::unpack(accounts[1].data()).unwrap();
+        token_account.owner = Pubkey::new_unique();
+        token_account.delegate = COption::Some(delegate);
+        token_account.delegated_amount = 1;
+        let mut data = vec![0; TokenAccount::LEN];
+        TokenAccount::pack(token_account, &mut data).unwrap();
+        accounts[1].set_data(&data);
+        accounts
+    }
+
+    #[test]
+    fn a_listed_delegate_stakes_a_multisig_owned_nft() {
+        setup();
+        let delegate = Pubkey::new_unique();
+        let (mut config, _reward_accounts) = reward_pool(delegate);
+        config.delegate_stake_authorities = vec![delegate];
+        let mut accounts = delegated_stake_accounts(delegate, &config);
+
+        process_stake(&PROGRAM_ID, &infos(&mut accounts)).unwrap();
+
+        let account_data = accounts[2].stake_info();
+        assert!(account_data.is_stake_active());
+        assert_eq!(account_data.user, delegate);
+    }
+
+    #[test]
+    fn an_unlisted_delegate_cannot_stake_a_multisig_owned_nft() {
+        setup();
+        let delegate = Pubkey::new_unique();
+        let (config, _reward_accounts) = reward_pool(delegate);
+        let mut accounts = delegated_stake_accounts(delegate, &config);
+
+        assert_eq!(
+            process_stake(&PROGRAM_ID, &infos(&mut accounts)),
+            Err(StakeError::InvalidStakeAuthority.into())
+        );
+    }
+
+    #[test]
+    fn verify_stake_pda_accepts_only_the_stake_address() {
+        let user = Pubkey::new_unique();
+        let nft_token_account = Pubkey::new_unique();
+        let (_, bump) = stake_address(&user, &nft_token_account);
+        let mut stake_state = stake_account(&new_stake_info(user, nft_token_account));
+        let stake_state = stake_state.info();
+        let verify = |nft_token_account: &Pubkey, seed_version, stored_bump| {
+            verify_stake_pda(
+                &PROGRAM_ID,
+                &user,
+                nft_token_account,
+                &stake_state,
+                seed_version,
+                stored_bump,
+            )
+        };
+
+        assert_eq!(verify(&nft_token_account, STAKE_SEED_VERSION, bump), Ok(()));
+        assert_eq!(verify(&nft_token_account, STAKE_SEED_VERSION, 0), Ok(()));
+        let invalid_pda = Err(StakeError::InvalidPda.into());
+        assert_eq!(
+            verify(&Pubkey::new_unique(), STAKE_SEED_VERSION, 0),
+            invalid_pda
+        );
+        assert_eq!(
+            verify(&nft_token_account, STAKE_SEED_VERSION, bump.wrapping_sub(1)),
+            invalid_pda
+        );
+        assert_eq!(
+            verify(&nft_token_account, STAKE_SEED_VERSION + 1, bump),
+            invalid_pda
+        );
+    }
+
+    fn duration_tier(min_duration: UnixTimestamp, multiplier_bps: u16) -> DurationTier {
+        DurationTier {
+            min_duration,
+            multiplier_bps,
+        }
+    }
+
+    #[test]
+    fn the_longest_duration_tier_reached_applies() {
+        let user = Pubkey::new_unique();
+        let (mut config, _reward_accounts) = reward_pool(user);
+        config.duration_tiers = vec![
+            duration_tier(100, 11_000),
+            duration_tier(200, 12_000),
+            duration_tier(300, 13_000),
+        ];
+        let account_data = active_stake_info(user, Pubkey::new_unique(), Pubkey::new_unique(), 0);
+
+        assert_eq!(duration_tier_bps(&config, &account_data, 99), None);
+        assert_eq!(duration_tier_bps(&config, &account_data, 100), Some(11_000));
+        assert_eq!(duration_tier_bps(&config, &account_data, 299), Some(12_000));
+        assert_eq!(
+            duration_tier_bps(&config, &account_data, 1_000),
+            Some(13_000)
+        );
+    }
+
+    #[test]
+    fn unsorted_duration_tiers_are_rejected() {
+        setup();
+        let (config, _reward_accounts) = reward_pool(Pubkey::new_unique());
+        let mut accounts = vec![
+            TestAccount::wallet(config.admin, LAMPORTS_PER_SOL).signer(),
+            config_account(&config),
+        ];
+        for tiers in [
+            vec![duration_tier(200, 12_000), duration_tier(100, 11_000)],
+            vec![duration_tier(100, 11_000), duration_tier(100, 12_000)],
+        ] {
+            assert_eq!(
+                process_update_config(
+                    &PROGRAM_ID,
+                    &infos(&mut accounts),
+                    ConfigField::DurationTiers(tiers),
+                ),
+                Err(StakeError::TiersNotSorted.into())
+            );
+        }
+        assert!(accounts[1].config().duration_tiers.is_empty());
+    }
+
+    /// `BurnAndReward` accounts for a stake held for 1,000 seconds under a
+    /// `burn_reward` of 5,000: the `Unstake` accounts with the NFT mint
+    /// inserted before the `UserStakeAccount`.
+    fn burn_and_reward_accounts(allow_burn: bool) -> Vec<TestAccount> {
+        let user = Pubkey::new_unique();
+        let (mut config, reward_accounts) = reward_pool(user);
+        config.allow_burn = allow_burn;
+        config.burn_reward = 5_000;
+        let position = staked_position(user, START - 1_000);
+        let nft_mint = position[1].stake_info().nft_mint;
+        let mut accounts = unstake_accounts(user, &config, reward_accounts, position);
+        accounts.insert(4, mint_account(nft_mint, None, 1));
+        accounts
+    }
+
+    #[test]
+    fn burn_and_reward_burns_the_nft_and_pays_the_burn_reward() {
+        setup();
+        let mut accounts = burn_and_reward_accounts(true);
+
+        process_burn_and_reward(&PROGRAM_ID, &infos(&mut accounts), true).unwrap();
+
+        assert_eq!(accounts[1].token_amount(), 0);
+        assert_eq!(accounts[4].mint_supply(), 0);
+        assert_eq!(accounts[8].token_amount(), 1_000 + 5_000);
+        assert_eq!(accounts[2].lamports, 0);
+        assert!(accounts[2].data().iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn burn_and_reward_forfeits_an_expired_final_reward() {
+        setup();
+        for (expires_at, paid) in [(START - 1, 0), (START, 700)] {
+            let mut accounts = burn_and_reward_accounts(true);
+            let mut account_data = accounts[2].stake_info();
+            account_data.unclaimed_final_reward = 700;
+            account_data.final_reward_expires_at = expires_at;
+            accounts[2].set_stake_info(&account_data);
+
+            process_burn_and_reward(&PROGRAM_ID, &infos(&mut accounts), true).unwrap();
+
+            assert_eq!(accounts[8].token_amount(), 1_000 + 5_000 + paid);
+        }
+    }
+
+    #[test]
+    fn burn_and_reward_needs_the_config_flag_and_confirmation() {
+        setup();
+        for (allow_burn, confirm_burn) in [(false, true), (true, false)] {
+            let mut accounts = burn_and_reward_accounts(allow_burn);
+            assert_eq!(
+                process_burn_and_reward(&PROGRAM_ID, &infos(&mut accounts), confirm_burn),
+                Err(StakeError::BurnDisabled.into())
+            );
+            assert_eq!(accounts[4].mint_supply(), 1);
+        }
+    }
+
+    #[test]
+    fn snapshot_stakers_records_only_qualifying_stakes() {
+        setup();
+        let (config, _reward_accounts) = reward_pool(Pubkey::new_unique());
+        let snapshot_key = Pubkey::find_program_address(
+            &[AIRDROP_SNAPSHOT_SEED, &7u64.to_le_bytes()],
+            &PROGRAM_ID,
+        )
+        .0;
+        let qualifying = staked_position(Pubkey::new_unique(), START - 100).swap_remove(1);
+        let qualifying_key = qualifying.key();
+        let mut accounts = vec![
+            TestAccount::wallet(config.admin, LAMPORTS_PER_SOL).signer(),
+            config_account(&config),
+            empty_account(snapshot_key),
+            TestAccount::program(system_program::id()),
+            qualifying,
+            staked_position(Pubkey::new_unique(), START - 99).swap_remove(1),
+            stake_account(&new_stake_info(Pubkey::new_unique(), Pubkey::new_unique())),
+        ];
+
+        process_snapshot_stakers(&PROGRAM_ID, &infos(&mut accounts), 7, 100).unwrap();
+
+        let record: AirdropSnapshot = try_from_slice_unchecked(accounts[2].data()).unwrap();
+        assert_eq!(record.snapshot_id, 7);
+        assert_eq!(record.taken_at, START);
+        assert_eq!(record.stake_accounts, vec![qualifying_key]);
+
+        accounts[0] = TestAccount::wallet(Pubkey::new_unique(), LAMPORTS_PER_SOL).signer();
+        assert_eq!(
+            process_snapshot_stakers(&PROGRAM_ID, &infos(&mut accounts), 7, 100),
+            Err(StakeError::Unauthorized.into())
+        );
+    }
+
+    /// Redeems with nonce 1, then retries it 50 seconds later.
+    fn redeem_retried(strict_nonce: bool) -> (Vec<TestAccount>, ProgramResult) {
+        setup();
+        let user = Pubkey::new_unique();
+        let (mut config, reward_accounts) = reward_pool(user);
+        config.strict_nonce = strict_nonce;
+        let position = staked_position(user, START - 100);
+        let mut accounts = redeem_accounts(user, &config, reward_accounts, position);
+        process_redeem(&PROGRAM_ID, &infos(&mut accounts), Some(1), None).unwrap();
+        assert_eq!(accounts[5].token_amount(), 100);
+        set_time(START + 50);
+        let retried = process_redeem(&PROGRAM_ID, &infos(&mut accounts), Some(1), None);
+        assert_eq!(accounts[5].token_amount(), 100);
+        assert_eq!(accounts[2].stake_info().last_redeem_time, START);
+        (accounts, retried)
+    }
+
+    #[test]
+    fn a_retried_nonce_does_not_pay_twice() {
+        let (mut accounts, retried) = redeem_retried(false);
+        assert_eq!(retried, Ok(()));
+
+        process_redeem(&PROGRAM_ID, &infos(&mut accounts), Some(2), None).unwrap();
+        assert_eq!(accounts[5].token_amount(), 150);
+        assert_eq!(accounts[2].stake_info().last_nonce, 2);
+    }
+
+    #[test]
+    fn a_retried_nonce_fails_in_strict_mode() {
+        let (_accounts, retried) = redeem_retried(true);
+        assert_eq!(retried, Err(StakeError::DuplicateNonce.into()));
+    }
+
+    #[test]
+    fn migrating_mid_stake_neither_loses_nor_repeats_accrual() {
+        setup();
+        let user = Pubkey::new_unique();
+        let (config, reward_accounts) = reward_pool(user);
+        let nft_token_account = token_account(Pubkey::new_unique(), Pubkey::new_unique(), user, 1);
+        let legacy = LegacyUserStakeInfo {
+            is_initialized: true,
+            user,
+            token_account: nft_token_account.key(),
+            stake_start_time: START - 500,
+            last_redeem_time: START - 200,
+            is_stake_active: true,
+            total_redeemed: 300,
+        };
+        let (stake_state, _bump) = Pubkey::find_program_address(
+            &[user.as_ref(), nft_token_account.key().as_ref()],
+            &PROGRAM_ID,
+        );
+        let position = vec![
+            nft_token_account,
+            TestAccount::with_data(stake_state, PROGRAM_ID, &legacy.try_to_vec().unwrap()),
+        ];
+        let mut accounts = redeem_accounts(user, &config, reward_accounts, position);
+        let mut migrate_accounts = vec![
+            accounts.remove(0),
+            accounts.remove(1),
+            TestAccount::program(system_program::id()),
+        ];
+
+        set_time(START - 100);
+        process_migrate_stake_account(&PROGRAM_ID, &infos(&mut migrate_accounts)).unwrap();
+        accounts.insert(0, migrate_accounts.remove(0));
+        accounts.insert(2, migrate_accounts.remove(0));
+        set_time(START + 100);
+        process_redeem(&PROGRAM_ID, &infos(&mut accounts), None, None).unwrap();
+
+        assert_eq!(accounts[5].token_amount(), 300);
+        let account_data = accounts[2].stake_info();
+        assert_eq!(account_data.stake_start_time, START - 500);
+        assert_eq!(account_data.last_redeem_time, START + 100);
+        assert_eq!(account_data.total_redeemed, 600);
+    }
+
+    #[test]
+    fn redeem_pays_only_the_preferred_destination_once_set() {
+        setup();
+        let user = Pubkey::new_unique();
+        let (config, reward_accounts) = reward_pool(user);
+        let position = staked_position(user, START - 100);
+        let mut accounts = redeem_accounts(user, &config, reward_accounts, position);
+        let preferred = token_account(Pubkey::new_unique(), config.reward_mint, user, 0);
+        let other_mint = token_account(Pubkey::new_unique(), Pubkey::new_unique(), user, 0);
+        let (preferred_key, other_mint_key) = (preferred.key(), other_mint.key());
+        let mut set_accounts = accounts.drain(..4).collect::<Vec<_>>();
+        set_accounts.push(other_mint);
+
+        assert_eq!(
+            process_set_reward_destination(&PROGRAM_ID, &infos(&mut set_accounts), other_mint_key),
+            Err(StakeError::InvalidRewardDestination.into())
+        );
+        set_accounts[4] = preferred;
+        process_set_reward_destination(&PROGRAM_ID, &infos(&mut set_accounts), preferred_key)
+            .unwrap();
+        let preferred = set_accounts.pop().unwrap();
+        set_accounts.extend(accounts);
+        let mut accounts = set_accounts;
+        assert_eq!(
+            accounts[2].stake_info().preferred_reward_destination,
+            preferred_key
+        );
+
+        assert_eq!(
+            process_redeem(&PROGRAM_ID, &infos(&mut accounts), None, None),
+            Err(StakeError::InvalidRewardDestination.into())
+        );
+        accounts[5] = preferred;
+        process_redeem(&PROGRAM_ID, &infos(&mut accounts), None, None).unwrap();
+        assert_eq!(accounts[5].token_amount(), 100);
+    }
+
+    #[test]
+    fn compounding_grows_later_rewards_up_to_the_cap() {
+        setup();
+        let user = Pubkey::new_unique();
+        let (mut config, _reward_accounts) = reward_pool(user);
+        config.compound_rate_bps = MAX_BPS;
+        config.max_virtual_staked_amount = 100_000;
+        let mut accounts = vec![TestAccount::wallet(user, LAMPORTS_PER_SOL).signer()];
+        accounts.extend(staked_position(user, START - SECONDS_PER_DAY).drain(..2));
+        accounts.push(config_account(&config));
+
+        process_compound_reward(&PROGRAM_ID, &infos(&mut accounts)).unwrap();
+        let account_data = accounts[2].stake_info();
+        assert_eq!(account_data.virtual_staked_amount, 86_400);
+        assert_eq!(account_data.last_redeem_time, START);
+        // A day at 1/s, plus a day of the virtual balance at 100% a day.
+        assert_eq!(
+            compute_reward(&account_data, &config, START + SECONDS_PER_DAY),
+            86_400 + 86_400
+        );
+        // Neither accrues over a pause.
+        config.pause_intervals = vec![PauseInterval {
+            start: START,
+            end: START + SECONDS_PER_DAY / 2,
+        }];
+        assert_eq!(
+            compute_reward(&account_data, &config, START + SECONDS_PER_DAY),
+            43_200 + 43_200
+        );
+
+        set_time(START + SECONDS_PER_DAY);
+        process_compound_reward(&PROGRAM_ID, &infos(&mut accounts)).unwrap();
+        assert_eq!(accounts[2].stake_info().virtual_staked_amount, 100_000);
+    }
+
+    /// Stakes under a 100 slot `min_wallet_age_slots` after the admin attested
+    /// the user was first seen at `first_seen_slot`.
+    fn stake_with_wallet_first_seen_at(first_seen_slot: u64) -> ProgramResult {
+        setup();
+        let user = Pubkey::new_unique();
+        let (mut config, _reward_accounts) = reward_pool(user);
+        config.min_wallet_age_slots = 100;
+        let record_key =
+            Pubkey::find_program_address(&[WALLET_AGE_SEED, user.as_ref()], &PROGRAM_ID).0;
+        let mut attest_accounts = vec![
+            TestAccount::wallet(config.admin, LAMPORTS_PER_SOL).signer(),
+            config_account(&config),
+            empty_account(record_key),
+            TestAccount::program(system_program::id()),
+        ];
+        process_attest_wallet_age(
+            &PROGRAM_ID,
+            &infos(&mut attest_accounts),
+            user,
+            first_seen_slot,
+        )
+        .unwrap();
+
+        let mut accounts = stake_accounts(user, &config);
+        accounts.push(attest_accounts.swap_remove(2));
+        process_stake(&PROGRAM_ID, &infos(&mut accounts))
+    }
+
+    #[test]
+    fn an_attested_old_enough_wallet_may_stake() {
+        assert_eq!(stake_with_wallet_first_seen_at(START_SLOT - 100), Ok(()));
+    }
+
+    #[test]
+    fn a_wallet_younger_than_the_minimum_age_may_not_stake() {
+        assert_eq!(
+            stake_with_wallet_first_seen_at(START_SLOT - 99),
+            Err(StakeError::WalletTooNew.into())
+        );
+    }
+
+    #[test]
+    fn repeated_redeems_lose_no_accrual_time() {
+        setup();
+        let user = Pubkey::new_unique();
+        let (config, reward_accounts) = reward_pool(user);
+        let position = staked_position(user, START);
+        let mut accounts = redeem_accounts(user, &config, reward_accounts, position);
+
+        for elapsed in [7, 19, 20, 53] {
+            set_time(START + elapsed);
+            process_redeem(&PROGRAM_ID, &infos(&mut accounts), None, None).unwrap();
+            assert_eq!(accounts[2].stake_info().last_redeem_time, START + elapsed);
+        }
+        assert_eq!(accounts[5].token_amount(), 53);
+        assert_eq!(accounts[2].stake_info().total_redeemed, 53);
+    }
+
+    /// Saves `account_data`, edited by `edit`, over its freshly created stake
+    /// account with `lamports`.
+    #[cfg(feature = "debug-invariants")]
+    fn save_edited_stake(edit: fn(&mut UserStakeInfo), lamports: Option<u64>) -> ProgramResult {
+        setup();
+        let user = Pubkey::new_unique();
+        let mut account_data = active_stake_info(
+            user,
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            START - 100,
+        );
+        let mut stake_state = stake_account(&account_data);
+        if let Some(lamports) = lamports {
+            stake_state.lamports = lamports;
+        }
+        edit(&mut account_data);
+        save_stake_info(&account_data, &stake_state.info())
+    }
+
+    #[cfg(feature = "debug-invariants")]
+    #[test]
+    fn a_consistent_stake_account_saves() {
+        assert_eq!(save_edited_stake(|_| {}, None), Ok(()));
+    }
+
+    #[cfg(feature = "debug-invariants")]
+    #[test]
+    #[should_panic(expected = "1 stake account invariants broken")]
+    fn saving_a_stake_redeemed_before_it_started_panics() {
+        let _ = save_edited_stake(|info| info.last_redeem_time = START - 101, None);
+    }
+
+    #[cfg(feature = "debug-invariants")]
+    #[test]
+    #[should_panic(expected = "1 stake account invariants broken")]
+    fn saving_an_undefined_flag_panics() {
+        let _ = save_edited_stake(|info| info.flags |= !crate::state::ALL_FLAGS, None);
+    }
+
+    #[cfg(feature = "debug-invariants")]
+    #[test]
+    #[should_panic(expected = "1 stake account invariants broken")]
+    fn saving_an_active_but_uninitialized_stake_panics() {
+        let _ = save_edited_stake(|info| info.set_initialized(false), None);
+    }
+
+    #[cfg(feature = "debug-invariants")]
+    #[test]
+    #[should_panic(expected = "2 stake account invariants broken")]
+    fn saving_a_non_canonical_bump_below_rent_panics() {
+        let _ = save_edited_stake(|info| info.bump = info.bump.wrapping_sub(1), Some(1));
+    }
+
+    #[test]
+    fn recover_stray_tokens_moves_strays_but_not_the_protocol_fee_account() {
+        setup();
+        let (config, mut reward_accounts) = reward_pool(Pubkey::new_unique());
+        let stray_mint = Pubkey::new_unique();
+        let token_program = reward_accounts.pop().unwrap();
+        let mint_authority = reward_accounts.pop().unwrap();
+        let protocol_fee_account = reward_accounts.pop().unwrap();
+        let mut accounts = vec![
+            TestAccount::wallet(config.admin, LAMPORTS_PER_SOL).signer(),
+            config_account(&config),
+            token_account(
+                Pubkey::new_unique(),
+                stray_mint,
+                mint_authority_address(),
+                40,
+            ),
+            token_account(Pubkey::new_unique(), stray_mint, config.admin, 0),
+            mint_authority,
+            token_program,
+        ];
+
+        process_recover_stray_tokens(&PROGRAM_ID, &infos(&mut accounts)).unwrap();
+        assert_eq!(accounts[2].token_amount(), 0);
+        assert_eq!(accounts[3].token_amount(), 40);
+
+        accounts[2] = protocol_fee_account;
+        assert_eq!(
+            process_recover_stray_tokens(&PROGRAM_ID, &infos(&mut accounts)),
+            Err(StakeError::ProtectedAccount.into())
+        );
+    }
+
+    #[test]
+    fn recover_stray_tokens_protects_reward_tokens_and_program_pdas() {
+        setup();
+        let (config, mut reward_accounts) = reward_pool(Pubkey::new_unique());
+        let token_program = reward_accounts.pop().unwrap();
+        let mint_authority = reward_accounts.pop().unwrap();
+        let sol_pool = Pubkey::find_program_address(&[SOL_POOL_SEED], &PROGRAM_ID).0;
+        let rent_reserve = Pubkey::find_program_address(&[RENT_RESERVE_SEED], &PROGRAM_ID).0;
+        let stray_mint = Pubkey::new_unique();
+        let held_by_authority = |key, mint| token_account(key, mint, mint_authority_address(), 40);
+        let mut delegated = held_by_authority(Pubkey::new_unique(), stray_mint);
+        let mut token = TokenAccount::unpack(delegated.data()).unwrap();
+        token.delegate = COption::Some(rent_reserve);
+        token.delegated_amount = 40;
+        let mut data = vec![0; TokenAccount::LEN];
+        TokenAccount::pack(token, &mut data).unwrap();
+        delegated.set_data(&data);
+
+        for protected in [
+            held_by_authority(Pubkey::new_unique(), config.reward_mint),
+            held_by_authority(sol_pool, stray_mint),
+            delegated,
+        ] {
+            let mut accounts = vec![
+                TestAccount::wallet(config.admin, LAMPORTS_PER_SOL).signer(),
+                config_account(&config),
+                protected,
+                token_account(Pubkey::new_unique(), stray_mint, config.admin, 0),
+                mint_authority.clone(),
+                token_program.clone(),
+            ];
+            assert_eq!(
+                process_recover_stray_tokens(&PROGRAM_ID, &infos(&mut accounts)),
+                Err(StakeError::ProtectedAccount.into())
+            );
+            assert_eq!(accounts[2].token_amount(), 40);
+        }
+    }
+
+    #[test]
+    fn a_rate_override_applies_only_while_it_is_set() {
+        setup();
+        let user = Pubkey::new_unique();
+        let (config, reward_accounts) = reward_pool(user);
+        let position = staked_position(user, START);
+        let mut accounts = redeem_accounts(user, &config, reward_accounts, position);
+        let mut admin_accounts = vec![
+            TestAccount::wallet(config.admin, LAMPORTS_PER_SOL).signer(),
+            accounts.remove(3),
+            accounts.remove(2),
+        ];
+
+        set_time(START + 100);
+        process_set_rate_override(&PROGRAM_ID, &infos(&mut admin_accounts), Some(5)).unwrap();
+        assert_eq!(admin_accounts[2].stake_info().checkpoint_reward, 100);
+        set_time(START + 200);
+        process_set_rate_override(&PROGRAM_ID, &infos(&mut admin_accounts), None).unwrap();
+        let account_data = admin_accounts[2].stake_info();
+        assert_eq!(account_data.checkpoint_reward, 100 + 500);
+        assert_eq!(account_data.rate_override(), None);
+
+        accounts.insert(2, admin_accounts.pop().unwrap());
+        accounts.insert(3, admin_accounts.pop().unwrap());
+        set_time(START + 300);
+        process_redeem(&PROGRAM_ID, &infos(&mut accounts), None, None).unwrap();
+        assert_eq!(accounts[5].token_amount(), 100 + 500 + 100);
+    }
+
+    /// How many compute unit logs a `ReconcileStake` writes.
+    fn compute_unit_logs(log_compute_units: bool) -> usize {
+        setup();
+        let (mut config, _reward_accounts) = reward_pool(Pubkey::new_unique());
+        config.log_compute_units = log_compute_units;
+        let stake_state = staked_position(Pubkey::new_unique(), START).swap_remove(1);
+        let mut accounts = vec![stake_state, config_account(&config)];
+        process_instruction(&PROGRAM_ID, &infos(&mut accounts), &[5]).unwrap();
+        logs().iter().filter(|log| *log == "compute units").count()
+    }
+
+    #[cfg(feature = "compute-unit-logging")]
+    #[test]
+    fn compute_units_are_logged_around_an_instruction_when_enabled() {
+        assert_eq!(compute_unit_logs(true), 2);
+        assert_eq!(compute_unit_logs(false), 0);
+    }
+
+    #[cfg(not(feature = "compute-unit-logging"))]
+    #[test]
+    fn compute_units_are_only_logged_by_builds_with_the_feature() {
+        assert_eq!(compute_unit_logs(true), 0);
+    }
+
+    #[test]
+    fn accrual_excludes_exactly_the_paused_overlap() {
+        let user = Pubkey::new_unique();
+        let (mut config, _reward_accounts) = reward_pool(user);
+        config.pause_intervals = vec![PauseInterval {
+            start: START + 100,
+            end: START + 200,
+        }];
+        let reward = |staked_at, now| {
+            let account_data =
+                active_stake_info(user, Pubkey::new_unique(), Pubkey::new_unique(), staked_at);
+            compute_reward(&account_data, &config, now)
+        };
+
+        // Window containing the pause.
+        assert_eq!(reward(START, START + 300), 200);
+        // Windows overlapping its start and its end.
+        assert_eq!(reward(START, START + 150), 100);
+        assert_eq!(reward(START + 150, START + 300), 100);
+        // Windows before and after it.
+        assert_eq!(reward(START, START + 100), 100);
+        assert_eq!(reward(START + 200, START + 300), 100);
+    }
+
+    #[test]
+    fn a_full_pause_history_refuses_new_pauses_but_not_resumes() {
+        setup();
+        let (config, _reward_accounts) = reward_pool(Pubkey::new_unique());
+        let mut accounts = vec![
+            TestAccount::wallet(config.admin, LAMPORTS_PER_SOL).signer(),
+            config_account(&config),
+        ];
+        // The last resume records the interval that fills the history.
+        for i in 0..MAX_PAUSE_INTERVALS as i64 {
+            set_time(START + 2 * i);
+            process_set_paused(&PROGRAM_ID, &infos(&mut accounts), true).unwrap();
+            set_time(START + 2 * i + 1);
+            process_set_paused(&PROGRAM_ID, &infos(&mut accounts), false).unwrap();
+        }
+        let config = accounts[1].config();
+        assert_eq!(config.pause_intervals.len(), MAX_PAUSE_INTERVALS);
+        assert_eq!(config.paused_since, 0);
+
+        assert_eq!(
+            process_set_paused(&PROGRAM_ID, &infos(&mut accounts), true),
+            Err(StakeError::PauseHistoryFull.into())
+        );
+        assert_eq!(accounts[1].config().paused_since, 0);
+    }
+
+    /// `CanUnstake` accounts: `user`, `position` and their `UserStakeAccount`.
+    fn can_unstake_accounts(user: Pubkey, position: Vec<TestAccount>) -> Vec<TestAccount> {
+        let mut accounts = vec![TestAccount::wallet(user, LAMPORTS_PER_SOL)];
+        accounts.extend(position);
+        accounts.push(user_stake_account(user, 1));
+        accounts
+    }
+
+    fn can_unstake(user: Pubkey, position: Vec<TestAccount>) -> UnstakeStatus {
+        setup();
+        let mut accounts = can_unstake_accounts(user, position);
+        process_can_unstake(&PROGRAM_ID, &infos(&mut accounts)).unwrap();
+        try_from_slice_unchecked::<UnstakeEligibility>(&return_data().unwrap().1)
+            .unwrap()
+            .status
+    }
+
+    #[test]
+    fn can_unstake_reports_each_status() {
+        let user = Pubkey::new_unique();
+        let active = staked_position(user, START);
+        assert!(can_unstake(user, active) == UnstakeStatus::Unblocked);
+
+        let mut unstaked = staked_position(user, START);
+        let token_account = unstaked[0].key();
+        unstaked[1] = stake_account(&new_stake_info(user, token_account));
+        assert!(can_unstake(user, unstaked) == UnstakeStatus::NotStaked);
+
+        let legacy = LegacyUserStakeInfo {
+            is_initialized: true,
+            user,
+            token_account: Pubkey::new_unique(),
+            stake_start_time: START,
+            last_redeem_time: START,
+            is_stake_active: true,
+            total_redeemed: 0,
+        };
+        let mut position = staked_position(user, START);
+        position[1] = TestAccount::with_data(
+            Pubkey::new_unique(),
+            PROGRAM_ID,
+            &legacy.try_to_vec().unwrap(),
+        );
+        assert!(can_unstake(user, position) == UnstakeStatus::NeedsMigration);
+    }
+
+    #[test]
+    fn can_unstake_rejects_what_unstake_would_reject() {
+        setup();
+        let user = Pubkey::new_unique();
+        let mut accounts = can_unstake_accounts(user, staked_position(user, START));
+        accounts[0] = TestAccount::wallet(Pubkey::new_unique(), LAMPORTS_PER_SOL);
+        assert_eq!(
+            process_can_unstake(&PROGRAM_ID, &infos(&mut accounts)),
+            Err(StakeError::InvalidPda.into())
+        );
+
+        let mut accounts = can_unstake_accounts(user, staked_position(user, START));
+        accounts[4] = user_stake_account(Pubkey::new_unique(), 1);
+        assert!(process_can_unstake(&PROGRAM_ID, &infos(&mut accounts)).is_err());
+
+        let mut accounts = can_unstake_accounts(user, staked_position(user, START));
+        accounts[3] = staked_mint_account(&Pubkey::new_unique(), accounts[2].key());
+        assert!(process_can_unstake(&PROGRAM_ID, &infos(&mut accounts)).is_err());
+    }
+
+    /// Redeems a half-weighted stake after 3 seconds, then unstakes it 2
+    /// seconds later under `final_reward_rounding`, returning both payouts.
+    fn interim_and_final_payouts(final_reward_rounding: Rounding) -> (u64, u64) {
+        setup();
+        let user = Pubkey::new_unique();
+        let (mut config, reward_accounts) = reward_pool(user);
+        config.final_reward_rounding = final_reward_rounding;
+        let mut position = staked_position(user, START);
+        let mut account_data = position[1].stake_info();
+        account_data.stake_weight = LAMPORTS_PER_SOL / 2;
+        position[1].set_stake_info(&account_data);
+        let staked_mint = position.pop().unwrap();
+        let mut accounts = redeem_accounts(user, &config, reward_accounts, position);
+
+        set_time(START + 3);
+        process_redeem(&PROGRAM_ID, &infos(&mut accounts), None, None).unwrap();
+        let interim = accounts[5].token_amount();
+        accounts.insert(3, staked_mint);
+        accounts.insert(4, user_stake_account(user, 1));
+        set_time(START + 5);
+        process_unstake(&PROGRAM_ID, &infos(&mut accounts), None).unwrap();
+        (interim, accounts[7].token_amount() - interim)
+    }
+
+    #[test]
+    fn only_the_final_payout_follows_the_final_reward_rounding() {
+        // 1.5 accrues by the redeem and 2.5 by the unstake.
+        assert_eq!(interim_and_final_payouts(Rounding::Down), (1, 1));
+        assert_eq!(interim_and_final_payouts(Rounding::Up), (1, 2));
+    }
+
+    #[test]
+    fn nothing_accrues_before_the_reward_cliff() {
+        let user = Pubkey::new_unique();
+        let (mut config, _reward_accounts) = reward_pool(user);
+        config.reward_cliff = 100;
+        let account_data =
+            active_stake_info(user, Pubkey::new_unique(), Pubkey::new_unique(), START);
+
+        for cliff_pays_from_start in [false, true] {
+            config.cliff_pays_from_start = cliff_pays_from_start;
+            assert_eq!(compute_reward(&account_data, &config, START + 99), 0);
+        }
+        config.cliff_pays_from_start = true;
+        assert_eq!(compute_reward(&account_data, &config, START + 100), 100);
+        assert_eq!(compute_reward(&account_data, &config, START + 150), 150);
+        config.cliff_pays_from_start = false;
+        assert_eq!(compute_reward(&account_data, &config, START + 100), 0);
+        assert_eq!(compute_reward(&account_data, &config, START + 150), 50);
+    }
+
+    #[test]
+    fn consolidate_stakes_pays_both_and_refunds_the_source_rent() {
+        setup();
+        let user = Pubkey::new_unique();
+        let (config, reward_accounts) = reward_pool(user);
+        let mut target = staked_position(user, START - 100);
+        target.pop();
+        let source = staked_position(user, START - 300);
+        let source_rent = source[1].lamports;
+        let mut accounts = vec![TestAccount::wallet(user, LAMPORTS_PER_SOL).signer()];
+        accounts.extend(target);
+        accounts.push(user_stake_account(user, 2));
+        accounts.push(config_account(&config));
+        accounts.extend(reward_accounts);
+        accounts.extend(source);
+
+        process_consolidate_stakes(&PROGRAM_ID, &infos(&mut accounts)).unwrap();
+
+        assert_eq!(accounts[6].token_amount(), 100 + 300);
+        assert_eq!(accounts[0].lamports, LAMPORTS_PER_SOL + source_rent);
+        assert_eq!(accounts[11].lamports, 0);
+        assert_eq!(accounts[3].user_stakes().active_stakes, 1);
+        let account_data = accounts[2].stake_info();
+        assert!(account_data.is_stake_active());
+        assert_eq!(account_data.stake_start_time, START - 100);
+        assert_eq!(account_data.last_redeem_time, START);
+    }
+
+    #[test]
+    fn proof_of_hold_pays_while_held_and_forfeits_once_the_nft_is_gone() {
+        setup();
+        let user = Pubkey::new_unique();
+        let (mut config, reward_accounts) = reward_pool(user);
+        config.proof_of_hold = true;
+        let position = staked_position(user, START - 100);
+        let nft_mint = position[1].stake_info().nft_mint;
+        let mut accounts = redeem_accounts(user, &config, reward_accounts, position);
+
+        process_redeem(&PROGRAM_ID, &infos(&mut accounts), None, None).unwrap();
+        assert_eq!(accounts[5].token_amount(), 100);
+
+        let sold = token_account(accounts[1].key(), nft_mint, Pubkey::new_unique(), 1);
+        accounts[1] = sold;
+        set_time(START + 50);
+        process_redeem(&PROGRAM_ID, &infos(&mut accounts), None, None).unwrap();
+        assert_eq!(accounts[5].token_amount(), 100);
+        assert_eq!(accounts[2].stake_info().last_redeem_time, START + 50);
+    }
+
+    #[test]
+    fn a_dormant_stake_is_paid_at_most_the_max_backpay() {
+        setup();
+        let user = Pubkey::new_unique();
+        let (mut config, reward_accounts) = reward_pool(user);
+        config.max_backpay_seconds = SECONDS_PER_DAY;
+        let position = staked_position(user, START - 30 * SECONDS_PER_DAY);
+        let mut accounts = redeem_accounts(user, &config, reward_accounts, position);
+        let account_data = accounts[2].stake_info();
+        assert!(reward_breakdown(&account_data, &config, START).backpay_capped);
+        assert_eq!(
+            compute_reward(&account_data, &config, START - 30 * SECONDS_PER_DAY + 10),
+            10
+        );
+
+        process_redeem(&PROGRAM_ID, &infos(&mut accounts), None, None).unwrap();
+        assert_eq!(accounts[5].token_amount(), SECONDS_PER_DAY as u64);
+        assert_eq!(accounts[2].stake_info().last_redeem_time, START);
+    }
+
+    /// Asserts that `event` is the only event emitted since the last call.
+    fn assert_emitted(event: StakeEvent) {
+        assert_eq!(events(), vec![event.try_to_vec().unwrap()]);
+        setup();
+    }
+
+    #[test]
+    fn each_state_change_emits_its_event() {
+        setup();
+        let user = Pubkey::new_unique();
+        let (config, reward_accounts) = reward_pool(user);
+        let mut accounts = stake_accounts(user, &config);
+        let stake_state = accounts[2].key();
+        process_stake(&PROGRAM_ID, &infos(&mut accounts)).unwrap();
+        let nft_mint = accounts[2].stake_info().nft_mint;
+        assert_emitted(StakeEvent::Staked {
+            stake_state,
+            user,
+            nft_mint,
+            stake_weight: 0,
+            staked_at: START,
+        });
+
+        // Without the system program and with the reward accounts, the
+        // `Stake` accounts are in `Unstake` order; `Redeem` skips the
+        // `StakedMint` and `UserStakeAccount`.
+        accounts.pop();
+        accounts.extend(reward_accounts);
+        set_time(START + 10);
+        let mut redeem_accounts = accounts.drain(..3).collect::<Vec<_>>();
+        redeem_accounts.extend(accounts.drain(2..));
+        process_redeem(&PROGRAM_ID, &infos(&mut redeem_accounts), None, None).unwrap();
+        assert_emitted(StakeEvent::Redeemed {
+            stake_state,
+            user,
+            amount: 10,
+            redeemed_at: START + 10,
+        });
+
+        set_time(START + 25);
+        let mut unstake_accounts = redeem_accounts.drain(..3).collect::<Vec<_>>();
+        unstake_accounts.append(&mut accounts);
+        unstake_accounts.append(&mut redeem_accounts);
+        process_unstake(&PROGRAM_ID, &infos(&mut unstake_accounts), None).unwrap();
+        assert_emitted(StakeEvent::Unstaked {
+            stake_state,
+            user,
+            nft_mint,
+            reward: 15,
+            reward_deferred: false,
+            unstaked_at: START + 25,
+        });
+
+        let mut admin_accounts = vec![
+            TestAccount::wallet(config.admin, LAMPORTS_PER_SOL).signer(),
+            unstake_accounts.remove(5),
+        ];
+        process_update_config(
+            &PROGRAM_ID,
+            &infos(&mut admin_accounts),
+            ConfigField::MinStakeAmount(1),
+        )
+        .unwrap();
+        assert_emitted(StakeEvent::ConfigUpdated {
+            field: ConfigField::MinStakeAmount(1),
+        });
+    }
+
+    #[test]
+    fn every_redeem_ordering_pays_the_same_total() {
+        for redeem_at in [vec![], vec![33], vec![33, 33], vec![1, 2, 3, 99], vec![100]] {
+            setup();
+            let user = Pubkey::new_unique();
+            let (config, reward_accounts) = reward_pool(user);
+            let mut position = staked_position(user, START);
+            // Half a unit a second, so every odd split leaves a fraction.
+            let mut account_data = position[1].stake_info();
+            account_data.stake_weight = LAMPORTS_PER_SOL / 2;
+            position[1].set_stake_info(&account_data);
+            let staked_mint = position.pop().unwrap();
+            let mut accounts = redeem_accounts(user, &config, reward_accounts, position);
+            for elapsed in redeem_at {
+                set_time(START + elapsed);
+                process_redeem(&PROGRAM_ID, &infos(&mut accounts), None, None).unwrap();
+            }
+            accounts.insert(3, staked_mint);
+            accounts.insert(4, user_stake_account(user, 1));
+            set_time(START + 100);
+            process_unstake(&PROGRAM_ID, &infos(&mut accounts), None).unwrap();
+
+            assert_eq!(accounts[7].token_amount(), 50);
+            assert_eq!(accounts[2].stake_info().total_redeemed, 50);
+        }
+    }
+
+    /// Stakes an NFT whose mint has `mint_authority` while one bridge
+    /// authority is configured.
+    fn stake_bridged_nft(mint_authority: Option<Pubkey>, bridge: Pubkey) -> ProgramResult {
+        setup();
+        let user = Pubkey::new_unique();
+        let (mut config, _reward_accounts) = reward_pool(user);
+        config.wrapped_mint_authorities = vec![bridge];
+        let mut accounts = stake_accounts(user, &config);
+        let nft_mint = TokenAccount::unpack(accounts[1].data()).unwrap().mint;
+        accounts.push(mint_account(nft_mint, mint_authority, 1));
+        process_stake(&PROGRAM_ID, &infos(&mut accounts))
+    }
+
+    #[test]
+    fn an_nft_wrapped_by_a_configured_bridge_stakes() {
+        let bridge = Pubkey::new_unique();
+        assert_eq!(stake_bridged_nft(Some(bridge), bridge), Ok(()));
+        // A mint whose authority was revoked is a native NFT.
+        assert_eq!(stake_bridged_nft(None, bridge), Ok(()));
+    }
+
+    #[test]
+    fn an_nft_wrapped_by_an_unknown_bridge_is_rejected() {
+        assert_eq!(
+            stake_bridged_nft(Some(Pubkey::new_unique()), Pubkey::new_unique()),
+            Err(StakeError::UnsupportedWrappedMint.into())
+        );
+    }
+
+    /// Redeems a stake held for `held_for` seconds under a withheld 10%
+    /// protocol fee discounted for long-held stakes, returning what the user
+    /// and the protocol fee account received.
+    fn redeem_with_fee_discount(held_for: i64) -> (u64, u64) {
+        setup();
+        let user = Pubkey::new_unique();
+        let (mut config, reward_accounts) = reward_pool(user);
+        config.protocol_fee_bps = 1_000;
+        config.withhold_protocol_fee = true;
+        config.min_protocol_fee_bps = 200;
+        config.fee_discount_tiers = vec![
+            FeeDiscountTier {
+                min_duration: 1_000,
+                discount_bps: 500,
+            },
+            FeeDiscountTier {
+                min_duration: 10_000,
+                discount_bps: 900,
+            },
+        ];
+        let position = staked_position(user, START - held_for);
+        let mut accounts = redeem_accounts(user, &config, reward_accounts, position);
+        process_redeem(&PROGRAM_ID, &infos(&mut accounts), None, None).unwrap();
+        (accounts[5].token_amount(), accounts[6].token_amount())
+    }
+
+    #[test]
+    fn a_long_term_staker_pays_a_lower_but_floored_fee() {
+        // 10%, 10% - 5% and 10% - 9% floored at 2%.
+        assert_eq!(redeem_with_fee_discount(100), (90, 10));
+        assert_eq!(redeem_with_fee_discount(1_000), (950, 50));
+        assert_eq!(redeem_with_fee_discount(10_000), (9_800, 200));
+    }
+
+    /// Unstakes, while paused since 50 seconds in, a stake held for 100
+    /// seconds.
+    fn unstake_while_paused(pay_reward_on_paused_unstake: bool) -> Vec<TestAccount> {
+        setup();
+        let user = Pubkey::new_unique();
+        let (mut config, reward_accounts) = reward_pool(user);
+        config.paused_since = START - 50;
+        config.pay_reward_on_paused_unstake = pay_reward_on_paused_unstake;
+        let position = staked_position(user, START - 100);
+        let mut accounts = unstake_accounts(user, &config, reward_accounts, position);
+        process_unstake(&PROGRAM_ID, &infos(&mut accounts), None).unwrap();
+        assert!(!accounts[2].stake_info().is_stake_active());
+        accounts
+    }
+
+    #[test]
+    fn a_paused_unstake_pays_when_configured_to() {
+        let accounts = unstake_while_paused(true);
+        assert_eq!(accounts[7].token_amount(), 50);
+        assert_eq!(accounts[2].stake_info().unclaimed_final_reward, 0);
+    }
+
+    #[test]
+    fn a_paused_unstake_withholds_the_reward_until_resumed() {
+        let accounts = unstake_while_paused(false);
+        assert_eq!(accounts[7].token_amount(), 0);
+        let account_data = accounts[2].stake_info();
+        assert_eq!(account_data.unclaimed_final_reward, 50);
+        assert_eq!(account_data.final_reward_expires_at, UnixTimestamp::MAX);
+
+        // `ClaimFinalReward` takes the `Redeem` accounts.
+        let mut accounts = accounts;
+        let mut claim_accounts = accounts.drain(..3).collect::<Vec<_>>();
+        claim_accounts.extend(accounts.drain(2..));
+        assert_eq!(
+            process_claim_final_reward(&PROGRAM_ID, &infos(&mut claim_accounts)),
+            Err(StakeError::RewardsPaused.into())
+        );
+        let mut config = claim_accounts[3].config();
+        config.paused_since = 0;
+        claim_accounts[3] = config_account(&config);
+        process_claim_final_reward(&PROGRAM_ID, &infos(&mut claim_accounts)).unwrap();
+        assert_eq!(claim_accounts[5].token_amount(), 50);
+    }
+
+    #[test]
+    fn initialize_reward_mint_creates_a_mint_under_the_program_authority() {
+        setup();
+        let (config, _reward_accounts) = reward_pool(Pubkey::new_unique());
+        let reward_mint = Pubkey::find_program_address(&[REWARD_MINT_SEED], &PROGRAM_ID).0;
+        let mut accounts = vec![
+            TestAccount::wallet(config.admin, LAMPORTS_PER_SOL).signer(),
+            config_account(&config),
+            empty_account(reward_mint),
+            empty_account(Pubkey::new_unique()).signer(),
+            TestAccount::program(spl_token::id()),
+            TestAccount::program(system_program::id()),
+        ];
+
+        process_initialize_reward_mint(&PROGRAM_ID, &infos(&mut accounts), 6).unwrap();
+
+        let mint = Mint::unpack(accounts[2].data()).unwrap();
+        assert_eq!(accounts[2].owner, spl_token::id());
+        assert_eq!(mint.mint_authority, COption::Some(mint_authority_address()));
+        assert_eq!(mint.decimals, 6);
+        let fee_account = TokenAccount::unpack(accounts[3].data()).unwrap();
+        assert_eq!(fee_account.mint, reward_mint);
+        let config = accounts[1].config();
+        assert_eq!(config.reward_mint, reward_mint);
+        assert_eq!(config.protocol_fee_account, accounts[3].key());
+
+        assert_eq!(
+            process_initialize_reward_mint(&PROGRAM_ID, &infos(&mut accounts), 6),
+            Err(ProgramError::AccountAlreadyInitialized)
+        );
+    }
+
+    /// Stakes under a 50% master edition boost with an edition account holding
+    /// `edition_key`, at `edition_pda` or the real edition PDA, returning the
+    /// reward the stake earns over 100 seconds.
+    fn stake_with_edition(
+        edition_key: u8,
+        edition_pda: Option<Pubkey>,
+    ) -> Result<u64, ProgramError> {
+        setup();
+        let user = Pubkey::new_unique();
+        let (mut config, _reward_accounts) = reward_pool(user);
+        config.master_edition_boost_bps = 5_000;
+        let mut accounts = stake_accounts(user, &config);
+        let nft_mint = TokenAccount::unpack(accounts[1].data()).unwrap().mint;
+        let edition_pda = edition_pda.unwrap_or_else(|| {
+            Pubkey::find_program_address(
+                &[
+                    b"metadata",
+                    TOKEN_METADATA_PROGRAM_ID.as_ref(),
+                    nft_mint.as_ref(),
+                    b"edition",
+                ],
+                &TOKEN_METADATA_PROGRAM_ID,
+            )
+            .0
+        });
+        accounts.push(TestAccount::with_data(
+            edition_pda,
+            TOKEN_METADATA_PROGRAM_ID,
+            &[edition_key, 0, 0, 0],
+        ));
+        process_stake(&PROGRAM_ID, &infos(&mut accounts))?;
+        Ok(compute_reward(
+            &accounts[2].stake_info(),
+            &config,
+            START + 100,
+        ))
+    }
+
+    #[test]
+    fn master_editions_earn_the_edition_boost() {
+        // Both the V1 and V2 master edition keys.
+        assert_eq!(stake_with_edition(2, None), Ok(150));
+        assert_eq!(stake_with_edition(6, None), Ok(150));
+    }
+
+    #[test]
+    fn print_editions_earn_the_base_rate() {
+        assert_eq!(stake_with_edition(1, None), Ok(100));
+    }
+
+    #[test]
+    fn an_edition_account_at_another_address_is_rejected() {
+        assert_eq!(
+            stake_with_edition(6, Some(Pubkey::new_unique())),
+            Err(StakeError::InvalidPda.into())
+        );
+    }
+
+    #[test]
+    fn restaking_waits_out_the_restake_cooloff() {
+        setup();
+        let user = Pubkey::new_unique();
+        let (mut config, _reward_accounts) = reward_pool(user);
+        config.restake_cooloff = 100;
+        let mut accounts = stake_accounts(user, &config);
+        let mut account_data = accounts[2].stake_info();
+        account_data.last_unstake_time = START - 50;
+        accounts[2].set_stake_info(&account_data);
+
+        assert_eq!(
+            process_stake(&PROGRAM_ID, &infos(&mut accounts)),
+            Err(StakeError::RestakeCooloffActive.into())
+        );
+        set_time(START + 50);
+        process_stake(&PROGRAM_ID, &infos(&mut accounts)).unwrap();
+        assert!(accounts[2].stake_info().is_stake_active());
+    }
+
+    #[test]
+    fn the_restake_cooloff_follows_the_nft_into_a_new_token_account() {
+        setup();
+        let user = Pubkey::new_unique();
+        let (mut config, reward_accounts) = reward_pool(user);
+        config.restake_cooloff = 100;
+        let position = staked_position(user, START - 1_000);
+        let nft_mint = position[1].stake_info().nft_mint;
+        let mut unstake = unstake_accounts(user, &config, reward_accounts, position);
+        process_unstake(&PROGRAM_ID, &infos(&mut unstake), None).unwrap();
+        assert_eq!(unstake[3].staked_mint().last_unstake_time, START);
+
+        let mut accounts = restake_accounts(user, &config, nft_mint, unstake.remove(3));
+        assert_eq!(
+            process_stake(&PROGRAM_ID, &infos(&mut accounts)),
+            Err(StakeError::RestakeCooloffActive.into())
+        );
+        set_time(START + 100);
+        process_stake(&PROGRAM_ID, &infos(&mut accounts)).unwrap();
+        assert!(accounts[2].stake_info().is_stake_active());
+    }
+
+    #[test]
+    fn a_legacy_staked_mint_record_is_grown_when_its_mint_is_staked() {
+        setup();
+        let user = Pubkey::new_unique();
+        let (config, _reward_accounts) = reward_pool(user);
+        let mut accounts = stake_accounts(user, &config);
+        let nft_mint = TokenAccount::unpack(accounts[1].data()).unwrap().mint;
+        accounts[3] = TestAccount::with_data(
+            staked_mint_address(&nft_mint),
+            PROGRAM_ID,
+            &[0; StakedMint::LEGACY_SIZE],
+        );
+
+        process_stake(&PROGRAM_ID, &infos(&mut accounts)).unwrap();
+        assert_eq!(accounts[3].data().len(), StakedMint::SIZE);
+        assert_eq!(accounts[3].staked_mint().stake_state, accounts[2].key());
+        assert_eq!(
+            accounts[3].lamports,
+            Rent::default().minimum_balance(StakedMint::SIZE)
+        );
+    }
+
+    #[test]
+    fn a_legacy_user_stake_account_is_grown_when_its_user_stakes() {
+        setup();
+        let user = Pubkey::new_unique();
+        let (mut config, _reward_accounts) = reward_pool(user);
+        config.min_nfts_to_qualify = 2;
+        let mut accounts = stake_accounts(user, &config);
+        let legacy = user_stake_account(user, 1);
+        accounts[4] = TestAccount::with_data(
+            legacy.key(),
+            PROGRAM_ID,
+            &legacy.data()[..UserStakeAccount::LEGACY_SIZE],
+        );
+
+        process_stake(&PROGRAM_ID, &infos(&mut accounts)).unwrap();
+        assert_eq!(accounts[4].data().len(), UserStakeAccount::SIZE);
+        let user_stakes = accounts[4].user_stakes();
+        assert_eq!(user_stakes.active_stakes, 2);
+        assert_eq!(user_stakes.qualified_since, START);
+        assert_eq!(
+            accounts[4].lamports,
+            Rent::default().minimum_balance(UserStakeAccount::SIZE)
+        );
+    }
+
+    fn config_version(stored_version: u8) -> ConfigVersion {
+        setup();
+        let (mut config, _reward_accounts) = reward_pool(Pubkey::new_unique());
+        config.version = stored_version;
+        let mut accounts = vec![config_account(&config)];
+        process_get_config_version(&PROGRAM_ID, &infos(&mut accounts)).unwrap();
+        try_from_slice_unchecked(&return_data().unwrap().1).unwrap()
+    }
+
+    #[test]
+    fn get_config_version_reports_the_stored_version() {
+        let version = config_version(StakeConfig::VERSION);
+        assert_eq!(version.stored_version, StakeConfig::VERSION);
+        assert_eq!(version.program_version, StakeConfig::VERSION);
+        assert!(version.compatible);
+
+        let version = config_version(StakeConfig::VERSION - 1);
+        assert_eq!(version.stored_version, StakeConfig::VERSION - 1);
+        assert!(!version.compatible);
+    }
+
+    /// Redeems a stake held for a day and a half, returning the payout and the
+    /// new `last_redeem_time`.
+    fn redeem_after_a_day_and_a_half(prorate_partial_days: bool) -> (u64, UnixTimestamp) {
+        setup();
+        let user = Pubkey::new_unique();
+        let (mut config, reward_accounts) = reward_pool(user);
+        config.prorate_partial_days = prorate_partial_days;
+        let position = staked_position(user, START - SECONDS_PER_DAY * 3 / 2);
+        let mut accounts = redeem_accounts(user, &config, reward_accounts, position);
+        process_redeem(&PROGRAM_ID, &infos(&mut accounts), None, None).unwrap();
+        (
+            accounts[5].token_amount(),
+            accounts[2].stake_info().last_redeem_time,
+        )
+    }
+
+    #[test]
+    fn partial_days_are_prorated_when_configured() {
+        assert_eq!(
+            redeem_after_a_day_and_a_half(true),
+            (SECONDS_PER_DAY as u64 * 3 / 2, START)
+        );
+    }
+
+    #[test]
+    fn partial_days_are_carried_to_the_next_redeem_otherwise() {
+        assert_eq!(
+            redeem_after_a_day_and_a_half(false),
+            (SECONDS_PER_DAY as u64, START - SECONDS_PER_DAY / 2)
+        );
+    }
+
+    #[test]
+    fn rewards_dilute_with_the_number_of_active_stakes() {
+        let user = Pubkey::new_unique();
+        let (mut config, _reward_accounts) = reward_pool(user);
+        config.stake_dilution_divisor = 10;
+        let account_data = active_stake_info(user, Pubkey::new_unique(), Pubkey::new_unique(), 0);
+
+        for (total_active_stakes, reward) in [(0, 600), (19, 600), (20, 300), (35, 200), (600, 10)]
+        {
+            config.total_active_stakes = total_active_stakes;
+            assert_eq!(compute_reward(&account_data, &config, 600), reward);
+        }
+        config.stake_dilution_divisor = 0;
+        assert_eq!(compute_reward(&account_data, &config, 600), 600);
+    }
+
+    /// Funds the rent reserve with `lamports`, then stakes a fresh NFT with
+    /// `sponsor_rent` set.
+    fn stake_with_rent_reserve(lamports: u64) -> (Vec<TestAccount>, ProgramResult) {
+        setup();
+        let user = Pubkey::new_unique();
+        let (mut config, _reward_accounts) = reward_pool(user);
+        config.sponsor_rent = true;
+        let rent_reserve = Pubkey::find_program_address(&[RENT_RESERVE_SEED], &PROGRAM_ID).0;
+        let mut fund_accounts = vec![
+            TestAccount::wallet(config.admin, LAMPORTS_PER_SOL).signer(),
+            config_account(&config),
+            empty_account(rent_reserve),
+            TestAccount::program(system_program::id()),
+        ];
+        process_fund_rent_reserve(&PROGRAM_ID, &infos(&mut fund_accounts), lamports).unwrap();
+
+        let mut accounts = stake_accounts(user, &config);
+        accounts.push(fund_accounts.swap_remove(2));
+        let result = process_stake(&PROGRAM_ID, &infos(&mut accounts));
+        (accounts, result)
+    }
+
+    #[test]
+    fn the_rent_reserve_pays_the_rent_of_accounts_stake_creates() {
+        let rent = Rent::default();
+        let sponsored =
+            rent.minimum_balance(UserStakeAccount::SIZE) + rent.minimum_balance(StakedMint::SIZE);
+        let (accounts, result) = stake_with_rent_reserve(sponsored);
+
+        assert_eq!(result, Ok(()));
+        assert_eq!(accounts[0].lamports, LAMPORTS_PER_SOL);
+        assert_eq!(accounts[7].lamports, rent.minimum_balance(0));
+        assert_eq!(accounts[3].staked_mint().stake_state, accounts[2].key());
+    }
+
+    #[test]
+    fn an_underfunded_rent_reserve_fails_the_stake() {
+        let (_accounts, result) = stake_with_rent_reserve(1);
+        assert_eq!(result, Err(StakeError::RentReserveEmpty.into()));
+    }
+
+    #[test]
+    fn redeem_and_unstake_pay_the_same_reward() {
+        setup();
+        let user = Pubkey::new_unique();
+        let (mut config, reward_accounts) = reward_pool(user);
+        config.duration_tiers = vec![duration_tier(500, 12_000)];
+        config.stake_dilution_divisor = 2;
+        config.total_active_stakes = 4;
+        let weighted = |mut position: Vec<TestAccount>| {
+            let mut account_data = position[1].stake_info();
+            account_data.stake_weight = LAMPORTS_PER_SOL * 3 / 4;
+            account_data.edition_boost_bps = 1_000;
+            position[1].set_stake_info(&account_data);
+            position
+        };
+        config.governance_mint = Pubkey::new_unique();
+        config.governance_boost_tiers = vec![GovernanceBoostTier {
+            min_balance: 1,
+            boost_bps: 1_000,
+        }];
+        config.protocol_fee_bps = 1_000;
+        config.withhold_protocol_fee = true;
+        config.fee_discount_tiers = vec![FeeDiscountTier {
+            min_duration: 500,
+            discount_bps: 500,
+        }];
+        let governance = token_account(Pubkey::new_unique(), config.governance_mint, user, 1);
+        let redeemed = weighted(staked_position(user, START - 1_001));
+        let unstaked = weighted(staked_position(user, START - 1_001));
+        let mut accounts = redeem_accounts(user, &config, reward_accounts, redeemed);
+        accounts.push(governance);
+        process_redeem(&PROGRAM_ID, &infos(&mut accounts), None, None).unwrap();
+        let redeem_reward = accounts[5].token_amount();
+
+        let governance = accounts.pop().unwrap();
+        let config = accounts[3].config();
+        let reward_accounts = accounts.split_off(4);
+        let mut accounts = unstake_accounts(user, &config, reward_accounts, unstaked);
+        accounts.push(governance);
+        process_unstake(&PROGRAM_ID, &infos(&mut accounts), None).unwrap();
+        let unstake_reward = accounts[7].token_amount() - redeem_reward;
+
+        // 1,001 seconds, halved, weighted by 3/4, boosted by 10% and 20%,
+        // then by the 10% governance tier, less the 5% discounted fee.
+        assert_eq!(redeem_reward, 517);
+        assert_eq!(unstake_reward, redeem_reward);
+    }
+
+    #[test]
+    fn a_config_batch_applies_every_change_or_none() {
+        setup();
+        let (config, _reward_accounts) = reward_pool(Pubkey::new_unique());
+        let mut accounts = vec![
+            TestAccount::wallet(config.admin, LAMPORTS_PER_SOL).signer(),
+            config_account(&config),
+        ];
+        let update = ConfigUpdate {
+            fields: vec![
+                ConfigField::EmissionSchedule(vec![segment(0, 5)]),
+                ConfigField::ProtocolFeeBps(250),
+            ],
+            paused: Some(true),
+        };
+        process_update_config_batch(&PROGRAM_ID, &infos(&mut accounts), update).unwrap();
+        let config = accounts[1].config();
+        assert_eq!(config.emission_schedule[0].rate, 5);
+        assert_eq!(config.protocol_fee_bps, 250);
+        assert_eq!(config.paused_since, START);
+
+        // The invalid fee fails the rate change and the resume with it.
+        let update = ConfigUpdate {
+            fields: vec![
+                ConfigField::EmissionSchedule(vec![segment(0, 7)]),
+                ConfigField::ProtocolFeeBps(MAX_BPS + 1),
+            ],
+            paused: Some(false),
+        };
+        assert_eq!(
+            process_update_config_batch(&PROGRAM_ID, &infos(&mut accounts), update),
+            Err(StakeError::InvalidFeeBps.into())
+        );
+        let config = accounts[1].config();
+        assert_eq!(config.emission_schedule[0].rate, 5);
+        assert_eq!(config.protocol_fee_bps, 250);
+        assert_eq!(config.paused_since, START);
+    }
+
+    #[test]
+    fn a_timelocked_config_accepts_changes_only_through_the_timelock() {
+        setup();
+        let timelock_program = Pubkey::new_unique();
+        let user = Pubkey::new_unique();
+        let (mut config, _reward_accounts) = reward_pool(user);
+        config.timelock_program = timelock_program;
+        let stake_state = staked_position(user, START).swap_remove(1);
+        let called_by = |program_id| {
+            instructions_sysvar(&[Instruction::new_with_bytes(program_id, &[], vec![])], 0)
+        };
+        let mut accounts = vec![
+            TestAccount::wallet(config.admin, LAMPORTS_PER_SOL).signer(),
+            config_account(&config),
+            stake_state,
+            called_by(PROGRAM_ID),
+        ];
+        let pause_accounts = |instructions_sysvar| {
+            vec![
+                accounts[0].clone(),
+                accounts[1].clone(),
+                instructions_sysvar,
+            ]
+        };
+
+        let mut direct = pause_accounts(called_by(PROGRAM_ID));
+        assert_eq!(
+            process_set_paused(&PROGRAM_ID, &infos(&mut direct), true),
+            Err(StakeError::TimelockRequired.into())
+        );
+        let mut through_timelock = pause_accounts(called_by(timelock_program));
+        process_set_paused(&PROGRAM_ID, &infos(&mut through_timelock), true).unwrap();
+        assert_eq!(through_timelock[1].config().paused_since, START);
+
+        assert_eq!(
+            process_set_rate_override(&PROGRAM_ID, &infos(&mut accounts), Some(5)),
+            Err(StakeError::TimelockRequired.into())
+        );
+        accounts[3] = called_by(timelock_program);
+        process_set_rate_override(&PROGRAM_ID, &infos(&mut accounts), Some(5)).unwrap();
+        assert_eq!(accounts[2].stake_info().rate_override(), Some(5));
+    }
+
+    /// The accounts of an admin instruction on a timelocked config: the admin,
+    /// the config, `accounts` and the instructions sysvar of a transaction
+    /// calling the program directly rather than through the timelock.
+    fn direct_admin_call(accounts: Vec<TestAccount>) -> Vec<TestAccount> {
+        setup();
+        let (mut config, _reward_accounts) = reward_pool(Pubkey::new_unique());
+        config.timelock_program = Pubkey::new_unique();
+        let mut admin_accounts = vec![
+            TestAccount::wallet(config.admin, LAMPORTS_PER_SOL).signer(),
+            config_account(&config),
+        ];
+        admin_accounts.extend(accounts);
+        admin_accounts.push(instructions_sysvar(
+            &[Instruction::new_with_bytes(PROGRAM_ID, &[], vec![])],
+            0,
+        ));
+        admin_accounts
+    }
+
+    #[test]
+    fn recover_stray_tokens_requires_the_timelock() {
+        let stray_mint = Pubkey::new_unique();
+        let mut accounts = direct_admin_call(vec![
+            token_account(
+                Pubkey::new_unique(),
+                stray_mint,
+                mint_authority_address(),
+                40,
+            ),
+            token_account(Pubkey::new_unique(), stray_mint, Pubkey::new_unique(), 0),
+            empty_account(mint_authority_address()),
+            TestAccount::program(spl_token::id()),
+        ]);
+        assert_eq!(
+            process_recover_stray_tokens(&PROGRAM_ID, &infos(&mut accounts)),
+            Err(StakeError::TimelockRequired.into())
+        );
+        assert_eq!(accounts[2].token_amount(), 40);
+    }
+
+    #[test]
+    fn fund_rent_reserve_requires_the_timelock() {
+        let rent_reserve = Pubkey::find_program_address(&[RENT_RESERVE_SEED], &PROGRAM_ID).0;
+        let mut accounts = direct_admin_call(vec![
+            empty_account(rent_reserve),
+            TestAccount::program(system_program::id()),
+        ]);
+        assert_eq!(
+            process_fund_rent_reserve(&PROGRAM_ID, &infos(&mut accounts), 100),
+            Err(StakeError::TimelockRequired.into())
+        );
+        assert_eq!(accounts[2].lamports, 0);
+    }
+
+    #[test]
+    fn fund_sol_pool_requires_the_timelock() {
+        let sol_pool = Pubkey::find_program_address(&[SOL_POOL_SEED], &PROGRAM_ID).0;
+        let mut accounts = direct_admin_call(vec![
+            empty_account(sol_pool),
+            TestAccount::program(system_program::id()),
+        ]);
+        assert_eq!(
+            process_fund_sol_pool(&PROGRAM_ID, &infos(&mut accounts), 100),
+            Err(StakeError::TimelockRequired.into())
+        );
+        assert_eq!(accounts[2].lamports, 0);
+    }
+
+    #[test]
+    fn migrate_config_requires_the_timelock() {
+        let mut accounts = direct_admin_call(vec![TestAccount::program(system_program::id())]);
+        let mut config = accounts[1].config();
+        config.version = StakeConfig::VERSION - 1;
+        accounts[1] = config_account(&config);
+        assert_eq!(
+            process_migrate_config(&PROGRAM_ID, &infos(&mut accounts)),
+            Err(StakeError::TimelockRequired.into())
+        );
+        assert_eq!(accounts[1].config().version, StakeConfig::VERSION - 1);
+    }
+
+    fn unstake_with_auto_redeem(auto_redeem_on_unstake: bool) -> Vec<TestAccount> {
+        setup();
+        let user = Pubkey::new_unique();
+        let (mut config, reward_accounts) = reward_pool(user);
+        config.auto_redeem_on_unstake = auto_redeem_on_unstake;
+        let position = staked_position(user, START - 100);
+        let mut accounts = unstake_accounts(user, &config, reward_accounts, position);
+        process_unstake(&PROGRAM_ID, &infos(&mut accounts), None).unwrap();
+        assert!(!accounts[2].stake_info().is_stake_active());
+        accounts
+    }
+
+    #[test]
+    fn unstake_pays_the_reward_with_auto_redeem() {
+        let accounts = unstake_with_auto_redeem(true);
+        assert_eq!(accounts[7].token_amount(), 100);
+        assert_eq!(accounts[2].stake_info().unclaimed_final_reward, 0);
+    }
+
+    #[test]
+    fn unstake_leaves_the_reward_to_claim_without_auto_redeem() {
+        let mut accounts = unstake_with_auto_redeem(false);
+        assert_eq!(accounts[7].token_amount(), 0);
+        let account_data = accounts[2].stake_info();
+        assert_eq!(account_data.unclaimed_final_reward, 100);
+        assert_eq!(account_data.final_reward_expires_at, UnixTimestamp::MAX);
+
+        // `ClaimFinalReward` takes the `Redeem` accounts.
+        let mut claim_accounts = accounts.drain(..3).collect::<Vec<_>>();
+        claim_accounts.extend(accounts.drain(2..));
+        set_time(START + 1_000);
+        process_claim_final_reward(&PROGRAM_ID, &infos(&mut claim_accounts)).unwrap();
+        assert_eq!(claim_accounts[5].token_amount(), 100);
+        assert_eq!(claim_accounts[2].stake_info().unclaimed_final_reward, 0);
+    }
+
+    #[test]
+    fn crossed_milestones_raise_a_multiplier_that_sticks() {
+        setup();
+        let user = Pubkey::new_unique();
+        let (mut config, reward_accounts) = reward_pool(user);
+        config.reward_milestones = vec![duration_tier(100, 1_000), duration_tier(200, 2_000)];
+        let position = staked_position(user, START);
+        let mut accounts = redeem_accounts(user, &config, reward_accounts, position);
+        let mut redeem_at = |now| {
+            set_time(now);
+            let before = accounts[5].token_amount();
+            process_redeem(&PROGRAM_ID, &infos(&mut accounts), None, None).unwrap();
+            let account_data = accounts[2].stake_info();
+            (
+                accounts[5].token_amount() - before,
+                account_data.milestone_multiplier_bps,
+            )
+        };
+
+        // A milestone applies from the window after it is crossed.
+        assert_eq!(redeem_at(START + 150), (150, 1_000));
+        assert_eq!(redeem_at(START + 250), (110, 2_000));
+        assert_eq!(redeem_at(START + 350), (120, 2_000));
+
+        // Dropping the milestones keeps the multiplier already reached.
+        config.reward_milestones = Vec::new();
+        accounts[3] = config_account(&config);
+        set_time(START + 450);
+        process_redeem(&PROGRAM_ID, &infos(&mut accounts), None, None).unwrap();
+        assert_eq!(accounts[5].token_amount(), 150 + 110 + 120 + 120);
+        assert_eq!(accounts[2].stake_info().milestone_multiplier_bps, 2_000);
+    }
+
+    /// `InitializeStakeAccount` accounts for a user holding `lamports` and a
+    /// fresh NFT token account of theirs.
+    fn initialize_accounts(user: Pubkey, lamports: u64) -> Vec<TestAccount> {
+        let nft_token_account = token_account(Pubkey::new_unique(), Pubkey::new_unique(), user, 1);
+        let (stake_state, _bump) = stake_address(&user, &nft_token_account.key());
+        vec![
+            TestAccount::wallet(user, lamports).signer(),
+            nft_token_account,
+            empty_account(stake_state),
+            TestAccount::program(system_program::id()),
+        ]
+    }
+
+    #[test]
+    fn initialize_requires_the_user_to_cover_the_rent() {
+        setup();
+        let rent_lamports = Rent::default().minimum_balance(UserStakeInfo::SIZE);
+        let mut accounts = initialize_accounts(Pubkey::new_unique(), rent_lamports - 1);
+        assert_eq!(
+            process_initialize_stake_account(&PROGRAM_ID, &infos(&mut accounts)),
+            Err(StakeError::InsufficientFundsForRent.into())
+        );
+        assert_eq!(accounts[2].lamports, 0);
+        assert_eq!(accounts[2].owner, system_program::id());
+
+        let mut accounts = initialize_accounts(Pubkey::new_unique(), rent_lamports);
+        process_initialize_stake_account(&PROGRAM_ID, &infos(&mut accounts)).unwrap();
+        assert_eq!(accounts[0].lamports, 0);
+        assert!(accounts[2].stake_info().is_initialized());
+    }
+
+    #[test]
+    fn telemetry_counters_advance_with_each_action() {
+        setup();
+        let user = Pubkey::new_unique();
+        let (mut config, reward_accounts) = reward_pool(user);
+        config.protocol_fee_bps = 1_000;
+        let mut accounts = stake_accounts(user, &config);
+        accounts.extend(reward_accounts);
+        let infos = infos(&mut accounts);
+        let pick =
+            |indices: &[usize]| -> Vec<_> { indices.iter().map(|&i| infos[i].clone()).collect() };
+        let counters = || {
+            let config = try_from_slice_unchecked::<StakeConfig>(&infos[5].data.borrow()).unwrap();
+            (
+                config.stake_count,
+                config.redeem_count,
+                config.unstake_count,
+                config.total_emitted,
+            )
+        };
+
+        process_stake(&PROGRAM_ID, &infos[..7]).unwrap();
+        assert_eq!(counters(), (1, 0, 0, 0));
+        set_time(START + 100);
+        process_redeem(
+            &PROGRAM_ID,
+            &pick(&[0, 1, 2, 5, 7, 8, 9, 10, 11]),
+            None,
+            None,
+        )
+        .unwrap();
+        // The protocol fee is minted on top and counts as emitted.
+        assert_eq!(counters(), (1, 1, 0, 110));
+        set_time(START + 200);
+        let unstake_accounts = pick(&[0, 1, 2, 3, 4, 5, 7, 8, 9, 10, 11]);
+        process_unstake(&PROGRAM_ID, &unstake_accounts, None).unwrap();
+        assert_eq!(counters(), (1, 1, 1, 220));
+    }
+
+    #[test]
+    fn redeems_mint_with_the_rotated_mint_authority() {
+        setup();
+        let user = Pubkey::new_unique();
+        let new_authority = Pubkey::new_unique();
+        let (config, reward_accounts) = reward_pool(user);
+        let position = staked_position(user, START - 100);
+        let mut accounts = redeem_accounts(user, &config, reward_accounts, position);
+        accounts.push(TestAccount::wallet(config.admin, LAMPORTS_PER_SOL).signer());
+        accounts.push(TestAccount::wallet(user, LAMPORTS_PER_SOL).signer());
+        {
+            let infos = infos(&mut accounts);
+            let pick = |indices: &[usize]| -> Vec<_> {
+                indices.iter().map(|&i| infos[i].clone()).collect()
+            };
+            // Only the admin may rotate.
+            assert_eq!(
+                process_rotate_mint_authority(&PROGRAM_ID, &pick(&[10, 3, 4, 7, 8]), new_authority),
+                Err(StakeError::Unauthorized.into())
+            );
+            process_rotate_mint_authority(&PROGRAM_ID, &pick(&[9, 3, 4, 7, 8]), new_authority)
+                .unwrap();
+            assert_eq!(
+                process_redeem(&PROGRAM_ID, &infos[..9], None, None),
+                Err(StakeError::InvalidPda.into())
+            );
+        }
+        let mint = Mint::unpack(accounts[4].data()).unwrap();
+        assert_eq!(mint.mint_authority, COption::Some(new_authority));
+        assert_eq!(accounts[3].config().mint_authority, new_authority);
+
+        accounts[7] = TestAccount::wallet(new_authority, 0).signer();
+        process_redeem(&PROGRAM_ID, &infos(&mut accounts[..9]), None, None).unwrap();
+        assert_eq!(accounts[5].token_amount(), 100);
+    }
+
+    /// An account at `key` holding a serialized `Clock` at `unix_timestamp`.
+    fn clock_account(key: Pubkey, unix_timestamp: UnixTimestamp) -> TestAccount {
+        let mut account = TestAccount::new(key, sysvar::id(), 1, &vec![0; Clock::size_of()]);
+        let clock = Clock {
+            unix_timestamp,
+            ..Clock::default()
+        };
+        clock.to_account_info(&mut account.info()).unwrap();
+        account
+    }
+
+    #[test]
+    fn current_time_reads_a_passed_clock_sysvar_and_falls_back_to_get() {
+        setup();
+        let mut accounts = vec![TestAccount::wallet(Pubkey::new_unique(), 0)];
+        assert_eq!(
+            current_time(&infos(&mut accounts)).unwrap().unix_timestamp,
+            START
+        );
+
+        // Clock data at any other address is ignored.
+        accounts.push(clock_account(Pubkey::new_unique(), START + 50));
+        assert_eq!(
+            current_time(&infos(&mut accounts)).unwrap().unix_timestamp,
+            START
+        );
+
+        accounts.push(clock_account(sysvar::clock::id(), START + 100));
+        assert_eq!(
+            current_time(&infos(&mut accounts)).unwrap().unix_timestamp,
+            START + 100
+        );
+    }
+
+    #[test]
+    fn every_bounded_config_list_takes_its_max_and_no_more() {
+        let (mut config, _reward_accounts) = reward_pool(Pubkey::new_unique());
+        /// A valid field setting a list of the given length.
+        type FieldOfLen = fn(usize) -> ConfigField;
+        let fields: [(usize, FieldOfLen); 7] = [
+            (MAX_EMISSION_SEGMENTS, |n| {
+                ConfigField::EmissionSchedule((0..n as i64).map(|i| segment(i, 1)).collect())
+            }),
+            (MAX_GOVERNANCE_BOOST_TIERS, |n| {
+                ConfigField::GovernanceBoostTiers(
+                    (0..n as u64)
+                        .map(|i| GovernanceBoostTier {
+                            min_balance: i,
+                            boost_bps: 100,
+                        })
+                        .collect(),
+                )
+            }),
+            (MAX_DURATION_TIERS, |n| {
+                ConfigField::DurationTiers((0..n as i64).map(|i| duration_tier(i, 100)).collect())
+            }),
+            (MAX_DURATION_TIERS, |n| {
+                ConfigField::RewardMilestones(
+                    (0..n as i64).map(|i| duration_tier(i, 100)).collect(),
+                )
+            }),
+            (MAX_FEE_DISCOUNT_TIERS, |n| {
+                ConfigField::FeeDiscountTiers(
+                    (0..n as i64)
+                        .map(|i| FeeDiscountTier {
+                            min_duration: i,
+                            discount_bps: 100,
+                        })
+                        .collect(),
+                )
+            }),
+            (MAX_WRAPPED_MINT_AUTHORITIES, |n| {
+                ConfigField::WrappedMintAuthorities(vec![Pubkey::new_unique(); n])
+            }),
+            (MAX_DELEGATE_STAKE_AUTHORITIES, |n| {
+                ConfigField::DelegateStakeAuthorities(vec![Pubkey::new_unique(); n])
+            }),
+        ];
+        for (max, field) in fields {
+            assert_eq!(
+                apply_config_field(&mut config, field(max + 1), START),
+                Err(StakeError::TooManyEntries.into())
+            );
+            apply_config_field(&mut config, field(max), START).unwrap();
+        }
+        config.pause_intervals = vec![PauseInterval { start: 0, end: 0 }; MAX_PAUSE_INTERVALS];
+
+        // Every list full still fits the space reserved for the config.
+        assert!(config.try_to_vec().unwrap().len() <= StakeConfig::SIZE);
+    }
+
+    #[test]
+    fn close_inactive_stakes_refunds_the_combined_rent() {
+        setup();
+        let user = Pubkey::new_unique();
+        let inactive_stake = || stake_account(&new_stake_info(user, Pubkey::new_unique()));
+        let mut accounts = vec![TestAccount::wallet(user, 0).signer()];
+        accounts.extend((0..3).map(|_| inactive_stake()));
+        let rent: u64 = accounts[1..].iter().map(|account| account.lamports).sum();
+
+        process_close_inactive_stakes(&PROGRAM_ID, &infos(&mut accounts)).unwrap();
+        assert_eq!(accounts[0].lamports, rent);
+        assert!(accounts[1..].iter().all(|account| account.lamports == 0));
+    }
+
+    #[test]
+    fn close_inactive_stakes_rejects_an_active_stake() {
+        setup();
+        let user = Pubkey::new_unique();
+        let active_stake = staked_position(user, START).swap_remove(1);
+        let inactive_stake = stake_account(&new_stake_info(user, Pubkey::new_unique()));
+        let mut accounts = vec![
+            TestAccount::wallet(user, 0).signer(),
+            active_stake,
+            inactive_stake,
+        ];
+        assert_eq!(
+            process_close_inactive_stakes(&PROGRAM_ID, &infos(&mut accounts)),
+            Err(StakeError::StakeStillActive.into())
+        );
+        assert_eq!(accounts[0].lamports, 0);
+    }
+
+    #[test]
+    fn a_tripped_circuit_breaker_blocks_staking_and_redeeming_until_reset() {
+        setup();
+        let user = Pubkey::new_unique();
+        let guardian = Pubkey::new_unique();
+        let circuit_breaker = Pubkey::new_unique();
+        let (mut config, reward_accounts) = reward_pool(user);
+        config.circuit_breaker = circuit_breaker;
+        let breaker = |tripped| {
+            let data = CircuitBreaker { tripped }.try_to_vec().unwrap();
+            TestAccount::with_data(circuit_breaker, guardian, &data)
+        };
+        let mut stake = stake_accounts(user, &config);
+        stake.push(breaker(true));
+        let position = staked_position(user, START - 100);
+        let mut redeem = redeem_accounts(user, &config, reward_accounts, position);
+        redeem.push(breaker(true));
+
+        assert_eq!(
+            process_stake(&PROGRAM_ID, &infos(&mut stake)),
+            Err(StakeError::CircuitBreakerTripped.into())
+        );
+        assert_eq!(
+            process_redeem(&PROGRAM_ID, &infos(&mut redeem), None, None),
+            Err(StakeError::CircuitBreakerTripped.into())
+        );
+
+        stake[7] = breaker(false);
+        redeem[9] = breaker(false);
+        process_stake(&PROGRAM_ID, &infos(&mut stake)).unwrap();
+        assert!(stake[2].stake_info().is_stake_active());
+        process_redeem(&PROGRAM_ID, &infos(&mut redeem), None, None).unwrap();
+        assert_eq!(redeem[5].token_amount(), 100);
+    }
+
+    /// What redeeming a stake accruing 3/8 of a unit a second at each of
+    /// `redeem_at` pays in total, with the fraction it carries forward.
+    fn redeem_three_eighths_per_second(redeem_at: impl Iterator<Item = i64>) -> (u64, u64) {
+        setup();
+        let user = Pubkey::new_unique();
+        let (config, reward_accounts) = reward_pool(user);
+        let mut position = staked_position(user, START);
+        let mut account_data = position[1].stake_info();
+        account_data.stake_weight = LAMPORTS_PER_SOL * 3 / 8;
+        position[1].set_stake_info(&account_data);
+        let mut accounts = redeem_accounts(user, &config, reward_accounts, position);
+        for elapsed in redeem_at {
+            set_time(START + elapsed);
+            process_redeem(&PROGRAM_ID, &infos(&mut accounts), None, None).unwrap();
+        }
+        (
+            accounts[5].token_amount(),
+            accounts[2].stake_info().reward_remainder,
+        )
+    }
+
+    #[test]
+    fn many_tiny_redeems_pay_what_one_large_redeem_does() {
+        let one_large_redeem = redeem_three_eighths_per_second(std::iter::once(300));
+        assert_eq!(one_large_redeem, (112, REWARD_PRECISION / 2));
+        assert_eq!(redeem_three_eighths_per_second(1..=300), one_large_redeem);
+    }
+
+    /// ed25519 program instruction data verifying `signer`'s signature over
+    /// `message`, with the key, signature and message in the data itself.
+    fn ed25519_proof(signer: &Pubkey, message: &[u8]) -> Vec<u8> {
+        const DATA_START: u16 = 2 + 14;
+        let this_instruction = u16::MAX;
+        let offsets = [
+            DATA_START + 32,
+            this_instruction,
+            DATA_START,
+            this_instruction,
+            DATA_START + 32 + 64,
+            message.len() as u16,
+            this_instruction,
+        ];
+        let mut data = vec![1, 0];
+        data.extend(offsets.iter().flat_map(|offset| offset.to_le_bytes()));
+        data.extend_from_slice(signer.as_ref());
+        data.extend_from_slice(&[0; 64]);
+        data.extend_from_slice(message);
+        data
+    }
+
+    /// The instructions sysvar of a transaction where an ed25519 instruction
+    /// with `proof` data precedes `SubmitOwnershipProof`.
+    fn proof_transaction(proof: Vec<u8>) -> TestAccount {
+        let verify = Instruction::new_with_bytes(ed25519_program::id(), &proof, vec![]);
+        let submit = Instruction::new_with_bytes(PROGRAM_ID, &[31], vec![]);
+        instructions_sysvar(&[verify, submit], 1)
+    }
+
+    /// `SubmitOwnershipProof` accounts for an NFT `owner` holds on behalf of
+    /// the user, up to the proof account.
+    fn ownership_proof_accounts(owner: Pubkey) -> Vec<TestAccount> {
+        let user = Pubkey::new_unique();
+        let nft_token_account = token_account(Pubkey::new_unique(), Pubkey::new_unique(), owner, 1);
+        let stake_state = stake_account(&new_stake_info(user, nft_token_account.key()));
+        vec![
+            TestAccount::wallet(user, LAMPORTS_PER_SOL).signer(),
+            nft_token_account,
+            stake_state,
+        ]
+    }
+
+    fn proof_message(stake_state: Pubkey, owner: Pubkey, slot: u64) -> Vec<u8> {
+        [stake_state.as_ref(), owner.as_ref(), &slot.to_le_bytes()].concat()
+    }
+
+    #[test]
+    fn an_ownership_proof_signed_by_the_owner_for_a_recent_slot_is_recorded() {
+        setup();
+        let owner = Pubkey::new_unique();
+        let mut accounts = ownership_proof_accounts(owner);
+        let message = proof_message(accounts[2].key(), owner, START_SLOT - 10);
+        accounts.push(proof_transaction(ed25519_proof(&owner, &message)));
+
+        process_submit_ownership_proof(&PROGRAM_ID, &infos(&mut accounts)).unwrap();
+        let account_data = accounts[2].stake_info();
+        assert!(account_data.is_proof_verified_for(&owner));
+        assert_eq!(account_data.proof_slot, START_SLOT - 10);
+
+        // The same proof cannot be submitted again, only a later one.
+        assert_eq!(
+            process_submit_ownership_proof(&PROGRAM_ID, &infos(&mut accounts)),
+            Err(StakeError::InvalidProof.into())
+        );
+        let message = proof_message(accounts[2].key(), owner, START_SLOT);
+        accounts[3] = proof_transaction(ed25519_proof(&owner, &message));
+        process_submit_ownership_proof(&PROGRAM_ID, &infos(&mut accounts)).unwrap();
+        assert_eq!(accounts[2].stake_info().proof_slot, START_SLOT);
+    }
+
+    #[test]
+    fn an_ownership_proof_by_another_key_or_for_another_message_is_rejected() {
+        setup();
+        let owner = Pubkey::new_unique();
+        let accounts = ownership_proof_accounts(owner);
+        let stake_state = accounts[2].key();
+        let invalid_proofs = [
+            ed25519_proof(
+                &Pubkey::new_unique(),
+                &proof_message(stake_state, owner, START_SLOT),
+            ),
+            ed25519_proof(
+                &owner,
+                &proof_message(Pubkey::new_unique(), owner, START_SLOT),
+            ),
+            ed25519_proof(&owner, stake_state.as_ref()),
+            ed25519_proof(
+                &owner,
+                &proof_message(stake_state, owner, START_SLOT - MAX_PROOF_AGE_SLOTS - 1),
+            ),
+            ed25519_proof(&owner, &proof_message(stake_state, owner, START_SLOT + 1)),
+        ];
+        for proof in invalid_proofs {
+            let mut accounts = accounts.clone();
+            accounts.push(proof_transaction(proof));
+            assert_eq!(
+                process_submit_ownership_proof(&PROGRAM_ID, &infos(&mut accounts)),
+                Err(StakeError::InvalidProof.into())
+            );
+            assert!(!accounts[2].stake_info().is_proof_verified());
+        }
+    }
+
+    #[test]
+    fn a_pda_owner_proves_ownership_by_signing_through_cpi() {
+        setup();
+        let owner = Pubkey::new_unique();
+        let mut accounts = ownership_proof_accounts(owner);
+        accounts.push(TestAccount::wallet(owner, 0));
+        assert_eq!(
+            process_submit_ownership_proof(&PROGRAM_ID, &infos(&mut accounts)),
+            Err(ProgramError::MissingRequiredSignature)
+        );
+
+        accounts[3].is_signer = true;
+        process_submit_ownership_proof(&PROGRAM_ID, &infos(&mut accounts)).unwrap();
+        assert!(accounts[2].stake_info().is_proof_verified_for(&owner));
+    }
+
+    #[test]
+    fn a_proven_owner_lets_the_user_stake_the_nft_until_unstaked() {
+        setup();
+        let user = Pubkey::new_unique();
+        let owner = Pubkey::new_unique();
+        let (config, reward_accounts) = reward_pool(user);
+        let mut accounts = stake_accounts(user, &config);
+        let nft_mint = Pubkey::new_unique();
+        accounts[1] = token_account(accounts[1].key(), nft_mint, owner, 1);
+        accounts[3] = empty_account(staked_mint_address(&nft_mint));
+        assert_eq!(
+            process_stake(&PROGRAM_ID, &infos(&mut accounts)),
+            Err(StakeError::InvalidStakeAuthority.into())
+        );
+
+        let mut account_data = accounts[2].stake_info();
+        account_data.set_proof_verified(true);
+        account_data.proof_owner = owner;
+        accounts[2].set_stake_info(&account_data);
+        process_stake(&PROGRAM_ID, &infos(&mut accounts)).unwrap();
+        assert!(accounts[2].stake_info().is_stake_active());
+
+        accounts.truncate(6);
+        accounts.extend(reward_accounts);
+        process_unstake(&PROGRAM_ID, &infos(&mut accounts), None).unwrap();
+        assert!(!accounts[2].stake_info().is_proof_verified());
+    }
+
+    #[test]
+    fn total_redeemed_saturates_unless_strict() {
+        let user = Pubkey::new_unique();
+        let (mut config, _reward_accounts) = reward_pool(user);
+        let nearly_saturated = || {
+            let mut account_data =
+                active_stake_info(user, Pubkey::new_unique(), Pubkey::new_unique(), START);
+            account_data.total_redeemed = u64::MAX - 10;
+            account_data
+        };
+
+        config.strict_total_redeemed = true;
+        assert_eq!(
+            apply_reward(
+                &mut nearly_saturated(),
+                START + 100,
+                &config,
+                Settlement::Pay
+            ),
+            Err(StakeError::TotalRedeemedSaturated.into())
+        );
+
+        config.strict_total_redeemed = false;
+        let mut account_data = nearly_saturated();
+        assert_eq!(
+            apply_reward(&mut account_data, START + 100, &config, Settlement::Pay),
+            Ok(100)
+        );
+        assert_eq!(account_data.total_redeemed, u64::MAX);
+        assert_eq!(account_data.last_redeem_time, START + 100);
+    }
+
+    #[test]
+    fn only_spl_token_or_token_2022_can_be_pinned() {
+        let (mut config, _reward_accounts) = reward_pool(Pubkey::new_unique());
+        assert_eq!(
+            apply_config_field(
+                &mut config,
+                ConfigField::TokenProgram(Pubkey::new_unique()),
+                START
+            ),
+            Err(StakeError::WrongTokenProgram.into())
+        );
+        for token_program in [TOKEN_2022_PROGRAM_ID, spl_token::id(), Pubkey::default()] {
+            apply_config_field(&mut config, ConfigField::TokenProgram(token_program), START)
+                .unwrap();
+            assert_eq!(config.token_program, token_program);
+        }
+    }
+
+    #[test]
+    fn redeem_rejects_a_token_program_other_than_the_pinned_one() {
+        setup();
+        let user = Pubkey::new_unique();
+        let (mut config, reward_accounts) = reward_pool(user);
+        config.token_program = TOKEN_2022_PROGRAM_ID;
+        let position = staked_position(user, START - 100);
+        let mut accounts = redeem_accounts(user, &config, reward_accounts, position);
+        assert_eq!(
+            process_redeem(&PROGRAM_ID, &infos(&mut accounts), None, None),
+            Err(StakeError::WrongTokenProgram.into())
+        );
+    }
+
+    #[test]
+    fn rewards_are_minted_through_a_pinned_token_2022() {
+        setup();
+        mock_program(TOKEN_2022_PROGRAM_ID, |_, _| Ok(()));
+        let user = Pubkey::new_unique();
+        let (mut config, reward_accounts) = reward_pool(user);
+        config.token_program = TOKEN_2022_PROGRAM_ID;
+        let position = staked_position(user, START - 100);
+        let mut accounts = redeem_accounts(user, &config, reward_accounts, position);
+        accounts[8] = TestAccount::program(TOKEN_2022_PROGRAM_ID);
+
+        process_redeem(&PROGRAM_ID, &infos(&mut accounts), None, None).unwrap();
+        let mint_to = invoked().pop().unwrap();
+        assert_eq!(mint_to.program_id, TOKEN_2022_PROGRAM_ID);
+        assert_eq!(mint_to.accounts[0].pubkey, accounts[4].key());
+        assert_eq!(mint_to.accounts[1].pubkey, accounts[5].key());
+    }
+
+    #[test]
+    fn a_token_2022_nft_with_extensions_stakes() {
+        setup();
+        let user = Pubkey::new_unique();
+        let (config, _reward_accounts) = reward_pool(user);
+        let mut accounts = stake_accounts(user, &config);
+        // The account type byte and an extension after the base state.
+        let data = [accounts[1].data(), &[2, 0, 0, 0, 0]].concat();
+        accounts[1] = TestAccount::with_data(accounts[1].key(), TOKEN_2022_PROGRAM_ID, &data);
+
+        process_stake(&PROGRAM_ID, &infos(&mut accounts)).unwrap();
+        assert!(accounts[2].stake_info().is_stake_active());
+    }
+
+    /// `ReconcileActiveFlag` accounts, signed by `authority`, for a stake of
+    /// `user`'s whose NFT has since moved to another wallet.
+    fn moved_nft_accounts(
+        user: Pubkey,
+        authority: Pubkey,
+        config: &StakeConfig,
+    ) -> Vec<TestAccount> {
+        let position = staked_position(user, START - 100);
+        let mut accounts = unstake_accounts(user, config, Vec::new(), position);
+        let nft_mint = accounts[2].stake_info().nft_mint;
+        accounts[0] = TestAccount::wallet(authority, LAMPORTS_PER_SOL).signer();
+        accounts[1] = token_account(accounts[1].key(), nft_mint, Pubkey::new_unique(), 1);
+        accounts
+    }
+
+    #[test]
+    fn the_user_or_the_admin_reconciles_a_stake_whose_nft_moved() {
+        setup();
+        let user = Pubkey::new_unique();
+        let (mut config, _reward_accounts) = reward_pool(user);
+        config.total_active_stakes = 2;
+        for authority in [user, config.admin] {
+            let mut accounts = moved_nft_accounts(user, authority, &config);
+            process_reconcile_active_flag(&PROGRAM_ID, &infos(&mut accounts)).unwrap();
+            let account_data = accounts[2].stake_info();
+            assert!(!account_data.is_stake_active());
+            assert_eq!(account_data.last_unstake_time, START);
+            assert_eq!(accounts[3].staked_mint().stake_state, Pubkey::default());
+            assert_eq!(accounts[3].staked_mint().last_unstake_time, START);
+            assert_eq!(accounts[4].user_stakes().active_stakes, 0);
+            assert_eq!(accounts[5].config().total_active_stakes, 1);
+        }
+    }
+
+    #[test]
+    fn reconcile_active_flag_rejects_a_held_nft_and_other_signers() {
+        setup();
+        let user = Pubkey::new_unique();
+        let (config, _reward_accounts) = reward_pool(user);
+        let mut accounts = moved_nft_accounts(user, Pubkey::new_unique(), &config);
+        assert_eq!(
+            process_reconcile_active_flag(&PROGRAM_ID, &infos(&mut accounts)),
+            Err(StakeError::Unauthorized.into())
+        );
+
+        let mut accounts = moved_nft_accounts(user, user, &config);
+        let nft_mint = accounts[2].stake_info().nft_mint;
+        accounts[1] = token_account(accounts[1].key(), nft_mint, user, 1);
+        assert_eq!(
+            process_reconcile_active_flag(&PROGRAM_ID, &infos(&mut accounts)),
+            Err(ProgramError::InvalidArgument)
+        );
+        assert!(accounts[2].stake_info().is_stake_active());
+    }
+
+    /// What `GetRewardBreakdown` reports for a stake staked 1,001 seconds ago,
+    /// under a config edited by `configure` and with the stake edited by
+    /// `edit`, and what redeeming it right after pays.
+    fn breakdown_then_redeem(
+        configure: fn(&mut StakeConfig),
+        edit: fn(&mut UserStakeInfo),
+    ) -> (RewardBreakdown, u64) {
+        setup();
+        let user = Pubkey::new_unique();
+        let (mut config, reward_accounts) = reward_pool(user);
+        configure(&mut config);
+        let mut position = staked_position(user, START - 1_001);
+        let mut account_data = position[1].stake_info();
+        edit(&mut account_data);
+        position[1].set_stake_info(&account_data);
+        let mut accounts = redeem_accounts(user, &config, reward_accounts, position);
+
+        let stake_and_config = infos(&mut accounts)[2..4].to_vec();
+        process_get_reward_breakdown(&PROGRAM_ID, &stake_and_config).unwrap();
+        let breakdown = try_from_slice_unchecked(&return_data().unwrap().1).unwrap();
+        process_redeem(&PROGRAM_ID, &infos(&mut accounts), None, None).unwrap();
+        (breakdown, accounts[5].token_amount())
+    }
+
+    #[test]
+    fn the_reward_breakdown_adds_up_to_what_a_redeem_pays() {
+        let (breakdown, paid) = breakdown_then_redeem(|_| {}, |_| {});
+        assert_eq!(
+            (breakdown.window_from, breakdown.window_to),
+            (START - 1_001, START)
+        );
+        assert_eq!(breakdown.duration_tier_bps, None);
+        assert_eq!((breakdown.reward, paid), (1_001, 1_001));
+
+        let (breakdown, paid) = breakdown_then_redeem(
+            |config| {
+                config.duration_tiers = vec![duration_tier(500, 12_000)];
+                config.stake_dilution_divisor = 2;
+                config.total_active_stakes = 4;
+            },
+            |account_data| {
+                account_data.stake_weight = LAMPORTS_PER_SOL * 3 / 4;
+                account_data.edition_boost_bps = 1_000;
+            },
+        );
+        assert_eq!(breakdown.stake_weight, LAMPORTS_PER_SOL * 3 / 4);
+        assert_eq!(breakdown.edition_boost_bps, 1_000);
+        assert_eq!(breakdown.duration_tier_bps, Some(12_000));
+        assert_eq!((breakdown.reward, paid), (495, 495));
+
+        let (breakdown, paid) = breakdown_then_redeem(
+            |_| {},
+            |account_data| account_data.set_rate_override(Some(3)),
+        );
+        assert_eq!(breakdown.rate_override, Some(3));
+        assert_eq!((breakdown.reward, paid), (3_003, 3_003));
+
+        let (breakdown, paid) =
+            breakdown_then_redeem(|config| config.max_backpay_seconds = 100, |_| {});
+        assert!(breakdown.backpay_capped);
+        assert_eq!((breakdown.reward, paid), (100, 100));
+
+        let (breakdown, paid) = breakdown_then_redeem(|config| config.reward_cliff = 2_000, |_| {});
+        assert!(breakdown.before_cliff);
+        assert_eq!((breakdown.reward, paid), (0, 0));
+    }
+
+    #[test]
+    fn points_mode_accrues_points_without_any_token_cpi() {
+        setup();
+        let user = Pubkey::new_unique();
+        let (mut config, reward_accounts) = reward_pool(user);
+        config.accrual_mode = AccrualMode::Points;
+        let position = staked_position(user, START - 100);
+        let mut accounts = redeem_accounts(user, &config, reward_accounts, position);
+
+        process_redeem(&PROGRAM_ID, &infos(&mut accounts), None, None).unwrap();
+        set_time(START + 50);
+        process_redeem(&PROGRAM_ID, &infos(&mut accounts), None, None).unwrap();
+        assert_eq!(accounts[2].stake_info().points, 150);
+        assert_eq!(accounts[3].config().total_points, 150);
+        assert_eq!(accounts[5].token_amount(), 0);
+        assert!(invoked().is_empty());
+    }
+
+    #[test]
+    fn points_convert_to_tokens_at_the_configured_rate_once() {
+        setup();
+        let user = Pubkey::new_unique();
+        let (mut config, reward_accounts) = reward_pool(user);
+        config.accrual_mode = AccrualMode::Points;
+        config.points_to_token_rate = REWARD_PRECISION * 3 / 2;
+        config.total_points = 1_000;
+        let mut position = staked_position(user, START - 100);
+        let mut account_data = position[1].stake_info();
+        account_data.points = 1_000;
+        position[1].set_stake_info(&account_data);
+        let mut accounts = redeem_accounts(user, &config, reward_accounts, position);
+
+        process_convert_points_to_tokens(&PROGRAM_ID, &infos(&mut accounts)).unwrap();
+        assert_eq!(accounts[5].token_amount(), 1_500);
+        let account_data = accounts[2].stake_info();
+        assert_eq!(account_data.points, 0);
+        assert!(account_data.is_points_converted());
+        assert_eq!(accounts[3].config().total_points, 0);
+
+        // Points earned afterwards stay points.
+        process_redeem(&PROGRAM_ID, &infos(&mut accounts), None, None).unwrap();
+        assert_eq!(accounts[2].stake_info().points, 100);
+        assert_eq!(
+            process_convert_points_to_tokens(&PROGRAM_ID, &infos(&mut accounts)),
+            Err(StakeError::PointsAlreadyConverted.into())
+        );
+        assert_eq!(accounts[5].token_amount(), 1_500);
+    }
+
+    #[test]
+    fn rewards_are_redeemable_only_once_the_minimum_set_is_staked() {
+        setup();
+        let user = Pubkey::new_unique();
+        let (mut config, reward_accounts) = reward_pool(user);
+        config.min_nfts_to_qualify = 2;
+        let position = staked_position(user, START - 100);
+        let mut accounts = redeem_accounts(user, &config, reward_accounts, position);
+        assert_eq!(
+            process_redeem(&PROGRAM_ID, &infos(&mut accounts), None, None),
+            Err(ProgramError::NotEnoughAccountKeys)
+        );
+
+        accounts.push(user_stake_account(user, 1));
+        assert_eq!(
+            process_redeem(&PROGRAM_ID, &infos(&mut accounts), None, None),
+            Err(StakeError::BelowMinimumSetSize.into())
+        );
+        assert_eq!(accounts[5].token_amount(), 0);
+
+        accounts[9] = user_stake_account(user, 2);
+        process_redeem(&PROGRAM_ID, &infos(&mut accounts), None, None).unwrap();
+        assert_eq!(accounts[5].token_amount(), 100);
+    }
+
+    #[test]
+    fn only_time_with_the_minimum_set_staked_is_paid() {
+        setup();
+        let user = Pubkey::new_unique();
+        let (mut config, reward_accounts) = reward_pool(user);
+        config.min_nfts_to_qualify = 2;
+        let first = staked_position(user, START);
+
+        // The second stake completes the set 100 seconds into the first.
+        set_time(START + 100);
+        let mut stake = stake_accounts(user, &config);
+        stake[4] = user_stake_account(user, 1);
+        process_stake(&PROGRAM_ID, &infos(&mut stake)).unwrap();
+        assert_eq!(stake[4].user_stakes().qualified_since, START + 100);
+        let config = stake[5].config();
+        let user_stakes = stake.remove(4);
+        let second = stake.drain(1..4).collect::<Vec<_>>();
+
+        set_time(START + 150);
+        let mut accounts = redeem_accounts(user, &config, reward_accounts, first.clone());
+        accounts.push(user_stakes);
+        process_redeem(&PROGRAM_ID, &infos(&mut accounts), None, None).unwrap();
+        assert_eq!(accounts[5].token_amount(), 50);
+
+        // Unstaking the second stake breaks the set again.
+        set_time(START + 200);
+        let user_stakes = accounts.pop().unwrap();
+        let first = vec![accounts[1].clone(), accounts[2].clone(), first[2].clone()];
+        let config = accounts[3].config();
+        let reward_accounts = accounts.split_off(4);
+        let mut accounts = unstake_batch_accounts(user, &config, reward_accounts, 2, vec![second]);
+        accounts[1] = user_stakes;
+        process_unstake_batch(&PROGRAM_ID, &infos(&mut accounts)).unwrap();
+        assert_eq!(accounts[4].token_amount(), 50 + 100);
+        assert_eq!(accounts[1].user_stakes().qualified_until, START + 200);
+
+        // The first stake is paid up to then, not for the 100 seconds since.
+        set_time(START + 300);
+        accounts.truncate(accounts.len() - 3);
+        accounts.extend(first);
+        process_unstake_batch(&PROGRAM_ID, &infos(&mut accounts)).unwrap();
+        assert_eq!(accounts[4].token_amount(), 50 + 100 + 50);
+    }
+
+    fn preview_reward(config: &StakeConfig, duration_seconds: i64) -> u64 {
+        setup();
+        let mut accounts = vec![config_account(config)];
+        process_preview_reward(&PROGRAM_ID, &infos(&mut accounts), duration_seconds).unwrap();
+        u64::from_le_bytes(return_data().unwrap().1.try_into().unwrap())
+    }
+
+    #[test]
+    fn preview_reward_projects_a_stake_of_the_given_duration() {
+        let (mut config, _reward_accounts) = reward_pool(Pubkey::new_unique());
+        assert_eq!(preview_reward(&config, 0), 0);
+        assert_eq!(preview_reward(&config, 1_000), 1_000);
+        assert_eq!(preview_reward(&config, SECONDS_PER_DAY), 86_400);
+
+        // 100 seconds at 1 per second, then 3 per second.
+        config.emission_schedule = vec![segment(0, 1), segment(START + 100, 3)];
+        assert_eq!(preview_reward(&config, 1_000), 100 + 900 * 3);
+        config.emission_schedule.clear();
+
+        config.duration_tiers = vec![duration_tier(500, 12_000)];
+        assert_eq!(preview_reward(&config, 499), 499);
+        assert_eq!(preview_reward(&config, 1_000), 1_200);
+        config.duration_tiers.clear();
+
+        config.reward_cliff = 200;
+        assert_eq!(preview_reward(&config, 199), 0);
+        assert_eq!(preview_reward(&config, 1_000), 800);
+        config.reward_cliff = 0;
+
+        config.max_backpay_seconds = 300;
+        assert_eq!(preview_reward(&config, 1_000), 300);
+
+        let mut accounts = vec![config_account(&config)];
+        assert_eq!(
+            process_preview_reward(&PROGRAM_ID, &infos(&mut accounts), -1),
+            Err(ProgramError::InvalidArgument)
+        );
+    }
+
+    fn mock_allow(_instruction: &Instruction, _accounts: &[AccountInfo]) -> ProgramResult {
+        set_return_data(&[1]);
+        Ok(())
+    }
+
+    fn mock_deny(_instruction: &Instruction, _accounts: &[AccountInfo]) -> ProgramResult {
+        set_return_data(&[0]);
+        Ok(())
+    }
+
+    /// Redeems 100 seconds of reward with `compliance` configured and passed as
+    /// the compliance program, returning what the user was paid.
+    fn redeem_checked_by(compliance: MockProgram) -> Result<u64, ProgramError> {
+        setup();
+        let compliance_program = Pubkey::new_unique();
+        mock_program(compliance_program, compliance);
+        let user = Pubkey::new_unique();
+        let (mut config, reward_accounts) = reward_pool(user);
+        config.compliance_program = compliance_program;
+        let position = staked_position(user, START - 100);
+        let mut accounts = redeem_accounts(user, &config, reward_accounts, position);
+        accounts.push(TestAccount::program(compliance_program));
+        process_redeem(&PROGRAM_ID, &infos(&mut accounts), None, None)?;
+        let compliance_check = invoked().remove(0);
+        assert_eq!(compliance_check.program_id, compliance_program);
+        assert_eq!(compliance_check.accounts[0].pubkey, user);
+        Ok(accounts[5].token_amount())
+    }
+
+    #[test]
+    fn a_redeem_the_compliance_program_allows_pays() {
+        assert_eq!(redeem_checked_by(mock_allow), Ok(100));
+    }
+
+    #[test]
+    fn a_redeem_the_compliance_program_denies_fails() {
+        assert_eq!(
+            redeem_checked_by(mock_deny),
+            Err(StakeError::ComplianceCheckFailed.into())
+        );
+        // A program setting no return data denies too.
+        assert_eq!(
+            redeem_checked_by(|_, _| Ok(())),
+            Err(StakeError::ComplianceCheckFailed.into())
+        );
+    }
+
+    #[test]
+    fn migrating_the_config_mid_stake_keeps_accrual_per_second() {
+        setup();
+        let user = Pubkey::new_unique();
+        let (mut config, reward_accounts) = reward_pool(user);
+        // Version 0 configs predate `prorate_partial_days` and accrued per
+        // second.
+        config.version = 0;
+        config.prorate_partial_days = false;
+        let position = staked_position(user, START - 1_000);
+        let mut accounts = redeem_accounts(user, &config, reward_accounts, position);
+
+        set_time(START - 500);
+        process_redeem(&PROGRAM_ID, &infos(&mut accounts), None, None).unwrap();
+        assert_eq!(accounts[5].token_amount(), 500);
+
+        let mut migrate_accounts = vec![
+            TestAccount::wallet(config.admin, LAMPORTS_PER_SOL).signer(),
+            accounts.remove(3),
+            TestAccount::program(system_program::id()),
+        ];
+        process_migrate_config(&PROGRAM_ID, &infos(&mut migrate_accounts)).unwrap();
+        accounts.insert(3, migrate_accounts.remove(1));
+        let config = accounts[3].config();
+        assert_eq!(config.version, StakeConfig::VERSION);
+        assert!(config.prorate_partial_days);
+
+        set_time(START);
+        process_redeem(&PROGRAM_ID, &infos(&mut accounts), None, None).unwrap();
+        assert_eq!(accounts[5].token_amount(), 1_000);
+        assert_eq!(accounts[2].stake_info().total_redeemed, 1_000);
+    }
+
+    /// Checkpoints the stake of `Redeem` `accounts`, signed by no one.
+    fn checkpoint(accounts: &mut [TestAccount]) -> ProgramResult {
+        let infos = infos(accounts);
+        process_checkpoint(&PROGRAM_ID, &[infos[2].clone(), infos[3].clone()])
+    }
+
+    #[test]
+    fn a_checkpoint_restarts_the_window_and_keeps_the_total_owed() {
+        setup();
+        let user = Pubkey::new_unique();
+        let (config, reward_accounts) = reward_pool(user);
+        let position = staked_position(user, START - 100);
+        let mut accounts = redeem_accounts(user, &config, reward_accounts, position);
+
+        checkpoint(&mut accounts).unwrap();
+        let account_data = accounts[2].stake_info();
+        assert_eq!(account_data.checkpoint_reward, 100);
+        assert_eq!(account_data.last_redeem_time, START);
+
+        set_time(START + 50);
+        checkpoint(&mut accounts).unwrap();
+        let account_data = accounts[2].stake_info();
+        assert_eq!(account_data.checkpoint_reward, 150);
+        assert_eq!(account_data.last_redeem_time, START + 50);
+
+        set_time(START + 60);
+        process_redeem(&PROGRAM_ID, &infos(&mut accounts), None, None).unwrap();
+        assert_eq!(accounts[5].token_amount(), 160);
+        assert_eq!(accounts[2].stake_info().checkpoint_reward, 0);
+    }
+
+    #[test]
+    fn a_checkpoint_leaves_milestones_to_the_next_redeem() {
+        setup();
+        let user = Pubkey::new_unique();
+        let (mut config, reward_accounts) = reward_pool(user);
+        config.reward_milestones = vec![duration_tier(100, 1_000)];
+        let position = staked_position(user, START);
+        let mut accounts = redeem_accounts(user, &config, reward_accounts, position);
+
+        // Checkpointing past the milestone does not apply it any earlier.
+        set_time(START + 150);
+        checkpoint(&mut accounts).unwrap();
+        let account_data = accounts[2].stake_info();
+        assert_eq!(account_data.checkpoint_reward, 150);
+        assert_eq!(account_data.milestone_multiplier_bps, 0);
+
+        set_time(START + 250);
+        process_redeem(&PROGRAM_ID, &infos(&mut accounts), None, None).unwrap();
+        assert_eq!(accounts[5].token_amount(), 250);
+        assert_eq!(accounts[2].stake_info().milestone_multiplier_bps, 1_000);
+    }
+
+    /// The account compression program's `verify_leaf`: hashes the leaf up
+    /// through the proof node accounts and compares the result to the root.
+    fn mock_verify_leaf(instruction: &Instruction, accounts: &[AccountInfo]) -> ProgramResult {
+        let data = &instruction.data;
+        assert_eq!(data[..8], VERIFY_LEAF_DISCRIMINATOR);
+        let index = u32::from_le_bytes(data[72..76].try_into().unwrap());
+        let mut node: [u8; 32] = data[40..72].try_into().unwrap();
+        for (depth, sibling) in accounts[1..].iter().enumerate() {
+            node = if index >> depth & 1 == 0 {
+                keccak::hashv(&[&node, sibling.key.as_ref()]).to_bytes()
+            } else {
+                keccak::hashv(&[sibling.key.as_ref(), &node]).to_bytes()
+            };
+        }
+        if node != data[8..40] {
+            return Err(ProgramError::InvalidArgument);
+        }
+        Ok(())
+    }
+
+    /// A compressed NFT owned by `user` at index 1 of a depth 2 `tree`: its
+    /// leaf, with the root of the tree, and its two proof nodes.
+    fn compressed_nft(user: Pubkey, tree: Pubkey) -> (CompressedNftLeaf, [Pubkey; 2]) {
+        let mut leaf = CompressedNftLeaf {
+            root: [0; 32],
+            data_hash: [1; 32],
+            creator_hash: [2; 32],
+            nonce: 1,
+            index: 1,
+            delegate: user,
+            proof_len: 2,
+        };
+        let asset_id = compressed_asset_id(tree, leaf.nonce);
+        let leaf_hash = keccak::hashv(&[
+            &[BUBBLEGUM_LEAF_VERSION],
+            asset_id.as_ref(),
+            user.as_ref(),
+            leaf.delegate.as_ref(),
+            &leaf.nonce.to_le_bytes(),
+            &leaf.data_hash,
+            &leaf.creator_hash,
+        ]);
+        let proof = [Pubkey::new_unique(), Pubkey::new_unique()];
+        let node = keccak::hashv(&[proof[0].as_ref(), leaf_hash.as_ref()]);
+        leaf.root = keccak::hashv(&[node.as_ref(), proof[1].as_ref()]).to_bytes();
+        (leaf, proof)
+    }
+
+    fn compressed_asset_id(tree: Pubkey, nonce: u64) -> Pubkey {
+        let seeds = [b"asset".as_ref(), tree.as_ref(), &nonce.to_le_bytes()];
+        Pubkey::find_program_address(&seeds, &BUBBLEGUM_PROGRAM_ID).0
+    }
+
+    /// `StakeCompressed` accounts for `user`'s compressed NFT in `tree`: the
+    /// tree, the compression program, the `proof` nodes, then the `Stake`
+    /// accounts with the asset id in place of the NFT token account.
+    fn stake_compressed_accounts(
+        user: Pubkey,
+        tree: Pubkey,
+        proof: [Pubkey; 2],
+        config: &StakeConfig,
+    ) -> Vec<TestAccount> {
+        let asset_id = compressed_asset_id(tree, 1);
+        let mut stake_accounts = stake_accounts(user, config);
+        stake_accounts[1] = TestAccount::wallet(asset_id, 0);
+        stake_accounts[2] = stake_account(&new_stake_info(user, asset_id));
+        stake_accounts[3] = empty_account(staked_mint_address(&asset_id));
+        let mut accounts = vec![
+            TestAccount::new(tree, ACCOUNT_COMPRESSION_PROGRAM_ID, 1, &[]),
+            TestAccount::program(ACCOUNT_COMPRESSION_PROGRAM_ID),
+        ];
+        accounts.extend(proof.map(|node| TestAccount::wallet(node, 0)));
+        accounts.extend(stake_accounts);
+        accounts
+    }
+
+    #[test]
+    fn a_compressed_nft_with_a_valid_proof_stakes_under_its_asset_id() {
+        setup();
+        mock_program(ACCOUNT_COMPRESSION_PROGRAM_ID, mock_verify_leaf);
+        let user = Pubkey::new_unique();
+        let tree = Pubkey::new_unique();
+        let (mut config, _reward_accounts) = reward_pool(user);
+        config.compressed_nft_tree = tree;
+        let (leaf, proof) = compressed_nft(user, tree);
+        let mut accounts = stake_compressed_accounts(user, tree, proof, &config);
+
+        process_stake_compressed(&PROGRAM_ID, &infos(&mut accounts), leaf).unwrap();
+        let account_data = accounts[6].stake_info();
+        assert!(account_data.is_stake_active());
+        assert!(account_data.is_compressed());
+        assert_eq!(account_data.nft_mint, compressed_asset_id(tree, 1));
+        assert_eq!(accounts[7].staked_mint().stake_state, accounts[6].key());
+        assert_eq!(
+            compute_reward(&account_data, &accounts[9].config(), START + 100),
+            100
+        );
+    }
+
+    #[test]
+    fn a_compressed_nft_with_an_invalid_proof_or_tree_is_rejected() {
+        setup();
+        mock_program(ACCOUNT_COMPRESSION_PROGRAM_ID, mock_verify_leaf);
+        let user = Pubkey::new_unique();
+        let tree = Pubkey::new_unique();
+        let (mut config, _reward_accounts) = reward_pool(user);
+        config.compressed_nft_tree = tree;
+
+        // A leaf of another owner does not hash to the root.
+        let (leaf, proof) = compressed_nft(Pubkey::new_unique(), tree);
+        let mut accounts = stake_compressed_accounts(user, tree, proof, &config);
+        assert_eq!(
+            process_stake_compressed(&PROGRAM_ID, &infos(&mut accounts), leaf),
+            Err(ProgramError::InvalidArgument)
+        );
+        assert!(!accounts[6].stake_info().is_stake_active());
+
+        let (mut leaf, proof) = compressed_nft(user, tree);
+        leaf.nonce = 2;
+        let mut accounts = stake_compressed_accounts(user, tree, proof, &config);
+        assert_eq!(
+            process_stake_compressed(&PROGRAM_ID, &infos(&mut accounts), leaf),
+            Err(StakeError::InvalidCnftProof.into())
+        );
+
+        let other_tree = Pubkey::new_unique();
+        let (leaf, proof) = compressed_nft(user, other_tree);
+        let mut accounts = stake_compressed_accounts(user, other_tree, proof, &config);
+        assert_eq!(
+            process_stake_compressed(&PROGRAM_ID, &infos(&mut accounts), leaf),
+            Err(StakeError::InvalidCnftProof.into())
+        );
+        assert!(!accounts[6].stake_info().is_stake_active());
+    }
+
+    /// `Stake` accounts whose stake account sits at the address the original,
+    /// unversioned seeds derive and records `seed_version`.
+    fn stake_accounts_at_the_unversioned_address(
+        user: Pubkey,
+        config: &StakeConfig,
+        seed_version: u8,
+    ) -> Vec<TestAccount> {
+        let mut accounts = stake_accounts(user, config);
+        let nft_token_account = accounts[1].key();
+        let (key, bump) =
+            Pubkey::find_program_address(&[user.as_ref(), nft_token_account.as_ref()], &PROGRAM_ID);
+        let mut account_data = new_stake_info(user, nft_token_account);
+        account_data.seed_version = seed_version;
+        account_data.bump = bump;
+        accounts[2] = TestAccount::with_data(key, PROGRAM_ID, &[0; UserStakeInfo::SIZE]);
+        accounts[2].set_stake_info(&account_data);
+        accounts
+    }
+
+    #[test]
+    fn stakes_under_the_current_seed_version_and_rejects_older_derivations() {
+        setup();
+        let user = Pubkey::new_unique();
+        let (mut config, _reward_accounts) = reward_pool(user);
+        config.require_current_seed_version = true;
+        let mut accounts = stake_accounts(user, &config);
+        process_stake(&PROGRAM_ID, &infos(&mut accounts)).unwrap();
+        let account_data = accounts[2].stake_info();
+        assert!(account_data.is_stake_active());
+        assert_eq!(account_data.seed_version, STAKE_SEED_VERSION);
+
+        let mut accounts = stake_accounts_at_the_unversioned_address(user, &config, 0);
+        assert_eq!(
+            process_stake(&PROGRAM_ID, &infos(&mut accounts)),
+            Err(StakeError::InvalidPda.into())
+        );
+        // An old address cannot pass for one of the current scheme either.
+        let mut accounts =
+            stake_accounts_at_the_unversioned_address(user, &config, STAKE_SEED_VERSION);
+        assert_eq!(
+            process_stake(&PROGRAM_ID, &infos(&mut accounts)),
+            Err(StakeError::InvalidPda.into())
+        );
+        assert!(!accounts[2].stake_info().is_stake_active());
+
+        // Until the current scheme is required, old accounts keep staking.
+        config.require_current_seed_version = false;
+        let mut accounts = stake_accounts_at_the_unversioned_address(user, &config, 0);
+        process_stake(&PROGRAM_ID, &infos(&mut accounts)).unwrap();
+        assert!(accounts[2].stake_info().is_stake_active());
+    }
+
+    /// Has the admin of `config` fund `sol_pool` with `lamports`.
+    fn fund_sol_pool(config: &StakeConfig, sol_pool: &mut TestAccount, lamports: u64) {
+        let mut accounts = vec![
+            TestAccount::wallet(config.admin, LAMPORTS_PER_SOL).signer(),
+            config_account(config),
+            std::mem::replace(sol_pool, empty_account(Pubkey::default())),
+            TestAccount::program(system_program::id()),
+        ];
+        process_fund_sol_pool(&PROGRAM_ID, &infos(&mut accounts), lamports).unwrap();
+        *sol_pool = accounts.swap_remove(2);
+    }
+
+    #[test]
+    fn sol_rewards_are_paid_from_the_pool_until_it_runs_short() {
+        setup();
+        let user = Pubkey::new_unique();
+        let (mut config, mut reward_accounts) = reward_pool(user);
+        config.accrual_mode = AccrualMode::Sol;
+        let pool_address = Pubkey::find_program_address(&[SOL_POOL_SEED], &PROGRAM_ID).0;
+        let mut sol_pool = empty_account(pool_address);
+        fund_sol_pool(&config, &mut sol_pool, 150);
+        let rent = Rent::default().minimum_balance(0);
+        assert_eq!(sol_pool.owner, PROGRAM_ID);
+        assert_eq!(sol_pool.lamports, rent + 150);
+        reward_accounts[0] = sol_pool;
+        reward_accounts[1] = TestAccount::wallet(user, 0);
+        let position = staked_position(user, START - 100);
+        let mut accounts = redeem_accounts(user, &config, reward_accounts, position);
+
+        process_redeem(&PROGRAM_ID, &infos(&mut accounts), None, None).unwrap();
+        assert_eq!(accounts[5].lamports, 100);
+        assert_eq!(accounts[4].lamports, rent + 50);
+        assert!(invoked()
+            .iter()
+            .all(|instruction| instruction.program_id == system_program::id()));
+
+        // The pool's rent is never paid out.
+        set_time(START + 100);
+        assert_eq!(
+            process_redeem(&PROGRAM_ID, &infos(&mut accounts), None, None),
+            Err(StakeError::InsufficientPoolLamports.into())
+        );
+        assert_eq!(accounts[4].lamports, rent + 50);
+
+        fund_sol_pool(&config, &mut accounts[4], 50);
+        process_redeem(&PROGRAM_ID, &infos(&mut accounts), None, None).unwrap();
+        assert_eq!(accounts[5].lamports, 200);
+        assert_eq!(accounts[4].lamports, rent);
+    }
+
+    #[test]
+    fn rate_changes_may_be_scheduled_up_to_the_horizon_only() {
+        setup();
+        let (mut config, _reward_accounts) = reward_pool(Pubkey::new_unique());
+        config.max_schedule_horizon = 30 * SECONDS_PER_DAY;
+        let mut accounts = vec![
+            TestAccount::wallet(config.admin, LAMPORTS_PER_SOL).signer(),
+            config_account(&config),
+        ];
+        let rate_change_at =
+            |start_time| ConfigField::EmissionSchedule(vec![segment(0, 1), segment(start_time, 2)]);
+
+        let next_week = START + 7 * SECONDS_PER_DAY;
+        process_update_config(
+            &PROGRAM_ID,
+            &infos(&mut accounts),
+            rate_change_at(next_week),
+        )
+        .unwrap();
+        assert_eq!(
+            accounts[1].config().emission_schedule[1].start_time,
+            next_week
+        );
+        let horizon_end = START + 30 * SECONDS_PER_DAY;
+        process_update_config(
+            &PROGRAM_ID,
+            &infos(&mut accounts),
+            rate_change_at(horizon_end),
+        )
+        .unwrap();
+
+        assert_eq!(
+            process_update_config(
+                &PROGRAM_ID,
+                &infos(&mut accounts),
+                rate_change_at(horizon_end + 1)
+            ),
+            Err(StakeError::ScheduleTooFar.into())
+        );
+        assert_eq!(
+            accounts[1].config().emission_schedule[1].start_time,
+            horizon_end
+        );
+    }
+
+    #[test]
+    fn a_never_staked_account_cannot_redeem_or_unstake_and_accrues_nothing() {
+        setup();
+        let user = Pubkey::new_unique();
+        let (config, reward_accounts) = reward_pool(user);
+        let nft_mint = Pubkey::new_unique();
+        let nft_token_account = token_account(Pubkey::new_unique(), nft_mint, user, 1);
+        let account_data = new_stake_info(user, nft_token_account.key());
+        let stake_state = stake_account(&account_data);
+        let staked_mint = staked_mint_account(&nft_mint, Pubkey::default());
+        let position = vec![nft_token_account, stake_state, staked_mint];
+
+        let mut accounts =
+            redeem_accounts(user, &config, reward_accounts.clone(), position.clone());
+        assert_eq!(
+            process_redeem(&PROGRAM_ID, &infos(&mut accounts), None, None),
+            Err(StakeError::StakeNotActive.into())
+        );
+        let mut accounts = unstake_accounts(user, &config, reward_accounts, position);
+        assert_eq!(
+            process_unstake(&PROGRAM_ID, &infos(&mut accounts), None),
+            Err(StakeError::StakeNotActive.into())
+        );
+
+        assert_eq!(account_data.stake_start_time, 0);
+        for now in [0, START, UnixTimestamp::MAX] {
+            assert_eq!(compute_reward(&account_data, &config, now), 0);
+        }
+    }
+
+    #[test]
+    fn a_finalized_pool_blocks_stakes_and_config_changes_but_not_exits() {
+        setup();
+        let user = Pubkey::new_unique();
+        let (mut config, reward_accounts) = reward_pool(user);
+        config.auto_redeem_on_unstake = false;
+        let mut admin_accounts = vec![
+            TestAccount::wallet(config.admin, LAMPORTS_PER_SOL).signer(),
+            config_account(&config),
+        ];
+        process_finalize_pool(&PROGRAM_ID, &infos(&mut admin_accounts)).unwrap();
+        let config = admin_accounts[1].config();
+        assert!(config.finalized);
+        let pool_finalized = Err(StakeError::PoolFinalized.into());
+        assert_eq!(
+            process_finalize_pool(&PROGRAM_ID, &infos(&mut admin_accounts)),
+            pool_finalized
+        );
+        assert_eq!(
+            process_update_config(
+                &PROGRAM_ID,
+                &infos(&mut admin_accounts),
+                ConfigField::ProtocolFeeBps(100)
+            ),
+            pool_finalized
+        );
+        let mut accounts = stake_accounts(user, &config);
+        assert_eq!(
+            process_stake(&PROGRAM_ID, &infos(&mut accounts)),
+            pool_finalized
+        );
+
+        let position = staked_position(user, START - 100);
+        let mut accounts = unstake_accounts(user, &config, reward_accounts, position);
+        process_unstake(&PROGRAM_ID, &infos(&mut accounts), None).unwrap();
+        let account_data = accounts[2].stake_info();
+        assert!(!account_data.is_stake_active());
+        assert_eq!(account_data.unclaimed_final_reward, 100);
+
+        // `ClaimFinalReward` takes the `Unstake` accounts less the
+        // `StakedMint` and `UserStakeAccount`.
+        accounts.drain(3..5);
+        process_claim_final_reward(&PROGRAM_ID, &infos(&mut accounts)).unwrap();
+        assert_eq!(accounts[5].token_amount(), 100);
+    }
+
+    #[test]
+    fn a_finalized_pool_refuses_every_admin_instruction_but_swap_reward_mint() {
+        setup();
+        let user = Pubkey::new_unique();
+        let (mut config, reward_accounts) = reward_pool(user);
+        config.finalized = true;
+        let mut accounts = redeem_accounts(user, &config, reward_accounts, Vec::new());
+        let new_mint = Pubkey::new_unique();
+        accounts.extend([
+            TestAccount::wallet(config.admin, LAMPORTS_PER_SOL).signer(),
+            TestAccount::program(system_program::id()),
+            mint_account(new_mint, Some(mint_authority_address()), 0),
+            token_account(Pubkey::new_unique(), new_mint, config.admin, 0),
+        ]);
+        let infos = infos(&mut accounts);
+        let pick =
+            |indices: &[usize]| -> Vec<_> { indices.iter().map(|&i| infos[i].clone()).collect() };
+        // Every refused instruction fails before reading the accounts after
+        // the admin and config, so the config stands in for them.
+        let admin_call = pick(&[7, 1, 1, 1, 1, 1]);
+        let pool_finalized = Err(StakeError::PoolFinalized.into());
+        assert_eq!(
+            process_recover_stray_tokens(&PROGRAM_ID, &admin_call),
+            pool_finalized
+        );
+        assert_eq!(
+            process_fund_rent_reserve(&PROGRAM_ID, &admin_call, 100),
+            pool_finalized
+        );
+        assert_eq!(
+            process_fund_sol_pool(&PROGRAM_ID, &admin_call, 100),
+            pool_finalized
+        );
+        assert_eq!(
+            process_migrate_config(&PROGRAM_ID, &admin_call),
+            pool_finalized
+        );
+        assert_eq!(
+            process_snapshot_stakers(&PROGRAM_ID, &admin_call, 1, 0),
+            pool_finalized
+        );
+
+        process_swap_reward_mint(&PROGRAM_ID, &pick(&[7, 1, 2, 9, 10]), new_mint, 0).unwrap();
+        let config = try_from_slice_unchecked::<StakeConfig>(&infos[1].data.borrow()).unwrap();
+        assert_eq!(config.reward_mint, new_mint);
+    }
+
+    #[test]
+    fn a_closed_stake_account_reinitializes_fresh_and_restakes_the_nft() {
+        setup();
+        let user = Pubkey::new_unique();
+        let (config, reward_accounts) = reward_pool(user);
+        let position = staked_position(user, START - 100);
+        let mut accounts = unstake_accounts(user, &config, reward_accounts, position);
+        accounts.push(TestAccount::program(system_program::id()));
+        let infos = infos(&mut accounts);
+        let pick =
+            |indices: &[usize]| -> Vec<_> { indices.iter().map(|&i| infos[i].clone()).collect() };
+        let stake_info = || load_stake_info(&infos[2]).unwrap();
+
+        process_unstake(&PROGRAM_ID, &infos[..11], None).unwrap();
+        assert_eq!(stake_info().total_redeemed, 100);
+        process_close_inactive_stakes(&PROGRAM_ID, &pick(&[0, 2])).unwrap();
+        assert_eq!(infos[2].lamports(), 0);
+
+        set_time(START + 50);
+        process_initialize_stake_account(&PROGRAM_ID, &pick(&[0, 1, 2, 11])).unwrap();
+        let account_data = stake_info();
+        assert!(account_data.is_initialized());
+        assert!(!account_data.is_stake_active());
+        assert_eq!(account_data.total_redeemed, 0);
+        assert_eq!(account_data.initialized_at, START + 50);
+
+        process_stake(&PROGRAM_ID, &pick(&[0, 1, 2, 3, 4, 5, 11])).unwrap();
+        let account_data = stake_info();
+        assert!(account_data.is_stake_active());
+        assert_eq!(account_data.stake_start_time, START + 50);
+        let staked_mint = try_from_slice_unchecked::<StakedMint>(&infos[3].data.borrow()).unwrap();
+        assert_eq!(staked_mint.stake_state, *infos[2].key);
+    }
+
+    #[test]
+    fn closing_and_reinitializing_does_not_reset_the_restake_cooloff() {
+        setup();
+        let user = Pubkey::new_unique();
+        let (mut config, reward_accounts) = reward_pool(user);
+        config.restake_cooloff = 100;
+        let position = staked_position(user, START - 100);
+        let mut accounts = unstake_accounts(user, &config, reward_accounts, position);
+        accounts.push(TestAccount::program(system_program::id()));
+        let infos = infos(&mut accounts);
+        let pick =
+            |indices: &[usize]| -> Vec<_> { indices.iter().map(|&i| infos[i].clone()).collect() };
+
+        process_unstake(&PROGRAM_ID, &infos[..11], None).unwrap();
+        process_close_inactive_stakes(&PROGRAM_ID, &pick(&[0, 2])).unwrap();
+        set_time(START + 50);
+        process_initialize_stake_account(&PROGRAM_ID, &pick(&[0, 1, 2, 11])).unwrap();
+        assert_eq!(load_stake_info(&infos[2]).unwrap().last_unstake_time, 0);
+
+        let stake = pick(&[0, 1, 2, 3, 4, 5, 11]);
+        assert_eq!(
+            process_stake(&PROGRAM_ID, &stake),
+            Err(StakeError::RestakeCooloffActive.into())
+        );
+        set_time(START + 100);
+        process_stake(&PROGRAM_ID, &stake).unwrap();
+        assert!(load_stake_info(&infos[2]).unwrap().is_stake_active());
+    }
+
+    #[test]
+    fn redeem_and_unstake_fail_past_their_deadline_slot() {
+        setup();
+        let user = Pubkey::new_unique();
+        let (config, reward_accounts) = reward_pool(user);
+        let position = staked_position(user, START - 100);
+        let mut accounts = unstake_accounts(user, &config, reward_accounts, position);
+        let expired = Err(StakeError::TransactionExpired.into());
+        let infos = infos(&mut accounts);
+        let redeem_accounts: Vec<_> = [0, 1, 2, 5, 6, 7, 8, 9, 10]
+            .iter()
+            .map(|&i| infos[i].clone())
+            .collect();
+
+        assert_eq!(
+            process_redeem(&PROGRAM_ID, &redeem_accounts, None, Some(START_SLOT - 1)),
+            expired
+        );
+        process_redeem(&PROGRAM_ID, &redeem_accounts, None, Some(START_SLOT)).unwrap();
+        assert_eq!(
+            process_unstake(&PROGRAM_ID, &infos, Some(START_SLOT - 1)),
+            expired
+        );
+        set_time(START + 50);
+        process_unstake(&PROGRAM_ID, &infos, Some(START_SLOT + 10)).unwrap();
+        let user_reward = TokenAccount::unpack(&infos[7].data.borrow()).unwrap();
+        assert_eq!(user_reward.amount, 150);
+    }
+
+    #[test]
+    fn stacked_modifiers_apply_in_order_and_never_underflow() {
+        let user = Pubkey::new_unique();
+        let (mut config, _reward_accounts) = reward_pool(user);
+        config.stake_dilution_divisor = 1;
+        config.total_active_stakes = 3;
+        config.duration_tiers = vec![duration_tier(500, 15_000)];
+        let mut account_data = active_stake_info(
+            user,
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            START - 1_000,
+        );
+        account_data.stake_weight = LAMPORTS_PER_SOL / 2;
+        account_data.edition_boost_bps = 1_000;
+        account_data.milestone_multiplier_bps = 500;
+
+        // Each step rounds down the amount the step before it left.
+        let diluted = 1_000 * REWARD_PRECISION as u128 / 3;
+        let weighted = diluted / 2;
+        let edition_boosted = weighted * 11_000 / 10_000;
+        let milestone_boosted = edition_boosted * 10_500 / 10_000;
+        let scaled = milestone_boosted * 15_000 / 10_000;
+        let breakdown = reward_breakdown(&account_data, &config, START);
+        assert_eq!(breakdown.scaled_accrual_fixed, scaled);
+        assert_eq!(scaled, 288_749_997);
+        assert_eq!(compute_reward(&account_data, &config, START), 288);
+
+        // Reductions stacked as far as they go bottom out at zero, and only
+        // the amounts added after them are left.
+        config.total_active_stakes = u32::MAX;
+        config.duration_tiers = vec![duration_tier(500, 1)];
+        account_data.stake_weight = 1;
+        assert_eq!(compute_reward(&account_data, &config, START), 0);
+        account_data.checkpoint_reward = 5;
+        assert_eq!(compute_reward(&account_data, &config, START), 5);
+
+        // Boosts stacked as far as they go saturate instead of overflowing.
+        config.total_active_stakes = 0;
+        config.duration_tiers = vec![duration_tier(500, u16::MAX)];
+        account_data.stake_weight = u64::MAX;
+        account_data.edition_boost_bps = u16::MAX;
+        account_data.milestone_multiplier_bps = u16::MAX;
+        account_data.set_rate_override(Some(u64::MAX));
+        assert_eq!(compute_reward(&account_data, &config, START), u64::MAX);
+    }
+
+    #[test]
+    fn redeems_mint_the_new_reward_mint_after_a_swap_without_losing_accrual() {
+        setup();
+        let user = Pubkey::new_unique();
+        let (config, reward_accounts) = reward_pool(user);
+        let position = staked_position(user, START - 100);
+        let mut accounts = redeem_accounts(user, &config, reward_accounts, position);
+        let new_mint = Pubkey::new_unique();
+        accounts.extend([
+            TestAccount::wallet(config.admin, LAMPORTS_PER_SOL).signer(),
+            mint_account(new_mint, Some(mint_authority_address()), 0),
+            token_account(Pubkey::new_unique(), new_mint, user, 0),
+            token_account(Pubkey::new_unique(), new_mint, config.admin, 0),
+            mint_account(Pubkey::new_unique(), Some(Pubkey::new_unique()), 0),
+        ]);
+        let infos = infos(&mut accounts);
+        let pick =
+            |indices: &[usize]| -> Vec<_> { indices.iter().map(|&i| infos[i].clone()).collect() };
+        let token_amount = |i: usize| {
+            TokenAccount::unpack(&infos[i].data.borrow())
+                .unwrap()
+                .amount
+        };
+
+        process_redeem(&PROGRAM_ID, &infos[..9], None, None).unwrap();
+        assert_eq!(token_amount(5), 100);
+
+        let unmintable = *infos[13].key;
+        assert_eq!(
+            process_swap_reward_mint(&PROGRAM_ID, &pick(&[9, 3, 4, 13, 12]), unmintable, 0),
+            Err(StakeError::InvalidMintAuthority.into())
+        );
+        process_swap_reward_mint(&PROGRAM_ID, &pick(&[9, 3, 4, 10, 12]), new_mint, 0).unwrap();
+        let config = try_from_slice_unchecked::<StakeConfig>(&infos[3].data.borrow()).unwrap();
+        assert_eq!(config.reward_mint, new_mint);
+        assert_eq!(config.protocol_fee_account, *infos[12].key);
+
+        set_time(START + 50);
+        assert_eq!(
+            process_redeem(&PROGRAM_ID, &infos[..9], None, None),
+            Err(StakeError::InvalidProtocolFeeAccount.into())
+        );
+        let new_reward_accounts = pick(&[0, 1, 2, 3, 10, 11, 12, 7, 8]);
+        process_redeem(&PROGRAM_ID, &new_reward_accounts, None, None).unwrap();
+        assert_eq!(token_amount(11), 50);
+        assert_eq!(token_amount(5), 100);
+    }
+
+    #[test]
+    fn a_swap_to_a_mint_with_more_decimals_rescales_rates_and_stored_rewards() {
+        setup();
+        let user = Pubkey::new_unique();
+        let (config, reward_accounts) = reward_pool(user);
+        let mut position = staked_position(user, START - 100);
+        let mut account_data = position[1].stake_info();
+        account_data.checkpoint_reward = 7;
+        position[1].set_stake_info(&account_data);
+        let mut accounts = redeem_accounts(user, &config, reward_accounts, position);
+        let new_mint = Pubkey::new_unique();
+        let mut new_mint_account = mint_account(new_mint, Some(mint_authority_address()), 0);
+        let mut mint = Mint::unpack(new_mint_account.data()).unwrap();
+        mint.decimals = 2;
+        let mut data = vec![0; Mint::LEN];
+        Mint::pack(mint, &mut data).unwrap();
+        new_mint_account.set_data(&data);
+        accounts.extend([
+            TestAccount::wallet(config.admin, LAMPORTS_PER_SOL).signer(),
+            new_mint_account,
+            token_account(Pubkey::new_unique(), new_mint, user, 0),
+            token_account(Pubkey::new_unique(), new_mint, config.admin, 0),
+        ]);
+        let infos = infos(&mut accounts);
+        let pick =
+            |indices: &[usize]| -> Vec<_> { indices.iter().map(|&i| infos[i].clone()).collect() };
+
+        // The old mint's decimals are checked while it can be read, and taken
+        // on trust once it cannot.
+        assert_eq!(
+            process_swap_reward_mint(&PROGRAM_ID, &pick(&[9, 3, 4, 10, 12]), new_mint, 3),
+            Err(StakeError::InvalidRewardMint.into())
+        );
+        infos[4].data.borrow_mut().fill(0);
+        process_swap_reward_mint(&PROGRAM_ID, &pick(&[9, 3, 4, 10, 12]), new_mint, 0).unwrap();
+        let swapped = try_from_slice_unchecked::<StakeConfig>(&infos[3].data.borrow()).unwrap();
+        assert_eq!(swapped.reward_mint_swaps.len(), 1);
+        assert_eq!(swapped.rate_revision, config.rate_revision.wrapping_add(1));
+        assert_eq!(emission_rate_at(&swapped, START), 100 * DEFAULT_REWARD_RATE);
+
+        // 150 seconds at the scaled rate plus the checkpoint, scaled as well.
+        set_time(START + 50);
+        process_redeem(
+            &PROGRAM_ID,
+            &pick(&[0, 1, 2, 3, 10, 11, 12, 7, 8]),
+            None,
+            None,
+        )
+        .unwrap();
+        let paid = TokenAccount::unpack(&infos[11].data.borrow())
+            .unwrap()
+            .amount;
+        assert_eq!(paid, 150 * 100 * DEFAULT_REWARD_RATE + 700);
+        let account_data = load_stake_info(&infos[2]).unwrap();
+        assert_eq!(account_data.reward_mint_generation, 1);
+        assert_eq!(account_data.checkpoint_reward, 0);
+    }
+
+    /// Initializes a stake account for the NFT token account at `at_initialize`
+    /// at `START`, then stakes it 100 seconds later, when the account at that
+    /// address is `at_stake`, and returns when the stake accrues from.
+    fn accrual_start(
+        config: &StakeConfig,
+        user: Pubkey,
+        at_initialize: TestAccount,
+        at_stake: TestAccount,
+    ) -> UnixTimestamp {
+        let nft_mint = TokenAccount::unpack(at_stake.data()).unwrap().mint;
+        let staked_mint = empty_account(staked_mint_address(&nft_mint));
+        accrual_start_with(
+            config,
+            user,
+            at_initialize,
+            at_stake,
+            staked_mint,
+            START + 100,
+        )
+    }
+
+    /// `accrual_start` with the NFT's `staked_mint` record as given, staking
+    /// at `staked_at`.
+    fn accrual_start_with(
+        config: &StakeConfig,
+        user: Pubkey,
+        at_initialize: TestAccount,
+        at_stake: TestAccount,
+        staked_mint: TestAccount,
+        staked_at: UnixTimestamp,
+    ) -> UnixTimestamp {
+        setup();
+        let (stake_state, _bump) = stake_address(&user, &at_initialize.key());
+        let mut accounts = vec![
+            TestAccount::wallet(user, LAMPORTS_PER_SOL).signer(),
+            at_initialize,
+            empty_account(stake_state),
+            TestAccount::program(system_program::id()),
+        ];
+        process_initialize_stake_account(&PROGRAM_ID, &infos(&mut accounts)).unwrap();
+
+        set_time(staked_at);
+        accounts[1] = at_stake;
+        let system_program = accounts.pop().unwrap();
+        accounts.extend([
+            staked_mint,
+            empty_account(user_stake_address(&user)),
+            config_account(config),
+            system_program,
+        ]);
+        process_stake(&PROGRAM_ID, &infos(&mut accounts)).unwrap();
+        let account_data = accounts[2].stake_info();
+        assert_eq!(account_data.last_redeem_time, account_data.stake_start_time);
+        account_data.stake_start_time
+    }
+
+    #[test]
+    fn accrual_starts_at_initialize_only_when_configured() {
+        let user = Pubkey::new_unique();
+        let (mut config, _reward_accounts) = reward_pool(user);
+        let nft_token_account = token_account(Pubkey::new_unique(), Pubkey::new_unique(), user, 1);
+        let held = || nft_token_account.clone();
+
+        assert_eq!(accrual_start(&config, user, held(), held()), START + 100);
+        config.accrue_from_initialize = true;
+        assert_eq!(accrual_start(&config, user, held(), held()), START);
+        config.max_backpay_seconds = 30;
+        assert_eq!(accrual_start(&config, user, held(), held()), START + 70);
+    }
+
+    #[test]
+    fn accrual_starts_at_the_stake_without_a_verified_holding_at_initialize() {
+        let user = Pubkey::new_unique();
+        let (mut config, _reward_accounts) = reward_pool(user);
+        config.accrue_from_initialize = true;
+        let key = Pubkey::new_unique();
+        let nft_mint = Pubkey::new_unique();
+        let held = token_account(key, nft_mint, user, 1);
+        let not_yet_created = empty_account(key);
+        let held_by_another = token_account(key, nft_mint, Pubkey::new_unique(), 1);
+        let not_an_nft = token_account(key, nft_mint, user, 2);
+        let another_nft = token_account(key, Pubkey::new_unique(), user, 1);
+
+        for at_initialize in [not_yet_created, held_by_another, not_an_nft, another_nft] {
+            assert_eq!(
+                accrual_start(&config, user, at_initialize, held.clone()),
+                START + 100
+            );
+        }
+    }
+
+    #[test]
+    fn an_nft_passed_on_and_back_is_not_backdated_before_its_last_unstake() {
+        let user = Pubkey::new_unique();
+        let (mut config, _reward_accounts) = reward_pool(user);
+        config.accrue_from_initialize = true;
+        let nft_mint = Pubkey::new_unique();
+        let held = token_account(Pubkey::new_unique(), nft_mint, user, 1);
+        // The user initializes while holding the NFT, passes it to another
+        // wallet that stakes and unstakes it, and gets it back: the other
+        // wallet was already paid for the time up to its unstake.
+        let unstaked_at = |last_unstake_time| {
+            let mut staked_mint = staked_mint_account(&nft_mint, Pubkey::default());
+            staked_mint.set_data(
+                &StakedMint {
+                    stake_state: Pubkey::default(),
+                    last_unstake_time,
+                }
+                .try_to_vec()
+                .unwrap(),
+            );
+            staked_mint
+        };
+
+        let staked_mint = unstaked_at(START + 50);
+        assert_eq!(
+            accrual_start_with(
+                &config,
+                user,
+                held.clone(),
+                held.clone(),
+                staked_mint,
+                START + 100
+            ),
+            START + 50
+        );
+        let staked_mint = unstaked_at(START - 500);
+        assert_eq!(
+            accrual_start_with(&config, user, held.clone(), held, staked_mint, START + 100),
+            START
+        );
+    }
+
+    #[test]
+    fn accrual_from_initialize_backdates_a_week_at_most_without_a_backpay_cap() {
+        let user = Pubkey::new_unique();
+        let (mut config, _reward_accounts) = reward_pool(user);
+        config.accrue_from_initialize = true;
+        let nft_mint = Pubkey::new_unique();
+        let held = token_account(Pubkey::new_unique(), nft_mint, user, 1);
+        let staked_at = START + 8 * SECONDS_PER_DAY;
+
+        let staked_mint = empty_account(staked_mint_address(&nft_mint));
+        assert_eq!(
+            accrual_start_with(&config, user, held.clone(), held, staked_mint, staked_at),
+            staked_at - DEFAULT_INITIALIZE_BACKDATE
+        );
+    }
+
+    #[test]
+    fn a_compressed_nft_asset_id_initializes_without_an_accrual_start() {
+        setup();
+        let user = Pubkey::new_unique();
+        let asset_id = compressed_asset_id(Pubkey::new_unique(), 1);
+        let mut accounts = vec![
+            TestAccount::wallet(user, LAMPORTS_PER_SOL).signer(),
+            TestAccount::wallet(asset_id, 0),
+            empty_account(stake_address(&user, &asset_id).0),
+            TestAccount::program(system_program::id()),
+        ];
+        process_initialize_stake_account(&PROGRAM_ID, &infos(&mut accounts)).unwrap();
+        let account_data = accounts[2].stake_info();
+        assert!(account_data.is_initialized());
+        assert_eq!(account_data.initialized_at, 0);
+        assert_eq!(account_data.initialized_mint, Pubkey::default());
+    }
+}
@@ -5,21 +5,828 @@ use solana_program::{
     pubkey::Pubkey,
 };
 
+pub const CONFIG_SEED: &[u8] = b"config";
+pub const MINT_AUTHORITY_SEED: &[u8] = b"mint";
+pub const STAKED_MINT_SEED: &[u8] = b"staked_mint";
+pub const USER_STAKE_SEED: &[u8] = b"user_stake";
+pub const AIRDROP_SNAPSHOT_SEED: &[u8] = b"airdrop_snapshot";
+pub const WALLET_AGE_SEED: &[u8] = b"wallet_age";
+pub const REWARD_MINT_SEED: &[u8] = b"reward_mint";
+pub const RENT_RESERVE_SEED: &[u8] = b"rent_reserve";
+pub const SOL_POOL_SEED: &[u8] = b"sol_pool";
+pub const AUDIT_TREE_AUTHORITY_SEED: &[u8] = b"audit_tree";
+pub const MAX_BPS: u16 = 10_000;
+pub const DEFAULT_REWARD_RATE: u64 = 1;
+pub const MAX_EMISSION_SEGMENTS: usize = 16;
+pub const MAX_GOVERNANCE_BOOST_TIERS: usize = 8;
+pub const MAX_DURATION_TIERS: usize = 16;
+pub const MAX_SNAPSHOT_STAKE_ACCOUNTS: usize = 256;
+pub const SECONDS_PER_DAY: UnixTimestamp = 86_400;
+/// How far back `accrue_from_initialize` backdates a stake while
+/// `max_backpay_seconds` is unset.
+pub const DEFAULT_INITIALIZE_BACKDATE: UnixTimestamp = 7 * SECONDS_PER_DAY;
+/// Rewards are computed in fixed point, in units of `1 / REWARD_PRECISION`
+/// of the smallest reward token unit, and only rounded to whole units when
+/// paid. The fraction a payout leaves behind is kept in the stake's
+/// `reward_remainder` and paid with a later one.
+pub const REWARD_PRECISION: u64 = 1_000_000;
+pub const MAX_PAUSE_INTERVALS: usize = 32;
+pub const MAX_WRAPPED_MINT_AUTHORITIES: usize = 8;
+pub const MAX_DELEGATE_STAKE_AUTHORITIES: usize = 8;
+pub const MAX_FEE_DISCOUNT_TIERS: usize = 8;
+pub const MAX_REWARD_MINT_SWAPS: usize = 8;
+pub const FUTURE_REDEEM_TIME_TOLERANCE: UnixTimestamp = 60;
+/// How many slots an ownership proof stays valid after the slot it was
+/// signed for: about a minute, the lifetime of a recent blockhash.
+pub const MAX_PROOF_AGE_SLOTS: u64 = 150;
+/// Seed scheme `InitializeStakeAccount` derives new stake accounts with.
+/// Version 0 is the original `[user, nft_token_account]` scheme; later
+/// versions prefix the seeds with their number, so no two schemes derive the
+/// same address.
+pub const STAKE_SEED_VERSION: u8 = 1;
+
+pub const FLAG_INITIALIZED: u8 = 1 << 0;
+pub const FLAG_STAKE_ACTIVE: u8 = 1 << 1;
+pub const FLAG_PROOF_VERIFIED: u8 = 1 << 2;
+pub const FLAG_POINTS_CONVERTED: u8 = 1 << 3;
+pub const FLAG_COMPRESSED: u8 = 1 << 4;
+/// Every defined `UserStakeInfo` flag; new flags must be added here.
+pub const ALL_FLAGS: u8 = FLAG_INITIALIZED
+    | FLAG_STAKE_ACTIVE
+    | FLAG_PROOF_VERIFIED
+    | FLAG_POINTS_CONVERTED
+    | FLAG_COMPRESSED;
+
+/// UTC day `ts` falls in: days since the unix epoch, each starting at
+/// midnight UTC, so every validator and client agrees on the boundary
+/// regardless of local time. Times before the epoch floor to negative days.
+/// Use this wherever a daily boundary matters, e.g. for streaks or check-ins.
+pub fn day_index(ts: UnixTimestamp) -> i64 {
+    ts.div_euclid(SECONDS_PER_DAY)
+}
+
+/// Stake account state.
+///
+/// Serialization contract: the first byte is the layout `version`. Within a
+/// version fields are append-only: a new field goes at the end of the struct,
+/// `SIZE` grows with it and its all-zero encoding must mean "unset". Accounts
+/// allocated before a field existed read it as zero, and are grown to `SIZE`
+/// by `MigrateStakeAccount` before their next write. Reordering, resizing or
+/// removing a field needs a new `VERSION` and a migration from the old layout.
 #[derive(BorshSerialize, BorshDeserialize)]
 pub struct UserStakeInfo {
-    pub is_initialized: bool,
+    pub version: u8,
+    pub flags: u8,
     pub user: Pubkey,
     pub token_account: Pubkey,
     pub stake_start_time: UnixTimestamp,
     pub last_redeem_time: UnixTimestamp,
-    pub is_stake_active: bool,
+    pub total_redeemed: u64,
+    pub nft_mint: Pubkey,
+    pub stake_weight: u64,
+    pub unclaimed_final_reward: u64,
+    pub final_reward_expires_at: UnixTimestamp,
+    pub cached_rate: u64,
+    pub cached_rate_valid_until: UnixTimestamp,
+    pub cached_rate_revision: u32,
+    pub bump: u8,
+    pub last_nonce: u64,
+    pub preferred_reward_destination: Pubkey,
+    pub virtual_staked_amount: u64,
+    /// The admin's per-second rate override, see `rate_override`. Stored at a
+    /// fixed width, with `override_rate` meaningless while
+    /// `has_rate_override` is unset, so later fields keep their offsets.
+    pub has_rate_override: bool,
+    pub override_rate: u64,
+    pub checkpoint_reward: u64,
+    pub edition_boost_bps: u16,
+    pub last_unstake_time: UnixTimestamp,
+    pub milestone_multiplier_bps: u16,
+    pub reward_remainder: u64,
+    pub points: u64,
+    pub seed_version: u8,
+    pub initialized_at: UnixTimestamp,
+    /// The token account owner that last proved control of the NFT through
+    /// `SubmitOwnershipProof`, and the slot the proof was signed for.
+    pub proof_owner: Pubkey,
+    pub proof_slot: u64,
+    /// The NFT `InitializeStakeAccount` verified the user held in
+    /// `token_account` at `initialized_at`. Both stay unset when the account
+    /// did not hold exactly one token of a mint then, e.g. for a compressed
+    /// NFT's asset id.
+    pub initialized_mint: Pubkey,
+    /// How many of the config's `reward_mint_swaps` the stored reward
+    /// amounts are already denominated for, see `rescale_to_current_mint`.
+    pub reward_mint_generation: u8,
 }
 impl UserStakeInfo {
-    pub const SIZE: usize = 1 + 32 + 32 + 64 + 64 + 1;
+    pub const VERSION: u8 = 3;
+    /// Layout that stored the rate override as a borsh `Option<u64>`: a
+    /// single byte while unset, which moved every later field 8 bytes
+    /// forward. `SIZE` is unchanged, so `upgrade_from_v2` converts such an
+    /// account in place.
+    pub const V2_VERSION: u8 = 2;
+    const RATE_OVERRIDE_OFFSET: usize =
+        1 + 1 + 32 + 32 + 8 + 8 + 8 + 32 + 8 + 8 + 8 + 8 + 8 + 4 + 1 + 8 + 32 + 8;
+    pub const SIZE: usize = 1
+        + 1
+        + 32
+        + 32
+        + 8
+        + 8
+        + 8
+        + 32
+        + 8
+        + 8
+        + 8
+        + 8
+        + 8
+        + 4
+        + 1
+        + 8
+        + 32
+        + 8
+        + 1
+        + 8
+        + 8
+        + 2
+        + 8
+        + 2
+        + 8
+        + 8
+        + 1
+        + 8
+        + 32
+        + 8
+        + 32
+        + 1;
+
+    /// Moves `last_redeem_time` forward to `now` once the pending reward,
+    /// including `checkpoint_reward`, has been settled. A clock that regressed
+    /// behind it leaves it unchanged, so the skipped seconds are not paid twice.
+    pub fn advance_last_redeem_time(&mut self, now: UnixTimestamp) {
+        self.last_redeem_time = self.last_redeem_time.max(now);
+        self.checkpoint_reward = 0;
+    }
+    /// Rewrites the `data` of a version 2 account, at least `SIZE` bytes, in
+    /// the current layout.
+    pub fn upgrade_from_v2(data: &mut [u8]) {
+        data[0] = Self::VERSION;
+        let tag = Self::RATE_OVERRIDE_OFFSET;
+        if data[tag] == 0 {
+            data.copy_within(tag + 1..Self::SIZE - 8, tag + 9);
+            data[tag + 1..tag + 9].fill(0);
+        }
+    }
+    /// The per-second rate replacing the emission schedule for this stake,
+    /// if the admin set one.
+    pub fn rate_override(&self) -> Option<u64> {
+        self.has_rate_override.then_some(self.override_rate)
+    }
+    pub fn set_rate_override(&mut self, rate: Option<u64>) {
+        self.has_rate_override = rate.is_some();
+        self.override_rate = rate.unwrap_or(0);
+    }
+    pub fn is_stake_active(&self) -> bool {
+        self.flags & FLAG_STAKE_ACTIVE != 0
+    }
+    pub fn set_stake_active(&mut self, active: bool) {
+        self.set_flag(FLAG_STAKE_ACTIVE, active);
+    }
+    /// Whether the NFT token account's owner proved control of the NFT
+    /// through `SubmitOwnershipProof` since the stake last ended.
+    pub fn is_proof_verified(&self) -> bool {
+        self.flags & FLAG_PROOF_VERIFIED != 0
+    }
+    pub fn set_proof_verified(&mut self, verified: bool) {
+        self.set_flag(FLAG_PROOF_VERIFIED, verified);
+    }
+    /// Whether `owner` is the token account owner whose proof is on record.
+    pub fn is_proof_verified_for(&self, owner: &Pubkey) -> bool {
+        self.is_proof_verified() && self.proof_owner == *owner
+    }
+    /// Whether `ConvertPointsToTokens` already paid out the stake's points.
+    pub fn is_points_converted(&self) -> bool {
+        self.flags & FLAG_POINTS_CONVERTED != 0
+    }
+    pub fn set_points_converted(&mut self, converted: bool) {
+        self.set_flag(FLAG_POINTS_CONVERTED, converted);
+    }
+    /// Whether the staked NFT is compressed, with `token_account` and
+    /// `nft_mint` holding its asset id.
+    pub fn is_compressed(&self) -> bool {
+        self.flags & FLAG_COMPRESSED != 0
+    }
+    pub fn set_compressed(&mut self, compressed: bool) {
+        self.set_flag(FLAG_COMPRESSED, compressed);
+    }
+    pub fn set_initialized(&mut self, initialized: bool) {
+        self.set_flag(FLAG_INITIALIZED, initialized);
+    }
+    fn set_flag(&mut self, flag: u8, value: bool) {
+        if value {
+            self.flags |= flag;
+        } else {
+            self.flags &= !flag;
+        }
+    }
 }
 impl Sealed for UserStakeInfo {}
 impl IsInitialized for UserStakeInfo {
+    fn is_initialized(&self) -> bool {
+        self.flags & FLAG_INITIALIZED != 0
+    }
+}
+
+/// Layout used before the flags were packed into `UserStakeInfo::flags`. Its
+/// leading `is_initialized` byte doubles as version 1.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct LegacyUserStakeInfo {
+    pub is_initialized: bool,
+    pub user: Pubkey,
+    pub token_account: Pubkey,
+    pub stake_start_time: UnixTimestamp,
+    pub last_redeem_time: UnixTimestamp,
+    pub is_stake_active: bool,
+    pub total_redeemed: u64,
+}
+impl LegacyUserStakeInfo {
+    pub const VERSION: u8 = 1;
+}
+
+/// Layout of the floor price feed account: the collection floor price in
+/// lamports and when it was last updated.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct FloorPriceFeed {
+    pub price: u64,
+    pub updated_at: UnixTimestamp,
+}
+
+/// Layout of the circuit breaker account: whether its guardian has tripped
+/// it. Only the leading flag is read.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct CircuitBreaker {
+    pub tripped: bool,
+}
+
+/// Reward `rate` per second applied from `start_time` until the next segment.
+#[derive(BorshSerialize, BorshDeserialize, Clone, Copy)]
+pub struct EmissionSegment {
+    pub start_time: UnixTimestamp,
+    pub rate: u64,
+}
+impl EmissionSegment {
+    pub const SIZE: usize = 8 + 8;
+}
+
+/// Redeem boost for users holding at least `min_balance` governance tokens.
+#[derive(BorshSerialize, BorshDeserialize, Clone, Copy)]
+pub struct GovernanceBoostTier {
+    pub min_balance: u64,
+    pub boost_bps: u16,
+}
+impl GovernanceBoostTier {
+    pub const SIZE: usize = 8 + 2;
+}
+
+/// Reward multiplier for stakes held for at least `min_duration` seconds.
+#[derive(BorshSerialize, BorshDeserialize, Clone, Copy)]
+pub struct DurationTier {
+    pub min_duration: UnixTimestamp,
+    pub multiplier_bps: u16,
+}
+impl DurationTier {
+    pub const SIZE: usize = 8 + 2;
+}
+
+/// Protocol fee discount on redeems of stakes held for at least
+/// `min_duration` seconds.
+#[derive(BorshSerialize, BorshDeserialize, Clone, Copy)]
+pub struct FeeDiscountTier {
+    pub min_duration: UnixTimestamp,
+    pub discount_bps: u16,
+}
+impl FeeDiscountTier {
+    pub const SIZE: usize = 8 + 2;
+}
+
+/// A `SwapRewardMint` from a mint with `old_decimals` to one with
+/// `new_decimals`: amounts denominated before it are worth
+/// 10^(new_decimals - old_decimals) as many units after it.
+#[derive(BorshSerialize, BorshDeserialize, Clone, Copy)]
+pub struct RewardMintSwap {
+    pub old_decimals: u8,
+    pub new_decimals: u8,
+}
+impl RewardMintSwap {
+    pub const SIZE: usize = 1 + 1;
+}
+
+/// A past `[start, end)` interval during which accrual was paused.
+#[derive(BorshSerialize, BorshDeserialize, Clone, Copy)]
+pub struct PauseInterval {
+    pub start: UnixTimestamp,
+    pub end: UnixTimestamp,
+}
+impl PauseInterval {
+    pub const SIZE: usize = 8 + 8;
+}
+
+/// What a stake's reward is paid in. `Points` credits it to the stake's
+/// non-transferable `points` instead of minting reward tokens; `Sol` pays it
+/// in lamports from the `SOL_POOL_SEED` PDA, one lamport per reward unit.
+#[derive(BorshSerialize, BorshDeserialize, Clone, Copy, PartialEq, Eq)]
+pub enum AccrualMode {
+    Tokens,
+    Points,
+    Sol,
+}
+
+/// How reward arithmetic treats fractional units.
+#[derive(BorshSerialize, BorshDeserialize, Clone, Copy, PartialEq, Eq)]
+pub enum Rounding {
+    Down,
+    Up,
+}
+
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct StakeConfig {
+    pub is_initialized: bool,
+    pub admin: Pubkey,
+    pub reward_mint: Pubkey,
+    pub protocol_fee_account: Pubkey,
+    pub protocol_fee_bps: u16,
+    pub withhold_protocol_fee: bool,
+    pub emission_schedule: Vec<EmissionSegment>,
+    pub governance_mint: Pubkey,
+    pub governance_boost_tiers: Vec<GovernanceBoostTier>,
+    pub max_stakes_per_user: u32,
+    pub audit_merkle_tree: Pubkey,
+    pub swap_program: Pubkey,
+    pub swap_target_mint: Pubkey,
+    pub min_stake_amount: u64,
+    pub max_stakers: u32,
+    pub total_unique_stakers: u32,
+    pub reject_zero_reward_redeem: bool,
+    pub floor_price_feed: Pubkey,
+    pub max_floor_price_age: UnixTimestamp,
+    pub final_reward_grace_period: UnixTimestamp,
+    pub rate_revision: u32,
+    pub duration_tiers: Vec<DurationTier>,
+    pub allow_burn: bool,
+    pub burn_reward: u64,
+    pub strict_nonce: bool,
+    pub compound_rate_bps: u16,
+    pub max_virtual_staked_amount: u64,
+    pub min_wallet_age_slots: u64,
+    pub log_compute_units: bool,
+    pub paused_since: UnixTimestamp,
+    pub pause_intervals: Vec<PauseInterval>,
+    pub final_reward_rounding: Rounding,
+    pub reward_cliff: UnixTimestamp,
+    pub cliff_pays_from_start: bool,
+    pub proof_of_hold: bool,
+    pub max_backpay_seconds: UnixTimestamp,
+    pub wrapped_mint_authorities: Vec<Pubkey>,
+    pub fee_discount_tiers: Vec<FeeDiscountTier>,
+    pub min_protocol_fee_bps: u16,
+    pub pay_reward_on_paused_unstake: bool,
+    pub master_edition_boost_bps: u16,
+    pub restake_cooloff: UnixTimestamp,
+    pub version: u8,
+    pub prorate_partial_days: bool,
+    pub total_active_stakes: u32,
+    pub stake_dilution_divisor: u32,
+    pub sponsor_rent: bool,
+    pub timelock_program: Pubkey,
+    pub auto_redeem_on_unstake: bool,
+    pub reward_milestones: Vec<DurationTier>,
+    pub redeem_count: u64,
+    pub unstake_count: u64,
+    pub stake_count: u64,
+    pub total_emitted: u64,
+    pub mint_authority: Pubkey,
+    pub circuit_breaker: Pubkey,
+    pub strict_total_redeemed: bool,
+    pub token_program: Pubkey,
+    pub accrual_mode: AccrualMode,
+    pub total_points: u64,
+    pub points_to_token_rate: u64,
+    pub min_nfts_to_qualify: u32,
+    pub compliance_program: Pubkey,
+    pub compressed_nft_tree: Pubkey,
+    pub require_current_seed_version: bool,
+    pub max_schedule_horizon: UnixTimestamp,
+    pub finalized: bool,
+    pub accrue_from_initialize: bool,
+    pub delegate_stake_authorities: Vec<Pubkey>,
+    /// Every `SwapRewardMint` so far, oldest first.
+    pub reward_mint_swaps: Vec<RewardMintSwap>,
+}
+impl StakeConfig {
+    /// Schema version written by `InitializeConfig`. Configs created before
+    /// the version was stored read zero.
+    pub const VERSION: u8 = 1;
+    pub const SIZE: usize = 1
+        + 32
+        + 32
+        + 32
+        + 2
+        + 1
+        + 4
+        + MAX_EMISSION_SEGMENTS * EmissionSegment::SIZE
+        + 32
+        + 4
+        + MAX_GOVERNANCE_BOOST_TIERS * GovernanceBoostTier::SIZE
+        + 4
+        + 32
+        + 32
+        + 32
+        + 8
+        + 4
+        + 4
+        + 1
+        + 32
+        + 8
+        + 8
+        + 4
+        + 4
+        + MAX_DURATION_TIERS * DurationTier::SIZE
+        + 1
+        + 8
+        + 1
+        + 2
+        + 8
+        + 8
+        + 1
+        + 8
+        + 4
+        + MAX_PAUSE_INTERVALS * PauseInterval::SIZE
+        + 1
+        + 8
+        + 1
+        + 1
+        + 8
+        + 4
+        + MAX_WRAPPED_MINT_AUTHORITIES * 32
+        + 4
+        + MAX_FEE_DISCOUNT_TIERS * FeeDiscountTier::SIZE
+        + 2
+        + 1
+        + 2
+        + 8
+        + 1
+        + 1
+        + 4
+        + 4
+        + 1
+        + 32
+        + 1
+        + 4
+        + MAX_DURATION_TIERS * DurationTier::SIZE
+        + 8
+        + 8
+        + 8
+        + 8
+        + 32
+        + 32
+        + 1
+        + 32
+        + 1
+        + 8
+        + 8
+        + 4
+        + 32
+        + 32
+        + 1
+        + 8
+        + 1
+        + 1
+        + 4
+        + MAX_DELEGATE_STAKE_AUTHORITIES * 32
+        + 4
+        + MAX_REWARD_MINT_SWAPS * RewardMintSwap::SIZE;
+
+    /// Whether partial days accrue. Version 0 configs predate the flag and
+    /// read it as zero, but accrued per second.
+    pub fn prorates_partial_days(&self) -> bool {
+        self.prorate_partial_days || self.version == 0
+    }
+
+    /// Whether Unstake pays the final reward. Version 0 configs predate the
+    /// flag and read it as zero, but always paid.
+    pub fn auto_redeems_on_unstake(&self) -> bool {
+        self.auto_redeem_on_unstake || self.version == 0
+    }
+}
+impl Sealed for StakeConfig {}
+impl IsInitialized for StakeConfig {
+    fn is_initialized(&self) -> bool {
+        self.is_initialized
+    }
+}
+
+/// One per NFT mint, pointing at the stake account currently staking it (the
+/// default pubkey when the mint is not staked). `last_unstake_time` is when
+/// the mint was last unstaked through any stake account, so rules about a
+/// prior unstake follow the NFT rather than the token account it sits in;
+/// zero if it never was, or while the record is still `LEGACY_SIZE` long.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct StakedMint {
+    pub stake_state: Pubkey,
+    pub last_unstake_time: UnixTimestamp,
+}
+impl StakedMint {
+    /// Records created before `last_unstake_time` was appended. They read as
+    /// never unstaked until the next stake grows them to `SIZE`.
+    pub const LEGACY_SIZE: usize = 32;
+    pub const SIZE: usize = 32 + 8;
+}
+
+/// Per-user aggregate across all of a user's stake accounts. With
+/// `min_nfts_to_qualify` set, `qualified_since` is when `active_stakes` last
+/// rose to the minimum and `qualified_until` when it last fell below it; both
+/// are zero until first recorded, and while the account is still
+/// `LEGACY_SIZE` long.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct UserStakeAccount {
+    pub is_initialized: bool,
+    pub user: Pubkey,
+    pub active_stakes: u32,
+    pub qualified_since: UnixTimestamp,
+    pub qualified_until: UnixTimestamp,
+}
+impl UserStakeAccount {
+    /// Accounts created before the qualification times were appended. They
+    /// are grown to `SIZE` by the user's next stake.
+    pub const LEGACY_SIZE: usize = 1 + 32 + 4;
+    pub const SIZE: usize = 1 + 32 + 4 + 8 + 8;
+}
+impl Sealed for UserStakeAccount {}
+impl IsInitialized for UserStakeAccount {
     fn is_initialized(&self) -> bool {
         self.is_initialized
     }
 }
+
+/// Stake accounts that qualified for airdrop `snapshot_id`: actively staked for
+/// at least `min_duration` seconds at `taken_at`.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct AirdropSnapshot {
+    pub is_initialized: bool,
+    pub snapshot_id: u64,
+    pub taken_at: UnixTimestamp,
+    pub min_duration: UnixTimestamp,
+    pub stake_accounts: Vec<Pubkey>,
+}
+impl AirdropSnapshot {
+    pub const SIZE: usize = 1 + 8 + 8 + 8 + 4 + MAX_SNAPSHOT_STAKE_ACCOUNTS * 32;
+}
+impl Sealed for AirdropSnapshot {}
+impl IsInitialized for AirdropSnapshot {
+    fn is_initialized(&self) -> bool {
+        self.is_initialized
+    }
+}
+
+/// Admin-attested registry entry: the first slot `wallet` was seen active.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct WalletAgeRecord {
+    pub is_initialized: bool,
+    pub wallet: Pubkey,
+    pub first_seen_slot: u64,
+}
+impl WalletAgeRecord {
+    pub const SIZE: usize = 1 + 32 + 8;
+}
+impl Sealed for WalletAgeRecord {}
+impl IsInitialized for WalletAgeRecord {
+    fn is_initialized(&self) -> bool {
+        self.is_initialized
+    }
+}
+
+/// Return data of `GetConfigVersion`. `compatible` is set when the stored
+/// config uses the schema version this program reads.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct ConfigVersion {
+    pub stored_version: u8,
+    pub program_version: u8,
+    pub compatible: bool,
+}
+
+/// Return data of `GetRewardBreakdown`: every input of a stake's pending
+/// reward and what it comes to. `window_from` and `window_to` bound the
+/// accrual window, empty when the clock is behind `last_redeem_time` or
+/// before the `reward_cliff`; `backpay_capped` is set when
+/// `max_backpay_seconds` cut the window short and `deferred_seconds` counts
+/// the partial day carried to the next redeem without
+/// `prorate_partial_days`. Amounts ending in `_fixed` are in fixed point (see
+/// `REWARD_PRECISION`); `reward` is what a redeem would pay before the
+/// governance boost and the protocol fee.
+#[derive(BorshSerialize, BorshDeserialize, Default)]
+pub struct RewardBreakdown {
+    pub now: UnixTimestamp,
+    pub window_from: UnixTimestamp,
+    pub window_to: UnixTimestamp,
+    pub clock_behind: bool,
+    pub before_cliff: bool,
+    pub backpay_capped: bool,
+    pub deferred_seconds: i64,
+    pub rate_override: Option<u64>,
+    pub base_accrual: u64,
+    pub dilution_factor: u32,
+    pub stake_weight: u64,
+    pub edition_boost_bps: u16,
+    pub milestone_multiplier_bps: u16,
+    pub duration_tier_bps: Option<u16>,
+    pub scaled_accrual_fixed: u128,
+    pub compound_bonus_fixed: u128,
+    pub carried_fixed: u128,
+    pub pending_fixed: u128,
+    pub reward: u64,
+}
+
+/// Why an unstake would currently be refused, as reported by `CanUnstake`.
+#[derive(BorshSerialize, BorshDeserialize, Clone, Copy, PartialEq, Eq)]
+pub enum UnstakeStatus {
+    Unblocked,
+    NotStaked,
+    NeedsMigration,
+}
+
+/// Return data of `CanUnstake`. Every block it reports lifts by user action
+/// rather than with time, so there is no unblock time to count down to.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct UnstakeEligibility {
+    pub status: UnstakeStatus,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use solana_program::borsh0_10::try_from_slice_unchecked;
+
+    type FlagAccessors = (u8, fn(&UserStakeInfo) -> bool, fn(&mut UserStakeInfo, bool));
+
+    fn zeroed_stake_info() -> UserStakeInfo {
+        try_from_slice_unchecked(&[0; UserStakeInfo::SIZE]).unwrap()
+    }
+
+    #[test]
+    fn stake_flags_set_and_clear_independently() {
+        let accessors: [FlagAccessors; 5] = [
+            (
+                FLAG_INITIALIZED,
+                |info| info.is_initialized(),
+                UserStakeInfo::set_initialized,
+            ),
+            (
+                FLAG_STAKE_ACTIVE,
+                UserStakeInfo::is_stake_active,
+                UserStakeInfo::set_stake_active,
+            ),
+            (
+                FLAG_PROOF_VERIFIED,
+                UserStakeInfo::is_proof_verified,
+                UserStakeInfo::set_proof_verified,
+            ),
+            (
+                FLAG_POINTS_CONVERTED,
+                UserStakeInfo::is_points_converted,
+                UserStakeInfo::set_points_converted,
+            ),
+            (
+                FLAG_COMPRESSED,
+                UserStakeInfo::is_compressed,
+                UserStakeInfo::set_compressed,
+            ),
+        ];
+        let defined = accessors.iter().fold(0, |flags, (flag, _, _)| flags | flag);
+        assert_eq!(defined, ALL_FLAGS);
+        for (flag, get, set) in accessors {
+            let mut info = zeroed_stake_info();
+            assert!(!get(&info));
+            set(&mut info, true);
+            assert!(get(&info));
+            assert_eq!(info.flags, flag);
+            info.flags = u8::MAX;
+            set(&mut info, false);
+            assert!(!get(&info));
+            assert_eq!(info.flags, !flag);
+        }
+    }
+
+    #[test]
+    fn last_redeem_time_never_moves_backwards() {
+        let mut info = zeroed_stake_info();
+        info.last_redeem_time = 1_000;
+        info.advance_last_redeem_time(999);
+        assert_eq!(info.last_redeem_time, 1_000);
+        info.advance_last_redeem_time(1_001);
+        assert_eq!(info.last_redeem_time, 1_001);
+    }
+
+    #[test]
+    fn day_index_turns_over_at_midnight_utc() {
+        // 2023-11-15T00:00:00Z.
+        let midnight = 19_676 * SECONDS_PER_DAY;
+        assert_eq!(day_index(midnight - 1), 19_675);
+        assert_eq!(day_index(midnight), 19_676);
+        assert_eq!(day_index(midnight + SECONDS_PER_DAY - 1), 19_676);
+        assert_eq!(day_index(midnight + SECONDS_PER_DAY), 19_677);
+        assert_eq!(day_index(0), 0);
+        assert_eq!(day_index(-1), -1);
+        assert_eq!(day_index(-SECONDS_PER_DAY), -1);
+        assert_eq!(day_index(-SECONDS_PER_DAY - 1), -2);
+    }
+
+    #[test]
+    fn a_stake_with_every_field_at_its_max_fills_size_and_round_trips() {
+        let max_key = Pubkey::new_from_array([u8::MAX; 32]);
+        let info = UserStakeInfo {
+            version: u8::MAX,
+            flags: u8::MAX,
+            user: max_key,
+            token_account: max_key,
+            stake_start_time: UnixTimestamp::MAX,
+            last_redeem_time: UnixTimestamp::MAX,
+            total_redeemed: u64::MAX,
+            nft_mint: max_key,
+            stake_weight: u64::MAX,
+            unclaimed_final_reward: u64::MAX,
+            final_reward_expires_at: UnixTimestamp::MAX,
+            cached_rate: u64::MAX,
+            cached_rate_valid_until: UnixTimestamp::MAX,
+            cached_rate_revision: u32::MAX,
+            bump: u8::MAX,
+            last_nonce: u64::MAX,
+            preferred_reward_destination: max_key,
+            virtual_staked_amount: u64::MAX,
+            has_rate_override: true,
+            override_rate: u64::MAX,
+            checkpoint_reward: u64::MAX,
+            edition_boost_bps: u16::MAX,
+            last_unstake_time: UnixTimestamp::MAX,
+            milestone_multiplier_bps: u16::MAX,
+            reward_remainder: u64::MAX,
+            points: u64::MAX,
+            seed_version: u8::MAX,
+            initialized_at: UnixTimestamp::MAX,
+            proof_owner: max_key,
+            proof_slot: u64::MAX,
+            initialized_mint: max_key,
+            reward_mint_generation: u8::MAX,
+        };
+        let mut data = [0; UserStakeInfo::SIZE];
+        let mut unwritten = &mut data[..];
+        info.serialize(&mut unwritten).unwrap();
+        assert!(unwritten.is_empty());
+
+        // Strict deserialization also rejects bytes left over at the end.
+        let decoded = UserStakeInfo::try_from_slice(&data).unwrap();
+        assert_eq!(decoded.try_to_vec().unwrap(), data);
+        assert_eq!(decoded.rate_override(), Some(u64::MAX));
+        assert_eq!(decoded.proof_owner, max_key);
+        assert_eq!(decoded.proof_slot, u64::MAX);
+    }
+
+    #[test]
+    fn later_fields_keep_their_offset_with_or_without_a_rate_override() {
+        let mut info = zeroed_stake_info();
+        info.checkpoint_reward = 7;
+        let unset = info.try_to_vec().unwrap();
+        info.set_rate_override(Some(0));
+        let set = info.try_to_vec().unwrap();
+        assert_eq!(info.rate_override(), Some(0));
+        assert_eq!(unset.len(), UserStakeInfo::SIZE);
+        assert_eq!(set.len(), UserStakeInfo::SIZE);
+        let at = UserStakeInfo::RATE_OVERRIDE_OFFSET + 9;
+        assert_eq!(unset[at..at + 8], 7u64.to_le_bytes());
+        assert_eq!(set[at..at + 8], 7u64.to_le_bytes());
+    }
+
+    #[test]
+    fn version_2_accounts_upgrade_to_the_fixed_offsets() {
+        let mut info = zeroed_stake_info();
+        info.version = UserStakeInfo::VERSION;
+        info.checkpoint_reward = 7;
+        info.initialized_at = 1_700_000_000;
+        for rate in [None, Some(5)] {
+            info.set_rate_override(rate);
+            let current = info.try_to_vec().unwrap();
+
+            // The same stake as version 2 wrote it, the override as a borsh
+            // option.
+            let at = UserStakeInfo::RATE_OVERRIDE_OFFSET;
+            let mut v2 = vec![UserStakeInfo::V2_VERSION];
+            v2.extend_from_slice(&current[1..at]);
+            v2.extend_from_slice(&rate.try_to_vec().unwrap());
+            v2.extend_from_slice(&current[at + 9..]);
+            v2.resize(UserStakeInfo::SIZE, 0);
+
+            UserStakeInfo::upgrade_from_v2(&mut v2);
+            assert_eq!(v2, current);
+        }
+    }
+}
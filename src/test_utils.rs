@@ -0,0 +1,565 @@
+//! Unit test scaffolding: accounts laid out in memory the way the runtime
+//! serializes them, so `realloc` and `assign` work on them, and syscall stubs
+//! serving the clock, rent and return data, and CPIs into SPL Token, the
+//! system program and mock programs.
+
+use crate::state::{
+    StakeConfig, StakedMint, UserStakeAccount, UserStakeInfo, CONFIG_SEED, FLAG_INITIALIZED,
+    FLAG_STAKE_ACTIVE, MINT_AUTHORITY_SEED, STAKED_MINT_SEED, STAKE_SEED_VERSION, USER_STAKE_SEED,
+};
+use borsh::BorshSerialize;
+use solana_program::{
+    account_info::AccountInfo,
+    borsh0_10::try_from_slice_unchecked,
+    clock::{Clock, UnixTimestamp},
+    entrypoint::{ProgramResult, MAX_PERMITTED_DATA_INCREASE},
+    instruction::Instruction,
+    program_error::ProgramError,
+    program_option::COption,
+    program_pack::Pack,
+    program_stubs::{set_syscall_stubs, SyscallStubs},
+    program_utils::limited_deserialize,
+    pubkey::Pubkey,
+    rent::Rent,
+    system_instruction::SystemInstruction,
+    system_program,
+    sysvar::{
+        self,
+        instructions::{
+            construct_instructions_data, store_current_index, BorrowedAccountMeta,
+            BorrowedInstruction,
+        },
+    },
+};
+use spl_token::state::{Account as TokenAccount, AccountState, Mint};
+use std::cell::RefCell;
+use std::sync::Once;
+
+pub const PROGRAM_ID: Pubkey = Pubkey::new_from_array([7; 32]);
+/// Unix timestamp the clock starts at in every test.
+pub const START: UnixTimestamp = 1_700_000_000;
+pub const START_SLOT: u64 = 1_000;
+
+/// A program invoked through CPI in place of a real one.
+pub type MockProgram = fn(&Instruction, &[AccountInfo]) -> ProgramResult;
+
+thread_local! {
+    static CLOCK: RefCell<Clock> = RefCell::new(Clock::default());
+    static RETURN_DATA: RefCell<Option<(Pubkey, Vec<u8>)>> = const { RefCell::new(None) };
+    static LOGS: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+    static EVENTS: RefCell<Vec<Vec<u8>>> = const { RefCell::new(Vec::new()) };
+    static INVOKED: RefCell<Vec<Instruction>> = const { RefCell::new(Vec::new()) };
+    static MOCKS: RefCell<Vec<(Pubkey, MockProgram)>> = const { RefCell::new(Vec::new()) };
+    /// Program whose code runs, so return data is attributed to it.
+    static CALLER: RefCell<Pubkey> = const { RefCell::new(PROGRAM_ID) };
+}
+
+struct TestStubs;
+
+impl SyscallStubs for TestStubs {
+    fn sol_log(&self, message: &str) {
+        LOGS.with(|logs| logs.borrow_mut().push(message.to_string()));
+    }
+
+    fn sol_log_compute_units(&self) {
+        self.sol_log("compute units");
+    }
+
+    fn sol_log_data(&self, fields: &[&[u8]]) {
+        EVENTS.with(|events| events.borrow_mut().push(fields.concat()));
+    }
+
+    fn sol_get_clock_sysvar(&self, var_addr: *mut u8) -> u64 {
+        let clock = CLOCK.with(|clock| clock.borrow().clone());
+        unsafe { *(var_addr as *mut Clock) = clock };
+        solana_program::entrypoint::SUCCESS
+    }
+
+    fn sol_get_rent_sysvar(&self, var_addr: *mut u8) -> u64 {
+        unsafe { *(var_addr as *mut Rent) = Rent::default() };
+        solana_program::entrypoint::SUCCESS
+    }
+
+    fn sol_set_return_data(&self, data: &[u8]) {
+        let caller = CALLER.with(|caller| *caller.borrow());
+        RETURN_DATA.with(|return_data| *return_data.borrow_mut() = Some((caller, data.to_vec())));
+    }
+
+    fn sol_get_return_data(&self) -> Option<(Pubkey, Vec<u8>)> {
+        RETURN_DATA.with(|return_data| return_data.borrow().clone())
+    }
+
+    fn sol_invoke_signed(
+        &self,
+        instruction: &Instruction,
+        account_infos: &[AccountInfo],
+        signers_seeds: &[&[&[u8]]],
+    ) -> ProgramResult {
+        INVOKED.with(|invoked| invoked.borrow_mut().push(instruction.clone()));
+        RETURN_DATA.with(|return_data| *return_data.borrow_mut() = None);
+        let signers: Vec<Pubkey> = signers_seeds
+            .iter()
+            .filter_map(|seeds| Pubkey::create_program_address(seeds, &PROGRAM_ID).ok())
+            .collect();
+        let mut accounts = Vec::with_capacity(instruction.accounts.len());
+        for meta in &instruction.accounts {
+            let mut info = account_infos
+                .iter()
+                .find(|info| *info.key == meta.pubkey)
+                .ok_or(ProgramError::NotEnoughAccountKeys)?
+                .clone();
+            if meta.is_signer && !info.is_signer {
+                if !signers.contains(info.key) {
+                    return Err(ProgramError::MissingRequiredSignature);
+                }
+                info.is_signer = true;
+            }
+            accounts.push(info);
+        }
+        let program_id = instruction.program_id;
+        let previous = CALLER.with(|caller| caller.replace(program_id));
+        let result = if program_id == spl_token::id() {
+            spl_token::processor::Processor::process(&program_id, &accounts, &instruction.data)
+        } else if program_id == system_program::id() {
+            process_system_instruction(&accounts, &instruction.data)
+        } else {
+            let mock = MOCKS.with(|mocks| {
+                mocks
+                    .borrow()
+                    .iter()
+                    .find(|(id, _)| *id == program_id)
+                    .map(|(_, mock)| *mock)
+            });
+            match mock {
+                Some(mock) => mock(instruction, &accounts),
+                None => Ok(()),
+            }
+        };
+        CALLER.with(|caller| *caller.borrow_mut() = previous);
+        result
+    }
+}
+
+/// The system program instructions the processor issues.
+fn process_system_instruction(accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+    let instruction: SystemInstruction =
+        limited_deserialize(data, 1024).map_err(|_| ProgramError::InvalidInstructionData)?;
+    match instruction {
+        SystemInstruction::CreateAccount {
+            lamports,
+            space,
+            owner,
+        } => {
+            let (from, to) = (&accounts[0], &accounts[1]);
+            if !to.data_is_empty() || to.lamports() > 0 {
+                return Err(ProgramError::AccountAlreadyInitialized);
+            }
+            transfer(from, to, lamports)?;
+            to.realloc(space as usize, true)?;
+            to.assign(&owner);
+            Ok(())
+        }
+        SystemInstruction::Transfer { lamports } => transfer(&accounts[0], &accounts[1], lamports),
+        SystemInstruction::Allocate { space } => accounts[0].realloc(space as usize, true),
+        SystemInstruction::Assign { owner } => {
+            accounts[0].assign(&owner);
+            Ok(())
+        }
+        _ => Err(ProgramError::InvalidInstructionData),
+    }
+}
+
+fn transfer(from: &AccountInfo, to: &AccountInfo, lamports: u64) -> ProgramResult {
+    if !from.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    let remaining = from
+        .lamports()
+        .checked_sub(lamports)
+        .ok_or(ProgramError::InsufficientFunds)?;
+    **from.try_borrow_mut_lamports()? = remaining;
+    **to.try_borrow_mut_lamports()? += lamports;
+    Ok(())
+}
+
+/// Installs the syscall stubs and resets the clock, logs, return data, CPI
+/// log and mocks of the calling test's thread.
+pub fn setup() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        set_syscall_stubs(Box::new(TestStubs));
+    });
+    CLOCK.with(|clock| {
+        *clock.borrow_mut() = Clock {
+            slot: START_SLOT,
+            unix_timestamp: START,
+            ..Clock::default()
+        }
+    });
+    RETURN_DATA.with(|return_data| *return_data.borrow_mut() = None);
+    LOGS.with(|logs| logs.borrow_mut().clear());
+    EVENTS.with(|events| events.borrow_mut().clear());
+    INVOKED.with(|invoked| invoked.borrow_mut().clear());
+    MOCKS.with(|mocks| mocks.borrow_mut().clear());
+}
+
+pub fn set_time(unix_timestamp: UnixTimestamp) {
+    CLOCK.with(|clock| clock.borrow_mut().unix_timestamp = unix_timestamp);
+}
+
+/// Routes CPIs into `program_id` to `mock`.
+pub fn mock_program(program_id: Pubkey, mock: MockProgram) {
+    MOCKS.with(|mocks| mocks.borrow_mut().push((program_id, mock)));
+}
+
+pub fn logs() -> Vec<String> {
+    LOGS.with(|logs| logs.borrow().clone())
+}
+
+/// Serialized `StakeEvent`s emitted so far.
+pub fn events() -> Vec<Vec<u8>> {
+    EVENTS.with(|events| events.borrow().clone())
+}
+
+pub fn return_data() -> Option<(Pubkey, Vec<u8>)> {
+    RETURN_DATA.with(|return_data| return_data.borrow().clone())
+}
+
+/// Instructions invoked through CPI so far.
+pub fn invoked() -> Vec<Instruction> {
+    INVOKED.with(|invoked| invoked.borrow().clone())
+}
+
+/// The key preceded by the original data length, which `realloc` reads from
+/// just before the key as in the runtime's input serialization.
+#[derive(Clone)]
+#[repr(C)]
+struct KeySlot {
+    original_data_len: u32,
+    key: Pubkey,
+}
+
+#[derive(Clone)]
+pub struct TestAccount {
+    slot: KeySlot,
+    pub owner: Pubkey,
+    pub lamports: u64,
+    /// The data length as a `u64`, then the data and room for it to grow.
+    buf: Vec<u64>,
+    pub is_signer: bool,
+    pub is_writable: bool,
+    pub executable: bool,
+}
+
+impl TestAccount {
+    pub fn new(key: Pubkey, owner: Pubkey, lamports: u64, data: &[u8]) -> Self {
+        let words = 1 + (data.len() + MAX_PERMITTED_DATA_INCREASE).div_ceil(8);
+        let mut account = Self {
+            slot: KeySlot {
+                original_data_len: data.len() as u32,
+                key,
+            },
+            owner,
+            lamports,
+            buf: vec![0; words],
+            is_signer: false,
+            is_writable: true,
+            executable: false,
+        };
+        account.buf[0] = data.len() as u64;
+        account.bytes_mut()[..data.len()].copy_from_slice(data);
+        account
+    }
+
+    /// A rent exempt account of `owner` holding `data`.
+    pub fn with_data(key: Pubkey, owner: Pubkey, data: &[u8]) -> Self {
+        Self::new(
+            key,
+            owner,
+            Rent::default().minimum_balance(data.len()),
+            data,
+        )
+    }
+
+    /// A wallet holding `lamports`.
+    pub fn wallet(key: Pubkey, lamports: u64) -> Self {
+        Self::new(key, system_program::id(), lamports, &[])
+    }
+
+    /// An executable program account.
+    pub fn program(key: Pubkey) -> Self {
+        let mut account = Self::new(key, solana_program::bpf_loader::id(), 1, &[]);
+        account.executable = true;
+        account.is_writable = false;
+        account
+    }
+
+    pub fn signer(mut self) -> Self {
+        self.is_signer = true;
+        self
+    }
+
+    pub fn key(&self) -> Pubkey {
+        self.slot.key
+    }
+
+    pub fn data(&self) -> &[u8] {
+        let len = self.buf[0] as usize;
+        &self.bytes()[..len]
+    }
+
+    fn bytes(&self) -> &[u8] {
+        let words = &self.buf[1..];
+        unsafe { std::slice::from_raw_parts(words.as_ptr() as *const u8, words.len() * 8) }
+    }
+
+    fn bytes_mut(&mut self) -> &mut [u8] {
+        let words = &mut self.buf[1..];
+        unsafe { std::slice::from_raw_parts_mut(words.as_mut_ptr() as *mut u8, words.len() * 8) }
+    }
+
+    pub fn set_data(&mut self, data: &[u8]) {
+        self.buf[0] = data.len() as u64;
+        self.bytes_mut()[..data.len()].copy_from_slice(data);
+    }
+
+    pub fn info(&mut self) -> AccountInfo<'_> {
+        let len = self.buf[0] as usize;
+        let words = &mut self.buf[1..];
+        let data = unsafe { std::slice::from_raw_parts_mut(words.as_mut_ptr() as *mut u8, len) };
+        AccountInfo::new(
+            &self.slot.key,
+            self.is_signer,
+            self.is_writable,
+            &mut self.lamports,
+            data,
+            &self.owner,
+            self.executable,
+            0,
+        )
+    }
+
+    pub fn stake_info(&self) -> UserStakeInfo {
+        let mut data = self.data().to_vec();
+        data.resize(UserStakeInfo::SIZE, 0);
+        try_from_slice_unchecked(&data).unwrap()
+    }
+
+    pub fn set_stake_info(&mut self, account_data: &UserStakeInfo) {
+        let mut data = account_data.try_to_vec().unwrap();
+        data.resize(self.data().len().max(UserStakeInfo::SIZE), 0);
+        self.set_data(&data);
+    }
+
+    pub fn config(&self) -> StakeConfig {
+        let mut data = self.data().to_vec();
+        data.resize(StakeConfig::SIZE, 0);
+        try_from_slice_unchecked(&data).unwrap()
+    }
+
+    pub fn user_stakes(&self) -> UserStakeAccount {
+        try_from_slice_unchecked(self.data()).unwrap()
+    }
+
+    pub fn staked_mint(&self) -> StakedMint {
+        try_from_slice_unchecked(self.data()).unwrap()
+    }
+
+    pub fn token_amount(&self) -> u64 {
+        TokenAccount::unpack(self.data()).unwrap().amount
+    }
+
+    pub fn mint_supply(&self) -> u64 {
+        Mint::unpack(self.data()).unwrap().supply
+    }
+}
+
+/// `AccountInfo`s of `accounts`, in order.
+pub fn infos(accounts: &mut [TestAccount]) -> Vec<AccountInfo<'_>> {
+    accounts.iter_mut().map(TestAccount::info).collect()
+}
+
+pub fn config_address() -> Pubkey {
+    Pubkey::find_program_address(&[CONFIG_SEED], &PROGRAM_ID).0
+}
+
+pub fn mint_authority_address() -> Pubkey {
+    Pubkey::find_program_address(&[MINT_AUTHORITY_SEED], &PROGRAM_ID).0
+}
+
+pub fn stake_address(user: &Pubkey, nft_token_account: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[
+            &[STAKE_SEED_VERSION],
+            user.as_ref(),
+            nft_token_account.as_ref(),
+        ],
+        &PROGRAM_ID,
+    )
+}
+
+pub fn user_stake_address(user: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[USER_STAKE_SEED, user.as_ref()], &PROGRAM_ID).0
+}
+
+pub fn staked_mint_address(nft_mint: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[STAKED_MINT_SEED, nft_mint.as_ref()], &PROGRAM_ID).0
+}
+
+/// A config of `admin` as `InitializeConfig` leaves it, paying rewards in
+/// `reward_mint` with the protocol fee going to `protocol_fee_account`.
+pub fn new_config(admin: Pubkey, reward_mint: Pubkey, protocol_fee_account: Pubkey) -> StakeConfig {
+    let mut config: StakeConfig = try_from_slice_unchecked(&[0; StakeConfig::SIZE]).unwrap();
+    config.is_initialized = true;
+    config.version = StakeConfig::VERSION;
+    config.admin = admin;
+    config.reward_mint = reward_mint;
+    config.protocol_fee_account = protocol_fee_account;
+    config.prorate_partial_days = true;
+    config.auto_redeem_on_unstake = true;
+    config
+}
+
+pub fn config_account(config: &StakeConfig) -> TestAccount {
+    let mut data = config.try_to_vec().unwrap();
+    data.resize(StakeConfig::SIZE, 0);
+    TestAccount::with_data(config_address(), PROGRAM_ID, &data)
+}
+
+/// A stake account of `user` for `nft_token_account`, initialized but not
+/// staked.
+pub fn new_stake_info(user: Pubkey, nft_token_account: Pubkey) -> UserStakeInfo {
+    let mut account_data: UserStakeInfo =
+        try_from_slice_unchecked(&[0; UserStakeInfo::SIZE]).unwrap();
+    account_data.version = UserStakeInfo::VERSION;
+    account_data.flags = FLAG_INITIALIZED;
+    account_data.user = user;
+    account_data.token_account = nft_token_account;
+    account_data.seed_version = STAKE_SEED_VERSION;
+    account_data.bump = stake_address(&user, &nft_token_account).1;
+    account_data
+}
+
+/// A stake of `nft_mint` by `user` through `nft_token_account`, active since
+/// `staked_at`.
+pub fn active_stake_info(
+    user: Pubkey,
+    nft_token_account: Pubkey,
+    nft_mint: Pubkey,
+    staked_at: UnixTimestamp,
+) -> UserStakeInfo {
+    let mut account_data = new_stake_info(user, nft_token_account);
+    account_data.flags |= FLAG_STAKE_ACTIVE;
+    account_data.nft_mint = nft_mint;
+    account_data.stake_start_time = staked_at;
+    account_data.last_redeem_time = staked_at;
+    account_data
+}
+
+pub fn stake_account(account_data: &UserStakeInfo) -> TestAccount {
+    let key = stake_address(&account_data.user, &account_data.token_account).0;
+    let mut account = TestAccount::with_data(key, PROGRAM_ID, &[0; UserStakeInfo::SIZE]);
+    account.set_stake_info(account_data);
+    account
+}
+
+pub fn user_stake_account(user: Pubkey, active_stakes: u32) -> TestAccount {
+    let user_stakes = UserStakeAccount {
+        is_initialized: true,
+        user,
+        active_stakes,
+        qualified_since: 0,
+        qualified_until: 0,
+    };
+    TestAccount::with_data(
+        user_stake_address(&user),
+        PROGRAM_ID,
+        &user_stakes.try_to_vec().unwrap(),
+    )
+}
+
+pub fn staked_mint_account(nft_mint: &Pubkey, stake_state: Pubkey) -> TestAccount {
+    TestAccount::with_data(
+        staked_mint_address(nft_mint),
+        PROGRAM_ID,
+        &StakedMint {
+            stake_state,
+            last_unstake_time: 0,
+        }
+        .try_to_vec()
+        .unwrap(),
+    )
+}
+
+/// An address nothing exists at yet, for accounts the processor creates.
+pub fn empty_account(key: Pubkey) -> TestAccount {
+    TestAccount::new(key, system_program::id(), 0, &[])
+}
+
+/// The instructions sysvar of a transaction made of `instructions`, with the
+/// one at `current` executing.
+pub fn instructions_sysvar(instructions: &[Instruction], current: u16) -> TestAccount {
+    let borrowed: Vec<_> = instructions
+        .iter()
+        .map(|instruction| BorrowedInstruction {
+            program_id: &instruction.program_id,
+            accounts: instruction
+                .accounts
+                .iter()
+                .map(|meta| BorrowedAccountMeta {
+                    pubkey: &meta.pubkey,
+                    is_signer: meta.is_signer,
+                    is_writable: meta.is_writable,
+                })
+                .collect(),
+            data: &instruction.data,
+        })
+        .collect();
+    let mut data = construct_instructions_data(&borrowed);
+    store_current_index(&mut data, current);
+    TestAccount::new(sysvar::instructions::id(), sysvar::id(), 1, &data)
+}
+
+pub fn mint_account(key: Pubkey, mint_authority: Option<Pubkey>, supply: u64) -> TestAccount {
+    let mint = Mint {
+        mint_authority: mint_authority.into(),
+        supply,
+        decimals: 0,
+        is_initialized: true,
+        freeze_authority: COption::None,
+    };
+    let mut data = vec![0; Mint::LEN];
+    Mint::pack(mint, &mut data).unwrap();
+    TestAccount::with_data(key, spl_token::id(), &data)
+}
+
+pub fn token_account(key: Pubkey, mint: Pubkey, owner: Pubkey, amount: u64) -> TestAccount {
+    let token_account = TokenAccount {
+        mint,
+        owner,
+        amount,
+        state: AccountState::Initialized,
+        ..TokenAccount::default()
+    };
+    let mut data = vec![0; TokenAccount::LEN];
+    TokenAccount::pack(token_account, &mut data).unwrap();
+    TestAccount::with_data(key, spl_token::id(), &data)
+}
+
+/// A config paying rewards through freshly created reward accounts, with its
+/// admin, and those accounts in `RewardAccounts` order: the reward mint, the
+/// user's reward token account, the protocol fee token account, the mint
+/// authority PDA and the token program.
+pub fn reward_pool(user: Pubkey) -> (StakeConfig, Vec<TestAccount>) {
+    let reward_mint = Pubkey::new_unique();
+    let protocol_fee_account = Pubkey::new_unique();
+    let config = new_config(Pubkey::new_unique(), reward_mint, protocol_fee_account);
+    let accounts = vec![
+        mint_account(reward_mint, Some(mint_authority_address()), 0),
+        token_account(Pubkey::new_unique(), reward_mint, user, 0),
+        token_account(protocol_fee_account, reward_mint, config.admin, 0),
+        TestAccount::wallet(mint_authority_address(), 0),
+        TestAccount::program(spl_token::id()),
+    ];
+    (config, accounts)
+}